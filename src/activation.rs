@@ -0,0 +1,132 @@
+//! Socket activation for child processes
+//!
+//! Instead of waiting for the child to create its socket, eddi binds the
+//! listener itself and hands it to the child as file descriptor 3, using the
+//! systemd `LISTEN_FDS`/`LISTEN_PID` convention. The listener outlives any one
+//! child, so connections queue in its backlog while the app restarts.
+
+use crate::socket::{self, SocketPermissions};
+use anyhow::Result;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::info;
+
+/// First file descriptor used for passed sockets (`SD_LISTEN_FDS_START`)
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// Sets `LISTEN_PID` to the shell's own PID, which `exec` hands to the app
+const LISTEN_PID_WRAPPER: &str = r#"LISTEN_PID=$$; export LISTEN_PID; exec "$0" "$@""#;
+
+/// A listening socket bound by eddi on behalf of its child
+///
+/// The socket file is removed when this is dropped.
+#[derive(Debug)]
+pub struct ActivatedSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ActivatedSocket {
+    /// Bind a listener at `path`, with `permissions` applied before it appears
+    pub fn bind(path: &Path, permissions: &SocketPermissions) -> Result<Self> {
+        let listener = socket::bind_secured(path, permissions)?;
+
+        info!("Bound activation socket: {:?}", path);
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Get the socket path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Build a command that runs `program` with this socket as fd 3
+    ///
+    /// The program is started through `sh` so that `LISTEN_PID` can be set to
+    /// its final PID.
+    pub fn command(&self, program: &str, args: &[String]) -> Command {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(LISTEN_PID_WRAPPER)
+            .arg(program)
+            .args(args)
+            .env("LISTEN_FDS", "1");
+
+        let fd = self.listener.as_raw_fd();
+        // SAFETY: the closure only calls dup2(2) and fcntl(2), which are
+        // async-signal-safe, and does not allocate
+        unsafe {
+            command.pre_exec(move || pass_fd(fd));
+        }
+
+        command
+    }
+}
+
+impl Drop for ActivatedSocket {
+    fn drop(&mut self) {
+        socket::remove_socket_file(&self.path);
+    }
+}
+
+/// Place `fd` at `LISTEN_FDS_START` without close-on-exec (runs in the forked child)
+fn pass_fd(fd: RawFd) -> std::io::Result<()> {
+    if fd == LISTEN_FDS_START {
+        // dup2 onto itself is a no-op, so clear the flag directly
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+        if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    } else if unsafe { libc::dup2(fd, LISTEN_FDS_START) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_bind_applies_permissions_and_cleans_up() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("app.sock");
+
+        let socket = ActivatedSocket::bind(&path, &SocketPermissions::default()).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().mode() & 0o7777;
+        assert_eq!(mode, socket::DEFAULT_SOCKET_MODE);
+
+        // A second bind must not steal the live socket
+        assert!(ActivatedSocket::bind(&path, &SocketPermissions::default()).is_err());
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_child_receives_listen_fds() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("app.sock");
+        let socket = ActivatedSocket::bind(&path, &SocketPermissions::default()).unwrap();
+
+        let check = r#"[ "$LISTEN_FDS" = 1 ] && [ "$LISTEN_PID" = "$$" ] && [ -S /dev/fd/3 ]"#;
+        let status = socket
+            .command("sh", &["-c".to_string(), check.to_string()])
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+        // The listener stays usable by eddi after the child exits
+        assert!(std::os::unix::net::UnixStream::connect(&path).is_ok());
+    }
+}
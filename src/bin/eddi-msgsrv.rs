@@ -1,11 +1,26 @@
 // eddi message server binary
 
 use clap::Parser;
+use eddi::msgserver::commands::detach_listener;
+use eddi::msgserver::detach::Detached;
 use eddi::msgserver::{MsgSrvCli, execute_command};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
+    // Parse CLI arguments
+    let cli = MsgSrvCli::parse();
+
+    // Background listeners fork before the runtime starts any threads
+    let pidfile = match detach_listener(&cli.command) {
+        Ok(Some(Detached::Parent { .. })) => return Ok(()),
+        Ok(Some(Detached::Listener(pidfile))) => Some(pidfile),
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
     // Initialize logging
     tracing_subscriber::registry()
         .with(
@@ -15,11 +30,15 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // Parse CLI arguments
-    let cli = MsgSrvCli::parse();
+    let runtime = tokio::runtime::Runtime::new()?;
 
     // Execute command
-    if let Err(e) = execute_command(cli.command).await {
+    let result = runtime.block_on(execute_command(cli.command));
+
+    // Exiting skips destructors, so release the pidfile first
+    drop(pidfile);
+
+    if let Err(e) = result {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
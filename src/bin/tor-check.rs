@@ -11,7 +11,6 @@ use anyhow::Result;
 use std::time::Duration;
 use std::sync::Arc;
 use tracing::{info, warn, error};
-use tracing_subscriber;
 
 use arti_client::{TorClient, TorClientConfig};
 use tor_rtcompat::PreferredRuntime;
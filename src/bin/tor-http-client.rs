@@ -12,7 +12,6 @@ use arti_client::{TorClient, TorClientConfig};
 use tor_rtcompat::PreferredRuntime;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::{info, error, debug};
-use tracing_subscriber;
 use clap::Parser;
 
 /// tor-http-client - Test connections to onion services and websites via Tor
@@ -103,6 +102,7 @@ fn parse_url(url: &str) -> Result<(String, u16, String, bool)> {
 }
 
 /// Make an HTTP GET request over a Tor stream
+#[allow(clippy::too_many_arguments)]
 async fn http_get_over_tor(
     tor_client: &TorClient<PreferredRuntime>,
     address: &str,
@@ -0,0 +1,168 @@
+//! Local control socket for a running eddi instance
+//!
+//! `eddi ctl <command>` connects to the control socket of a running eddi and
+//! sends a single line; the server replies with `ok` or `error: <reason>`.
+
+use crate::socket::{self, SocketPermissions};
+use anyhow::{Context, Result};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{error, info, warn};
+
+/// Longest command line the server will read
+const MAX_COMMAND_LEN: u64 = 256;
+
+/// Commands accepted on the control socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Start a new app process and switch traffic to it
+    ReloadApp,
+}
+
+impl ControlCommand {
+    /// Wire name of the command
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ControlCommand::ReloadApp => "reload-app",
+        }
+    }
+
+    /// Parse a command from its wire name
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "reload-app" => Some(ControlCommand::ReloadApp),
+            _ => None,
+        }
+    }
+}
+
+/// Default control socket path for an app socket
+pub fn control_socket_path(app_socket: &Path) -> PathBuf {
+    let mut name = app_socket.as_os_str().to_os_string();
+    name.push(".ctl");
+    PathBuf::from(name)
+}
+
+/// Bind the control socket, readable only by the current user
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    let listener = socket::bind_secured(path, &SocketPermissions::default())
+        .with_context(|| format!("Failed to bind control socket: {:?}", path))?;
+    listener.set_nonblocking(true)?;
+    let listener = UnixListener::from_std(listener)?;
+
+    info!("Control socket listening on {:?}", path);
+    Ok(listener)
+}
+
+/// Accept control connections forever, running `handler` for each command
+pub async fn serve<F, Fut>(listener: UnixListener, handler: F)
+where
+    F: Fn(ControlCommand) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send,
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Control socket accept error: {}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, handler).await {
+                warn!("Control connection error: {:#}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection<F, Fut>(stream: UnixStream, handler: F) -> Result<()>
+where
+    F: Fn(ControlCommand) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half).take(MAX_COMMAND_LEN);
+
+    let mut line = String::new();
+    reader.read_line(&mut line).await?;
+
+    let reply = match ControlCommand::parse(line.trim()) {
+        Some(command) => {
+            info!("Control command received: {}", command.as_str());
+            match handler(command).await {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("error: {:#}", e),
+            }
+        }
+        None => format!("error: unknown command '{}'", line.trim()),
+    };
+
+    write_half.write_all(format!("{}\n", reply).as_bytes()).await?;
+    Ok(())
+}
+
+/// Send a command to a running eddi and wait for its reply
+pub async fn send_command(path: &Path, command: ControlCommand) -> Result<()> {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("Failed to connect to control socket {:?}. Is eddi running?", path))?;
+    let (read_half, mut write_half) = stream.into_split();
+
+    write_half
+        .write_all(format!("{}\n", command.as_str()).as_bytes())
+        .await?;
+
+    let mut reply = String::new();
+    BufReader::new(read_half).read_line(&mut reply).await?;
+
+    match reply.trim() {
+        "ok" => Ok(()),
+        "" => anyhow::bail!("eddi closed the control connection without replying"),
+        other => anyhow::bail!("{}", other.strip_prefix("error: ").unwrap_or(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_control_socket_path() {
+        assert_eq!(
+            control_socket_path(Path::new("/tmp/eddi.sock")),
+            PathBuf::from("/tmp/eddi.sock.ctl")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_round_trip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("eddi.ctl");
+
+        let listener = bind(&path).unwrap();
+        tokio::spawn(serve(listener, |command| async move {
+            assert_eq!(command, ControlCommand::ReloadApp);
+            Ok(())
+        }));
+
+        send_command(&path, ControlCommand::ReloadApp).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_command_error_is_reported() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("eddi.ctl");
+
+        let listener = bind(&path).unwrap();
+        tokio::spawn(serve(listener, |_| async { anyhow::bail!("app failed readiness") }));
+
+        let err = send_command(&path, ControlCommand::ReloadApp).await.unwrap_err();
+        assert!(err.to_string().contains("app failed readiness"));
+    }
+}
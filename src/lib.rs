@@ -4,6 +4,17 @@
 //! bound to Unix Domain Sockets and exposing them via Arti onion services.
 
 pub mod process;
+pub mod output;
+pub mod probe;
+pub mod supervisor;
+pub mod socket;
+pub mod activation;
+pub mod control;
 pub mod msgserver;
 
 pub use process::{ChildProcessManager, ProcessConfig};
+pub use output::{OutputConfig, OutputStream};
+pub use probe::{ProbeConfig, ProbeKind, Prober};
+pub use supervisor::Supervisor;
+pub use socket::SocketPermissions;
+pub use activation::ActivatedSocket;
@@ -20,8 +20,8 @@ use anyhow::{Context, Result, bail};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::fs;
+use std::time::Duration;
 use tracing::{info, warn, error, debug};
-use tracing_subscriber;
 use clap::Parser;
 
 use arti_client::TorClient;
@@ -35,7 +35,9 @@ use tokio::net::UnixStream;
 use tokio::io::AsyncWriteExt;
 use futures::StreamExt;
 
-use eddi::{ChildProcessManager, ProcessConfig};
+use eddi::control::{self, ControlCommand};
+use eddi::supervisor::Backend;
+use eddi::{OutputConfig, ProbeConfig, ProbeKind, ProcessConfig, SocketPermissions, Supervisor};
 
 /// eddi - Serve web applications over Tor via Unix Domain Sockets
 ///
@@ -45,13 +47,26 @@ use eddi::{ChildProcessManager, ProcessConfig};
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Unix Domain Socket path to connect to
     ///
     /// Path where your web server (gunicorn, uvicorn, nginx, etc.) is listening.
     /// Example: /tmp/my-app.sock
-    #[arg(short = 's', long, default_value = "/tmp/eddi.sock")]
+    #[arg(short = 's', long, global = true, default_value = "/tmp/eddi.sock")]
     socket: PathBuf,
 
+    /// Control socket path (default: <socket>.ctl)
+    ///
+    /// Used by `eddi ctl` to talk to a running eddi.
+    #[arg(long, global = true)]
+    control_socket: Option<PathBuf>,
+
+    /// Time to let open connections to the old app finish after a reload
+    #[arg(long, default_value = "30")]
+    drain_timeout: u64,
+
     /// Onion service nickname
     ///
     /// A unique identifier for this onion service. Used to store and retrieve
@@ -106,6 +121,120 @@ struct Cli {
     /// on the Unix Domain Socket.
     #[arg(long)]
     no_spawn: bool,
+
+    /// Directory for the child's stdout/stderr log files (optional)
+    ///
+    /// Output is always routed into eddi's logs. When set, it is also written
+    /// to <process>.stdout.log and <process>.stderr.log in this directory.
+    #[arg(long)]
+    app_log_dir: Option<PathBuf>,
+
+    /// Maximum size of a child log file in bytes before it is rotated
+    #[arg(long, default_value_t = eddi::output::DEFAULT_MAX_FILE_SIZE)]
+    app_log_max_size: u64,
+
+    /// Number of rotated child log files to keep
+    #[arg(long, default_value_t = eddi::output::DEFAULT_MAX_FILES)]
+    app_log_max_files: usize,
+
+    /// Probe used to decide whether the app is ready
+    ///
+    /// connect: the socket accepts a connection.
+    /// http: GET --probe-path over the socket returns --probe-status.
+    /// exec: --probe-command exits with status 0.
+    #[arg(long, value_enum, default_value = "connect")]
+    probe: ProbeType,
+
+    /// Path requested by the http probe
+    #[arg(long, default_value = "/")]
+    probe_path: String,
+
+    /// Status code expected from the http probe
+    #[arg(long, default_value = "200")]
+    probe_status: u16,
+
+    /// Shell command run by the exec probe (socket path in $EDDI_SOCKET_PATH)
+    #[arg(long)]
+    probe_command: Option<String>,
+
+    /// Delay before the first probe, in milliseconds
+    #[arg(long, default_value = "0")]
+    probe_initial_delay_ms: u64,
+
+    /// Time between probes, in milliseconds
+    #[arg(long, default_value = "500")]
+    probe_interval_ms: u64,
+
+    /// Time allowed for a single probe, in milliseconds
+    #[arg(long, default_value = "2000")]
+    probe_timeout_ms: u64,
+
+    /// Consecutive liveness failures before the app is restarted
+    #[arg(long, default_value = "3")]
+    probe_failure_threshold: u32,
+
+    /// Seconds to wait for the app to pass its readiness probe
+    #[arg(long, default_value = "10")]
+    ready_timeout: u64,
+
+    /// Keep probing the app while running and restart it when unhealthy
+    #[arg(long)]
+    liveness: bool,
+
+    /// Give up after restarting the app this many times
+    #[arg(long, default_value = "5")]
+    max_restarts: u32,
+
+    /// Octal mode enforced on the app's socket file
+    #[arg(long, default_value = "600")]
+    socket_mode: String,
+
+    /// User (name or uid) that should own the app's socket file
+    #[arg(long)]
+    socket_owner: Option<String>,
+
+    /// Group (name or gid) that should own the app's socket file
+    #[arg(long)]
+    socket_group: Option<String>,
+
+    /// Bind the app's socket in eddi and pass it to the app (fd 3, LISTEN_FDS)
+    #[arg(long)]
+    socket_activation: bool,
+}
+
+/// Subcommands (running without one starts eddi)
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Control a running eddi instance
+    Ctl {
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+}
+
+/// Actions for `eddi ctl`
+#[derive(clap::Subcommand, Debug, Clone, Copy)]
+enum CtlAction {
+    /// Start a new app process, switch traffic to it, then stop the old one
+    ///
+    /// Equivalent to sending SIGHUP to eddi.
+    ReloadApp,
+}
+
+impl CtlAction {
+    fn command(self) -> ControlCommand {
+        match self {
+            CtlAction::ReloadApp => ControlCommand::ReloadApp,
+        }
+    }
+}
+
+/// Kinds of readiness/liveness probe selectable from the CLI
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ProbeType {
+    Connect,
+    Http,
+    Exec,
 }
 
 /// Configuration for the eddi application
@@ -133,6 +262,33 @@ struct EddiConfig {
 
     /// Whether to spawn the child process
     should_spawn: bool,
+
+    /// How the child's stdout/stderr are captured
+    app_output: OutputConfig,
+
+    /// Probe used for readiness (and liveness, if enabled)
+    probe: ProbeConfig,
+
+    /// Seconds to wait for the app to become ready
+    ready_timeout: u64,
+
+    /// Whether to run the probe continuously and restart on failure
+    liveness: bool,
+
+    /// Maximum number of automatic restarts
+    max_restarts: u32,
+
+    /// Owner, group and mode enforced on the socket
+    socket_permissions: SocketPermissions,
+
+    /// Whether eddi binds the socket and passes it to the app
+    socket_activation: bool,
+
+    /// Path of the control socket used by `eddi ctl`
+    control_socket: PathBuf,
+
+    /// Time to let connections to an old app drain after a reload
+    drain_timeout: Duration,
 }
 
 impl EddiConfig {
@@ -148,6 +304,25 @@ impl EddiConfig {
             PathBuf::from(home).join(".eddi").join("onion-services")
         };
 
+        let kind = match cli.probe {
+            ProbeType::Connect => ProbeKind::Connect,
+            ProbeType::Http => ProbeKind::Http {
+                path: cli.probe_path,
+                expected_status: cli.probe_status,
+            },
+            ProbeType::Exec => ProbeKind::Exec {
+                command: "sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    cli.probe_command.context("--probe-command is required with --probe exec")?,
+                ],
+            },
+        };
+
+        let control_socket = cli
+            .control_socket
+            .unwrap_or_else(|| control::control_socket_path(&cli.socket));
+
         Ok(Self {
             socket_path: cli.socket,
             app_dir: cli.app_dir,
@@ -157,6 +332,30 @@ impl EddiConfig {
             key_dir,
             test_connection: cli.test_connection,
             should_spawn: !cli.no_spawn,
+            app_output: OutputConfig {
+                log_dir: cli.app_log_dir,
+                max_file_size: cli.app_log_max_size,
+                max_files: cli.app_log_max_files,
+                ..Default::default()
+            },
+            probe: ProbeConfig {
+                kind,
+                initial_delay: Duration::from_millis(cli.probe_initial_delay_ms),
+                interval: Duration::from_millis(cli.probe_interval_ms),
+                timeout: Duration::from_millis(cli.probe_timeout_ms),
+                failure_threshold: cli.probe_failure_threshold,
+            },
+            ready_timeout: cli.ready_timeout,
+            liveness: cli.liveness,
+            max_restarts: cli.max_restarts,
+            socket_permissions: SocketPermissions {
+                mode: Some(eddi::socket::parse_mode(&cli.socket_mode)?),
+                owner: cli.socket_owner,
+                group: cli.socket_group,
+            },
+            socket_activation: cli.socket_activation,
+            control_socket,
+            drain_timeout: Duration::from_secs(cli.drain_timeout),
         })
     }
 
@@ -192,10 +391,15 @@ async fn test_uds_connection(socket_path: &PathBuf) -> Result<bool> {
 }
 
 /// Handle an incoming stream request from the onion service
+///
+/// `backend` is held until the stream closes so that a reload can tell when
+/// the previous app process has no connections left.
 async fn handle_stream_request(
     stream_request: StreamRequest,
-    socket_path: Arc<PathBuf>,
+    backend: Arc<Backend>,
 ) -> Result<()> {
+    let socket_path = backend.socket_path();
+
     match stream_request.request() {
         IncomingStreamRequest::Begin(begin) => {
             let port = begin.port();
@@ -218,7 +422,7 @@ async fn handle_stream_request(
             info!("Connecting to Unix socket: {:?}", socket_path);
 
             // Connect to the Unix socket
-            let mut unix_stream = UnixStream::connect(socket_path.as_ref())
+            let mut unix_stream = UnixStream::connect(socket_path)
                 .await
                 .context("Failed to connect to Unix socket")?;
 
@@ -314,32 +518,38 @@ async fn run_eddi(config: EddiConfig) -> Result<()> {
     info!("");
 
     // Step 3: Handle child process or verify existing connection
-    let child_process = if config.should_spawn {
+    let supervisor = if config.should_spawn {
         if config.app_dir.is_none() {
             bail!("--app-dir is required when spawning a child process. Use --no-spawn if the app is already running.");
         }
 
         info!("Step 3: Spawning child process...");
-        let process_config = ProcessConfig::gunicorn(
+        let mut process_config = ProcessConfig::gunicorn(
             config.socket_path.clone(),
             config.app_dir.clone().unwrap(),
             &config.app_module,
             config.workers,
         );
+        process_config.output = config.app_output.clone();
+        process_config.readiness = config.probe.clone();
+        process_config.socket_permissions = config.socket_permissions.clone();
+        if config.liveness {
+            process_config.liveness = Some(config.probe.clone());
+        }
+        if config.socket_activation {
+            process_config = process_config.with_socket_activation();
+        }
 
-        let child = ChildProcessManager::spawn(&process_config)
-            .context("Failed to spawn child process")?;
-
-        info!("✓ Child process spawned (PID: {})", child.pid());
-
-        // Wait for the child process to be ready
-        child
-            .wait_for_ready(10)
-            .await
-            .context("Child process failed to become ready")?;
+        // Spawn the child and wait for its readiness probe
+        let mut supervisor = Supervisor::start(process_config, config.ready_timeout, config.max_restarts)
+            .await?;
+        supervisor.set_drain_timeout(config.drain_timeout);
 
+        if let Some(child) = supervisor.child() {
+            info!("✓ Child process spawned (PID: {})", child.pid());
+        }
         info!("✓ Child process is ready and accepting connections");
-        Some(child)
+        Some(supervisor)
     } else {
         info!("Step 3: Skipping child process spawn (--no-spawn)");
         info!("Assuming web application is already running on: {:?}", config.socket_path);
@@ -408,11 +618,12 @@ async fn run_eddi(config: EddiConfig) -> Result<()> {
     info!("🔑  Onion Service Keys:");
     info!("     {:?}", config.get_key_storage_path());
     info!("");
-    if let Some(ref child) = child_process {
+    if let Some(child) = supervisor.as_ref().and_then(|s| s.child()) {
         info!("⚙️   Web Application:");
         info!("     Process PID: {}", child.pid());
         info!("     Workers: {}", config.workers);
         info!("     Module: {}", config.app_module);
+        info!("     Liveness probe: {}", if config.liveness { "enabled" } else { "disabled" });
         info!("");
     }
     info!("🔒  Security:");
@@ -429,24 +640,78 @@ async fn run_eddi(config: EddiConfig) -> Result<()> {
     info!("Step 6: Accepting incoming connections...");
     info!("");
 
-    let socket_path = Arc::new(config.socket_path.clone());
-    let stream_requests = handle_rend_requests(request_stream);
-    tokio::pin!(stream_requests);
-
-    while let Some(stream_request) = stream_requests.next().await {
-        let socket_path = Arc::clone(&socket_path);
+    // New connections go to whichever app process is current
+    let (_static_backend, mut backend) = tokio::sync::watch::channel(Arc::new(Backend::new(
+        config.socket_path.clone(),
+    )));
+    if let Some(ref supervisor) = supervisor {
+        backend = supervisor.backend();
+
+        // Reload on `eddi ctl reload-app` or SIGHUP
+        let reload = supervisor.reload_handle();
+        let listener = control::bind(&config.control_socket)?;
+        tokio::spawn(control::serve(listener, move |command| {
+            let reload = reload.clone();
+            async move {
+                match command {
+                    ControlCommand::ReloadApp => reload.reload().await,
+                }
+            }
+        }));
 
-        // Spawn a new task for each incoming connection
+        let reload = supervisor.reload_handle();
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .context("Failed to install SIGHUP handler")?;
         tokio::spawn(async move {
-            if let Err(e) = handle_stream_request(stream_request, socket_path).await {
-                error!("Error handling stream: {}", e);
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading application...");
+                if let Err(e) = reload.reload().await {
+                    error!("Reload failed: {:#}", e);
+                }
             }
         });
+
+        info!("Reload the app with: eddi ctl reload-app --control-socket {:?}", config.control_socket);
+        info!("");
     }
 
-    info!("Request stream ended, shutting down...");
+    let stream_requests = handle_rend_requests(request_stream);
+    tokio::pin!(stream_requests);
+
+    // Keep the child running (restarting it on exit or liveness failure)
+    let supervision = async move {
+        match supervisor {
+            Some(supervisor) => supervisor.run().await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(supervision);
+
+    loop {
+        tokio::select! {
+            next = stream_requests.next() => {
+                let Some(stream_request) = next else {
+                    info!("Request stream ended, shutting down...");
+                    break;
+                };
+                let backend = Arc::clone(&backend.borrow());
+
+                // Spawn a new task for each incoming connection
+                tokio::spawn(async move {
+                    if let Err(e) = handle_stream_request(stream_request, backend).await {
+                        error!("Error handling stream: {}", e);
+                    }
+                });
+            }
+            result = &mut supervision => {
+                result.context("Web application could not be kept running")?;
+                break;
+            }
+        }
+    }
 
-    // child_process will be cleaned up when it's dropped
+    // The child process is cleaned up when the supervisor is dropped
+    eddi::socket::remove_socket_file(&config.control_socket);
     Ok(())
 }
 
@@ -463,6 +728,16 @@ async fn main() -> Result<()> {
     // Parse command-line arguments
     let cli = Cli::parse();
 
+    // `eddi ctl ...` talks to a running instance and exits
+    if let Some(Command::Ctl { action }) = cli.command {
+        let path = cli
+            .control_socket
+            .unwrap_or_else(|| control::control_socket_path(&cli.socket));
+        control::send_command(&path, action.command()).await?;
+        println!("✓ {} succeeded", action.command().as_str());
+        return Ok(());
+    }
+
     // Create configuration from CLI
     let config = EddiConfig::from_cli(cli)?;
 
@@ -487,7 +762,7 @@ mod tests {
 
     #[test]
     fn test_eddi_config_default() {
-        let config = EddiConfig::default();
+        let config = EddiConfig::from_cli(Cli::parse_from(["eddi", "--key-dir", "keys"])).unwrap();
         assert_eq!(config.socket_path, PathBuf::from("/tmp/eddi.sock"));
         assert_eq!(config.workers, 2);
         assert_eq!(config.app_module, "app:app");
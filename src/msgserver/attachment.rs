@@ -0,0 +1,213 @@
+// File attachments
+//
+// Frames are lines of JSON, so files travel beside the message content rather
+// than in it. A client uploads a file in base64 encoded chunks, each with its own
+// SHA-256, after announcing its name, size and hash; the server checks every
+// chunk and the whole file against them before a message may refer to it. The
+// file is kept for as long as that message, and receivers that can see the
+// message fetch it the same way, chunk by chunk.
+
+use crate::msgserver::message::ProtocolMessage;
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Bytes of file data per chunk (a third more once base64 encoded)
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Largest attachment a server accepts unless configured otherwise (10 MiB)
+pub const DEFAULT_MAX_ATTACHMENT_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Most bytes of attachments a server keeps at once, counting uploads in
+/// progress, unless configured otherwise (1 GiB)
+pub const DEFAULT_MAX_ATTACHMENT_STORAGE: u64 = 1024 * 1024 * 1024;
+
+/// How long an upload may go without a chunk before it is abandoned
+pub const UPLOAD_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Longest attachment file name, in bytes
+pub const MAX_FILE_NAME_LEN: usize = 255;
+
+/// What a message says about the file attached to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttachmentInfo {
+    /// ID the server gave the upload
+    pub id: String,
+    /// File name, without any directory
+    pub name: String,
+    /// Size in bytes
+    pub size: u64,
+    /// Hex encoded SHA-256 of the whole file
+    pub sha256: String,
+}
+
+/// Hex encoded SHA-256 of some data
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Check an attachment's file name
+///
+/// Names are a single path component, so a receiver can save the file under
+/// it without it landing anywhere else.
+pub fn validate_file_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_FILE_NAME_LEN
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0'])
+        && !name.chars().any(char::is_control);
+
+    if !valid {
+        anyhow::bail!("Invalid attachment file name '{}'", name.escape_debug());
+    }
+
+    Ok(())
+}
+
+/// The chunk frames that carry `data` for attachment `attachment_id`
+pub fn chunks<'a>(attachment_id: &'a str, data: &'a [u8]) -> impl Iterator<Item = ProtocolMessage> + 'a {
+    data.chunks(CHUNK_SIZE).enumerate().map(move |(index, chunk)| ProtocolMessage::AttachmentChunk {
+        attachment_id: attachment_id.to_string(),
+        index: index as u32,
+        data: BASE64.encode(chunk),
+        sha256: sha256_hex(chunk),
+    })
+}
+
+/// A file being put back together from its chunks
+#[derive(Debug)]
+pub struct Assembly {
+    info: AttachmentInfo,
+    data: Vec<u8>,
+    next_index: u32,
+}
+
+impl Assembly {
+    /// Start receiving the file `info` describes
+    pub fn new(info: AttachmentInfo) -> Self {
+        Self {
+            info,
+            data: Vec::new(),
+            next_index: 0,
+        }
+    }
+
+    /// The file being received
+    pub fn info(&self) -> &AttachmentInfo {
+        &self.info
+    }
+
+    /// Add the next chunk, checking its hash and that the file stays in size
+    pub fn add(&mut self, index: u32, data: &str, sha256: &str) -> Result<()> {
+        if index != self.next_index {
+            anyhow::bail!("Expected chunk {} of {}, got chunk {}", self.next_index, self.info.name, index);
+        }
+
+        let bytes = BASE64.decode(data).context("Malformed attachment chunk")?;
+        if sha256_hex(&bytes) != sha256 {
+            anyhow::bail!("Chunk {} of {} does not match its hash", index, self.info.name);
+        }
+        if bytes.is_empty() || bytes.len() > CHUNK_SIZE || self.data.len() + bytes.len() > self.info.size as usize {
+            anyhow::bail!("Chunk {} of {} does not fit the announced size", index, self.info.name);
+        }
+
+        self.data.extend(bytes);
+        self.next_index += 1;
+        Ok(())
+    }
+
+    /// Whether every byte announced has arrived
+    pub fn is_complete(&self) -> bool {
+        self.data.len() as u64 == self.info.size
+    }
+
+    /// The whole file, once it is complete and matches its hash
+    pub fn finish(self) -> Result<(AttachmentInfo, Vec<u8>)> {
+        if !self.is_complete() {
+            anyhow::bail!(
+                "Attachment {} is incomplete ({} of {} bytes)",
+                self.info.name,
+                self.data.len(),
+                self.info.size
+            );
+        }
+        if sha256_hex(&self.data) != self.info.sha256 {
+            anyhow::bail!("Attachment {} does not match its hash", self.info.name);
+        }
+
+        Ok((self.info, self.data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info_for(data: &[u8]) -> AttachmentInfo {
+        AttachmentInfo {
+            id: "attachment-id".to_string(),
+            name: "data.bin".to_string(),
+            size: data.len() as u64,
+            sha256: sha256_hex(data),
+        }
+    }
+
+    fn assemble(info: AttachmentInfo, frames: impl Iterator<Item = ProtocolMessage>) -> Result<Vec<u8>> {
+        let mut assembly = Assembly::new(info);
+        for frame in frames {
+            let ProtocolMessage::AttachmentChunk { index, data, sha256, .. } = frame else {
+                panic!("Not a chunk: {:?}", frame);
+            };
+            assembly.add(index, &data, &sha256)?;
+        }
+        assembly.finish().map(|(_, data)| data)
+    }
+
+    #[test]
+    fn test_chunks_reassemble() {
+        // Binary data spanning several chunks, the last one partial
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        assert_eq!(chunks("id", &data).count(), 3);
+        assert_eq!(assemble(info_for(&data), chunks("id", &data)).unwrap(), data);
+
+        assert_eq!(assemble(info_for(b""), chunks("id", b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_bad_chunks_are_refused() {
+        let data = vec![7u8; CHUNK_SIZE + 1];
+        let frames: Vec<_> = chunks("id", &data).collect();
+
+        // Out of order
+        assert!(assemble(info_for(&data), frames.iter().rev().cloned()).is_err());
+
+        // Tampered with in transit
+        let mut tampered = frames.clone();
+        if let ProtocolMessage::AttachmentChunk { ref mut data, .. } = tampered[1] {
+            *data = "CA==".to_string();
+        }
+        assert!(assemble(info_for(&data), tampered.into_iter()).is_err());
+
+        // More than announced, or not all of it
+        let short = AttachmentInfo { size: 10, ..info_for(&data) };
+        assert!(assemble(short, frames.iter().cloned()).is_err());
+        assert!(assemble(info_for(&data), frames.iter().take(1).cloned()).is_err());
+
+        // Consistent chunks, but not the file that was promised
+        let other = AttachmentInfo { sha256: sha256_hex(b"other"), ..info_for(&data) };
+        assert!(assemble(other, frames.into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_file_names() {
+        assert!(validate_file_name("report.pdf").is_ok());
+        assert!(validate_file_name("notes v2 (final).txt").is_ok());
+        for name in ["", ".", "..", "../etc/passwd", "dir/file", "a\\b", "bell\u{7}"] {
+            assert!(validate_file_name(name).is_err(), "{:?} should be refused", name);
+        }
+        assert!(validate_file_name(&"x".repeat(MAX_FILE_NAME_LEN + 1)).is_err());
+    }
+}
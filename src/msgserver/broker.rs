@@ -1,33 +1,77 @@
 // Message broker for broadcasting and routing
 
+use crate::msgserver::attachment::{
+    self, Assembly, AttachmentInfo, DEFAULT_MAX_ATTACHMENT_SIZE, DEFAULT_MAX_ATTACHMENT_STORAGE, UPLOAD_IDLE_TIMEOUT,
+};
 use crate::msgserver::client::ClientManager;
-use crate::msgserver::message::{MessageQueue, ProtocolMessage};
-use crate::msgserver::storage::{ClientStatus, StateManager};
+use crate::msgserver::outbox::SlowConsumerPolicy;
+use crate::msgserver::e2e::PeerKey;
+use crate::msgserver::hook::{Hook, HookSender};
+use crate::msgserver::identity::{MessageSignature, Origin};
+use crate::msgserver::limits::{ClientLimits, LimitAction, Limiter, Queued, Violation};
+use crate::msgserver::message::{self, Audience, Envelope, ErrorCode, MessageQueue, MessageStore, ProtocolMessage};
+use crate::msgserver::storage::{ClientConfig, ClientStatus, StateManager};
 use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, Mutex, Notify};
+use uuid::Uuid;
+
+/// How often authenticated connections are re-checked for revocation and expiry
+pub const ACCESS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most attachments a connection may have uploaded but not sent yet
+pub const MAX_PENDING_UPLOADS: usize = 4;
+
+/// How long a client may leave the next chunk of a file it fetched unread
+/// before it is disconnected
+pub const FETCH_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Commands that may wait for the broker before senders have to wait too
+pub const BROKER_QUEUE_CAPACITY: usize = 1024;
+
+/// Failed authentication attempts after which a connection is disconnected
+pub const MAX_AUTH_FAILURES: u32 = 5;
+
+/// How long a connection must wait to retry after its first failed
+/// authentication attempt; the wait doubles with each further failure
+pub const AUTH_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Wrong codes or tokens the server takes in `AUTH_FAILURE_WINDOW`, from all
+/// connections together, before any further one ends its connection at once
+pub const MAX_SERVER_AUTH_FAILURES: usize = 20;
+
+/// How far back `MAX_SERVER_AUTH_FAILURES` counts
+pub const AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(60);
 
 /// Handle for communicating with the broker
 #[derive(Clone)]
 pub struct BrokerHandle {
-    pub tx: mpsc::UnboundedSender<BrokerCommand>,
+    pub tx: mpsc::Sender<BrokerCommand>,
 }
 
 impl BrokerHandle {
-    pub fn new(tx: mpsc::UnboundedSender<BrokerCommand>) -> Self {
+    pub fn new(tx: mpsc::Sender<BrokerCommand>) -> Self {
         Self { tx }
     }
 
-    /// Send a command to the broker
-    pub fn send_command(&self, cmd: BrokerCommand) -> Result<()> {
+    /// Send a command to the broker, waiting while it is behind
+    pub async fn send_command(&self, cmd: BrokerCommand) -> Result<()> {
         self.tx
             .send(cmd)
-            .context("Failed to send command to broker")
+            .await
+            .map_err(|_| anyhow::anyhow!("Failed to send command to broker"))
+    }
+
+    /// Send a command to the broker if it has room for it now
+    pub fn try_send_command(&self, cmd: BrokerCommand) -> Result<(), mpsc::error::TrySendError<BrokerCommand>> {
+        self.tx.try_send(cmd)
     }
 
     /// Get the command sender
-    pub fn get_sender(&self) -> mpsc::UnboundedSender<BrokerCommand> {
+    pub fn get_sender(&self) -> mpsc::Sender<BrokerCommand> {
         self.tx.clone()
     }
 }
@@ -38,21 +82,109 @@ pub enum BrokerCommand {
     /// Client sent a message
     ClientMessage {
         client_id: String,
-        message: ProtocolMessage,
+        /// ID to send the replies with, if the client gave one
+        request_id: Option<String>,
+        message: Box<ProtocolMessage>,
     },
     /// Client disconnected
     ClientDisconnected { client_id: String },
+    /// Re-check authenticated connections now (e.g. after a revocation)
+    EnforceAccess,
     /// Shutdown the broker
     Shutdown,
 }
 
+/// A file a connection is uploading (with when it last sent a chunk), or
+/// has uploaded but not sent yet
+enum Upload {
+    Receiving(Assembly, Instant),
+    Complete(AttachmentInfo, Vec<u8>),
+}
+
+impl Upload {
+    /// The file's announced size, which it is allowed to grow to
+    fn size(&self) -> u64 {
+        match self {
+            Upload::Receiving(assembly, _) => assembly.info().size,
+            Upload::Complete(info, _) => info.size,
+        }
+    }
+}
+
+/// The connection a request came from, and the ID to reply to it with
+#[derive(Debug, Clone, Copy)]
+struct Request<'a> {
+    client_id: &'a str,
+    id: Option<&'a str>,
+    // Set once anything has been sent in reply
+    replied: &'a AtomicBool,
+}
+
+/// A connection's claim on sending it a file; released when dropped
+struct FetchSlot {
+    fetches: Arc<std::sync::Mutex<HashSet<String>>>,
+    client_id: String,
+}
+
+impl FetchSlot {
+    /// Claim the connection's slot, unless a file is being sent to it already
+    fn claim(fetches: &Arc<std::sync::Mutex<HashSet<String>>>, client_id: &str) -> Option<Self> {
+        fetches.lock().unwrap().insert(client_id.to_string()).then(|| Self {
+            fetches: fetches.clone(),
+            client_id: client_id.to_string(),
+        })
+    }
+}
+
+impl Drop for FetchSlot {
+    fn drop(&mut self) {
+        self.fetches.lock().unwrap().remove(&self.client_id);
+    }
+}
+
+/// A connection's failed authentication attempts
+#[derive(Debug)]
+struct AuthFailures {
+    count: u32,
+    retry_at: Instant,
+}
+
+/// How an authentication attempt went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AuthOutcome {
+    Authenticated,
+    // The code or token was wrong
+    BadCredential,
+    // Refused for another reason, such as the alias being taken
+    Refused,
+}
+
 /// Message broker that handles routing and broadcasting
 pub struct MessageBroker {
-    queue: Arc<MessageQueue>,
+    queue: Arc<dyn MessageStore>,
     client_manager: Arc<ClientManager>,
     state_manager: Option<Arc<StateManager>>,
     server_id: Option<String>,
-    rx: mpsc::UnboundedReceiver<BrokerCommand>,
+    // Each queued message is also handed to the server's hook, if it has one
+    hook_tx: Option<HookSender>,
+    max_attachment_size: u64,
+    max_attachment_storage: u64,
+    upload_idle_timeout: Duration,
+    limiter: Mutex<Limiter>,
+    // Uploads by attachment ID, with the connection they belong to
+    uploads: Mutex<HashMap<String, (String, Upload)>>,
+    // Connections a fetched file is being sent to, one file at a time each
+    fetches: Arc<std::sync::Mutex<HashSet<String>>>,
+    // Failed authentication attempts by connection
+    auth_failures: Mutex<HashMap<String, AuthFailures>>,
+    auth_retry_delay: Duration,
+    // When the last `max_server_auth_failures` wrong credentials were tried,
+    // on any connection; kept across reconnects so they cannot start the
+    // count over
+    recent_auth_failures: Mutex<VecDeque<Instant>>,
+    max_server_auth_failures: usize,
+    auth_failure_window: Duration,
+    rx: mpsc::Receiver<BrokerCommand>,
 }
 
 impl MessageBroker {
@@ -63,13 +195,25 @@ impl MessageBroker {
         state_manager: Option<Arc<StateManager>>,
         server_id: Option<String>,
     ) -> (Self, BrokerHandle) {
-        let (tx, rx) = mpsc::unbounded_channel();
+        let (tx, rx) = mpsc::channel(BROKER_QUEUE_CAPACITY);
 
         let broker = Self {
             queue: Arc::new(MessageQueue::new(ttl, max_queue_size)),
             client_manager: Arc::new(ClientManager::new()),
             state_manager,
             server_id,
+            hook_tx: None,
+            max_attachment_size: DEFAULT_MAX_ATTACHMENT_SIZE,
+            max_attachment_storage: DEFAULT_MAX_ATTACHMENT_STORAGE,
+            upload_idle_timeout: UPLOAD_IDLE_TIMEOUT,
+            limiter: Mutex::new(Limiter::new(ClientLimits::default())),
+            uploads: Mutex::new(HashMap::new()),
+            fetches: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            auth_failures: Mutex::new(HashMap::new()),
+            auth_retry_delay: AUTH_RETRY_DELAY,
+            recent_auth_failures: Mutex::new(VecDeque::new()),
+            max_server_auth_failures: MAX_SERVER_AUTH_FAILURES,
+            auth_failure_window: AUTH_FAILURE_WINDOW,
             rx,
         };
 
@@ -78,13 +222,74 @@ impl MessageBroker {
         (broker, handle)
     }
 
+    /// Keep messages in another store (e.g. a `PersistentMessageQueue`)
+    pub fn with_message_store(mut self, store: Arc<dyn MessageStore>) -> Self {
+        self.queue = store;
+        self
+    }
+
+    /// Run a hook for each message sent through this broker
+    pub fn with_hook(mut self, hook: Hook) -> Self {
+        self.hook_tx = Some(hook.spawn());
+        self
+    }
+
+    /// Accept attachments up to `max_size` bytes
+    pub fn with_max_attachment_size(mut self, max_size: u64) -> Self {
+        self.max_attachment_size = max_size;
+        self
+    }
+
+    /// Keep at most `max_total` bytes of attachments at once, counting the
+    /// announced size of uploads not sent yet
+    pub fn with_max_attachment_storage(mut self, max_total: u64) -> Self {
+        self.max_attachment_storage = max_total;
+        self
+    }
+
+    /// Abandon uploads that go `timeout` without a chunk
+    pub fn with_upload_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.upload_idle_timeout = timeout;
+        self
+    }
+
+    /// Limit what each client may send
+    pub fn with_limits(mut self, limits: ClientLimits) -> Self {
+        self.limiter = Mutex::new(Limiter::new(limits));
+        self
+    }
+
+    /// Make connections wait `delay` to retry after a failed authentication
+    /// attempt, doubling with each further failure
+    pub fn with_auth_retry_delay(mut self, delay: Duration) -> Self {
+        self.auth_retry_delay = delay;
+        self
+    }
+
+    /// Cut off each connection at its first wrong credential once `failures`
+    /// wrong ones have been tried within `window`, on whichever connections
+    pub fn with_auth_failure_budget(mut self, failures: usize, window: Duration) -> Self {
+        self.max_server_auth_failures = failures;
+        self.auth_failure_window = window;
+        self
+    }
+
+    /// Queue up to `capacity` frames for each client, handling clients that
+    /// fall further behind by `policy`
+    ///
+    /// Call before any client connects.
+    pub fn with_client_queue(mut self, capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        self.client_manager = Arc::new(ClientManager::new().with_outbox(capacity, policy));
+        self
+    }
+
     /// Get the client manager
     pub fn client_manager(&self) -> Arc<ClientManager> {
         self.client_manager.clone()
     }
 
     /// Get the message queue
-    pub fn message_queue(&self) -> Arc<MessageQueue> {
+    pub fn message_queue(&self) -> Arc<dyn MessageStore> {
         self.queue.clone()
     }
 
@@ -93,19 +298,53 @@ impl MessageBroker {
         tracing::info!("Message broker started");
 
         // Start cleanup task
-        let queue = self.queue.clone();
-        queue.start_cleanup_task(Duration::from_secs(30));
+        message::start_expiry_sweep(self.queue.clone(), Duration::from_secs(30));
+
+        // Access checks read the store, so they run beside the broker rather than on it
+        let access_check = Arc::new(Notify::new());
+        let enforcer = self.state_manager.clone().map(|state_manager| {
+            tokio::spawn(enforce_access(state_manager, self.client_manager.clone(), access_check.clone()))
+        });
+
+        let mut upload_sweep = tokio::time::interval(self.upload_idle_timeout / 2);
+
+        loop {
+            let cmd = tokio::select! {
+                cmd = self.rx.recv() => match cmd {
+                    Some(cmd) => cmd,
+                    None => break,
+                },
+                _ = upload_sweep.tick() => {
+                    self.drop_stale_uploads().await;
+                    continue;
+                }
+            };
 
-        while let Some(cmd) = self.rx.recv().await {
             match cmd {
-                BrokerCommand::ClientMessage { client_id, message } => {
-                    if let Err(e) = self.handle_client_message(&client_id, message).await {
+                BrokerCommand::ClientMessage { client_id, request_id, message } => {
+                    let replied = AtomicBool::new(false);
+                    let req = Request {
+                        client_id: &client_id,
+                        id: request_id.as_deref(),
+                        replied: &replied,
+                    };
+                    if let Err(e) = self.handle_client_message(req, *message).await {
                         tracing::error!("Error handling client message: {}", e);
+                        // The request still gets an answer, but only the one
+                        if !replied.load(Ordering::Relaxed) {
+                            let _ = self.send_error(req, ErrorCode::Internal, "Internal server error").await;
+                        }
                     }
                 }
                 BrokerCommand::ClientDisconnected { client_id } => {
                     tracing::info!("Client {} disconnected", client_id);
                     self.client_manager.remove_client(&client_id).await;
+                    // Files it never sent are not needed any more
+                    self.uploads.lock().await.retain(|_, (owner, _)| *owner != client_id);
+                    self.auth_failures.lock().await.remove(&client_id);
+                }
+                BrokerCommand::EnforceAccess => {
+                    access_check.notify_one();
                 }
                 BrokerCommand::Shutdown => {
                     tracing::info!("Broker shutting down");
@@ -114,83 +353,308 @@ impl MessageBroker {
             }
         }
 
+        if let Some(enforcer) = enforcer {
+            enforcer.abort();
+        }
+
         tracing::info!("Message broker stopped");
     }
 
+    /// Abandon uploads that have gone too long without a chunk, so a client
+    /// cannot hold their memory (and storage allowance) by going quiet
+    async fn drop_stale_uploads(&self) {
+        let mut uploads = self.uploads.lock().await;
+        uploads.retain(|id, (owner, upload)| match upload {
+            Upload::Receiving(_, last_chunk) if last_chunk.elapsed() >= self.upload_idle_timeout => {
+                tracing::info!("Abandoning stalled upload {} from {}", id, owner);
+                false
+            }
+            _ => true,
+        });
+    }
+
     /// Handle a message from a client
-    async fn handle_client_message(
-        &self,
-        client_id: &str,
-        message: ProtocolMessage,
-    ) -> Result<()> {
+    ///
+    /// A connection that has not authenticated may only authenticate or
+    /// ping; anything else, and anything that is not a request at all, is
+    /// refused with an error rather than ignored, so every request is
+    /// answered.
+    async fn handle_client_message(&self, req: Request<'_>, message: ProtocolMessage) -> Result<()> {
+        let authenticated = self.client_manager.is_authenticated(req.client_id).await;
+
+        if matches!(
+            message,
+            ProtocolMessage::Send { .. }
+                | ProtocolMessage::Receive { .. }
+                | ProtocolMessage::Subscribe { .. }
+                | ProtocolMessage::Unsubscribe { .. }
+                | ProtocolMessage::Keys
+                | ProtocolMessage::Ack { .. }
+                | ProtocolMessage::DeliveryStatus { .. }
+                | ProtocolMessage::AttachmentStart { .. }
+                | ProtocolMessage::AttachmentChunk { .. }
+                | ProtocolMessage::AttachmentEnd { .. }
+                | ProtocolMessage::FetchAttachment { .. }
+        ) {
+            if !authenticated {
+                return self.send_error(req, ErrorCode::NotAuthenticated, "Not authenticated").await;
+            }
+            if !self.check_access(req).await? {
+                return Ok(());
+            }
+        }
+
         match message {
-            ProtocolMessage::Auth { code, client_id: provided_client_id } => {
-                self.handle_auth(client_id, &code, &provided_client_id).await?;
+            ProtocolMessage::Auth { .. } if authenticated => {
+                self.send_error(req, ErrorCode::UnexpectedMessage, "Already authenticated").await
             }
-            ProtocolMessage::Send { content } => {
-                self.handle_send(client_id, content).await?;
+            ProtocolMessage::Auth { code, client_id: provided_client_id, alias } => {
+                self.handle_auth_attempt(req, &code, &provided_client_id, alias).await
             }
-            ProtocolMessage::Receive { since } => {
-                self.handle_receive(client_id, since).await?;
+            ProtocolMessage::Send { content, to, channel, signature, attachment } => {
+                self.handle_send(req, content, to, channel, signature, attachment).await
+            }
+            ProtocolMessage::Receive { since, after, channel } => {
+                self.handle_receive(req, since, after, channel).await
+            }
+            ProtocolMessage::Subscribe { channel } => {
+                self.handle_subscribe(req, channel, true).await
+            }
+            ProtocolMessage::Unsubscribe { channel } => {
+                self.handle_subscribe(req, channel, false).await
+            }
+            ProtocolMessage::Keys => {
+                self.handle_keys(req).await
+            }
+            ProtocolMessage::Ack { message_id } => {
+                self.handle_ack(req, &message_id).await
+            }
+            ProtocolMessage::DeliveryStatus { message_id } => {
+                self.handle_delivery_status(req, message_id).await
+            }
+            ProtocolMessage::AttachmentStart { name, size, sha256 } => {
+                self.handle_attachment_start(req, name, size, sha256).await
+            }
+            ProtocolMessage::AttachmentChunk { attachment_id, index, data, sha256 } => {
+                self.handle_attachment_chunk(req, &attachment_id, index, &data, &sha256).await
+            }
+            ProtocolMessage::AttachmentEnd { attachment_id } => {
+                self.handle_attachment_end(req, &attachment_id).await
+            }
+            ProtocolMessage::FetchAttachment { attachment_id } => {
+                self.handle_fetch_attachment(req, &attachment_id).await
             }
             ProtocolMessage::Ping => {
-                self.send_to_client(client_id, ProtocolMessage::Pong).await?;
+                self.reply(req, ProtocolMessage::Pong).await
             }
             _ => {
-                tracing::warn!("Unexpected message type from client {}", client_id);
+                tracing::warn!("Unexpected message type from client {}", req.client_id);
+                self.send_error(req, ErrorCode::UnexpectedMessage, "Not a request clients can make")
+                    .await
+            }
+        }
+    }
+
+    /// Check that an authenticated connection's client still has access,
+    /// disconnecting it (in answer to the request) if not
+    async fn check_access(&self, req: Request<'_>) -> Result<bool> {
+        let Some(state_manager) = &self.state_manager else {
+            return Ok(true);
+        };
+
+        let Some(identity) = self.client_manager.identity(req.client_id).await else {
+            return Ok(true);
+        };
+
+        let valid = Self::blocking(state_manager, move |store| store.client_access_valid(&identity)).await?;
+        if !valid {
+            self.disconnect(req, ErrorCode::AccessRevoked, "Access revoked or expired").await;
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Look up the stored client a code or access token authenticates
+    async fn authenticate(&self, state_manager: &Arc<StateManager>, credential: &str) -> Result<Option<ClientConfig>> {
+        let server_id = self.server_id.clone();
+        let credential = credential.to_string();
+        Self::blocking(state_manager, move |store| match &server_id {
+            Some(server_id) => store.authenticate_client(server_id, &credential),
+            None => store.get_client_by_code(&credential),
+        })
+        .await
+    }
+
+    /// Run a store operation on the blocking pool, so a slow or locked
+    /// database does not hold up the runtime the connections are served on
+    async fn blocking<T, F>(state_manager: &Arc<StateManager>, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&StateManager) -> Result<T> + Send + 'static,
+    {
+        let state_manager = state_manager.clone();
+        tokio::task::spawn_blocking(move || op(&state_manager))
+            .await
+            .context("Client store task failed")?
+    }
+
+    /// Handle an authentication request, limiting how often it may fail
+    ///
+    /// After each failure the connection must wait before trying again, twice
+    /// as long each time; trying sooner is refused and counts as a failure
+    /// too. At `MAX_AUTH_FAILURES` failures the connection is disconnected.
+    /// Wrong credentials also count against the whole server, whatever
+    /// client ID they claim; once `MAX_SERVER_AUTH_FAILURES` have been tried
+    /// within `AUTH_FAILURE_WINDOW`, a wrong one disconnects its connection
+    /// at once, so reconnecting buys one guess rather than five. Credentials
+    /// are still checked meanwhile, so a right one always gets in and a
+    /// flood of wrong ones locks no one out. A refused alias is not a failure.
+    async fn handle_auth_attempt(
+        &self,
+        req: Request<'_>,
+        code: &str,
+        provided_client_id: &str,
+        alias: Option<String>,
+    ) -> Result<()> {
+        let now = Instant::now();
+        let retry_at = self.auth_failures.lock().await.get(req.client_id).map(|f| f.retry_at);
+        let outcome = match retry_at {
+            Some(retry_at) if retry_at > now => {
+                let wait = retry_at - now;
+                let error = format!("Too many failed attempts; retry in {} ms", wait.as_millis());
+                self.send_error(req, ErrorCode::RateLimited, &error).await?;
+                None
+            }
+            _ => Some(self.handle_auth(req, code, provided_client_id, alias).await?),
+        };
+
+        let mut failures = self.auth_failures.lock().await;
+        match outcome {
+            Some(AuthOutcome::Authenticated) => {
+                failures.remove(req.client_id);
+                return Ok(());
             }
+            Some(AuthOutcome::Refused) => return Ok(()),
+            Some(AuthOutcome::BadCredential) | None => {}
+        }
+        let budget_spent = outcome == Some(AuthOutcome::BadCredential) && self.record_auth_failure(now).await;
+
+        let failure = failures.entry(req.client_id.to_string()).or_insert(AuthFailures {
+            count: 0,
+            retry_at: Instant::now(),
+        });
+        failure.count += 1;
+        failure.retry_at = Instant::now() + self.auth_retry_delay * 2u32.pow(failure.count - 1);
+        if failure.count >= MAX_AUTH_FAILURES || budget_spent {
+            let count = failure.count;
+            failures.remove(req.client_id);
+            drop(failures);
+            tracing::warn!("Client {} failed to authenticate {} times", req.client_id, count);
+            self.client_manager
+                .disconnect(req.client_id, ErrorCode::AuthFailed, "Too many failed authentication attempts")
+                .await;
         }
 
         Ok(())
     }
 
+    /// Count a wrong credential against the server, returning whether it
+    /// went over `max_server_auth_failures` within the window
+    async fn record_auth_failure(&self, now: Instant) -> bool {
+        let mut recent = self.recent_auth_failures.lock().await;
+        recent.push_back(now);
+        // Only the newest few are needed to tell whether the budget is spent
+        while recent.len() > self.max_server_auth_failures + 1 {
+            recent.pop_front();
+        }
+        recent.len() > self.max_server_auth_failures
+            && recent.front().is_some_and(|&at| now.duration_since(at) < self.auth_failure_window)
+    }
+
     /// Handle authentication request
     async fn handle_auth(
         &self,
-        client_id: &str,
+        req: Request<'_>,
         code: &str,
         provided_client_id: &str,
-    ) -> Result<()> {
-        // Validate the authentication code
+        alias: Option<String>,
+    ) -> Result<AuthOutcome> {
+        let alias = match alias.map(message::validate_alias).transpose() {
+            Ok(alias) => alias,
+            Err(e) => {
+                self.send_auth_response(req, false, &e.to_string()).await?;
+                return Ok(AuthOutcome::Refused);
+            }
+        };
+
+        // Validate the code or access token
         if let Some(state_manager) = &self.state_manager {
-            if let Some(client_config) = state_manager.get_client_by_code(code)? {
-                // Check if this code is for this server
-                if let Some(server_id) = &self.server_id {
-                    if &client_config.server_id != server_id {
-                        self.send_auth_response(client_id, false, "Invalid code").await?;
-                        return Ok(());
-                    }
-                }
+            let Some(client_config) = self.authenticate(state_manager, code).await? else {
+                self.send_auth_response(req, false, "Invalid or expired code").await?;
+                return Ok(AuthOutcome::BadCredential);
+            };
 
-                // Mark client as authenticated
-                self.client_manager.authenticate_client(client_id).await?;
+            if !self.claim_alias(req.client_id, alias.as_deref(), Some(&client_config)).await? {
+                self.send_auth_response(req, false, "Alias already in use").await?;
+                return Ok(AuthOutcome::Refused);
+            }
 
-                // Update state
-                state_manager.update_client_status(&client_config.id, ClientStatus::Connected)?;
+            // Mark client as authenticated
+            self.client_manager
+                .authenticate_client_as(req.client_id, &client_config.id)
+                .await?;
 
-                self.send_auth_response(client_id, true, "Authenticated").await?;
+            // Update state
+            let id = client_config.id.clone();
+            Self::blocking(state_manager, move |store| store.update_client_status(&id, ClientStatus::Connected)).await?;
 
-                tracing::info!(
-                    "Client {} authenticated with code {}",
-                    provided_client_id,
-                    code
-                );
-            } else {
-                self.send_auth_response(client_id, false, "Invalid code").await?;
-            }
+            self.send_auth_response(req, true, "Authenticated").await?;
+
+            tracing::info!(
+                "Client {} authenticated as {}",
+                provided_client_id,
+                client_config.id
+            );
         } else {
             // No state manager, accept all connections (for testing)
-            self.client_manager.authenticate_client(client_id).await?;
-            self.send_auth_response(client_id, true, "Authenticated").await?;
+            if !self.claim_alias(req.client_id, alias.as_deref(), None).await? {
+                self.send_auth_response(req, false, "Alias already in use").await?;
+                return Ok(AuthOutcome::Refused);
+            }
+            self.client_manager.authenticate_client(req.client_id).await?;
+            self.send_auth_response(req, true, "Authenticated").await?;
         }
 
-        Ok(())
+        self.redeliver(req.client_id, None).await?;
+        Ok(AuthOutcome::Authenticated)
+    }
+
+    /// Give a connection the alias it asked for, if any; false if it is taken
+    ///
+    /// The first stored client to claim an alias keeps it, connected or not,
+    /// so no one else can take over its direct messages.
+    async fn claim_alias(&self, client_id: &str, alias: Option<&str>, client: Option<&ClientConfig>) -> Result<bool> {
+        let Some(alias) = alias else {
+            return Ok(true);
+        };
+
+        if let (Some(state_manager), Some(client)) = (&self.state_manager, client) {
+            let (server_id, alias, id) = (client.server_id.clone(), alias.to_string(), client.id.clone());
+            if !Self::blocking(state_manager, move |store| store.claim_alias(&server_id, &alias, &id)).await? {
+                return Ok(false);
+            }
+        }
+
+        self.client_manager
+            .set_alias(client_id, alias, client.map(|c| c.id.as_str()))
+            .await
     }
 
     /// Send authentication response
     async fn send_auth_response(
         &self,
-        client_id: &str,
+        req: Request<'_>,
         success: bool,
         message: &str,
     ) -> Result<()> {
@@ -200,53 +664,569 @@ impl MessageBroker {
             server_id: self.server_id.clone(),
         };
 
-        self.send_to_client(client_id, response).await
+        self.reply(req, response).await
     }
 
     /// Handle send message request
-    async fn handle_send(&self, client_id: &str, content: String) -> Result<()> {
-        // Add message to queue
-        let message = self.queue.push(client_id.to_string(), content).await;
+    async fn handle_send(
+        &self,
+        req: Request<'_>,
+        content: String,
+        to: Option<String>,
+        channel: Option<String>,
+        signature: Option<MessageSignature>,
+        attachment_id: Option<String>,
+    ) -> Result<()> {
+        let audience = match Audience::from_parts(to, channel) {
+            Ok(audience) => audience,
+            Err(e) => return self.send_error(req, ErrorCode::InvalidRequest, &e.to_string()).await,
+        };
+
+        // The attachment must have been uploaded, intact, on this connection
+        let mut uploads = self.uploads.lock().await;
+        let attachment = match attachment_id {
+            Some(id) => match uploads.get(&id) {
+                Some((owner, Upload::Complete(info, _))) if owner == req.client_id => Some(info.clone()),
+                _ => {
+                    let error = format!("Unknown attachment {}; upload it first", id);
+                    return self.send_error(req, ErrorCode::NotFound, &error).await;
+                }
+            },
+            None => None,
+        };
+
+        // Messages are sent as the connection's alias, so replies can find it
+        let from = self
+            .client_manager
+            .name(req.client_id)
+            .await
+            .unwrap_or_else(|| req.client_id.to_string());
+
+        // Receivers check signatures too; this keeps forgeries and stale
+        // replays out of the queue
+        if let Some(ref signature) = signature {
+            let sha256 = attachment.as_ref().map(|a| a.sha256.as_str());
+            let origin = Origin {
+                sender: (from != req.client_id).then_some(from.as_str()),
+                server: self.server_id.as_deref(),
+            };
+            if let Err(e) = signature.verify(&content, &audience, sha256, origin) {
+                return self.send_error(req, ErrorCode::InvalidRequest, &e.to_string()).await;
+            }
+            if !signature.is_fresh(SystemTime::now()) {
+                return self.send_error(req, ErrorCode::InvalidRequest, "Message signature is stale").await;
+            }
+        }
+
+        // A refused message keeps its upload, to be sent again later
+        if !self.within_limits(req, &from, &content, attachment.as_ref()).await? {
+            return Ok(());
+        }
+
+        let data = match attachment {
+            Some(ref info) => match uploads.remove(&info.id) {
+                Some((_, Upload::Complete(_, data))) => Some(data),
+                _ => None,
+            },
+            None => None,
+        };
+        drop(uploads);
+
+        // Add message to queue, keeping its file for as long as the message
+        let limit_key = self.limit_key(req.client_id, &from).await;
+        let message = self.queue.push(from, content, audience, signature, attachment, Some(limit_key)).await?;
+        if let (Some(info), Some(data)) = (&message.attachment, data) {
+            self.queue.store_attachment(info, data, message.expires_at).await?;
+        }
+
+        tracing::info!("Message {} from {} queued", message.id, req.client_id);
+
+        // Acknowledge before broadcasting so the sender sees the ack first
+        self.reply(req,
+            ProtocolMessage::SendAck {
+                message_id: message.id.clone(),
+            },
+        )
+        .await?;
 
-        tracing::info!("Message {} from {} queued", message.id, client_id);
+        if let Some(ref hook_tx) = self.hook_tx {
+            hook_tx.submit(message.clone());
+        }
 
-        // Broadcast to all authenticated clients
-        self.client_manager.broadcast(message).await;
+        // Deliver to the authenticated clients the message is for, and track
+        // it until each acknowledges it (the sender excepted)
+        let sender = self.client_manager.recipient(req.client_id).await.map(|(recipient, _)| recipient);
+        let message_id = message.id.clone();
+        for (recipient, alias) in self.client_manager.broadcast(message).await {
+            if sender.as_ref() != Some(&recipient) {
+                self.queue.record_delivery(&message_id, &recipient, alias.as_deref()).await?;
+            }
+        }
 
         Ok(())
     }
 
-    /// Handle receive request
-    async fn handle_receive(&self, client_id: &str, since: Option<SystemTime>) -> Result<()> {
-        let messages = if let Some(since_time) = since {
-            self.queue.get_since(since_time).await
+    /// Handle a client announcing a file it will upload
+    async fn handle_attachment_start(&self, req: Request<'_>, name: String, size: u64, sha256: String) -> Result<()> {
+        if let Err(e) = attachment::validate_file_name(&name) {
+            return self.send_error(req, ErrorCode::InvalidRequest, &e.to_string()).await;
+        }
+        if size > self.max_attachment_size {
+            let error = format!(
+                "Attachment too large ({} bytes; this server accepts up to {})",
+                size, self.max_attachment_size
+            );
+            return self.send_error(req, ErrorCode::TooLarge, &error).await;
+        }
+        if hex::decode(&sha256).map_or(true, |hash| hash.len() != 32) {
+            return self.send_error(req, ErrorCode::InvalidRequest, "Malformed attachment hash").await;
+        }
+
+        let stored = self.queue.attachment_bytes().await?;
+        let mut uploads = self.uploads.lock().await;
+        if uploads.values().filter(|(owner, _)| owner == req.client_id).count() >= MAX_PENDING_UPLOADS {
+            drop(uploads);
+            return self.send_error(req, ErrorCode::QuotaExceeded, "Too many attachments waiting to be sent").await;
+        }
+        let pending: u64 = uploads.values().map(|(_, upload)| upload.size()).sum();
+        if stored + pending + size > self.max_attachment_storage {
+            drop(uploads);
+            return self
+                .send_error(req, ErrorCode::QuotaExceeded, "No room for more attachments on this server")
+                .await;
+        }
+
+        let info = AttachmentInfo {
+            id: Uuid::new_v4().to_string(),
+            name,
+            size,
+            sha256,
+        };
+        let attachment_id = info.id.clone();
+        let upload = Upload::Receiving(Assembly::new(info), Instant::now());
+        uploads.insert(attachment_id.clone(), (req.client_id.to_string(), upload));
+        drop(uploads);
+
+        self.reply(req, ProtocolMessage::AttachmentAccepted { attachment_id }).await
+    }
+
+    /// Handle the next chunk of a file being uploaded
+    ///
+    /// Chunks with a request ID are answered with `AttachmentChunkReceived`;
+    /// a bad one abandons the upload with an error either way.
+    async fn handle_attachment_chunk(
+        &self,
+        req: Request<'_>,
+        attachment_id: &str,
+        index: u32,
+        data: &str,
+        sha256: &str,
+    ) -> Result<()> {
+        let mut uploads = self.uploads.lock().await;
+        let (assembly, last_chunk) = match uploads.get_mut(attachment_id) {
+            Some((owner, Upload::Receiving(assembly, last_chunk))) if owner == req.client_id => (assembly, last_chunk),
+            _ => {
+                drop(uploads);
+                let error = format!("Unknown attachment {}", attachment_id);
+                return self.send_error(req, ErrorCode::NotFound, &error).await;
+            }
+        };
+
+        if let Err(e) = assembly.add(index, data, sha256) {
+            uploads.remove(attachment_id);
+            drop(uploads);
+            return self.send_error(req, ErrorCode::InvalidRequest, &e.to_string()).await;
+        }
+        *last_chunk = Instant::now();
+        drop(uploads);
+
+        if req.id.is_none() {
+            return Ok(());
+        }
+        let attachment_id = attachment_id.to_string();
+        self.reply(req, ProtocolMessage::AttachmentChunkReceived { attachment_id, index }).await
+    }
+
+    /// Handle the end of an upload, checking the file against its hash
+    async fn handle_attachment_end(&self, req: Request<'_>, attachment_id: &str) -> Result<()> {
+        let mut uploads = self.uploads.lock().await;
+        let result = match uploads.remove(attachment_id) {
+            Some((owner, Upload::Receiving(assembly, _))) if owner == req.client_id => assembly
+                .finish()
+                .map(|(info, data)| {
+                    uploads.insert(info.id.clone(), (owner, Upload::Complete(info.clone(), data)));
+                    info
+                })
+                .map_err(|e| (ErrorCode::InvalidRequest, e.to_string())),
+            Some(upload) => {
+                uploads.insert(attachment_id.to_string(), upload);
+                Err((ErrorCode::NotFound, format!("Unknown attachment {}", attachment_id)))
+            }
+            None => Err((ErrorCode::NotFound, format!("Unknown attachment {}", attachment_id))),
+        };
+        drop(uploads);
+
+        match result {
+            Ok(attachment) => {
+                tracing::info!("Attachment {} ({} bytes) uploaded by {}", attachment.id, attachment.size, req.client_id);
+                self.reply(req, ProtocolMessage::AttachmentStored { attachment }).await
+            }
+            Err((code, error)) => self.send_error(req, code, &error).await,
+        }
+    }
+
+    /// Check a message from `from` against the server's client limits
+    ///
+    /// Returns false if it must be refused, having told or muted or
+    /// disconnected the client as the limits say.
+    async fn within_limits(
+        &self,
+        req: Request<'_>,
+        from: &str,
+        content: &str,
+        attachment: Option<&AttachmentInfo>,
+    ) -> Result<bool> {
+        let mut limiter = self.limiter.lock().await;
+        if !limiter.limits().is_limited() {
+            return Ok(true);
+        }
+
+        // Authenticated clients are limited as their stored client, so that
+        // reconnecting (or taking another alias) does not start them over
+        let client = self.limit_key(req.client_id, from).await;
+        let queued = if limiter.limits().has_quota() {
+            self.queue.queued_by(&client).await?
         } else {
-            self.queue.get_all().await
+            Queued::default()
+        };
+
+        let now = Instant::now();
+        let attachment_size = attachment.map_or(0, |a| a.size);
+        let Err(violation) = limiter.check(&client, content.len(), attachment_size, queued, now) else {
+            return Ok(true);
+        };
+
+        tracing::warn!("Refused message from client {}: {}", req.client_id, violation);
+        let code = violation.code();
+        match limiter.limits().action {
+            LimitAction::Error => self.send_error(req, code, &violation.to_string()).await?,
+            LimitAction::Mute if !matches!(violation, Violation::Muted { .. }) => {
+                limiter.mute(&client, now);
+                let error = format!("{}; muted for {} seconds", violation, limiter.limits().mute_secs);
+                self.send_error(req, code, &error).await?;
+            }
+            LimitAction::Mute => self.send_error(req, code, &violation.to_string()).await?,
+            LimitAction::Disconnect => self.disconnect(req, code, &violation.to_string()).await,
+        }
+
+        Ok(false)
+    }
+
+    /// Who a connection's messages count against: its stored client, or
+    /// without one the name it sends as
+    async fn limit_key(&self, client_id: &str, from: &str) -> String {
+        self.client_manager
+            .identity(client_id)
+            .await
+            .unwrap_or_else(|| from.to_string())
+    }
+
+    /// Handle a request for a file attached to a message
+    ///
+    /// Anyone who may see the message may fetch its file: its recipients,
+    /// and anyone at all for a channel's messages, as with `Receive`. A
+    /// connection is sent one file at a time.
+    async fn handle_fetch_attachment(&self, req: Request<'_>, attachment_id: &str) -> Result<()> {
+        let Some(slot) = FetchSlot::claim(&self.fetches, req.client_id) else {
+            return self
+                .send_error(req, ErrorCode::RateLimited, "Already sending a file; fetch one at a time")
+                .await;
+        };
+
+        let message = match self.queue.get_by_attachment(attachment_id).await? {
+            Some(message) if message.channel.is_some() => Some(message),
+            Some(message) => self.client_manager.visible_to(req.client_id, vec![message]).await.pop(),
+            None => None,
+        };
+
+        let (Some(info), Some(data)) = (
+            message.and_then(|m| m.attachment),
+            self.queue.attachment(attachment_id).await?,
+        ) else {
+            let error = format!("Unknown or expired attachment {}", attachment_id);
+            return self.send_error(req, ErrorCode::NotFound, &error).await;
+        };
+
+        // The client asked for every chunk, so they wait for room in its
+        // outbox rather than counting against it as a slow consumer, up to
+        // `FETCH_STALL_TIMEOUT` each; that waiting is done beside the
+        // broker, not in it
+        let Some(outbox) = self.client_manager.outbox(req.client_id).await else {
+            return Ok(());
+        };
+        let client_manager = self.client_manager.clone();
+        let attachment_id = attachment_id.to_string();
+        let request_id = req.id.map(str::to_string);
+        req.replied.store(true, Ordering::Relaxed);
+        tokio::spawn(async move {
+            let end = ProtocolMessage::AttachmentEnd {
+                attachment_id: attachment_id.clone(),
+            };
+            let frames = std::iter::once(ProtocolMessage::AttachmentOffer { attachment: info })
+                .chain(attachment::chunks(&attachment_id, &data))
+                .chain(std::iter::once(end));
+            // Every frame of the file answers the request
+            for frame in frames {
+                let push = outbox.push_wait(Envelope::reply(request_id.clone(), frame));
+                match tokio::time::timeout(FETCH_STALL_TIMEOUT, push).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(_)) => break,
+                    Err(_) => {
+                        let reason = "Not reading the file it fetched";
+                        client_manager.disconnect(&slot.client_id, ErrorCode::SlowConsumer, reason).await;
+                        break;
+                    }
+                }
+            }
+            drop(slot);
+        });
+
+        Ok(())
+    }
+
+    /// Send a connection the messages its recipient has not acknowledged yet
+    ///
+    /// Called when it authenticates, and (for that channel's messages only)
+    /// when it subscribes to a channel.
+    async fn redeliver(&self, client_id: &str, channel: Option<&str>) -> Result<()> {
+        let Some((recipient, alias)) = self.client_manager.recipient(client_id).await else {
+            return Ok(());
+        };
+
+        let messages = self.queue.unacked(&recipient).await?;
+        let messages = self.client_manager.visible_to(client_id, messages).await;
+
+        for message in messages {
+            if channel.is_some() && message.channel.as_deref() != channel {
+                continue;
+            }
+            self.queue.record_delivery(&message.id, &recipient, alias.as_deref()).await?;
+            tracing::debug!("Redelivering message {} to {}", message.id, recipient);
+            self.send_to_client(client_id, ProtocolMessage::Broadcast { message }).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Handle a client acknowledging a message
+    ///
+    /// Acks are only answered when they carry a request ID, as clients that
+    /// predate `Acked` send them without one and would not know the reply.
+    async fn handle_ack(&self, req: Request<'_>, message_id: &str) -> Result<()> {
+        match self.client_manager.recipient(req.client_id).await {
+            Some((recipient, alias)) => {
+                if !self.may_see(req.client_id, message_id).await?
+                    || !self.queue.ack(message_id, &recipient, alias.as_deref()).await?
+                {
+                    tracing::debug!("{} acknowledged unknown message {}", recipient, message_id);
+                }
+            }
+            None => tracing::debug!("Ignoring acknowledgement from anonymous client {}", req.client_id),
+        }
+
+        if req.id.is_none() {
+            return Ok(());
+        }
+        let message_id = message_id.to_string();
+        self.reply(req, ProtocolMessage::Acked { message_id }).await
+    }
+
+    /// Whether a connection could have received a message, either as it was
+    /// sent or from the backlog
+    ///
+    /// A channel's backlog is open to anyone who names it, so its messages
+    /// are too.
+    async fn may_see(&self, client_id: &str, message_id: &str) -> Result<bool> {
+        let Some(message) = self.queue.get(message_id).await? else {
+            return Ok(false);
+        };
+        if matches!(message.audience(), Audience::Channel(_)) {
+            return Ok(true);
+        }
+        Ok(!self.client_manager.visible_to(client_id, vec![message]).await.is_empty())
+    }
+
+    /// Handle a sender asking who its message was delivered to
+    async fn handle_delivery_status(&self, req: Request<'_>, message_id: String) -> Result<()> {
+        let Some(message) = self.queue.get(&message_id).await? else {
+            return self.send_error(req, ErrorCode::NotFound, &format!("Unknown message {}", message_id)).await;
+        };
+
+        let name = self.client_manager.name(req.client_id).await;
+        if name.as_deref() != Some(message.from.as_str()) {
+            return self
+                .send_error(req, ErrorCode::Forbidden, "Only a message's sender can see its delivery status")
+                .await;
+        }
+
+        let deliveries = self.queue.deliveries(&message_id).await?;
+        self.reply(req,
+            ProtocolMessage::DeliveryStatusResponse { message_id, deliveries },
+        )
+        .await
+    }
+
+    /// Handle receive request
+    async fn handle_receive(
+        &self,
+        req: Request<'_>,
+        since: Option<SystemTime>,
+        after: Option<String>,
+        channel: Option<String>,
+    ) -> Result<()> {
+        let mut messages = self.queue.get_after(since, after.as_deref()).await?;
+
+        // A channel's backlog is open to anyone who names it
+        messages = match channel {
+            Some(channel) => messages
+                .into_iter()
+                .filter(|m| m.channel.as_deref() == Some(channel.as_str()))
+                .collect(),
+            None => self.client_manager.visible_to(req.client_id, messages).await,
         };
 
         let response = ProtocolMessage::ReceiveResponse { messages };
 
-        self.send_to_client(client_id, response).await
+        self.reply(req, response).await
     }
 
-    /// Send a message to a specific client
-    async fn send_to_client(&self, client_id: &str, message: ProtocolMessage) -> Result<()> {
-        let clients = self.client_manager.clients.read().await;
+    /// Handle a subscribe (or unsubscribe) request
+    async fn handle_subscribe(&self, req: Request<'_>, channel: String, subscribe: bool) -> Result<()> {
+        let channel = match message::validate_name(channel) {
+            Ok(channel) => channel,
+            Err(e) => return self.send_error(req, ErrorCode::InvalidRequest, &e.to_string()).await,
+        };
 
-        if let Some(client) = clients.get(client_id) {
-            client.send(message)?;
+        if subscribe {
+            self.client_manager.subscribe(req.client_id, &channel).await?;
+            self.reply(req, ProtocolMessage::Subscribed { channel: channel.clone() })
+                .await?;
+            self.redeliver(req.client_id, Some(&channel)).await
         } else {
-            anyhow::bail!("Client not found: {}", client_id);
+            self.client_manager.unsubscribe(req.client_id, &channel).await?;
+            self.reply(req, ProtocolMessage::Unsubscribed { channel }).await
         }
+    }
 
-        Ok(())
+    /// Handle a request for the clients' encryption keys
+    ///
+    /// Keys are registered when brokers introduce clients. Each is listed
+    /// under every alias its client has claimed, connected or not, so direct
+    /// messages can be sealed for them.
+    async fn handle_keys(&self, req: Request<'_>) -> Result<()> {
+        let keys = match (&self.state_manager, &self.server_id) {
+            (Some(state_manager), Some(server_id)) => {
+                let server_id = server_id.clone();
+                let (aliases, client_keys) = Self::blocking(state_manager, move |store| {
+                    Ok((store.list_client_aliases(&server_id)?, store.list_client_keys(&server_id)?))
+                })
+                .await?;
+                let mut keys = Vec::new();
+                for (identity, public_key) in client_keys {
+                    let claimed: Vec<_> = aliases.iter().filter(|(_, owner)| *owner == identity).collect();
+                    if claimed.is_empty() {
+                        keys.push(PeerKey { alias: None, public_key });
+                    }
+                    for (alias, _) in claimed {
+                        keys.push(PeerKey { alias: Some(alias.clone()), public_key });
+                    }
+                }
+                keys
+            }
+            _ => Vec::new(),
+        };
+
+        self.reply(req, ProtocolMessage::KeysResponse { keys }).await
+    }
+
+    /// Answer a request
+    async fn reply(&self, req: Request<'_>, message: ProtocolMessage) -> Result<()> {
+        req.replied.store(true, Ordering::Relaxed);
+        let reply = Envelope::reply(req.id.map(str::to_string), message);
+        self.client_manager.send_to(req.client_id, reply).await
+    }
+
+    /// Tell a client its request was refused
+    async fn send_error(&self, req: Request<'_>, code: ErrorCode, message: &str) -> Result<()> {
+        self.reply(req, ProtocolMessage::error(code, message)).await
+    }
+
+    /// Disconnect a client, the error saying why answering its request
+    async fn disconnect(&self, req: Request<'_>, code: ErrorCode, reason: &str) {
+        req.replied.store(true, Ordering::Relaxed);
+        self.client_manager.disconnect_replying(req.client_id, req.id, code, reason).await;
+    }
+
+    /// Send a client a message it did not ask for (e.g. a broadcast)
+    async fn send_to_client(&self, client_id: &str, message: ProtocolMessage) -> Result<()> {
+        self.client_manager.send_to(client_id, message).await
+    }
+}
+
+/// Disconnect every connection whose client was revoked or expired, each
+/// `ACCESS_CHECK_INTERVAL` and whenever woken
+///
+/// Revocations by other processes only show up in the store, hence the
+/// polling; the lookups run on the blocking pool.
+async fn enforce_access(state_manager: Arc<StateManager>, client_manager: Arc<ClientManager>, wake: Arc<Notify>) {
+    let mut interval = tokio::time::interval(ACCESS_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = wake.notified() => {}
+        }
+
+        let identities = client_manager.identities().await;
+        if identities.is_empty() {
+            continue;
+        }
+
+        let state_manager = state_manager.clone();
+        let revoked = tokio::task::spawn_blocking(move || {
+            identities
+                .into_iter()
+                .filter(|(_, identity)| match state_manager.client_access_valid(identity) {
+                    Ok(valid) => !valid,
+                    Err(e) => {
+                        tracing::error!("Failed to check access for {}: {}", identity, e);
+                        false
+                    }
+                })
+                .map(|(client_id, _)| client_id)
+                .collect::<Vec<_>>()
+        })
+        .await;
+
+        match revoked {
+            Ok(revoked) => {
+                for client_id in revoked {
+                    client_manager
+                        .disconnect(&client_id, ErrorCode::AccessRevoked, "Access revoked or expired")
+                        .await;
+                }
+            }
+            Err(e) => tracing::error!("Access check failed: {}", e),
+        }
     }
 }
 
 /// Fortress-specific broker with access token validation
+///
+/// Tokens live (hashed) in the `StateManager`, so they are shared with the
+/// brokers that mint them and with `revoke-client` in other processes.
 pub struct FortressBroker {
     inner: MessageBroker,
-    valid_tokens: Arc<tokio::sync::RwLock<std::collections::HashSet<String>>>,
 }
 
 impl FortressBroker {
@@ -257,37 +1237,46 @@ impl FortressBroker {
         state_manager: Arc<StateManager>,
         server_id: String,
     ) -> (Self, BrokerHandle) {
-        let (inner, handle) = MessageBroker::new(
-            ttl,
-            max_queue_size,
-            Some(state_manager),
-            Some(server_id),
-        );
+        let (inner, handle) = MessageBroker::new(ttl, max_queue_size, Some(state_manager), Some(server_id));
 
-        let fortress = Self {
-            inner,
-            valid_tokens: Arc::new(tokio::sync::RwLock::new(std::collections::HashSet::new())),
-        };
+        (Self { inner }, handle)
+    }
+
+    /// Keep the fortress's messages in another store
+    pub fn with_message_store(mut self, store: Arc<dyn MessageStore>) -> Self {
+        self.inner = self.inner.with_message_store(store);
+        self
+    }
 
-        (fortress, handle)
+    /// Run a hook for each message the fortress receives
+    pub fn with_hook(mut self, hook: Hook) -> Self {
+        self.inner = self.inner.with_hook(hook);
+        self
     }
 
-    /// Add a valid access token
-    pub async fn add_token(&self, token: String) {
-        let mut tokens = self.valid_tokens.write().await;
-        tokens.insert(token);
+    /// Accept attachments up to `max_size` bytes
+    pub fn with_max_attachment_size(mut self, max_size: u64) -> Self {
+        self.inner = self.inner.with_max_attachment_size(max_size);
+        self
     }
 
-    /// Remove a token (revoke access)
-    pub async fn revoke_token(&self, token: &str) {
-        let mut tokens = self.valid_tokens.write().await;
-        tokens.remove(token);
+    /// Keep at most `max_total` bytes of attachments at once
+    pub fn with_max_attachment_storage(mut self, max_total: u64) -> Self {
+        self.inner = self.inner.with_max_attachment_storage(max_total);
+        self
     }
 
-    /// Validate a token
-    pub async fn validate_token(&self, token: &str) -> bool {
-        let tokens = self.valid_tokens.read().await;
-        tokens.contains(token)
+    /// Queue up to `capacity` frames for each client, handling clients that
+    /// fall further behind by `policy`
+    pub fn with_client_queue(mut self, capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        self.inner = self.inner.with_client_queue(capacity, policy);
+        self
+    }
+
+    /// Limit what each client may send
+    pub fn with_limits(mut self, limits: ClientLimits) -> Self {
+        self.inner = self.inner.with_limits(limits);
+        self
     }
 
     /// Run the fortress broker
@@ -304,6 +1293,339 @@ impl FortressBroker {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::msgserver::client::ClientConnection;
+    use crate::msgserver::outbox::Outbox;
+
+    /// Run `broker` with one connection, returning its ID and outbox
+    async fn connect(broker: MessageBroker) -> (String, Arc<Outbox>) {
+        let manager = broker.client_manager();
+        let outbox = manager.new_outbox();
+        let client_id = manager.add_client(ClientConnection::new(outbox.clone())).await;
+        tokio::spawn(broker.run());
+        (client_id, outbox)
+    }
+
+    async fn request(handle: &BrokerHandle, client_id: &str, request_id: Option<&str>, message: ProtocolMessage) {
+        handle
+            .send_command(BrokerCommand::ClientMessage {
+                client_id: client_id.to_string(),
+                request_id: request_id.map(str::to_string),
+                message: Box::new(message),
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn next(outbox: &Outbox) -> Envelope {
+        tokio::time::timeout(Duration::from_secs(5), outbox.pop())
+            .await
+            .expect("No reply")
+            .expect("Connection closed")
+    }
+
+    fn auth(alias: &str) -> ProtocolMessage {
+        ProtocolMessage::Auth {
+            code: "code".to_string(),
+            client_id: "test".to_string(),
+            alias: Some(alias.to_string()),
+        }
+    }
+
+    /// A store holding one server, and a client of it
+    fn store_with_client(dir: &std::path::Path) -> (Arc<StateManager>, ClientConfig) {
+        use crate::msgserver::storage::{ServerConfig, ServerStatus};
+
+        let state_manager = Arc::new(StateManager::new(dir).unwrap());
+        let server = ServerConfig {
+            id: Uuid::new_v4().to_string(),
+            name: "server".to_string(),
+            socket_path: dir.join("server.sock"),
+            created_at: SystemTime::now(),
+            ttl_minutes: 5,
+            onion_address: None,
+            status: ServerStatus::Running,
+            hook: None,
+            persistent: false,
+            max_attachment_size: None,
+            max_attachment_storage: None,
+            slow_consumer_policy: Default::default(),
+            client_queue_size: None,
+            limits: Default::default(),
+        };
+        state_manager.create_server(server.clone()).unwrap();
+        let client = state_manager.create_client(&server.id).unwrap();
+        (state_manager, client)
+    }
+
+    fn error_code(envelope: &Envelope) -> Option<ErrorCode> {
+        match envelope.message {
+            ProtocolMessage::Error { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_follow_the_state_machine() {
+        let (broker, handle) = MessageBroker::new(Duration::from_secs(60), 100, None, None);
+        let (client_id, outbox) = connect(broker).await;
+        let send = ProtocolMessage::Send {
+            content: "hi".to_string(),
+            to: None,
+            channel: None,
+            signature: None,
+            attachment: None,
+        };
+
+        // Before authenticating only pings are answered, each with its ID
+        request(&handle, &client_id, Some("1"), send.clone()).await;
+        let reply = next(&outbox).await;
+        assert_eq!(reply.request_id.as_deref(), Some("1"));
+        assert_eq!(error_code(&reply), Some(ErrorCode::NotAuthenticated));
+
+        request(&handle, &client_id, Some("2"), ProtocolMessage::Ping).await;
+        let reply = next(&outbox).await;
+        assert_eq!(reply.request_id.as_deref(), Some("2"));
+        assert!(matches!(reply.message, ProtocolMessage::Pong));
+
+        // Frames only servers send are refused rather than ignored
+        request(&handle, &client_id, None, ProtocolMessage::Pong).await;
+        assert_eq!(error_code(&next(&outbox).await), Some(ErrorCode::UnexpectedMessage));
+
+        request(&handle, &client_id, Some("3"), auth("alice")).await;
+        let reply = next(&outbox).await;
+        assert_eq!(reply.request_id.as_deref(), Some("3"));
+        assert!(matches!(reply.message, ProtocolMessage::AuthResponse { success: true, .. }));
+
+        request(&handle, &client_id, Some("4"), auth("alice")).await;
+        assert_eq!(error_code(&next(&outbox).await), Some(ErrorCode::UnexpectedMessage));
+
+        // The ack answers the request; the broadcast of it answers nothing
+        request(&handle, &client_id, Some("5"), send).await;
+        let reply = next(&outbox).await;
+        assert_eq!(reply.request_id.as_deref(), Some("5"));
+        let ProtocolMessage::SendAck { message_id } = reply.message else {
+            panic!("Expected an ack, got {:?}", reply);
+        };
+        let broadcast = next(&outbox).await;
+        assert!(broadcast.request_id.is_none());
+        assert!(matches!(broadcast.message, ProtocolMessage::Broadcast { .. }));
+
+        // Acks are only answered when they can be told apart
+        let ack = ProtocolMessage::Ack { message_id };
+        request(&handle, &client_id, None, ack.clone()).await;
+        request(&handle, &client_id, Some("6"), ack).await;
+        let reply = next(&outbox).await;
+        assert_eq!(reply.request_id.as_deref(), Some("6"));
+        assert!(matches!(reply.message, ProtocolMessage::Acked { .. }));
+
+        let status = ProtocolMessage::DeliveryStatus {
+            message_id: "nope".to_string(),
+        };
+        request(&handle, &client_id, Some("7"), status).await;
+        assert_eq!(error_code(&next(&outbox).await), Some(ErrorCode::NotFound));
+    }
+
+    #[tokio::test]
+    async fn test_failed_auth_attempts_back_off_then_disconnect() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+        let (broker, handle) = MessageBroker::new(Duration::from_secs(60), 100, Some(state_manager), None);
+        let broker = broker.with_auth_retry_delay(Duration::from_millis(50));
+        let (client_id, outbox) = connect(broker).await;
+        let bad = auth("alice");
+
+        request(&handle, &client_id, Some("1"), bad.clone()).await;
+        assert!(matches!(next(&outbox).await.message, ProtocolMessage::AuthResponse { success: false, .. }));
+
+        // Retrying straight away is refused, and counts against the client
+        request(&handle, &client_id, Some("2"), bad.clone()).await;
+        assert_eq!(error_code(&next(&outbox).await), Some(ErrorCode::RateLimited));
+
+        // After the (doubled) wait it may try again
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        request(&handle, &client_id, Some("3"), bad.clone()).await;
+        assert!(matches!(next(&outbox).await.message, ProtocolMessage::AuthResponse { success: false, .. }));
+
+        // The last attempt allowed is still answered, then the connection closes
+        for id in ["4", "5"] {
+            request(&handle, &client_id, Some(id), bad.clone()).await;
+            assert_eq!(error_code(&next(&outbox).await), Some(ErrorCode::RateLimited));
+        }
+        assert_eq!(error_code(&next(&outbox).await), Some(ErrorCode::AuthFailed));
+        assert!(outbox.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wrong_credentials_count_across_connections() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state_manager, client) = store_with_client(dir.path());
+        let code = client.code;
+        let (broker, handle) = MessageBroker::new(Duration::from_secs(60), 100, Some(state_manager), None);
+        let broker = broker.with_auth_failure_budget(2, Duration::from_secs(60));
+        let manager = broker.client_manager();
+        let (first, first_outbox) = connect(broker).await;
+        let refused = |envelope: Envelope| matches!(envelope.message, ProtocolMessage::AuthResponse { success: false, .. });
+        let with_code = |code: &str, alias: &str| ProtocolMessage::Auth {
+            code: code.to_string(),
+            client_id: "test".to_string(),
+            alias: Some(alias.to_string()),
+        };
+
+        // A bad alias with a good code is refused, but is not a wrong guess;
+        // neither is one that could pass for a connection ID
+        let uuid_alias = Uuid::new_v4().to_string();
+        for (id, alias) in [("1", "not a valid alias"), ("2", uuid_alias.as_str()), ("3", "not a valid alias")] {
+            request(&handle, &first, Some(id), with_code(&code, alias)).await;
+            assert!(refused(next(&first_outbox).await));
+        }
+
+        // Wrong codes use up the server's budget, whichever connection tries
+        // them, and reconnecting does not get it back
+        request(&handle, &first, Some("4"), auth("alice")).await;
+        assert!(refused(next(&first_outbox).await));
+        handle
+            .send_command(BrokerCommand::ClientDisconnected { client_id: first })
+            .await
+            .unwrap();
+        let reconnect = || async {
+            let outbox = manager.new_outbox();
+            (manager.add_client(ClientConnection::new(outbox.clone())).await, outbox)
+        };
+        let (second, second_outbox) = reconnect().await;
+        request(&handle, &second, Some("5"), auth("alice")).await;
+        assert!(refused(next(&second_outbox).await));
+
+        // Past it, a wrong code ends the connection at once, whatever ID it claims
+        let (third, third_outbox) = reconnect().await;
+        let other_id = ProtocolMessage::Auth {
+            code: "wrong".to_string(),
+            client_id: "another".to_string(),
+            alias: None,
+        };
+        request(&handle, &third, Some("6"), other_id).await;
+        assert!(refused(next(&third_outbox).await));
+        assert_eq!(error_code(&next(&third_outbox).await), Some(ErrorCode::AuthFailed));
+        assert!(third_outbox.pop().await.is_none());
+
+        // The right code still gets in
+        let (fourth, fourth_outbox) = reconnect().await;
+        request(&handle, &fourth, Some("7"), with_code(&code, "alice")).await;
+        assert!(matches!(
+            next(&fourth_outbox).await.message,
+            ProtocolMessage::AuthResponse { success: true, .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_flood_of_wrong_credentials_does_not_lock_out_others() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state_manager, client) = store_with_client(dir.path());
+        let (broker, handle) = MessageBroker::new(Duration::from_secs(60), 100, Some(state_manager), None);
+        let broker = broker.with_auth_failure_budget(2, Duration::from_secs(60));
+        let manager = broker.client_manager();
+        connect(broker).await;
+        let connection = || async {
+            let outbox = manager.new_outbox();
+            (manager.add_client(ClientConnection::new(outbox.clone())).await, outbox)
+        };
+        let claiming = |code: &str, client_id: &str| ProtocolMessage::Auth {
+            code: code.to_string(),
+            client_id: client_id.to_string(),
+            alias: None,
+        };
+
+        // Far more wrong guesses than the budget, on fresh connections under
+        // fresh IDs: past the budget each costs its connection
+        let (valid, valid_outbox) = connection().await;
+        for n in 0..10 {
+            let (flooder, flooder_outbox) = connection().await;
+            request(&handle, &flooder, None, claiming("wrong", &n.to_string())).await;
+            let reply = next(&flooder_outbox).await;
+            assert!(matches!(reply.message, ProtocolMessage::AuthResponse { success: false, .. }));
+            if n >= 2 {
+                assert_eq!(error_code(&next(&flooder_outbox).await), Some(ErrorCode::AuthFailed));
+            }
+
+            // Meanwhile the valid client still gets in
+            if n == 5 {
+                request(&handle, &valid, Some("1"), claiming(&client.code, "valid")).await;
+                let reply = next(&valid_outbox).await;
+                assert!(matches!(reply.message, ProtocolMessage::AuthResponse { success: true, .. }));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_revoked_client_is_answered_then_disconnected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (state_manager, client) = store_with_client(dir.path());
+        let (broker, handle) = MessageBroker::new(Duration::from_secs(60), 100, Some(state_manager.clone()), None);
+        let (client_id, outbox) = connect(broker).await;
+        let auth = ProtocolMessage::Auth {
+            code: client.code.clone(),
+            client_id: "test".to_string(),
+            alias: None,
+        };
+        request(&handle, &client_id, Some("1"), auth).await;
+        assert!(matches!(next(&outbox).await.message, ProtocolMessage::AuthResponse { success: true, .. }));
+
+        state_manager.revoke_client(&client.id).unwrap();
+        request(&handle, &client_id, Some("2"), ProtocolMessage::Keys).await;
+        let reply = next(&outbox).await;
+        assert_eq!(reply.request_id.as_deref(), Some("2"));
+        assert_eq!(error_code(&reply), Some(ErrorCode::AccessRevoked));
+        assert!(outbox.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_uploads_are_bounded_in_total_and_time() {
+        let (broker, handle) = MessageBroker::new(Duration::from_secs(60), 100, None, None);
+        let broker = broker
+            .with_max_attachment_storage(100)
+            .with_upload_idle_timeout(Duration::from_millis(100));
+        let (client_id, outbox) = connect(broker).await;
+        request(&handle, &client_id, None, auth("alice")).await;
+        next(&outbox).await;
+
+        let start = |size| ProtocolMessage::AttachmentStart {
+            name: "file.bin".to_string(),
+            size,
+            sha256: attachment::sha256_hex(b"file"),
+        };
+        let accepted = |envelope: Envelope| match envelope.message {
+            ProtocolMessage::AttachmentAccepted { attachment_id } => attachment_id,
+            other => panic!("Expected the upload to be accepted, got {:?}", other),
+        };
+
+        // Uploads in progress count against the server's total
+        request(&handle, &client_id, None, start(60)).await;
+        let attachment_id = accepted(next(&outbox).await);
+        request(&handle, &client_id, None, start(60)).await;
+        assert_eq!(error_code(&next(&outbox).await), Some(ErrorCode::QuotaExceeded));
+
+        // Until one stalls and is abandoned
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let end = ProtocolMessage::AttachmentEnd { attachment_id };
+        request(&handle, &client_id, None, end).await;
+        assert_eq!(error_code(&next(&outbox).await), Some(ErrorCode::NotFound));
+        request(&handle, &client_id, None, start(60)).await;
+        accepted(next(&outbox).await);
+    }
+
+    #[test]
+    fn test_one_file_is_sent_to_a_connection_at_a_time() {
+        let fetches = Arc::new(std::sync::Mutex::new(HashSet::new()));
+
+        let slot = FetchSlot::claim(&fetches, "alice").unwrap();
+        assert!(FetchSlot::claim(&fetches, "alice").is_none());
+        let other = FetchSlot::claim(&fetches, "bob").unwrap();
+
+        // Finishing (or abandoning) the file frees the slot
+        drop(slot);
+        assert!(FetchSlot::claim(&fetches, "alice").is_some());
+        drop(other);
+        assert!(fetches.lock().unwrap().is_empty());
+    }
 
     #[tokio::test]
     async fn test_broker_message_flow() {
@@ -318,14 +1640,16 @@ mod tests {
         handle
             .send_command(BrokerCommand::ClientMessage {
                 client_id: "test".to_string(),
-                message: ProtocolMessage::Ping,
+                request_id: None,
+                message: Box::new(ProtocolMessage::Ping),
             })
+            .await
             .unwrap();
 
         // Wait a bit
         tokio::time::sleep(Duration::from_millis(100)).await;
 
         // Shutdown
-        handle.send_command(BrokerCommand::Shutdown).unwrap();
+        handle.send_command(BrokerCommand::Shutdown).await.unwrap();
     }
 }
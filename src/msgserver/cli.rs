@@ -1,6 +1,9 @@
 // CLI commands for message server
 
-use clap::{Parser, Subcommand};
+use crate::msgserver::hook::HookConfig;
+use crate::msgserver::limits::{ClientLimits, LimitAction, DEFAULT_MUTE_SECS};
+use crate::msgserver::outbox::SlowConsumerPolicy;
+use clap::{Args, Parser, Subcommand};
 use std::path::PathBuf;
 
 /// Message server CLI commands
@@ -12,10 +15,91 @@ pub struct MsgSrvCli {
     pub command: MsgSrvCommand,
 }
 
+/// Options for running a command on each received message
+#[derive(Debug, Clone, Args)]
+pub struct HookArgs {
+    /// Command to run for each message (via `sh -c`, content on stdin, EDDI_MSG_* env vars)
+    #[arg(long)]
+    pub hook: Option<String>,
+
+    /// Most hook runs in progress at once
+    #[arg(long, default_value = "1", requires = "hook")]
+    pub hook_concurrency: usize,
+
+    /// Seconds before a hook run is killed
+    #[arg(long, default_value = "30", requires = "hook")]
+    pub hook_timeout: u64,
+
+    /// Times a failed hook run is retried
+    #[arg(long, default_value = "0", requires = "hook")]
+    pub hook_retries: u32,
+}
+
+impl HookArgs {
+    /// The configured hook, if `--hook` was given
+    pub fn config(&self) -> Option<HookConfig> {
+        self.hook.as_ref().map(|command| {
+            HookConfig::new(command.clone())
+                .with_concurrency(self.hook_concurrency)
+                .with_timeout(self.hook_timeout)
+                .with_retries(self.hook_retries)
+        })
+    }
+}
+
+/// Limits on what each client of a server may send
+#[derive(Debug, Clone, Args)]
+pub struct LimitArgs {
+    /// Messages each client may send per minute
+    #[arg(long, value_name = "MESSAGES")]
+    pub rate_limit: Option<u32>,
+
+    /// Messages each client may have queued at once
+    #[arg(long, value_name = "MESSAGES")]
+    pub max_queued_messages: Option<usize>,
+
+    /// Bytes of messages and attachments each client may have queued at once
+    #[arg(long, value_name = "BYTES")]
+    pub max_queued_bytes: Option<u64>,
+
+    /// Largest message content accepted, in bytes
+    #[arg(long, value_name = "BYTES")]
+    pub max_message_size: Option<usize>,
+
+    /// What to do with a client that goes over a limit
+    #[arg(long, value_enum, default_value_t = LimitAction::Error)]
+    pub on_limit: LimitAction,
+
+    /// Seconds a client is muted for with `--on-limit mute`
+    #[arg(long, default_value_t = DEFAULT_MUTE_SECS)]
+    pub mute_secs: u64,
+}
+
+impl LimitArgs {
+    /// The configured limits
+    pub fn config(&self) -> ClientLimits {
+        ClientLimits {
+            messages_per_minute: self.rate_limit,
+            max_queued_messages: self.max_queued_messages,
+            max_queued_bytes: self.max_queued_bytes,
+            max_message_size: self.max_message_size,
+            action: self.on_limit,
+            mute_secs: self.mute_secs,
+        }
+    }
+}
+
 /// Message server subcommands
 #[derive(Debug, Subcommand)]
 pub enum MsgSrvCommand {
-    /// Create a new eddi messaging server (persistent emsgsrv)
+    /// Run the daemon that hosts servers and brokers across invocations
+    Daemon {
+        /// Stop the running daemon instead of starting one
+        #[arg(long)]
+        stop: bool,
+    },
+
+    /// Create a new eddi messaging server (persistent emsgsrv, hosted by the daemon)
     CreateServer {
         /// Server name
         #[arg(short, long)]
@@ -32,6 +116,32 @@ pub enum MsgSrvCommand {
         /// Enable stealth mode (Tor client authorization)
         #[arg(long)]
         stealth: bool,
+
+        /// Keep queued messages on disk so they survive a daemon restart
+        #[arg(long)]
+        persistent: bool,
+
+        /// Largest attachment the server accepts, in bytes (default: 10 MiB)
+        #[arg(long, value_name = "BYTES")]
+        max_attachment_size: Option<u64>,
+
+        /// Most bytes of attachments the server keeps at once (default: 1 GiB)
+        #[arg(long, value_name = "BYTES")]
+        max_attachment_storage: Option<u64>,
+
+        /// What to do with clients that stop reading their messages
+        #[arg(long, value_enum, default_value_t = SlowConsumerPolicy::Disconnect)]
+        slow_consumer_policy: SlowConsumerPolicy,
+
+        /// Messages queued for each client before the slow consumer policy applies (default: 256)
+        #[arg(long, value_name = "MESSAGES")]
+        client_queue_size: Option<usize>,
+
+        #[command(flatten)]
+        limits: LimitArgs,
+
+        #[command(flatten)]
+        hook: HookArgs,
     },
 
     /// Create a broker (ephemeral handshake server)
@@ -70,16 +180,41 @@ pub enum MsgSrvCommand {
         /// Alias for this connection (optional)
         #[arg(short, long)]
         alias: Option<String>,
+
+        /// Only look for brokers on this machine (no Tor)
+        #[arg(long)]
+        local_only: bool,
     },
 
     /// Send a message
     Send {
-        /// Message content
-        message: String,
+        /// Message content (may be left out when attaching a file)
+        #[arg(required_unless_present = "file")]
+        message: Option<String>,
 
         /// Server name or alias (default: last connected)
         #[arg(short, long)]
         server: Option<String>,
+
+        /// Send a direct message to the client with this alias
+        #[arg(long, conflicts_with = "channel")]
+        to: Option<String>,
+
+        /// Send the message on a channel
+        #[arg(long)]
+        channel: Option<String>,
+
+        /// Alias to send as (so direct replies reach you)
+        #[arg(long = "as", value_name = "ALIAS")]
+        alias: Option<String>,
+
+        /// Encrypt the message so only its recipients can read it
+        #[arg(long)]
+        encrypt: bool,
+
+        /// Attach a file (encrypted too with --encrypt)
+        #[arg(long, value_name = "PATH")]
+        file: Option<PathBuf>,
     },
 
     /// Receive messages
@@ -92,9 +227,25 @@ pub enum MsgSrvCommand {
         #[arg(long)]
         once: bool,
 
-        /// Show only messages since timestamp
+        /// Show only messages since timestamp (Unix seconds)
         #[arg(long)]
         since: Option<u64>,
+
+        /// Print each message as a line of JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Only messages on this channel
+        #[arg(long)]
+        channel: Option<String>,
+
+        /// Alias to receive as (to see direct messages sent to it)
+        #[arg(long = "as", value_name = "ALIAS")]
+        alias: Option<String>,
+
+        /// Download attached files into this directory
+        #[arg(long, value_name = "DIR")]
+        save_dir: Option<PathBuf>,
     },
 
     /// Listen for messages (continuous mode)
@@ -103,13 +254,36 @@ pub enum MsgSrvCommand {
         #[arg(short, long)]
         server: Option<String>,
 
-        /// Run as system daemon
-        #[arg(long)]
+        /// Run as system daemon (detach, change to /, log to a file)
+        #[arg(long, conflicts_with_all = ["stop", "status"])]
         daemon: bool,
 
-        /// Run in background (detach from terminal)
-        #[arg(long)]
+        /// Run in background (detach from terminal, log to a file)
+        #[arg(long, conflicts_with_all = ["stop", "status"])]
         background: bool,
+
+        /// Print each message as a line of JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Only follow this channel
+        #[arg(long)]
+        channel: Option<String>,
+
+        /// Alias to listen as (to get direct messages sent to it)
+        #[arg(long = "as", value_name = "ALIAS")]
+        alias: Option<String>,
+
+        #[command(flatten)]
+        hook: HookArgs,
+
+        /// Stop the background listener for this connection
+        #[arg(long, conflicts_with = "status")]
+        stop: bool,
+
+        /// Show whether a background listener is running for this connection
+        #[arg(long)]
+        status: bool,
     },
 
     /// List all eddi messaging servers
@@ -160,6 +334,31 @@ pub enum MsgSrvCommand {
         name: String,
     },
 
+    /// Show who a message you sent was delivered to and who acknowledged it
+    Delivery {
+        /// ID printed when the message was sent
+        message_id: String,
+
+        /// Server name or alias (default: last connected)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Alias the message was sent as
+        #[arg(long = "as", value_name = "ALIAS")]
+        alias: Option<String>,
+    },
+
+    /// Show this client's signing identity and the sender keys pinned for a connection
+    Identity {
+        /// Connection name or alias (default: last connected)
+        #[arg(short, long)]
+        server: Option<String>,
+
+        /// Forget the key pinned for this sender alias
+        #[arg(long, value_name = "ALIAS")]
+        forget: Option<String>,
+    },
+
     /// Revoke client access
     RevokeClient {
         /// Server name
@@ -224,6 +423,18 @@ mod tests {
 
         let cli_local = MsgSrvCli::try_parse_from(args_local);
         assert!(cli_local.is_ok());
+
+        let cli = MsgSrvCli::try_parse_from(["msgsrv", "delivery", "msg-id", "--as", "alice"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            MsgSrvCommand::Delivery { ref message_id, alias: Some(ref alias), .. }
+                if message_id == "msg-id" && alias == "alice"
+        ));
+
+        // A file may stand in for the message text, and is encrypted with it
+        assert!(MsgSrvCli::try_parse_from(["msgsrv", "send", "--file", "report.pdf"]).is_ok());
+        assert!(MsgSrvCli::try_parse_from(["msgsrv", "send"]).is_err());
+        assert!(MsgSrvCli::try_parse_from(["msgsrv", "send", "hi", "--file", "a.txt", "--encrypt"]).is_ok());
     }
 
     #[test]
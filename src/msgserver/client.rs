@@ -1,10 +1,14 @@
 // Client connection management
 
-use crate::msgserver::message::{Message, ProtocolMessage};
+use crate::msgserver::framing::{self, FrameError, FrameReader, Framing, MAX_FRAME_SIZE};
+use crate::msgserver::message::{Audience, Envelope, ErrorCode, Message, ProtocolMessage};
+use crate::msgserver::outbox::{Outbox, OutboxError, OutboxMetrics, SlowConsumerPolicy, DEFAULT_OUTBOX_CAPACITY};
 use anyhow::{Context, Result};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
 use tokio::net::UnixStream;
 use tokio::sync::{mpsc, RwLock};
 use uuid::Uuid;
@@ -13,35 +17,92 @@ use uuid::Uuid;
 pub struct ClientConnection {
     pub id: String,
     pub authenticated: bool,
-    tx: mpsc::UnboundedSender<ProtocolMessage>,
+    /// Stored client this connection authenticated as, if any
+    pub identity: Option<String>,
+    /// Name other clients address direct messages to
+    pub alias: Option<String>,
+    /// Channels whose messages this connection receives
+    pub subscriptions: HashSet<String>,
+    outbox: Arc<Outbox>,
 }
 
 impl ClientConnection {
-    /// Create a new client connection
-    pub fn new(tx: mpsc::UnboundedSender<ProtocolMessage>) -> Self {
+    /// Create a new client connection, whose frames wait in `outbox`
+    pub fn new(outbox: Arc<Outbox>) -> Self {
         Self {
             id: Uuid::new_v4().to_string(),
             authenticated: false,
-            tx,
+            identity: None,
+            alias: None,
+            subscriptions: HashSet::new(),
+            outbox,
         }
     }
 
-    /// Send a message to the client
-    pub fn send(&self, message: ProtocolMessage) -> Result<()> {
-        self.tx
-            .send(message)
-            .context("Failed to send message to client")
+    /// Name messages from this connection are sent as: its alias, or its ID
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.id)
+    }
+
+    /// Who deliveries to this connection are tracked for: the stored client
+    /// it authenticated as, or else its alias
+    ///
+    /// Anonymous connections have no delivery tracking, as nothing would
+    /// identify them again after a reconnect.
+    pub fn recipient(&self) -> Option<&str> {
+        self.identity.as_deref().or(self.alias.as_deref())
+    }
+
+    /// Whether a message is for this connection
+    ///
+    /// Everyone sees public messages; direct messages are seen by their
+    /// sender and recipient; channel messages by the channel's subscribers.
+    pub fn can_see(&self, message: &Message) -> bool {
+        match message.audience() {
+            Audience::Everyone => true,
+            Audience::Client(to) => self.alias.as_deref() == Some(to.as_str()) || message.from == self.name(),
+            Audience::Channel(channel) => self.subscriptions.contains(&channel),
+        }
+    }
+
+    /// Send a message to the client, applying its outbox's policy if the
+    /// client is behind
+    pub async fn send(&self, message: ProtocolMessage) -> Result<(), OutboxError> {
+        self.outbox.push(message).await
     }
 
     /// Broadcast a message to the client
-    pub fn broadcast(&self, message: Message) -> Result<()> {
-        self.send(ProtocolMessage::Broadcast { message })
+    pub async fn broadcast(&self, message: Message) -> Result<(), OutboxError> {
+        self.send(ProtocolMessage::Broadcast { message }).await
     }
 }
 
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        // The writer ends once it has written what is already queued
+        self.outbox.close();
+    }
+}
+
+/// A connection's outbound queue, as shown by `status`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientQueue {
+    /// Connection ID
+    pub id: String,
+    /// Name the connection's messages are sent as
+    pub name: String,
+    pub authenticated: bool,
+    pub metrics: OutboxMetrics,
+}
+
+/// Why a client that stopped reading is disconnected
+const SLOW_CONSUMER_REASON: &str = "Disconnected for not reading messages fast enough";
+
 /// Manages all connected clients
 pub struct ClientManager {
     pub clients: Arc<RwLock<HashMap<String, ClientConnection>>>,
+    outbox_capacity: usize,
+    policy: SlowConsumerPolicy,
 }
 
 impl ClientManager {
@@ -49,9 +110,24 @@ impl ClientManager {
     pub fn new() -> Self {
         Self {
             clients: Arc::new(RwLock::new(HashMap::new())),
+            outbox_capacity: DEFAULT_OUTBOX_CAPACITY,
+            policy: SlowConsumerPolicy::default(),
         }
     }
 
+    /// Give each connection an outbox of `capacity` frames, handled by `policy`
+    /// when it fills up
+    pub fn with_outbox(mut self, capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        self.outbox_capacity = capacity;
+        self.policy = policy;
+        self
+    }
+
+    /// An empty outbox for a new connection
+    pub fn new_outbox(&self) -> Arc<Outbox> {
+        Arc::new(Outbox::new(self.outbox_capacity, self.policy))
+    }
+
     /// Add a new client
     pub async fn add_client(&self, client: ClientConnection) -> String {
         let id = client.id.clone();
@@ -80,6 +156,146 @@ impl ClientManager {
         }
     }
 
+    /// Mark client as authenticated as the stored client `identity`
+    pub async fn authenticate_client_as(&self, id: &str, identity: &str) -> Result<()> {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients.get_mut(id) {
+            client.authenticated = true;
+            client.identity = Some(identity.to_string());
+            tracing::info!("Client {} authenticated as {}", id, identity);
+            Ok(())
+        } else {
+            anyhow::bail!("Client not found: {}", id)
+        }
+    }
+
+    /// Give a connection an alias, unless another client already uses it
+    ///
+    /// Returns false if the alias is taken. Connections authenticating as the
+    /// same stored client (`identity`) may share an alias.
+    pub async fn set_alias(&self, id: &str, alias: &str, identity: Option<&str>) -> Result<bool> {
+        let mut clients = self.clients.write().await;
+
+        let taken = clients.values().any(|c| {
+            c.id != id
+                && c.alias.as_deref() == Some(alias)
+                && (identity.is_none() || c.identity.as_deref() != identity)
+        });
+        if taken {
+            return Ok(false);
+        }
+
+        let client = clients.get_mut(id).context("Client not found")?;
+        client.alias = Some(alias.to_string());
+        Ok(true)
+    }
+
+    /// Name a connection's messages are sent as
+    pub async fn name(&self, id: &str) -> Option<String> {
+        let clients = self.clients.read().await;
+        clients.get(id).map(|c| c.name().to_string())
+    }
+
+    /// Recipient a connection's deliveries are tracked for, and its alias
+    pub async fn recipient(&self, id: &str) -> Option<(String, Option<String>)> {
+        let clients = self.clients.read().await;
+        let client = clients.get(id)?;
+        Some((client.recipient()?.to_string(), client.alias.clone()))
+    }
+
+    /// Start delivering a channel's messages to a connection
+    pub async fn subscribe(&self, id: &str, channel: &str) -> Result<()> {
+        let mut clients = self.clients.write().await;
+        let client = clients.get_mut(id).context("Client not found")?;
+        client.subscriptions.insert(channel.to_string());
+        Ok(())
+    }
+
+    /// Stop delivering a channel's messages to a connection
+    pub async fn unsubscribe(&self, id: &str, channel: &str) -> Result<()> {
+        let mut clients = self.clients.write().await;
+        let client = clients.get_mut(id).context("Client not found")?;
+        client.subscriptions.remove(channel);
+        Ok(())
+    }
+
+    /// Keep the messages a connection may see
+    pub async fn visible_to(&self, id: &str, messages: Vec<Message>) -> Vec<Message> {
+        let clients = self.clients.read().await;
+        let Some(client) = clients.get(id) else {
+            return Vec::new();
+        };
+
+        messages.into_iter().filter(|m| client.can_see(m)).collect()
+    }
+
+    /// Check whether a client has authenticated
+    pub async fn is_authenticated(&self, id: &str) -> bool {
+        let clients = self.clients.read().await;
+        clients.get(id).is_some_and(|c| c.authenticated)
+    }
+
+    /// Get the stored client a connection authenticated as
+    pub async fn identity(&self, id: &str) -> Option<String> {
+        let clients = self.clients.read().await;
+        clients.get(id).and_then(|c| c.identity.clone())
+    }
+
+    /// Get (connection ID, identity) for every connection with an identity
+    pub async fn identities(&self) -> Vec<(String, String)> {
+        let clients = self.clients.read().await;
+        clients
+            .iter()
+            .filter_map(|(id, c)| c.identity.clone().map(|identity| (id.clone(), identity)))
+            .collect()
+    }
+
+    /// Tell a client why, with an error `code`, then close its connection
+    pub async fn disconnect(&self, id: &str, code: ErrorCode, reason: &str) {
+        self.disconnect_replying(id, None, code, reason).await;
+    }
+
+    /// Like `disconnect`, but the error answers the client's request
+    /// `request_id`, the one that got it disconnected
+    pub async fn disconnect_replying(&self, id: &str, request_id: Option<&str>, code: ErrorCode, reason: &str) {
+        let mut clients = self.clients.write().await;
+        if let Some(client) = clients.remove(id) {
+            // The stream closes once this is written
+            let error = Envelope::reply(request_id.map(str::to_string), ProtocolMessage::error(code, reason));
+            client.outbox.close_with(error);
+            tracing::info!("Disconnected client {}: {}", id, reason);
+        }
+    }
+
+    /// The queue of frames waiting to be written to a connection
+    pub async fn outbox(&self, id: &str) -> Option<Arc<Outbox>> {
+        let clients = self.clients.read().await;
+        clients.get(id).map(|c| c.outbox.clone())
+    }
+
+    /// Send a message, or a reply in an `Envelope`, to a specific client
+    ///
+    /// Under `Block` this waits for room (up to `BLOCK_TIMEOUT`) on the
+    /// caller's task, which for the broker holds up every other client too;
+    /// the clients' lock is not held meanwhile. A client too slow to take
+    /// the message is disconnected. A client that has already gone is not an
+    /// error: there is no one left to tell.
+    pub async fn send_to(&self, id: &str, message: impl Into<Envelope>) -> Result<()> {
+        let Some(outbox) = self.outbox(id).await else {
+            tracing::debug!("Not sending to client {}: it has disconnected", id);
+            return Ok(());
+        };
+
+        match outbox.push(message).await {
+            Ok(()) => Ok(()),
+            Err(OutboxError::Full) => {
+                self.disconnect(id, ErrorCode::SlowConsumer, SLOW_CONSUMER_REASON).await;
+                anyhow::bail!("Client {} is not reading its messages", id)
+            }
+            Err(e) => Err(e).with_context(|| format!("Failed to send message to client {}", id)),
+        }
+    }
+
     /// Get authenticated client IDs
     pub async fn get_authenticated_clients(&self) -> Vec<String> {
         let clients = self.clients.read().await;
@@ -90,25 +306,59 @@ impl ClientManager {
             .collect()
     }
 
-    /// Broadcast a message to all authenticated clients
-    pub async fn broadcast(&self, message: Message) {
+    /// Broadcast a message to all authenticated clients it is for
+    ///
+    /// Under `Block` the clients are waited on together, so however many
+    /// are stalled the broadcast waits at most `BLOCK_TIMEOUT`. Returns the
+    /// recipients (and their aliases) it was meant to reach,
+    /// including those whose connection failed: it stays unacknowledged for
+    /// them, so it is sent again when they reconnect.
+    pub async fn broadcast(&self, message: Message) -> Vec<(String, Option<String>)> {
         let clients = self.clients.read().await;
 
-        let mut failed = Vec::new();
+        let mut outboxes = Vec::new();
+        let mut recipients = Vec::new();
 
         for (id, client) in clients.iter() {
-            if !client.authenticated {
+            if !client.authenticated || !client.can_see(&message) {
                 continue;
             }
 
-            if let Err(e) = client.broadcast(message.clone()) {
-                tracing::warn!("Failed to send to client {}: {}", id, e);
-                failed.push(id.clone());
+            if let Some(recipient) = client.recipient() {
+                if !recipients.iter().any(|(r, _)| r == recipient) {
+                    recipients.push((recipient.to_string(), client.alias.clone()));
+                }
             }
+
+            outboxes.push((id.clone(), client.outbox.clone()));
         }
 
+        // Queue outside the lock, as a blocking policy may wait for room
         drop(clients);
 
+        let pushes = outboxes.iter().map(|(_, outbox)| {
+            let frame = ProtocolMessage::Broadcast { message: message.clone() };
+            async move { outbox.push(frame).await }
+        });
+        let results = futures::future::join_all(pushes).await;
+
+        let mut slow = Vec::new();
+        let mut failed = Vec::new();
+        for ((id, _), result) in outboxes.into_iter().zip(results) {
+            match result {
+                Ok(()) => {}
+                Err(OutboxError::Full) => slow.push(id),
+                Err(e) => {
+                    tracing::warn!("Failed to send message {} to client {}: {}", message.id, id, e);
+                    failed.push(id);
+                }
+            }
+        }
+
+        for id in slow {
+            self.disconnect(&id, ErrorCode::SlowConsumer, SLOW_CONSUMER_REASON).await;
+        }
+
         // Remove failed clients
         if !failed.is_empty() {
             let mut clients = self.clients.write().await;
@@ -117,6 +367,24 @@ impl ClientManager {
                 tracing::info!("Removed failed client {}", id);
             }
         }
+
+        recipients
+    }
+
+    /// Every connection's outbound queue
+    pub async fn queues(&self) -> Vec<ClientQueue> {
+        let clients = self.clients.read().await;
+        let mut queues: Vec<_> = clients
+            .values()
+            .map(|c| ClientQueue {
+                id: c.id.clone(),
+                name: c.name().to_string(),
+                authenticated: c.authenticated,
+                metrics: c.outbox.metrics(),
+            })
+            .collect();
+        queues.sort_by(|a, b| a.name.cmp(&b.name));
+        queues
     }
 
     /// Get number of connected clients
@@ -138,56 +406,135 @@ impl Default for ClientManager {
     }
 }
 
+/// What the reading side of a connection asks its writing side to send
+enum Control {
+    /// Send a frame, then frame everything after it this way
+    Send(Envelope, Framing),
+    /// Send a last frame and close the connection
+    Close(Envelope),
+}
+
+/// How long a closing connection gets to write its last frame
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Frames the reading side may queue for the writing side before it stops
+/// reading
+const CONTROL_QUEUE_CAPACITY: usize = 16;
+
+/// Frames read from a connection that may wait for the broker
+pub const INCOMING_QUEUE_CAPACITY: usize = 64;
+
 /// Handle a client connection stream
-/// Returns a receiver for outgoing messages to the client
+///
+/// The hello exchange is handled here rather than by the broker, as it
+/// changes how the stream itself is framed. Frames are written from the
+/// connection's `outbox`; frames read wait in `incoming_tx`, and while that
+/// is full the connection is not read, so a client sending faster than the
+/// broker keeps up is slowed down rather than buffered for. Frames that are
+/// not valid messages are answered with an `invalid_request` error, and
+/// while those answers are not being read, neither is the connection.
 pub async fn handle_client_stream(
     stream: UnixStream,
-    mut outgoing_rx: mpsc::UnboundedReceiver<ProtocolMessage>,
-    incoming_tx: mpsc::UnboundedSender<ProtocolMessage>,
+    outbox: Arc<Outbox>,
+    incoming_tx: mpsc::Sender<Envelope>,
 ) -> Result<()> {
     let (read_half, mut write_half) = stream.into_split();
-    let mut reader = BufReader::new(read_half);
-
-    // Spawn task to handle outgoing messages
-    let write_task = tokio::spawn(async move {
-        while let Some(msg) = outgoing_rx.recv().await {
-            if let Ok(bytes) = msg.to_bytes() {
-                if write_half.write_all(&bytes).await.is_err() {
-                    break;
+    let mut reader = FrameReader::new(read_half, MAX_FRAME_SIZE);
+    let (control_tx, mut control_rx) = mpsc::channel(CONTROL_QUEUE_CAPACITY);
+
+    // Spawn task to handle outgoing messages; it ends once the client's
+    // connection is dropped from the manager, which closes its outbox
+    let mut write_task = tokio::spawn(async move {
+        let mut framing = Framing::Lines;
+        loop {
+            let (msg, next, close) = tokio::select! {
+                biased;
+                Some(control) = control_rx.recv() => match control {
+                    Control::Send(msg, next) => (msg, next, false),
+                    Control::Close(msg) => (msg, framing, true),
+                },
+                msg = outbox.pop() => match msg {
+                    Some(msg) => (msg, framing, false),
+                    None => break,
+                },
+            };
+
+            match framing::encode(&msg, framing) {
+                Ok(bytes) => {
+                    if write_half.write_all(&bytes).await.is_err() {
+                        break;
+                    }
                 }
+                Err(e) => tracing::warn!("Failed to encode message: {:#}", e),
+            }
+            framing = next;
+            if close {
+                break;
             }
         }
     });
 
-    // Handle incoming messages
-    let mut line = String::new();
+    // Handle incoming messages, answering in the framing the writing side
+    // is left with
+    let mut framing = Framing::Lines;
+    let mut first = true;
     loop {
-        line.clear();
-
-        match reader.read_line(&mut line).await {
-            Ok(0) => break, // EOF
-            Ok(_) => {
-                let trimmed = line.trim();
-                if trimmed.is_empty() {
-                    continue;
-                }
-
-                match ProtocolMessage::from_bytes(trimmed.as_bytes()) {
-                    Ok(msg) => {
-                        // Send to incoming channel
-                        if incoming_tx.send(msg).is_err() {
-                            break;
-                        }
-                    }
-                    Err(e) => {
-                        tracing::warn!("Failed to parse message: {}", e);
-                    }
+        let read = tokio::select! {
+            read = reader.read_envelope() => read,
+            _ = &mut write_task => break,
+        };
+
+        let envelope = match read {
+            Ok(Some(envelope)) => envelope,
+            Ok(None) => break, // EOF
+            Err(FrameError::Malformed(e)) => {
+                tracing::warn!("Failed to parse message: {}", e);
+                let error = ProtocolMessage::error(ErrorCode::InvalidRequest, format!("Malformed message: {}", e));
+                if control_tx.send(Control::Send(error.into(), framing)).await.is_err() {
+                    break;
                 }
+                first = false;
+                continue;
             }
             Err(e) => {
-                tracing::error!("Error reading from client: {}", e);
+                tracing::warn!("Closing client connection: {}", e);
+                let error = ProtocolMessage::error(ErrorCode::Protocol, e.to_string());
+                let close = async {
+                    let _ = control_tx.send(Control::Close(error.into())).await;
+                    let _ = (&mut write_task).await;
+                };
+                let _ = tokio::time::timeout(CLOSE_TIMEOUT, close).await;
                 break;
             }
+        };
+
+        if let ProtocolMessage::Hello { version, capabilities } = envelope.message {
+            let reply = if first {
+                framing::negotiate(version, &capabilities).map_err(|e| ProtocolMessage::error(ErrorCode::Protocol, e.to_string()))
+            } else {
+                Err(ProtocolMessage::error(ErrorCode::UnexpectedMessage, "Hello must be the first message"))
+            };
+            match reply {
+                Ok((version, capabilities)) => {
+                    // The client may send more lines before it reads this
+                    framing = Framing::negotiated(&capabilities);
+                    reader.switch_when_seen(framing);
+                    let hello = ProtocolMessage::Hello { version, capabilities };
+                    let _ = control_tx.send(Control::Send(Envelope::reply(envelope.request_id, hello), framing)).await;
+                }
+                Err(error) => {
+                    let error = Envelope::reply(envelope.request_id, error);
+                    let _ = control_tx.send(Control::Send(error, framing)).await;
+                }
+            }
+            first = false;
+            continue;
+        }
+        first = false;
+
+        // Send to incoming channel, waiting while it is full
+        if incoming_tx.send(envelope).await.is_err() {
+            break;
         }
     }
 
@@ -198,13 +545,13 @@ pub async fn handle_client_stream(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::msgserver::framing::{capabilities, PROTOCOL_VERSION};
 
     #[tokio::test]
     async fn test_client_manager() {
         let manager = ClientManager::new();
 
-        let (tx, _rx) = mpsc::unbounded_channel();
-        let client = ClientConnection::new(tx);
+        let client = ClientConnection::new(manager.new_outbox());
         let id = client.id.clone();
 
         manager.add_client(client).await;
@@ -218,4 +565,245 @@ mod tests {
         manager.remove_client(&id).await;
         assert_eq!(manager.client_count().await, 0);
     }
+
+    #[tokio::test]
+    async fn test_disconnect_sends_reason_and_closes() {
+        let manager = ClientManager::new();
+
+        let outbox = manager.new_outbox();
+        let id = manager.add_client(ClientConnection::new(outbox.clone())).await;
+        manager.authenticate_client_as(&id, "stored-client").await.unwrap();
+
+        assert_eq!(manager.identities().await, vec![(id.clone(), "stored-client".to_string())]);
+
+        manager.disconnect(&id, ErrorCode::AccessRevoked, "Access revoked").await;
+
+        assert!(matches!(
+            outbox.pop().await.unwrap().message,
+            ProtocolMessage::Error { code: ErrorCode::AccessRevoked, .. }
+        ));
+        // The outbox was closed with the connection
+        assert!(outbox.pop().await.is_none());
+        assert_eq!(manager.client_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_follows_audience() {
+        let manager = ClientManager::new();
+
+        let mut inboxes = Vec::new();
+        for alias in ["alice", "bob", "carol"] {
+            let outbox = manager.new_outbox();
+            let id = manager.add_client(ClientConnection::new(outbox.clone())).await;
+            assert!(manager.set_alias(&id, alias, Some(alias)).await.unwrap());
+            manager.authenticate_client_as(&id, alias).await.unwrap();
+            inboxes.push((id, outbox));
+        }
+        let (alice, bob) = (inboxes[0].0.clone(), inboxes[1].0.clone());
+
+        // Another client cannot take an alias in use
+        assert!(!manager.set_alias(&bob, "alice", Some("bob")).await.unwrap());
+        manager.subscribe(&bob, "ops").await.unwrap();
+
+        let ttl = std::time::Duration::from_secs(60);
+        let public = Message::new("alice".to_string(), "hi all".to_string(), ttl);
+        let direct = Message::new("alice".to_string(), "hi bob".to_string(), ttl)
+            .with_audience(Audience::Client("bob".to_string()));
+        let channel = Message::new("carol".to_string(), "deploying".to_string(), ttl)
+            .with_audience(Audience::Channel("ops".to_string()));
+        for message in [&public, &direct, &channel] {
+            manager.broadcast(message.clone()).await;
+        }
+
+        let mut received = Vec::new();
+        for (_, outbox) in inboxes.iter() {
+            let mut contents = Vec::new();
+            while let Some(ProtocolMessage::Broadcast { message }) = outbox.try_pop().map(|frame| frame.message) {
+                contents.push(message.content);
+            }
+            received.push(contents);
+        }
+        assert_eq!(received[0], ["hi all", "hi bob"]);
+        assert_eq!(received[1], ["hi all", "hi bob", "deploying"]);
+        assert_eq!(received[2], ["hi all"]);
+
+        // Receive applies the same rules
+        let all = vec![public, direct, channel];
+        assert_eq!(manager.visible_to(&alice, all.clone()).await.len(), 2);
+        manager.unsubscribe(&bob, "ops").await.unwrap();
+        assert_eq!(manager.visible_to(&bob, all).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumers_follow_policy() {
+        let ttl = std::time::Duration::from_secs(60);
+        for policy in [SlowConsumerPolicy::DropOldest, SlowConsumerPolicy::Disconnect] {
+            let manager = ClientManager::new().with_outbox(2, policy);
+
+            // One client keeps up, the other never reads
+            let mut outboxes = Vec::new();
+            for alias in ["fast", "stalled"] {
+                let outbox = manager.new_outbox();
+                let id = manager.add_client(ClientConnection::new(outbox.clone())).await;
+                manager.authenticate_client_as(&id, alias).await.unwrap();
+                outboxes.push(outbox);
+            }
+
+            for n in 0..5 {
+                manager.broadcast(Message::new("fast".to_string(), n.to_string(), ttl)).await;
+                assert!(outboxes[0].try_pop().is_some());
+            }
+
+            let stalled = outboxes[1].metrics();
+            assert_eq!(outboxes[0].metrics().sent, 5);
+            assert_eq!(stalled.high_water, 2);
+            match policy {
+                SlowConsumerPolicy::DropOldest => {
+                    assert_eq!(stalled.dropped, 3);
+                    assert_eq!(manager.client_count().await, 2);
+                    let kept: Vec<_> = std::iter::from_fn(|| outboxes[1].try_pop())
+                        .map(|frame| match frame.message {
+                            ProtocolMessage::Broadcast { message } => message.content,
+                            ProtocolMessage::Error { gap: Some(gap), .. } => format!("gap of {}", gap.dropped),
+                            other => panic!("Unexpected frame {:?}", other),
+                        })
+                        .collect();
+                    assert_eq!(kept, ["gap of 3", "3", "4"]);
+                }
+                _ => {
+                    // Told why, after what it had already been sent
+                    assert_eq!(manager.client_count().await, 1);
+                    assert!(matches!(outboxes[1].try_pop().unwrap().message, ProtocolMessage::Broadcast { .. }));
+                    assert!(matches!(
+                        outboxes[1].try_pop().unwrap().message,
+                        ProtocolMessage::Error { code: ErrorCode::SlowConsumer, .. }
+                    ));
+                    assert!(outboxes[1].pop().await.is_none());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_broadcast_waits_on_clients_together() {
+        let ttl = std::time::Duration::from_secs(60);
+        let manager = ClientManager::new().with_outbox(1, SlowConsumerPolicy::Block);
+
+        // None of them ever read, so each holds the broadcast up in full
+        for alias in ["one", "two", "three"] {
+            let outbox = manager.new_outbox();
+            let id = manager.add_client(ClientConnection::new(outbox.clone())).await;
+            manager.authenticate_client_as(&id, alias).await.unwrap();
+        }
+        manager.broadcast(Message::new("one".to_string(), "fills".to_string(), ttl)).await;
+
+        let started = std::time::Instant::now();
+        manager.broadcast(Message::new("one".to_string(), "blocks".to_string(), ttl)).await;
+        assert!(started.elapsed() < 2 * crate::msgserver::outbox::BLOCK_TIMEOUT);
+        assert_eq!(manager.client_count().await, 0);
+    }
+
+    /// Serve one end of a socket pair, returning the other end and the server's channels
+    fn serve() -> (
+        FrameReader<tokio::net::unix::OwnedReadHalf>,
+        tokio::net::unix::OwnedWriteHalf,
+        Arc<Outbox>,
+        mpsc::Receiver<Envelope>,
+    ) {
+        let (server_side, client_side) = UnixStream::pair().unwrap();
+        let outbox = Arc::new(Outbox::new(DEFAULT_OUTBOX_CAPACITY, SlowConsumerPolicy::default()));
+        let (incoming_tx, incoming_rx) = mpsc::channel(INCOMING_QUEUE_CAPACITY);
+        tokio::spawn(handle_client_stream(server_side, outbox.clone(), incoming_tx));
+
+        let (read_half, write_half) = client_side.into_split();
+        (FrameReader::new(read_half, MAX_FRAME_SIZE), write_half, outbox, incoming_rx)
+    }
+
+    #[tokio::test]
+    async fn test_hello_switches_to_length_prefixed_frames() {
+        let (mut reader, mut writer, outbox, mut incoming_rx) = serve();
+
+        let hello = ProtocolMessage::Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: capabilities(),
+        };
+        writer.write_all(&framing::encode(&hello, Framing::Lines).unwrap()).await.unwrap();
+        let Some(ProtocolMessage::Hello { version, capabilities }) = reader.read_frame().await.unwrap() else {
+            panic!("Expected hello back");
+        };
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(Framing::negotiated(&capabilities), Framing::LengthPrefixed);
+        reader.set_framing(Framing::LengthPrefixed);
+
+        // Both directions are length-prefixed from here on
+        writer.write_all(&framing::encode(&ProtocolMessage::Ping, Framing::LengthPrefixed).unwrap()).await.unwrap();
+        assert!(matches!(incoming_rx.recv().await.unwrap().message, ProtocolMessage::Ping));
+        outbox.push(ProtocolMessage::Pong).await.unwrap();
+        assert!(matches!(reader.read_frame().await.unwrap(), Some(ProtocolMessage::Pong)));
+
+        // A second hello is refused, and the framing stays as it is
+        let again = Envelope::reply(Some("2".to_string()), hello);
+        writer.write_all(&framing::encode(&again, Framing::LengthPrefixed).unwrap()).await.unwrap();
+        let error = reader.read_envelope().await.unwrap().unwrap();
+        assert_eq!(error.request_id.as_deref(), Some("2"));
+        assert!(matches!(error.message, ProtocolMessage::Error { code: ErrorCode::UnexpectedMessage, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_clients_without_hello_keep_lines() {
+        let (mut reader, mut writer, outbox, mut incoming_rx) = serve();
+
+        writer.write_all(b"{\"type\":\"ping\"}\n").await.unwrap();
+        assert!(matches!(incoming_rx.recv().await.unwrap().message, ProtocolMessage::Ping));
+        outbox.push(ProtocolMessage::Pong).await.unwrap();
+        assert!(matches!(reader.read_frame().await.unwrap(), Some(ProtocolMessage::Pong)));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_frames_are_answered() {
+        let (mut reader, mut writer, _outbox, mut incoming_rx) = serve();
+
+        // Not JSON, and a type no version of the protocol has
+        writer.write_all(b"not json\n{\"type\":\"teleport\"}\n").await.unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                reader.read_frame().await.unwrap(),
+                Some(ProtocolMessage::Error { code: ErrorCode::InvalidRequest, .. })
+            ));
+        }
+
+        // The connection carries on
+        writer.write_all(b"{\"type\":\"ping\",\"request_id\":\"1\"}\n").await.unwrap();
+        let ping = incoming_rx.recv().await.unwrap();
+        assert_eq!(ping.request_id.as_deref(), Some("1"));
+        assert!(matches!(ping.message, ProtocolMessage::Ping));
+    }
+
+    #[tokio::test]
+    async fn test_unread_errors_stop_the_connection_being_read() {
+        let (_reader, mut writer, _outbox, _incoming_rx) = serve();
+
+        // Malformed lines, and never a read of the errors they get back
+        let lines = b"x\n".repeat(32 * 1024);
+        let mut written = 0;
+        while let Ok(result) = tokio::time::timeout(Duration::from_millis(500), writer.write_all(&lines)).await {
+            result.unwrap();
+            written += lines.len();
+            assert!(written < 4 * 1024 * 1024, "The server read {} bytes without answers being read", written);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_line_closes_connection() {
+        let (mut reader, mut writer, _outbox, mut incoming_rx) = serve();
+
+        // No newline ever comes; the server gives up once the limit is passed
+        let _ = writer.write_all(&vec![b'x'; MAX_FRAME_SIZE + 1]).await;
+        assert!(matches!(
+            reader.read_frame().await.unwrap(),
+            Some(ProtocolMessage::Error { code: ErrorCode::Protocol, .. })
+        ));
+        assert!(reader.read_frame().await.unwrap().is_none());
+        assert!(incoming_rx.recv().await.is_none());
+    }
 }
@@ -1,9 +1,48 @@
 // Command handler for message server CLI
 
 use crate::msgserver::*;
+use crate::msgserver::limits::{ClientLimits, LimitAction};
 use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+
+/// Attachments `receive --save` queues for fetching before the stream waits
+const ATTACHMENT_QUEUE_CAPACITY: usize = 16;
+
+/// Detach `listen --daemon`/`--background` into a background process
+///
+/// Forking is only safe while the process is single-threaded, so this must
+/// run before the async runtime starts. Returns `None` for other commands; in
+/// the original process, reports where the listener went.
+pub fn detach_listener(command: &MsgSrvCommand) -> Result<Option<detach::Detached>> {
+    let MsgSrvCommand::Listen { server, daemon, background, stop: false, status: false, .. } = command else {
+        return Ok(None);
+    };
+    if !daemon && !background {
+        return Ok(None);
+    }
+
+    let state_dir = MsgSrvCli::state_dir();
+    std::fs::create_dir_all(&state_dir)
+        .context("Failed to create state directory")?;
+
+    let state_manager = StateManager::new(&state_dir)?;
+    let connection = resolve_connection(&state_manager, server.clone())?;
+    let paths = detach::ListenerPaths::new(&state_dir, &connection.server_name);
+
+    let detached = detach::daemonize(&paths, *daemon)?;
+    if let detach::Detached::Parent { pid } = detached {
+        println!("👂 Listening for messages on: {}", connection.server_name);
+        println!("  Mode: {}", if *daemon { "System daemon" } else { "Background (detached)" });
+        println!("  PID: {}", pid);
+        println!("  Log: {:?}", paths.log_file);
+        println!("  Pidfile: {:?}", paths.pidfile);
+        println!("\nStop it with: eddi-msgsrv listen --server {} --stop", connection.server_name);
+    }
+
+    Ok(Some(detached))
+}
 
 /// Execute a message server command
 pub async fn execute_command(command: MsgSrvCommand) -> Result<()> {
@@ -12,32 +51,63 @@ pub async fn execute_command(command: MsgSrvCommand) -> Result<()> {
         .context("Failed to create state directory")?;
 
     let state_manager = Arc::new(StateManager::new(&state_dir)?);
-    let server_manager = ServerManager::new(state_manager.clone());
+    // Servers and brokers live in the daemon; these commands only talk to it
+    let daemon = DaemonClient::new(daemon::control_socket_path(&state_dir));
 
     match command {
-        MsgSrvCommand::CreateServer { name, ttl, local_only, stealth } => {
-            handle_create_server(server_manager, name, ttl, local_only, stealth).await
+        MsgSrvCommand::Daemon { stop } => {
+            handle_daemon(state_manager, daemon, &state_dir, stop).await
+        }
+        MsgSrvCommand::CreateServer {
+            name,
+            ttl,
+            local_only,
+            stealth,
+            persistent,
+            max_attachment_size,
+            max_attachment_storage,
+            slow_consumer_policy,
+            client_queue_size,
+            limits,
+            hook,
+        } => {
+            let options = server::ServerOptions {
+                hook: hook.config(),
+                persistent,
+                max_attachment_size,
+                max_attachment_storage,
+                slow_consumer_policy,
+                client_queue_size,
+                limits: limits.config(),
+            };
+            handle_create_server(daemon, name, ttl, local_only, stealth, options).await
         }
         MsgSrvCommand::CreateBroker { server, namespace, timeout, local_only } => {
-            handle_create_broker(server_manager, state_manager, server, namespace, timeout, local_only).await
+            handle_create_broker(daemon, server, namespace, timeout, local_only).await
         }
-        MsgSrvCommand::Connect { code, namespace, time_window, alias } => {
-            handle_connect(state_manager, code, namespace, time_window, alias).await
+        MsgSrvCommand::Connect { code, namespace, time_window, alias, local_only } => {
+            handle_connect(state_manager, code, namespace, time_window, alias, local_only).await
         }
-        MsgSrvCommand::Send { message, server } => {
-            handle_send(state_manager, message, server).await
+        MsgSrvCommand::Send { message, server, to, channel, alias, encrypt, file } => {
+            let audience = message::Audience::from_parts(to, channel)?;
+            let message = message.unwrap_or_default();
+            handle_send(state_manager, message, server, audience, alias, encrypt, file).await
         }
-        MsgSrvCommand::Receive { server, once, since } => {
-            handle_receive(state_manager, server, once, since).await
+        MsgSrvCommand::Receive { server, once, since, json, channel, alias, save_dir } => {
+            let options = remote::ListenOptions { alias, channel };
+            handle_receive(state_manager, server, once, since, json, options, save_dir).await
         }
-        MsgSrvCommand::Listen { server, daemon, background } => {
-            handle_listen(state_manager, server, daemon, background).await
+        MsgSrvCommand::Listen { server, daemon, background, json, channel, alias, hook, stop, status } => {
+            let detached = daemon || background;
+            let hook = hook.config();
+            let options = remote::ListenOptions { alias, channel };
+            handle_listen(state_manager, &state_dir, server, detached, json, hook, options, stop, status).await
         }
         MsgSrvCommand::ListServers { verbose } => {
-            handle_list_servers(state_manager, verbose).await
+            handle_list_servers(daemon, verbose).await
         }
         MsgSrvCommand::ListBrokers => {
-            handle_list_brokers(server_manager).await
+            handle_list_brokers(state_manager, daemon).await
         }
         MsgSrvCommand::ListClients { server } => {
             handle_list_clients(state_manager, server).await
@@ -46,19 +116,25 @@ pub async fn execute_command(command: MsgSrvCommand) -> Result<()> {
             handle_list_connections(state_manager, verbose).await
         }
         MsgSrvCommand::Status { name } => {
-            handle_status(state_manager, server_manager, name).await
+            handle_status(state_manager, daemon, name).await
         }
         MsgSrvCommand::StopServer { name } => {
-            handle_stop_server(server_manager, state_manager, name).await
+            handle_stop_server(daemon, name).await
         }
         MsgSrvCommand::StopBroker { id } => {
-            handle_stop_broker(server_manager, id).await
+            handle_stop_broker(daemon, id).await
         }
         MsgSrvCommand::Disconnect { name } => {
             handle_disconnect(state_manager, name).await
         }
+        MsgSrvCommand::Delivery { message_id, server, alias } => {
+            handle_delivery(state_manager, message_id, server, alias).await
+        }
+        MsgSrvCommand::Identity { server, forget } => {
+            handle_identity(state_manager, server, forget).await
+        }
         MsgSrvCommand::RevokeClient { server, code } => {
-            handle_revoke_client(state_manager, server, code).await
+            handle_revoke_client(daemon, server, code).await
         }
         MsgSrvCommand::Cleanup { force } => {
             handle_cleanup(state_manager, force).await
@@ -66,12 +142,36 @@ pub async fn execute_command(command: MsgSrvCommand) -> Result<()> {
     }
 }
 
+async fn handle_daemon(
+    state_manager: Arc<StateManager>,
+    client: DaemonClient,
+    state_dir: &std::path::Path,
+    stop: bool,
+) -> Result<()> {
+    if stop {
+        client.shutdown().await?;
+        println!("✓ Daemon stopping");
+        return Ok(());
+    }
+
+    let socket_path = daemon::control_socket_path(state_dir);
+    println!("Starting eddi msgsrv daemon");
+    println!("  Control socket: {:?}", socket_path);
+    println!("  Servers that were running are restored; Ctrl+C stops the daemon");
+
+    Daemon::new(state_manager).run(&socket_path).await?;
+
+    println!("✓ Daemon stopped");
+    Ok(())
+}
+
 async fn handle_create_server(
-    server_manager: ServerManager,
+    daemon: DaemonClient,
     name: String,
     ttl: u64,
     local_only: bool,
     _stealth: bool,
+    options: server::ServerOptions,
 ) -> Result<()> {
     println!("Creating eddi messaging server: {}", name);
 
@@ -88,31 +188,35 @@ async fn handle_create_server(
         println!();
     }
 
-    let instance = server_manager.create_server(name.clone(), ttl, use_tor).await?;
+    let server = daemon.create_server(name.clone(), ttl, use_tor, options).await?;
 
     println!("✓ Eddi messaging server '{}' created", name);
-    println!("  Socket: {:?}", instance.config().socket_path);
+    println!("  Socket: {:?}", server.socket_path);
     println!("  Message TTL: {} minutes", ttl);
-    println!("  Status: Running");
+    if server.persistent {
+        println!("  Queue: persistent (kept across restarts)");
+    }
+    if let Some(ref hook) = server.hook {
+        println!("  Hook: {}", hook.command);
+    }
+    println!("  Slow clients: {}", server.slow_consumer_policy);
+    if server.limits.is_limited() {
+        println!("  Client limits: {}", describe_limits(&server.limits));
+    }
+    println!("  Status: Running (hosted by the daemon)");
 
-    if let Some(ref onion_addr) = instance.config().onion_address {
+    if let Some(ref onion_addr) = server.onion_address {
         println!("\n🧅 Onion Address: {}", onion_addr);
         println!("  (Accessible via Tor network)");
     }
 
-    // Keep the server running
-    println!("\nPress Ctrl+C to stop the server");
-    tokio::signal::ctrl_c().await?;
-
-    println!("\nStopping server...");
-    instance.shutdown().await?;
+    println!("\nStop it with: eddi-msgsrv stop-server {}", name);
 
     Ok(())
 }
 
 async fn handle_create_broker(
-    server_manager: ServerManager,
-    state_manager: Arc<StateManager>,
+    daemon: DaemonClient,
     server_name: String,
     namespace: String,
     timeout: u64,
@@ -131,37 +235,43 @@ async fn handle_create_broker(
         println!();
     }
 
-    // Verify server exists
-    let server_config = state_manager.get_server(&server_name)?
-        .context("Server not found")?;
-
-    // Generate code and create handshake
-    let code = handshake::generate_short_code();
-    let broker_handshake = BrokerHandshake::new(
-        namespace.clone(),
-        code.clone(),
-        server_config.onion_address.unwrap_or_else(|| server_name.clone()),
-    );
+    let (broker, code) = daemon
+        .create_broker(server_name, namespace.clone(), timeout, use_tor)
+        .await?;
 
     println!("✓ Broker created");
     println!("\n📋 Connection Details:");
     println!("  Namespace: {}", namespace);
     println!("  Short Code: {}", code);
     println!("  Valid for: {} seconds", timeout);
-    println!("  Broker ID: {}", broker_handshake.identifier());
+    println!("  Broker ID: {}", broker.id);
+    if let Some(ref onion_addr) = broker.onion_address {
+        println!("  Onion Address: {}", onion_addr);
+    }
 
     println!("\n💡 Share with your client:");
-    println!("  eddi-msgsrv connect --code {} --namespace {}", code, namespace);
-
-    // Create the broker instance
-    let instance = server_manager.create_broker(server_name, Duration::from_secs(timeout)).await?;
+    if use_tor {
+        println!("  eddi-msgsrv connect --code {} --namespace {}", code, namespace);
+    } else {
+        println!("  eddi-msgsrv connect --code {} --namespace {} --local-only", code, namespace);
+    }
 
-    // Wait for timeout or connection
+    // The broker stops by itself once used, timed out or stopped elsewhere
     println!("\n⏳ Waiting for client connection...");
-    tokio::time::sleep(Duration::from_secs(timeout)).await;
+    let finished = tokio::select! {
+        finished = daemon.wait_broker(broker.id.clone()) => finished?,
+        _ = tokio::signal::ctrl_c() => {
+            println!("\nStopping broker...");
+            daemon.stop_broker(broker.id.clone()).await?;
+            daemon.wait_broker(broker.id.clone()).await?
+        }
+    };
 
-    println!("✓ Broker timeout reached, shutting down");
-    instance.shutdown().await?;
+    match finished.status {
+        storage::BrokerStatus::Used => println!("✓ Client introduced, broker shut down"),
+        storage::BrokerStatus::Expired => println!("✓ Broker timeout reached, shut down"),
+        _ => println!("✓ Broker stopped"),
+    }
 
     Ok(())
 }
@@ -172,6 +282,7 @@ async fn handle_connect(
     namespace: String,
     time_window: i64,
     alias: Option<String>,
+    local_only: bool,
 ) -> Result<()> {
     println!("🔍 Searching for broker...");
     println!("  Code: {}", code);
@@ -179,49 +290,212 @@ async fn handle_connect(
     println!("  Time window: ±{} minutes", time_window);
 
     let client_handshake = ClientHandshake::new(namespace.clone(), code.clone());
-    let possible_identifiers = client_handshake.possible_identifiers(time_window);
-
-    println!("  Trying {} possible timestamps...", possible_identifiers.len());
-
-    // In a real implementation, this would try to connect to each broker
-    // For now, we'll simulate finding one
-    if let Some((timestamp, identifier)) = possible_identifiers.first() {
-        println!("✓ Found broker at timestamp {}", timestamp);
-        println!("  Broker ID: {}", identifier);
-
-        // Create introduction data (simulated)
-        let intro = BrokerHandshake::new(
-            namespace.clone(),
-            code.clone(),
-            "fortress-address.onion".to_string(),
-        ).create_introduction(24);
-
-        println!("\n✓ Handshake successful!");
-        println!("  Fortress: {}", intro.fortress_address);
-        println!("  Access token: {}...", &intro.access_token[..8]);
-
-        // Save connection
-        let connection = storage::ConnectionConfig {
-            id: uuid::Uuid::new_v4().to_string(),
-            server_name: intro.fortress_address.clone(),
-            alias: alias.clone(),
-            code,
-            socket_path: None,
-            onion_address: Some(intro.fortress_address),
-            connected_at: std::time::SystemTime::now(),
-            status: storage::ClientStatus::Connected,
+    let timestamps = handshake::generate_time_window(time_window);
+
+    println!("  Trying {} possible timestamps...", timestamps.len());
+    if !local_only {
+        println!("⏳ Brokers not found locally are searched for over Tor (this may take a while)...");
+    }
+
+    let client_id = uuid::Uuid::new_v4().to_string();
+    let secret_key = e2e::SecretKey::generate();
+    let intro = intro::discover(
+        &client_handshake,
+        time_window,
+        &client_id,
+        Some(secret_key.public_key()),
+        local_only,
+    )
+    .await?;
+
+    println!("\n✓ Handshake successful!");
+    println!("  Fortress: {}", intro.fortress_address);
+    println!("  Access token: {}...", &intro.access_token[..8.min(intro.access_token.len())]);
+
+    // Save connection
+    let onion_address = intro
+        .fortress_address
+        .ends_with(".onion")
+        .then(|| intro.fortress_address.clone());
+
+    let connection = storage::ConnectionConfig {
+        id: client_id,
+        server_name: intro.fortress_address,
+        alias: alias.clone(),
+        code: intro.access_token,
+        socket_path: intro.fortress_socket,
+        onion_address,
+        connected_at: std::time::SystemTime::now(),
+        status: storage::ClientStatus::Connected,
+        secret_key: Some(secret_key.clone()),
+        peer_keys: intro.peer_keys,
+        alias_keys: Default::default(),
+    };
+
+    state_manager.create_connection(connection)?;
+
+    println!("\n✓ Connected to fortress!");
+    if let Some(alias) = alias {
+        println!("  Alias: {}", alias);
+    }
+    println!("  Encryption key: {}", secret_key.public_key());
+
+    Ok(())
+}
+
+/// Look up a saved connection by name or alias, or the most recent one
+fn resolve_connection(
+    state_manager: &StateManager,
+    server: Option<String>,
+) -> Result<storage::ConnectionConfig> {
+    if let Some(server_name) = server {
+        state_manager.get_connection_config(&server_name)?
+            .context("Connection not found")
+    } else {
+        // Get most recent connection
+        let connections = state_manager.list_connections()?;
+        connections.into_iter().next()
+            .context("No active connections. Connect to a fortress first.")
+    }
+}
+
+/// How a message's sender compares with the key pinned for it
+///
+/// Pins the sender's key the first time its alias is seen. Senders without
+/// an alias are known only by a connection ID that changes, so are not pinned.
+/// An unsigned message from an alias with a pinned key, or a signature seen
+/// before on another message, is flagged rather than quietly accepted.
+fn sender_trust(state_manager: &StateManager, server_name: &str, message: &Message) -> identity::Trust {
+    let alias = message.sender_alias();
+    let Some(ref signature) = message.signature else {
+        let pinned = alias.and_then(|alias| {
+            state_manager.pinned_key(server_name, alias).unwrap_or_else(|e| {
+                tracing::error!("Failed to look up the key of {}: {:#}", alias, e);
+                None
+            })
+        });
+        return match pinned {
+            Some(pinned) => identity::Trust::Stripped { pinned },
+            None => identity::Trust::Unsigned,
         };
+    };
+    match state_manager.record_signature(server_name, signature, &message.id) {
+        Ok(true) => {}
+        Ok(false) => return identity::Trust::Replayed,
+        Err(e) => tracing::error!("Failed to record the signature of {}: {:#}", message.id, e),
+    }
+    let Some(alias) = alias else {
+        return identity::Trust::New;
+    };
 
-        state_manager.create_connection(connection)?;
+    state_manager
+        .check_sender_key(server_name, alias, &signature.key)
+        .unwrap_or_else(|e| {
+            tracing::error!("Failed to check the key of {}: {:#}", message.from, e);
+            identity::Trust::New
+        })
+}
+
+/// Short form of a fingerprint, for listing messages
+fn short_fingerprint(key: &identity::IdentityKey) -> String {
+    key.fingerprint().chars().take(9).collect()
+}
 
-        println!("\n✓ Connected to fortress!");
-        if let Some(alias) = alias {
-            println!("  Alias: {}", alias);
+/// Print a received message for a person or, with `json`, as one JSON line
+///
+/// Either way the sender's key is checked against (or pinned as) the one
+/// known for it on `server_name`.
+fn print_message(state_manager: &StateManager, server_name: &str, message: &Message, json: bool) {
+    let trust = sender_trust(state_manager, server_name, message);
+
+    if json {
+        match serde_json::to_string(message) {
+            Ok(line) => println!("{}", line),
+            Err(e) => tracing::error!("Failed to encode message {}: {}", message.id, e),
         }
     } else {
-        anyhow::bail!("No brokers found in time window");
+        let audience = match message.audience() {
+            message::Audience::Everyone => String::new(),
+            message::Audience::Client(to) => format!(" → {}", to),
+            message::Audience::Channel(channel) => format!(" [{}]", channel),
+        };
+        // Sealed messages we could not open are unreadable anyway
+        let content = if e2e::is_sealed(&message.content) {
+            "🔒 [encrypted message]"
+        } else {
+            &message.content
+        };
+        let sender = match (&trust, &message.signature) {
+            (identity::Trust::Changed { pinned }, Some(signature)) => format!(
+                "⚠ {} [{}, KEY CHANGED from {}]",
+                message.from,
+                short_fingerprint(&signature.key),
+                short_fingerprint(pinned)
+            ),
+            (identity::Trust::Replayed, Some(signature)) => format!(
+                "⚠ {} [{}, REPLAYED]",
+                message.from,
+                short_fingerprint(&signature.key)
+            ),
+            (identity::Trust::Stripped { pinned }, None) => format!(
+                "⚠ {} (UNSIGNED, key pinned {})",
+                message.from,
+                short_fingerprint(pinned)
+            ),
+            (_, Some(signature)) => format!("{} [{}]", message.from, short_fingerprint(&signature.key)),
+            (_, None) => format!("{} (unsigned)", message.from),
+        };
+        println!("[{}s ago] {}{}: {}", message.age_seconds(), sender, audience, content);
+        if let Some(ref attachment) = message.attachment {
+            println!("  📎 {} ({} bytes)", attachment.name, attachment.size);
+        }
     }
+}
 
+/// Write a fetched attachment into `dir`, never over an existing file
+///
+/// Returns where it was saved; a name already taken gets a " (N)" suffix.
+fn save_attachment(dir: &Path, info: &attachment::AttachmentInfo, data: &[u8]) -> Result<PathBuf> {
+    use std::io::Write;
+
+    // The server checks names, but we do not have to trust it
+    attachment::validate_file_name(&info.name)?;
+    let name = Path::new(&info.name);
+    let stem = name.file_stem().unwrap_or(name.as_os_str()).to_string_lossy();
+    let extension = name.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+
+    for n in 0.. {
+        let path = match n {
+            0 => dir.join(&info.name),
+            n => dir.join(format!("{} ({}){}", stem, n, extension)),
+        };
+        let mut file = match std::fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to create {}", path.display())),
+        };
+        file.write_all(data)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        return Ok(path);
+    }
+    unreachable!()
+}
+
+/// Fetch a message's attachment over `client` and save it into `dir`
+async fn fetch_and_save(
+    client: &mut RemoteClient,
+    dir: &Path,
+    info: &attachment::AttachmentInfo,
+    json: bool,
+) -> Result<()> {
+    let data = client
+        .fetch_attachment(info)
+        .await
+        .with_context(|| format!("Failed to fetch attachment {}", info.name))?;
+    let path = save_attachment(dir, info, &data)?;
+    if !json {
+        println!("  💾 Saved {} to {}", info.name, path.display());
+    }
     Ok(())
 }
 
@@ -229,85 +503,300 @@ async fn handle_send(
     state_manager: Arc<StateManager>,
     message: String,
     server: Option<String>,
+    audience: message::Audience,
+    alias: Option<String>,
+    encrypt: bool,
+    file: Option<PathBuf>,
 ) -> Result<()> {
-    // Get connection
-    let connection = if let Some(server_name) = server {
-        state_manager.get_connection_config(&server_name)?
-            .context("Connection not found")?
-    } else {
-        // Get most recent connection
-        let connections = state_manager.list_connections()?;
-        connections.into_iter().next()
-            .context("No active connections. Connect to a fortress first.")?
+    let connection = resolve_connection(&state_manager, server)?;
+
+    // Read the file first, so a bad path fails before anything is sent
+    let attachment = match file {
+        Some(path) => {
+            let name = path
+                .file_name()
+                .with_context(|| format!("{} is not a file", path.display()))?
+                .to_string_lossy()
+                .into_owned();
+            let data = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+            Some((name, data))
+        }
+        None => None,
     };
 
-    println!("📤 Sending message to: {}", connection.server_name);
-    println!("  Message: {}", message);
+    match audience {
+        message::Audience::Everyone => println!("📤 Sending message to: {}", connection.server_name),
+        message::Audience::Client(ref to) => {
+            println!("📤 Sending direct message to {} on: {}", to, connection.server_name)
+        }
+        message::Audience::Channel(ref channel) => {
+            println!("📤 Sending message to channel {} on: {}", channel, connection.server_name)
+        }
+    }
 
-    // In a real implementation, connect to the socket and send
-    // For now, we'll simulate it
-    println!("✓ Message sent");
+    let identity = Arc::new(state_manager.identity()?);
+    let fingerprint = identity.public_key().fingerprint();
+    let mut client = RemoteClient::connect(&connection)
+        .await?
+        .with_alias(alias.clone())
+        .with_identity(Some(identity));
+    client.authenticate(&connection.code).await?;
+    let message_id = match attachment {
+        Some((ref name, ref data)) if encrypt => {
+            client.send_encrypted_attachment(&message, audience, name, data).await?
+        }
+        Some((ref name, ref data)) => client.send_attachment(&message, audience, name, data).await?,
+        None if encrypt => client.send_encrypted(&message, audience).await?,
+        None => client.send_message_to(&message, audience).await?,
+    };
+
+    // Keys trusted on first use are held to from now on
+    let (peer_keys, alias_keys) = client.trusted_keys();
+    if peer_keys != connection.peer_keys.as_slice() || *alias_keys != connection.alias_keys {
+        state_manager.update_connection_keys(&connection.id, peer_keys, alias_keys)?;
+    }
+
+    println!("✓ Message sent{}", if encrypt { " (encrypted)" } else { "" });
+    println!("  ID: {}", message_id);
+    if let Some((name, data)) = attachment {
+        println!("  Attached: {} ({} bytes)", name, data.len());
+    }
+    println!("  Signed by: {}", fingerprint);
+    // Only a named sender can find its message again from another connection
+    if let Some(alias) = alias {
+        println!("\nCheck delivery with: eddi-msgsrv delivery {} --as {}", message_id, alias);
+    }
+
+    Ok(())
+}
+
+/// Connect and authenticate a client that only fetches attachments
+async fn connect_fetcher(
+    connection: &storage::ConnectionConfig,
+    options: &remote::ListenOptions,
+) -> Result<RemoteClient> {
+    let mut client = RemoteClient::connect(connection).await?.with_alias(options.alias.clone());
+    client.authenticate(&connection.code).await?;
+    Ok(client)
+}
+
+async fn handle_delivery(
+    state_manager: Arc<StateManager>,
+    message_id: String,
+    server: Option<String>,
+    alias: Option<String>,
+) -> Result<()> {
+    let connection = resolve_connection(&state_manager, server)?;
+
+    let mut client = RemoteClient::connect(&connection).await?.with_alias(alias);
+    client.authenticate(&connection.code).await?;
+    let deliveries = client.delivery_status(&message_id).await?;
+
+    println!("📬 Delivery of message {}:", message_id);
+    if deliveries.is_empty() {
+        println!("  Not delivered to anyone yet");
+    }
+
+    let ago = |time: SystemTime| SystemTime::now().duration_since(time).unwrap_or_default().as_secs();
+    for delivery in &deliveries {
+        let name = delivery.alias.as_deref().unwrap_or(&delivery.recipient);
+        match delivery.acked_at {
+            Some(acked_at) => println!(
+                "  ✓ {}: delivered {}s ago, acknowledged {}s ago",
+                name,
+                ago(delivery.delivered_at),
+                ago(acked_at)
+            ),
+            None => println!("  … {}: delivered {}s ago, not acknowledged", name, ago(delivery.delivered_at)),
+        }
+    }
+
+    let acked = deliveries.iter().filter(|d| d.acked_at.is_some()).count();
+    println!("\n{} of {} recipients acknowledged", acked, deliveries.len());
 
     Ok(())
 }
 
 async fn handle_receive(
-    _state_manager: Arc<StateManager>,
+    state_manager: Arc<StateManager>,
     server: Option<String>,
     once: bool,
-    _since: Option<u64>,
+    since: Option<u64>,
+    json: bool,
+    options: remote::ListenOptions,
+    save_dir: Option<PathBuf>,
 ) -> Result<()> {
-    let server_name = server.as_deref().unwrap_or("default");
+    let connection = resolve_connection(&state_manager, server)?;
+    let since = since.map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+    if let Some(ref dir) = save_dir {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
 
-    println!("📥 Receiving messages from: {}", server_name);
+    if !json {
+        println!("📥 Receiving messages from: {}", connection.server_name);
+    }
 
     if once {
-        println!("  Mode: One-time receive");
-        // Receive once and exit
-        println!("✓ No new messages");
+        let mut client = RemoteClient::connect(&connection).await?.with_alias(options.alias);
+        client.authenticate(&connection.code).await?;
+        let messages = client.receive_on(options.channel, since, None).await?;
+
+        if messages.is_empty() && !json {
+            println!("✓ No new messages");
+        }
+        for message in &messages {
+            print_message(&state_manager, &connection.server_name, message, json);
+            if let (Some(dir), Some(info)) = (&save_dir, &message.attachment) {
+                if let Err(e) = fetch_and_save(&mut client, dir, info, json).await {
+                    eprintln!("⚠ {:#}", e);
+                }
+            }
+        }
+        client.acknowledge_all(messages.iter().map(|m| m.id.as_str())).await?;
     } else {
-        println!("  Mode: Continuous");
-        println!("  (Press Ctrl+C to stop)");
+        if !json {
+            println!("  Mode: Continuous");
+            println!("  (Press Ctrl+C to stop)");
+        }
 
-        tokio::signal::ctrl_c().await?;
-        println!("\n✓ Stopped receiving");
+        // Attachments are fetched beside the stream, over a connection of
+        // their own. When the fetcher falls behind, the stream waits for it
+        // and the server holds on to the rest.
+        let (attachment_tx, mut attachment_rx) =
+            tokio::sync::mpsc::channel::<attachment::AttachmentInfo>(ATTACHMENT_QUEUE_CAPACITY);
+        let saver = save_dir.clone().map(|dir| {
+            let connection = connection.clone();
+            let options = options.clone();
+            tokio::spawn(async move {
+                let mut fetcher: Option<RemoteClient> = None;
+                while let Some(info) = attachment_rx.recv().await {
+                    if fetcher.is_none() {
+                        match connect_fetcher(&connection, &options).await {
+                            Ok(client) => fetcher = Some(client),
+                            Err(e) => {
+                                eprintln!("⚠ Failed to fetch attachment {}: {:#}", info.name, e);
+                                continue;
+                            }
+                        }
+                    }
+                    if let Some(ref mut client) = fetcher {
+                        if let Err(e) = fetch_and_save(client, &dir, &info, json).await {
+                            eprintln!("⚠ {:#}", e);
+                            // Start afresh in case the connection is what failed
+                            fetcher = None;
+                        }
+                    }
+                }
+            })
+        });
+
+        let since = since.unwrap_or(SystemTime::UNIX_EPOCH);
+        let result = tokio::select! {
+            result = RemoteClient::listen_with(&connection, since, &options, |m| {
+                print_message(&state_manager, &connection.server_name, m, json);
+                if let (Some(_), Some(info)) = (&save_dir, &m.attachment) {
+                    // The handler is synchronous, so wait for room off the runtime
+                    let _ = tokio::task::block_in_place(|| attachment_tx.blocking_send(info.clone()));
+                }
+            }) => result,
+            _ = tokio::signal::ctrl_c() => {
+                if !json {
+                    println!("\n✓ Stopped receiving");
+                }
+                Ok(())
+            }
+        };
+        if let Some(saver) = saver {
+            saver.abort();
+        }
+        result?;
     }
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_listen(
-    _state_manager: Arc<StateManager>,
+    state_manager: Arc<StateManager>,
+    state_dir: &std::path::Path,
     server: Option<String>,
-    daemon: bool,
-    background: bool,
+    detached: bool,
+    json: bool,
+    hook: Option<HookConfig>,
+    options: remote::ListenOptions,
+    stop: bool,
+    status: bool,
 ) -> Result<()> {
-    let server_name = server.as_deref().unwrap_or("default");
+    let connection = resolve_connection(&state_manager, server)?;
+    let paths = detach::ListenerPaths::new(state_dir, &connection.server_name);
 
-    println!("👂 Listening for messages on: {}", server_name);
+    if stop {
+        let pid = detach::stop(&paths)?;
+        println!("✓ Stopped background listener for {} (PID {})", connection.server_name, pid);
+        return Ok(());
+    }
 
-    if daemon {
-        println!("  Mode: System daemon");
-        // Would fork and run as daemon
-    } else if background {
-        println!("  Mode: Background (detached)");
-        // Would detach from terminal
-    } else {
+    if status {
+        match detach::running_pid(&paths.pidfile)? {
+            Some(pid) => {
+                println!("Listener for {}: running (PID {})", connection.server_name, pid);
+                println!("  Log: {:?}", paths.log_file);
+            }
+            None => println!("Listener for {}: not running", connection.server_name),
+        }
+        return Ok(());
+    }
+
+    // Detached listeners were forked off by `detach_listener` and log to a file
+    if detached {
+        tracing::info!(
+            "Background listener for {} started (PID {})",
+            connection.server_name,
+            std::process::id()
+        );
+    } else if !json {
+        println!("👂 Listening for messages on: {}", connection.server_name);
         println!("  Mode: Foreground");
         println!("  (Press Ctrl+C to stop)");
+    }
+
+    let hook_tx = hook.map(|config| {
+        hook::Hook::new(config)
+            .with_server(connection.server_name.clone())
+            .spawn()
+    });
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .context("Failed to install SIGTERM handler")?;
+
+    // Only messages that arrive from now on
+    let since = SystemTime::now();
+    let handler = |message: &Message| {
+        print_message(&state_manager, &connection.server_name, message, json);
+        if let Some(ref hook_tx) = hook_tx {
+            hook_tx.submit(message.clone());
+        }
+    };
 
-        tokio::signal::ctrl_c().await?;
-        println!("\n✓ Stopped listening");
+    tokio::select! {
+        result = RemoteClient::listen_with(&connection, since, &options, handler) => result?,
+        _ = tokio::signal::ctrl_c() => {
+            if !json {
+                println!("\n✓ Stopped listening");
+            }
+        }
+        _ = terminate.recv() => {
+            tracing::info!("SIGTERM received, stopping listener");
+        }
     }
 
     Ok(())
 }
 
 async fn handle_list_servers(
-    state_manager: Arc<StateManager>,
+    daemon: DaemonClient,
     verbose: bool,
 ) -> Result<()> {
-    let servers = state_manager.list_servers()?;
+    let servers = daemon.list_servers().await?;
 
     if servers.is_empty() {
         println!("No eddi messaging servers found");
@@ -316,7 +805,7 @@ async fn handle_list_servers(
 
     println!("Eddi messaging servers ({}):", servers.len());
     for server in servers {
-        println!("\n  {} [{}]", server.name, server.status.to_string());
+        println!("\n  {} [{}]", server.name, server.status);
         if verbose {
             println!("    ID: {}", server.id);
             println!("    Socket: {:?}", server.socket_path);
@@ -331,22 +820,34 @@ async fn handle_list_servers(
 }
 
 async fn handle_list_brokers(
-    server_manager: ServerManager,
+    state_manager: Arc<StateManager>,
+    daemon: DaemonClient,
 ) -> Result<()> {
-    let servers = server_manager.list_servers().await;
-    let brokers: Vec<_> = servers.into_iter()
-        .filter(|s| s.config().name.starts_with("broker-"))
-        .collect();
+    let brokers = daemon.list_brokers().await?;
 
     if brokers.is_empty() {
         println!("No active brokers");
         return Ok(());
     }
 
+    let now = std::time::SystemTime::now();
+
     println!("Active Brokers ({}):", brokers.len());
     for broker in brokers {
-        println!("  {}", broker.config().name);
-        println!("    Socket: {:?}", broker.config().socket_path);
+        let fortress = state_manager.get_server_by_id(&broker.fortress_id)?
+            .map(|server| server.name)
+            .unwrap_or_else(|| broker.fortress_id.clone());
+        let remaining = broker.expires_at.duration_since(now).unwrap_or_default();
+
+        println!("\n  ID: {}", broker.id);
+        println!("    Fortress: {}", fortress);
+        println!("    Namespace: {}", broker.namespace);
+        println!("    Expires in: {}s", remaining.as_secs());
+        println!("    Socket: {:?}", broker.socket_path);
+        if let Some(ref onion) = broker.onion_address {
+            println!("    Onion: {}", onion);
+        }
+        println!("    PID: {}", broker.pid);
     }
 
     Ok(())
@@ -369,7 +870,7 @@ async fn handle_list_clients(
     println!("Clients for '{}' ({}):", server_name, clients.len());
     for client in clients {
         println!("\n  Code: {}", client.code);
-        println!("    Status: {}", client.status.to_string());
+        println!("    Status: {}", client.status);
         println!("    Created: {:?}", client.created_at);
         if let Some(connected) = client.connected_at {
             println!("    Connected: {:?}", connected);
@@ -393,7 +894,7 @@ async fn handle_list_connections(
     println!("Connections ({}):", connections.len());
     for conn in connections {
         let display_name = conn.alias.as_ref().unwrap_or(&conn.server_name);
-        println!("\n  {} [{}]", display_name, conn.status.to_string());
+        println!("\n  {} [{}]", display_name, conn.status);
         if verbose {
             println!("    Server: {}", conn.server_name);
             println!("    Code: {}", conn.code);
@@ -412,55 +913,121 @@ async fn handle_list_connections(
 
 async fn handle_status(
     state_manager: Arc<StateManager>,
-    server_manager: ServerManager,
+    daemon: DaemonClient,
     name: Option<String>,
 ) -> Result<()> {
+    // Status is diagnostic, so it still reports what it can without a daemon
+    let daemon_status = if daemon.is_running() {
+        Some(daemon.status().await?)
+    } else {
+        None
+    };
+
     if let Some(server_name) = name {
         // Show specific server status
         let server = state_manager.get_server(&server_name)?
             .context("Server not found")?;
+        let hosted = daemon_status.as_ref()
+            .is_some_and(|status| status.servers.contains(&server.name));
 
         println!("Server: {}", server.name);
-        println!("  Status: {}", server.status.to_string());
+        println!("  Status: {}", server.status);
+        println!("  Hosted by daemon: {}", if hosted { "yes" } else { "no" });
         println!("  Socket: {:?}", server.socket_path);
         println!("  TTL: {} minutes", server.ttl_minutes);
 
+        println!("  Slow clients: {}", server.slow_consumer_policy);
+        if server.limits.is_limited() {
+            println!("  Client limits: {}", describe_limits(&server.limits));
+        }
+
         let clients = state_manager.list_clients(&server.id)?;
         println!("  Clients: {}", clients.len());
+
+        if hosted {
+            let queues = daemon.client_queues(server.name.clone()).await?;
+            println!("  Connections: {}", queues.len());
+            for queue in queues {
+                let metrics = queue.metrics;
+                println!(
+                    "    {}{}: {} queued (peak {}), {} sent, {} dropped, {} waits",
+                    queue.name,
+                    if queue.authenticated { "" } else { " (unauthenticated)" },
+                    metrics.queued,
+                    metrics.high_water,
+                    metrics.sent,
+                    metrics.dropped,
+                    metrics.blocked,
+                );
+            }
+        }
     } else {
         // Show all servers
         let servers = state_manager.list_servers()?;
-        let running = server_manager.list_servers().await;
         let connections = state_manager.list_connections()?;
 
         println!("eddi Message Server Status\n");
-        println!("Fortresses: {} ({} running)", servers.len(), running.len());
+        match daemon_status {
+            Some(status) => {
+                let uptime = SystemTime::now()
+                    .duration_since(status.started_at)
+                    .unwrap_or_default();
+                println!("Daemon: running (PID {}, up {}s)", status.pid, uptime.as_secs());
+                println!("Fortresses: {} ({} running)", servers.len(), status.servers.len());
+                println!("Brokers: {} active", status.brokers.len());
+            }
+            None => {
+                println!("Daemon: not running (start it with `eddi-msgsrv daemon`)");
+                println!("Fortresses: {} (none running)", servers.len());
+            }
+        }
         println!("Connections: {}", connections.len());
     }
 
     Ok(())
 }
 
+/// One line summing up a server's client limits
+fn describe_limits(limits: &ClientLimits) -> String {
+    let mut parts = Vec::new();
+    if let Some(rate) = limits.messages_per_minute {
+        parts.push(format!("{} messages/minute", rate));
+    }
+    if let Some(max) = limits.max_queued_messages {
+        parts.push(format!("{} queued messages", max));
+    }
+    if let Some(max) = limits.max_queued_bytes {
+        parts.push(format!("{} queued bytes", max));
+    }
+    if let Some(max) = limits.max_message_size {
+        parts.push(format!("{} bytes/message", max));
+    }
+    let action = match limits.action {
+        LimitAction::Mute => format!("mute for {}s", limits.mute_secs),
+        action => action.to_string(),
+    };
+    format!("{} (over: {})", parts.join(", "), action)
+}
+
 async fn handle_stop_server(
-    server_manager: ServerManager,
-    _state_manager: Arc<StateManager>,
+    daemon: DaemonClient,
     name: String,
 ) -> Result<()> {
     println!("Stopping eddi messaging server: {}", name);
 
-    server_manager.stop_server(&name).await?;
+    daemon.stop_server(name).await?;
 
     println!("✓ Server stopped");
     Ok(())
 }
 
 async fn handle_stop_broker(
-    server_manager: ServerManager,
+    daemon: DaemonClient,
     id: String,
 ) -> Result<()> {
     println!("Stopping broker: {}", id);
 
-    server_manager.stop_server(&id).await?;
+    daemon.stop_broker(id).await?;
 
     println!("✓ Broker stopped");
     Ok(())
@@ -478,23 +1045,58 @@ async fn handle_disconnect(
     Ok(())
 }
 
-async fn handle_revoke_client(
+async fn handle_identity(
     state_manager: Arc<StateManager>,
+    server: Option<String>,
+    forget: Option<String>,
+) -> Result<()> {
+    let identity = state_manager.identity()?;
+    println!("Identity:");
+    println!("  Fingerprint: {}", identity.public_key().fingerprint());
+    println!("  Public key: {}", identity.public_key());
+
+    // Pins are kept per connection, so only shown once there is one
+    let connection = match resolve_connection(&state_manager, server) {
+        Ok(connection) => connection,
+        Err(e) if forget.is_none() => {
+            tracing::debug!("No connection to show pinned keys for: {:#}", e);
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    if let Some(alias) = forget {
+        if state_manager.unpin_key(&connection.server_name, &alias)? {
+            println!("\n✓ Forgot the key pinned for {}; the next one seen will be pinned", alias);
+        } else {
+            println!("\nNo key is pinned for {} on {}", alias, connection.server_name);
+        }
+        return Ok(());
+    }
+
+    let pins = state_manager.list_pinned_keys(&connection.server_name)?;
+    println!("\nPinned sender keys on {} ({}):", connection.server_name, pins.len());
+    for (alias, key) in pins {
+        println!("  {}: {}", alias, key.fingerprint());
+    }
+
+    Ok(())
+}
+
+async fn handle_revoke_client(
+    daemon: DaemonClient,
     server_name: String,
     code: String,
 ) -> Result<()> {
     println!("Revoking client access...");
     println!("  Server: {}", server_name);
-    println!("  Code: {}", code);
-
-    // Get client by code
-    let client = state_manager.get_client_by_code(&code)?
-        .context("Client not found")?;
 
-    // Update status to disconnected
-    state_manager.update_client_status(&client.id, storage::ClientStatus::Disconnected)?;
+    // The daemon hosting the fortress drops the client's live connections
+    let client_id = daemon.revoke_client(server_name, code).await?;
 
     println!("✓ Client access revoked");
+    println!("  Client ID: {}", client_id);
+    println!("  Live connections have been dropped");
     Ok(())
 }
 
@@ -523,6 +1125,24 @@ async fn handle_cleanup(
         }
     }
 
+    // Find brokers that were used, expired, stopped or whose process died
+    let inactive = state_manager.list_brokers()?
+        .into_iter()
+        .filter(|broker| !broker.is_active())
+        .count();
+
+    println!("  Inactive brokers: {}", inactive);
+
+    if force && inactive > 0 {
+        state_manager.delete_inactive_brokers()?;
+    }
+
+    // Expired messages left behind by persistent servers that are not running
+    if force {
+        let expired = state_manager.delete_expired_messages(None)?;
+        println!("  Expired messages: {} deleted", expired);
+    }
+
     // Clean up stale sockets
     println!("  Checking for stale sockets...");
     let socket_pattern = "/tmp/eddi-msgsrv-*.sock";
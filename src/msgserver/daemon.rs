@@ -0,0 +1,605 @@
+// Long-running daemon that hosts servers and brokers
+//
+// `eddi-msgsrv daemon` owns every fortress and broker on the machine, so they
+// outlive the CLI invocation that created them. Other subcommands are thin
+// clients: each sends one JSON request over the control socket in the state
+// directory and prints the response. On startup the daemon reconciles the
+// state database with reality, restoring the servers that were running when
+// it last stopped and closing out brokers that can no longer be running.
+
+use crate::msgserver::client::ClientQueue;
+use crate::msgserver::handshake::{self, BrokerHandshake};
+use crate::msgserver::hook::HookConfig;
+use crate::msgserver::limits::ClientLimits;
+use crate::msgserver::outbox::SlowConsumerPolicy;
+use crate::msgserver::server::{ServerManager, ServerOptions};
+use crate::msgserver::storage::{BrokerConfig, BrokerStatus, ServerConfig, ServerStatus, StateManager};
+use crate::socket::{self, SocketPermissions, SocketState};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Name of the control socket in the state directory
+pub const CONTROL_SOCKET_NAME: &str = "daemon.sock";
+
+/// Longest request the daemon will read
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+/// Control socket path for a state directory
+pub fn control_socket_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(CONTROL_SOCKET_NAME)
+}
+
+/// Requests accepted on the control socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DaemonRequest {
+    /// Create and host a new fortress
+    CreateServer {
+        name: String,
+        ttl_minutes: u64,
+        use_tor: bool,
+        #[serde(default)]
+        hook: Option<HookConfig>,
+        #[serde(default)]
+        persistent: bool,
+        #[serde(default)]
+        max_attachment_size: Option<u64>,
+        #[serde(default)]
+        max_attachment_storage: Option<u64>,
+        #[serde(default)]
+        slow_consumer_policy: SlowConsumerPolicy,
+        #[serde(default)]
+        client_queue_size: Option<usize>,
+        #[serde(default)]
+        limits: ClientLimits,
+    },
+    /// Stop a hosted fortress for good
+    StopServer { name: String },
+    /// Create a broker for a fortress, with a fresh short code
+    CreateBroker {
+        server: String,
+        namespace: String,
+        timeout_secs: u64,
+        use_tor: bool,
+    },
+    /// Reply once a broker has been used, timed out or stopped
+    WaitBroker { id: String },
+    /// Stop a broker
+    StopBroker { id: String },
+    /// List all fortresses
+    ListServers,
+    /// List brokers still waiting for a client
+    ListBrokers,
+    /// Show the outbound queue of each client connected to a fortress
+    ClientQueues { server: String },
+    /// Revoke a fortress's client by its code or access token, dropping its
+    /// live connections
+    RevokeClient { server: String, code: String },
+    /// Describe the daemon
+    Status,
+    /// Stop the daemon (hosted servers are restored when it starts again)
+    Shutdown,
+}
+
+/// Replies sent on the control socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DaemonResponse {
+    Ok,
+    Server { server: ServerConfig },
+    Broker { broker: BrokerConfig },
+    /// A new broker, with the short code only its creator ever sees
+    BrokerCreated { broker: BrokerConfig, code: String },
+    Servers { servers: Vec<ServerConfig> },
+    Brokers { brokers: Vec<BrokerConfig> },
+    ClientQueues { queues: Vec<ClientQueue> },
+    /// A client was revoked
+    ClientRevoked { client_id: String },
+    Status { status: DaemonStatus },
+    Error { message: String },
+}
+
+/// What a running daemon reports about itself
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub started_at: SystemTime,
+    /// Names of the fortresses currently hosted
+    pub servers: Vec<String>,
+    /// Identifiers of the brokers still waiting for a client
+    pub brokers: Vec<String>,
+}
+
+/// The daemon: a `ServerManager` shared across CLI invocations
+pub struct Daemon {
+    state_manager: Arc<StateManager>,
+    server_manager: ServerManager,
+    started_at: SystemTime,
+    shutdown_tx: watch::Sender<bool>,
+}
+
+impl Daemon {
+    /// Create a daemon over the given state
+    pub fn new(state_manager: Arc<StateManager>) -> Arc<Self> {
+        let (shutdown_tx, _) = watch::channel(false);
+
+        Arc::new(Self {
+            server_manager: ServerManager::new(state_manager.clone()),
+            state_manager,
+            started_at: SystemTime::now(),
+            shutdown_tx,
+        })
+    }
+
+    /// Bring the state database in line with what is actually running
+    ///
+    /// Servers recorded as running are restarted (or marked as errored if
+    /// that fails), and active brokers, which only live as long as the
+    /// process that created them, are marked expired or stopped.
+    pub async fn reconcile(&self) -> Result<()> {
+        for server in self.state_manager.list_servers()? {
+            if server.status != ServerStatus::Running {
+                continue;
+            }
+
+            match self.server_manager.restore_server(server.clone()).await {
+                Ok(_) => tracing::info!("Restored server {}", server.name),
+                Err(e) => {
+                    tracing::error!("Failed to restore server {}: {:#}", server.name, e);
+                    self.state_manager.update_server_status(&server.id, ServerStatus::Error)?;
+                }
+            }
+        }
+
+        let now = SystemTime::now();
+        for broker in self.state_manager.list_brokers()? {
+            if broker.status != BrokerStatus::Active {
+                continue;
+            }
+
+            let status = if broker.expires_at <= now {
+                BrokerStatus::Expired
+            } else {
+                BrokerStatus::Stopped
+            };
+            tracing::info!("Closing out broker {} left by an earlier run ({})", broker.id, status);
+            self.state_manager.update_broker_status(&broker.id, status)?;
+        }
+
+        Ok(())
+    }
+
+    /// Serve the control socket until a shutdown request, SIGINT or SIGTERM
+    ///
+    /// Refuses to start if another daemon is already listening on the socket.
+    pub async fn run(self: Arc<Self>, socket_path: &Path) -> Result<()> {
+        if socket::inspect_socket(socket_path)? == SocketState::Live {
+            anyhow::bail!("A msgsrv daemon is already running on {:?}", socket_path);
+        }
+        let listener = socket::bind_secured(socket_path, &SocketPermissions::default())
+            .with_context(|| format!("Failed to bind control socket: {:?}", socket_path))?;
+        listener.set_nonblocking(true)?;
+        let listener = UnixListener::from_std(listener)?;
+
+        self.reconcile().await?;
+
+        tracing::info!("msgsrv daemon listening on {:?}", socket_path);
+
+        let mut terminate = signal(SignalKind::terminate())
+            .context("Failed to install SIGTERM handler")?;
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+
+        loop {
+            tokio::select! {
+                result = listener.accept() => {
+                    match result {
+                        Ok((stream, _addr)) => {
+                            let daemon = self.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = daemon.handle_connection(stream).await {
+                                    tracing::warn!("Control connection error: {:#}", e);
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            tracing::error!("Control socket accept error: {}", e);
+                        }
+                    }
+                }
+                _ = shutdown_rx.wait_for(|shutdown| *shutdown) => {
+                    tracing::info!("Shutdown requested");
+                    break;
+                }
+                _ = tokio::signal::ctrl_c() => {
+                    tracing::info!("Interrupted, shutting down");
+                    break;
+                }
+                _ = terminate.recv() => {
+                    tracing::info!("SIGTERM received, shutting down");
+                    break;
+                }
+            }
+        }
+
+        // Stop listening first so clients see the daemon as gone
+        drop(listener);
+        socket::remove_socket_file(socket_path);
+
+        self.server_manager.shutdown_all().await;
+
+        Ok(())
+    }
+
+    /// Read one request, answer it and close the connection
+    async fn handle_connection(&self, stream: UnixStream) -> Result<()> {
+        let (read_half, mut write_half) = stream.into_split();
+        let mut reader = BufReader::new(read_half).take(MAX_REQUEST_LEN);
+
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+
+        let response = match serde_json::from_str::<DaemonRequest>(line.trim()) {
+            Ok(request) => {
+                tracing::debug!("Control request: {:?}", request);
+                self.handle_request(request).await.unwrap_or_else(|e| DaemonResponse::Error {
+                    message: format!("{:#}", e),
+                })
+            }
+            Err(e) => DaemonResponse::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+
+        let mut bytes = serde_json::to_vec(&response)?;
+        bytes.push(b'\n');
+        write_half.write_all(&bytes).await?;
+
+        Ok(())
+    }
+
+    async fn handle_request(&self, request: DaemonRequest) -> Result<DaemonResponse> {
+        match request {
+            DaemonRequest::CreateServer {
+                name,
+                ttl_minutes,
+                use_tor,
+                hook,
+                persistent,
+                max_attachment_size,
+                max_attachment_storage,
+                slow_consumer_policy,
+                client_queue_size,
+                limits,
+            } => {
+                let options = ServerOptions {
+                    hook,
+                    persistent,
+                    max_attachment_size,
+                    max_attachment_storage,
+                    slow_consumer_policy,
+                    client_queue_size,
+                    limits,
+                };
+                let instance = self
+                    .server_manager
+                    .create_server_with_options(name, ttl_minutes, use_tor, options)
+                    .await?;
+                Ok(DaemonResponse::Server {
+                    server: instance.config().clone(),
+                })
+            }
+            DaemonRequest::StopServer { name } => {
+                self.server_manager.stop_server(&name).await?;
+                Ok(DaemonResponse::Ok)
+            }
+            DaemonRequest::CreateBroker { server, namespace, timeout_secs, use_tor } => {
+                let fortress = self.state_manager.get_server(&server)?
+                    .context("Server not found")?;
+
+                let code = handshake::generate_short_code();
+                let handshake = BrokerHandshake::derive(
+                    namespace,
+                    code.clone(),
+                    fortress.onion_address.clone().unwrap_or_else(|| fortress.name.clone()),
+                )
+                .await;
+                let handshake = if fortress.onion_address.is_none() {
+                    // Local-only fortresses are reached over their socket
+                    handshake.with_fortress_socket(fortress.socket_path.clone())
+                } else {
+                    handshake
+                };
+                let identifier = handshake.identifier();
+
+                self.server_manager
+                    .create_broker(server, handshake, Duration::from_secs(timeout_secs), use_tor)
+                    .await?;
+
+                Ok(DaemonResponse::BrokerCreated {
+                    broker: self.broker_record(&identifier)?,
+                    code,
+                })
+            }
+            DaemonRequest::WaitBroker { id } => {
+                let identifier = id.strip_prefix("broker-").unwrap_or(&id);
+                if let Some(instance) = self.server_manager.get_server(&format!("broker-{}", identifier)).await {
+                    instance.stopped().await;
+                }
+
+                Ok(DaemonResponse::Broker {
+                    broker: self.broker_record(identifier)?,
+                })
+            }
+            DaemonRequest::StopBroker { id } => {
+                self.server_manager.stop_broker(&id).await?;
+                Ok(DaemonResponse::Ok)
+            }
+            DaemonRequest::ListServers => Ok(DaemonResponse::Servers {
+                servers: self.state_manager.list_servers()?,
+            }),
+            DaemonRequest::ListBrokers => Ok(DaemonResponse::Brokers {
+                brokers: self.active_brokers()?,
+            }),
+            DaemonRequest::ClientQueues { server } => {
+                let instance = self
+                    .server_manager
+                    .get_server(&server)
+                    .await
+                    .with_context(|| format!("Server {} is not running", server))?;
+                Ok(DaemonResponse::ClientQueues {
+                    queues: instance.client_queues().await,
+                })
+            }
+            DaemonRequest::RevokeClient { server, code } => {
+                let client = self.server_manager.revoke_client(&server, &code).await?;
+                Ok(DaemonResponse::ClientRevoked { client_id: client.id })
+            }
+            DaemonRequest::Status => {
+                let mut servers: Vec<_> = self.server_manager.list_servers().await
+                    .into_iter()
+                    .map(|instance| instance.config().name.clone())
+                    .filter(|name| !name.starts_with("broker-"))
+                    .collect();
+                servers.sort();
+
+                Ok(DaemonResponse::Status {
+                    status: DaemonStatus {
+                        pid: std::process::id(),
+                        started_at: self.started_at,
+                        servers,
+                        brokers: self.active_brokers()?.into_iter().map(|broker| broker.id).collect(),
+                    },
+                })
+            }
+            DaemonRequest::Shutdown => {
+                self.shutdown_tx.send_replace(true);
+                Ok(DaemonResponse::Ok)
+            }
+        }
+    }
+
+    fn broker_record(&self, identifier: &str) -> Result<BrokerConfig> {
+        self.state_manager.get_broker(identifier)?
+            .with_context(|| format!("Broker '{}' not found", identifier))
+    }
+
+    fn active_brokers(&self) -> Result<Vec<BrokerConfig>> {
+        Ok(self.state_manager.list_brokers()?
+            .into_iter()
+            .filter(|broker| broker.is_active())
+            .collect())
+    }
+}
+
+/// Client side of the control socket, used by the other subcommands
+pub struct DaemonClient {
+    socket_path: PathBuf,
+}
+
+impl DaemonClient {
+    /// Create a client for the daemon listening on `socket_path`
+    pub fn new(socket_path: PathBuf) -> Self {
+        Self { socket_path }
+    }
+
+    /// Whether a daemon is listening
+    pub fn is_running(&self) -> bool {
+        matches!(socket::inspect_socket(&self.socket_path), Ok(SocketState::Live))
+    }
+
+    /// Send a request and wait for the reply, turning error replies into errors
+    pub async fn request(&self, request: &DaemonRequest) -> Result<DaemonResponse> {
+        let stream = UnixStream::connect(&self.socket_path).await.with_context(|| {
+            format!(
+                "Failed to connect to the msgsrv daemon at {:?}. Start it with `eddi-msgsrv daemon`",
+                self.socket_path
+            )
+        })?;
+        let (read_half, mut write_half) = stream.into_split();
+
+        let mut bytes = serde_json::to_vec(request)?;
+        bytes.push(b'\n');
+        write_half.write_all(&bytes).await?;
+
+        let mut line = String::new();
+        BufReader::new(read_half).read_line(&mut line).await?;
+        if line.is_empty() {
+            anyhow::bail!("The msgsrv daemon closed the connection without replying");
+        }
+
+        match serde_json::from_str(line.trim()).context("Invalid reply from the msgsrv daemon")? {
+            DaemonResponse::Error { message } => anyhow::bail!("{}", message),
+            response => Ok(response),
+        }
+    }
+
+    /// Create and host a new fortress
+    pub async fn create_server(
+        &self,
+        name: String,
+        ttl_minutes: u64,
+        use_tor: bool,
+        options: ServerOptions,
+    ) -> Result<ServerConfig> {
+        let request = DaemonRequest::CreateServer {
+            name,
+            ttl_minutes,
+            use_tor,
+            hook: options.hook,
+            persistent: options.persistent,
+            max_attachment_size: options.max_attachment_size,
+            max_attachment_storage: options.max_attachment_storage,
+            slow_consumer_policy: options.slow_consumer_policy,
+            client_queue_size: options.client_queue_size,
+            limits: options.limits,
+        };
+        match self.request(&request).await? {
+            DaemonResponse::Server { server } => Ok(server),
+            other => unexpected(other),
+        }
+    }
+
+    /// Stop a hosted fortress
+    pub async fn stop_server(&self, name: String) -> Result<()> {
+        match self.request(&DaemonRequest::StopServer { name }).await? {
+            DaemonResponse::Ok => Ok(()),
+            other => unexpected(other),
+        }
+    }
+
+    /// Create a broker for a fortress, returning it with its short code
+    pub async fn create_broker(
+        &self,
+        server: String,
+        namespace: String,
+        timeout_secs: u64,
+        use_tor: bool,
+    ) -> Result<(BrokerConfig, String)> {
+        let request = DaemonRequest::CreateBroker { server, namespace, timeout_secs, use_tor };
+        match self.request(&request).await? {
+            DaemonResponse::BrokerCreated { broker, code } => Ok((broker, code)),
+            other => unexpected(other),
+        }
+    }
+
+    /// Wait until a broker has finished, returning its final record
+    pub async fn wait_broker(&self, id: String) -> Result<BrokerConfig> {
+        match self.request(&DaemonRequest::WaitBroker { id }).await? {
+            DaemonResponse::Broker { broker } => Ok(broker),
+            other => unexpected(other),
+        }
+    }
+
+    /// Stop a broker
+    pub async fn stop_broker(&self, id: String) -> Result<()> {
+        match self.request(&DaemonRequest::StopBroker { id }).await? {
+            DaemonResponse::Ok => Ok(()),
+            other => unexpected(other),
+        }
+    }
+
+    /// List all fortresses
+    pub async fn list_servers(&self) -> Result<Vec<ServerConfig>> {
+        match self.request(&DaemonRequest::ListServers).await? {
+            DaemonResponse::Servers { servers } => Ok(servers),
+            other => unexpected(other),
+        }
+    }
+
+    /// List brokers still waiting for a client
+    pub async fn list_brokers(&self) -> Result<Vec<BrokerConfig>> {
+        match self.request(&DaemonRequest::ListBrokers).await? {
+            DaemonResponse::Brokers { brokers } => Ok(brokers),
+            other => unexpected(other),
+        }
+    }
+
+    /// Show the outbound queue of each client connected to a hosted fortress
+    pub async fn client_queues(&self, server: String) -> Result<Vec<ClientQueue>> {
+        match self.request(&DaemonRequest::ClientQueues { server }).await? {
+            DaemonResponse::ClientQueues { queues } => Ok(queues),
+            other => unexpected(other),
+        }
+    }
+
+    /// Revoke a client of a hosted fortress, returning its ID
+    pub async fn revoke_client(&self, server: String, code: String) -> Result<String> {
+        match self.request(&DaemonRequest::RevokeClient { server, code }).await? {
+            DaemonResponse::ClientRevoked { client_id } => Ok(client_id),
+            other => unexpected(other),
+        }
+    }
+
+    /// Describe the daemon
+    pub async fn status(&self) -> Result<DaemonStatus> {
+        match self.request(&DaemonRequest::Status).await? {
+            DaemonResponse::Status { status } => Ok(status),
+            other => unexpected(other),
+        }
+    }
+
+    /// Ask the daemon to stop
+    pub async fn shutdown(&self) -> Result<()> {
+        match self.request(&DaemonRequest::Shutdown).await? {
+            DaemonResponse::Ok => Ok(()),
+            other => unexpected(other),
+        }
+    }
+}
+
+fn unexpected<T>(response: DaemonResponse) -> Result<T> {
+    anyhow::bail!("Unexpected reply from the msgsrv daemon: {:?}", response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_request_wire_format() {
+        let request = DaemonRequest::StopServer {
+            name: "fortress".to_string(),
+        };
+        let json = serde_json::to_string(&request).unwrap();
+        assert_eq!(json, r#"{"type":"stop_server","name":"fortress"}"#);
+
+        let json = serde_json::to_string(&DaemonRequest::ListServers).unwrap();
+        assert_eq!(json, r#"{"type":"list_servers"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_refuses_second_daemon() {
+        let dir = tempdir().unwrap();
+        let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+        let socket_path = control_socket_path(dir.path());
+
+        let daemon = Daemon::new(state_manager.clone());
+        let running = tokio::spawn({
+            let socket_path = socket_path.clone();
+            async move { daemon.run(&socket_path).await }
+        });
+
+        let client = DaemonClient::new(socket_path.clone());
+        for _ in 0..50 {
+            if client.is_running() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let err = Daemon::new(state_manager).run(&socket_path).await.unwrap_err();
+        assert!(err.to_string().contains("already running"));
+
+        client.shutdown().await.unwrap();
+        running.await.unwrap().unwrap();
+        assert!(!client.is_running());
+    }
+}
@@ -0,0 +1,302 @@
+// Detaching `listen` from the terminal
+//
+// `listen --background` (and `--daemon`) double-fork before the async runtime
+// starts: the first child calls setsid() to leave the controlling terminal and
+// exits after forking again, so the grandchild can never reacquire one. The
+// grandchild writes a pidfile, sends stdout and stderr to a log file and
+// reports its PID back to the original process over a pipe. `listen --status`
+// and `listen --stop` find the listener again through the pidfile.
+
+use crate::msgserver::storage::process_alive;
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Directory (under the state directory) holding listener pidfiles and logs
+pub const LISTENERS_DIR: &str = "listeners";
+
+/// How long `stop` waits for a listener to exit after SIGTERM
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a background listener for one connection keeps its pidfile and log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenerPaths {
+    pub pidfile: PathBuf,
+    pub log_file: PathBuf,
+}
+
+impl ListenerPaths {
+    /// Paths for the listener of the connection to `server_name`
+    pub fn new(state_dir: &Path, server_name: &str) -> Self {
+        let name: String = server_name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+            .collect();
+        let dir = state_dir.join(LISTENERS_DIR);
+
+        Self {
+            pidfile: dir.join(format!("{}.pid", name)),
+            log_file: dir.join(format!("{}.log", name)),
+        }
+    }
+}
+
+/// A pidfile owned by this process, removed when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    pid: u32,
+}
+
+impl PidFile {
+    /// Create the pidfile for this process, failing if one already exists
+    pub fn create(path: &Path) -> Result<Self> {
+        let pid = std::process::id();
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(path)
+            .with_context(|| format!("Failed to create pidfile {:?}", path))?;
+        writeln!(file, "{}", pid)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            pid,
+        })
+    }
+
+    /// Path of the pidfile
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave it alone if another listener has since taken it over
+        if read_pid(&self.path).ok().flatten() == Some(self.pid) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Read the PID recorded in a pidfile, if there is one
+pub fn read_pid(path: &Path) -> Result<Option<u32>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => {
+            let pid = contents
+                .trim()
+                .parse()
+                .with_context(|| format!("Malformed pidfile {:?}", path))?;
+            Ok(Some(pid))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read pidfile {:?}", path)),
+    }
+}
+
+/// PID of the listener a pidfile points at, removing the pidfile if stale
+pub fn running_pid(path: &Path) -> Result<Option<u32>> {
+    match read_pid(path)? {
+        Some(pid) if pid > 0 && process_alive(pid) => Ok(Some(pid)),
+        Some(_) => {
+            tracing::info!("Removing stale pidfile {:?}", path);
+            let _ = std::fs::remove_file(path);
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Which side of the fork `daemonize` returned in
+#[derive(Debug)]
+pub enum Detached {
+    /// The original process: the listener is running with this PID
+    Parent { pid: u32 },
+    /// The detached listener, holding its pidfile
+    Listener(PidFile),
+}
+
+/// Detach into a background process with a pidfile and log file
+///
+/// Must be called before any threads (such as the tokio runtime) exist.
+/// With `change_dir`, the listener also changes to `/` so it does not keep a
+/// directory busy and sets a `027` umask, as a system daemon would.
+pub fn daemonize(paths: &ListenerPaths, change_dir: bool) -> Result<Detached> {
+    if let Some(pid) = running_pid(&paths.pidfile)? {
+        anyhow::bail!("A listener is already running (PID {})", pid);
+    }
+
+    if let Some(dir) = paths.pidfile.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {:?}", dir))?;
+    }
+
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(&paths.log_file)
+        .with_context(|| format!("Failed to open log file {:?}", paths.log_file))?;
+    let devnull = File::open("/dev/null").context("Failed to open /dev/null")?;
+
+    let mut fds = [0; 2];
+    // SAFETY: pipe(2) writes two descriptors into the array we pass
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error()).context("Failed to create pipe");
+    }
+    // SAFETY: both descriptors were just created and are owned only here
+    let (mut ready_rx, ready_tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // Anything still buffered would otherwise be written by both processes
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+
+    // SAFETY: the caller guarantees we are single-threaded, so the child
+    // starts with consistent state
+    match unsafe { libc::fork() } {
+        -1 => return Err(std::io::Error::last_os_error()).context("Failed to fork"),
+        0 => {}
+        child => {
+            drop(ready_tx);
+
+            // The intermediate child exits as soon as it has forked again
+            // SAFETY: waiting on our own child
+            unsafe {
+                libc::waitpid(child, std::ptr::null_mut(), 0);
+            }
+
+            let mut reply = String::new();
+            ready_rx.read_to_string(&mut reply)?;
+            let pid = reply.trim().parse().map_err(|_| {
+                anyhow::anyhow!("Listener failed to start; see {:?}", paths.log_file)
+            })?;
+
+            return Ok(Detached::Parent { pid });
+        }
+    }
+
+    // First child: leave the controlling terminal, then fork again
+    drop(ready_rx);
+    // SAFETY: plain syscalls in a single-threaded process; the intermediate
+    // child only calls _exit, which runs no destructors or atexit handlers
+    unsafe {
+        if libc::setsid() == -1 {
+            libc::_exit(1);
+        }
+        match libc::fork() {
+            -1 => libc::_exit(1),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+    }
+
+    // Grandchild: the listener itself
+    if change_dir {
+        // SAFETY: umask(2) only replaces the process file mode mask
+        unsafe {
+            libc::umask(0o027);
+        }
+        std::env::set_current_dir("/").context("Failed to change to /")?;
+    }
+
+    // SAFETY: dup2 onto the standard descriptors, from files we own
+    unsafe {
+        if libc::dup2(devnull.as_raw_fd(), libc::STDIN_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) == -1
+        {
+            return Err(std::io::Error::last_os_error()).context("Failed to redirect output");
+        }
+    }
+
+    let pidfile = PidFile::create(&paths.pidfile)?;
+
+    let mut ready_tx = ready_tx;
+    writeln!(ready_tx, "{}", pidfile.pid)?;
+    drop(ready_tx);
+
+    Ok(Detached::Listener(pidfile))
+}
+
+/// Stop the listener a pidfile points at, returning its PID
+pub fn stop(paths: &ListenerPaths) -> Result<u32> {
+    let pid = running_pid(&paths.pidfile)?.context("No background listener is running")?;
+
+    // SAFETY: kill(2) has no memory-safety requirements
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == -1 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to signal listener (PID {})", pid));
+    }
+
+    let start = Instant::now();
+    while process_alive(pid) {
+        if start.elapsed() > STOP_TIMEOUT {
+            anyhow::bail!("Listener (PID {}) did not exit within {:?}", pid, STOP_TIMEOUT);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    // In case it could not clean up after itself
+    let _ = std::fs::remove_file(&paths.pidfile);
+
+    Ok(pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_listener_paths_are_sanitized() {
+        let paths = ListenerPaths::new(Path::new("/state"), "../my server.onion");
+        assert_eq!(paths.pidfile, PathBuf::from("/state/listeners/.._my_server.onion.pid"));
+        assert_eq!(paths.log_file, PathBuf::from("/state/listeners/.._my_server.onion.log"));
+    }
+
+    #[test]
+    fn test_pidfile_lifecycle() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("listener.pid");
+
+        let pidfile = PidFile::create(&path).unwrap();
+        assert_eq!(read_pid(&path).unwrap(), Some(std::process::id()));
+        assert_eq!(running_pid(&path).unwrap(), Some(std::process::id()));
+        assert!(PidFile::create(&path).is_err());
+
+        drop(pidfile);
+        assert!(!path.exists());
+        assert_eq!(running_pid(&path).unwrap(), None);
+    }
+
+    #[test]
+    fn test_stale_pidfile_is_removed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("listener.pid");
+
+        // A process that has already exited
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+
+        std::fs::write(&path, format!("{}\n", pid)).unwrap();
+        assert_eq!(running_pid(&path).unwrap(), None);
+        assert!(!path.exists());
+
+        let paths = ListenerPaths {
+            pidfile: path,
+            log_file: dir.path().join("listener.log"),
+        };
+        assert!(stop(&paths).is_err());
+    }
+}
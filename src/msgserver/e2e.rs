@@ -0,0 +1,369 @@
+// End-to-end encryption of message content
+//
+// Every connection made through a broker has an X25519 key pair. The public
+// half is registered with the fortress during the introduction, which hands
+// back the keys of the clients it already knows. A sealed message is
+// encrypted once under a random content key with XChaCha20-Poly1305, and that
+// key is wrapped for each recipient (as age does) under a key derived from an
+// ephemeral X25519 exchange with them. Attached files are sealed the same way,
+// for the same recipients. Fortresses only ever route and store the sealed
+// envelopes.
+
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::str::FromStr;
+
+/// Envelope format version
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Domain separation tag for wrapping keys
+const WRAP_DSI: &[u8] = b"eddi-e2e-wrap";
+
+/// Length of an XChaCha20-Poly1305 nonce
+const NONCE_LEN: usize = 24;
+
+/// An X25519 public key, hex encoded on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        decode_key(s).map(Self).context("Malformed public key")
+    }
+}
+
+impl TryFrom<String> for PublicKey {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<PublicKey> for String {
+    fn from(key: PublicKey) -> Self {
+        key.to_string()
+    }
+}
+
+/// An X25519 secret key, hex encoded when stored
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    /// Generate a new random key
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+
+    /// The public key others encrypt to
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(MontgomeryPoint::mul_base_clamped(self.0).to_bytes())
+    }
+
+    /// Hex encoding, for storage
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    /// X25519 shared secret with `peer`, refusing low-order points
+    fn exchange(&self, peer: &PublicKey) -> Result<[u8; 32]> {
+        let shared = MontgomeryPoint(peer.0).mul_clamped(self.0).to_bytes();
+        if shared == [0; 32] {
+            anyhow::bail!("Degenerate public key");
+        }
+        Ok(shared)
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretKey({})", self.public_key())
+    }
+}
+
+impl FromStr for SecretKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        decode_key(s).map(Self).context("Malformed secret key")
+    }
+}
+
+impl TryFrom<String> for SecretKey {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<SecretKey> for String {
+    fn from(key: SecretKey) -> Self {
+        key.to_hex()
+    }
+}
+
+/// A client of a fortress and the key messages to it are sealed with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerKey {
+    /// Alias the client claimed, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    pub public_key: PublicKey,
+}
+
+/// What is sent in place of the content of an encrypted message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Envelope {
+    /// Format version (also what marks the content as an envelope)
+    e2e: u8,
+    /// Ephemeral public key the wrapping keys were derived with
+    ephemeral: PublicKey,
+    /// The content key, sealed for each recipient in turn
+    recipients: Vec<String>,
+    /// The content, sealed under the content key
+    ciphertext: String,
+}
+
+/// Encrypt `plaintext` so that only the holders of `recipients` can read it
+///
+/// The result is a JSON envelope to send as the message content. Recipients
+/// are not named in it; each tries the wrapped keys until one opens.
+pub fn seal(plaintext: &str, recipients: &[PublicKey]) -> Result<String> {
+    serde_json::to_string(&seal_envelope(plaintext.as_bytes(), recipients)?).context("Failed to encode envelope")
+}
+
+/// Encrypt a file so that only the holders of `recipients` can read it
+///
+/// The result is the same JSON envelope as `seal` makes, to upload in place
+/// of the file.
+pub fn seal_bytes(plaintext: &[u8], recipients: &[PublicKey]) -> Result<Vec<u8>> {
+    serde_json::to_vec(&seal_envelope(plaintext, recipients)?).context("Failed to encode envelope")
+}
+
+fn seal_envelope(plaintext: &[u8], recipients: &[PublicKey]) -> Result<Envelope> {
+    let mut unique = Vec::new();
+    for key in recipients {
+        if !unique.contains(key) {
+            unique.push(*key);
+        }
+    }
+    if unique.is_empty() {
+        anyhow::bail!("No recipients to encrypt to");
+    }
+
+    let mut content_key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut content_key);
+
+    let ephemeral = SecretKey::generate();
+    let ephemeral_public = ephemeral.public_key();
+
+    let recipients = unique
+        .iter()
+        .map(|recipient| {
+            let wrap_key = wrapping_key(&ephemeral.exchange(recipient)?, &ephemeral_public, recipient);
+            encrypt(&wrap_key, &content_key)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Envelope {
+        e2e: ENVELOPE_VERSION,
+        ephemeral: ephemeral_public,
+        recipients,
+        ciphertext: encrypt(&content_key, plaintext)?,
+    })
+}
+
+/// Whether message content is an encrypted envelope
+pub fn is_sealed(content: &str) -> bool {
+    parse(content).is_some()
+}
+
+/// Whether a file is an encrypted envelope
+pub fn is_sealed_bytes(data: &[u8]) -> bool {
+    parse_bytes(data).is_some()
+}
+
+/// Decrypt an envelope sealed (among others) for `secret`'s public key
+pub fn open(content: &str, secret: &SecretKey) -> Result<String> {
+    let envelope = parse(content).context("Not an encrypted message")?;
+    let plaintext = open_envelope(&envelope, secret)?;
+    String::from_utf8(plaintext).context("Decrypted message is not UTF-8")
+}
+
+/// Decrypt a file sealed (among others) for `secret`'s public key
+pub fn open_bytes(data: &[u8], secret: &SecretKey) -> Result<Vec<u8>> {
+    let envelope = parse_bytes(data).context("Not an encrypted file")?;
+    open_envelope(&envelope, secret)
+}
+
+fn open_envelope(envelope: &Envelope, secret: &SecretKey) -> Result<Vec<u8>> {
+    if envelope.e2e != ENVELOPE_VERSION {
+        anyhow::bail!("Unsupported envelope version {}", envelope.e2e);
+    }
+
+    let public = secret.public_key();
+    let wrap_key = wrapping_key(&secret.exchange(&envelope.ephemeral)?, &envelope.ephemeral, &public);
+
+    let content_key: [u8; 32] = envelope
+        .recipients
+        .iter()
+        .find_map(|wrapped| decrypt(&wrap_key, wrapped).ok())
+        .and_then(|key| key.try_into().ok())
+        .context("Message was not encrypted for this client")?;
+
+    decrypt(&content_key, &envelope.ciphertext)
+}
+
+fn parse(content: &str) -> Option<Envelope> {
+    parse_bytes(content.as_bytes())
+}
+
+fn parse_bytes(data: &[u8]) -> Option<Envelope> {
+    // Cheap check first: most content is not JSON at all
+    if !data.starts_with(b"{") {
+        return None;
+    }
+    serde_json::from_slice(data).ok()
+}
+
+/// Key wrapping the content key for one recipient of one message
+fn wrapping_key(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> [u8; 32] {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend(ephemeral.as_bytes());
+    salt.extend(recipient.as_bytes());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_DSI, &mut key)
+        .expect("valid HKDF length");
+    key
+}
+
+fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let ciphertext = XChaCha20Poly1305::new(key.into())
+        .encrypt(XNonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(hex::encode(sealed))
+}
+
+fn decrypt(key: &[u8; 32], sealed: &str) -> Result<Vec<u8>> {
+    let sealed = hex::decode(sealed).context("Malformed ciphertext")?;
+    if sealed.len() < NONCE_LEN {
+        anyhow::bail!("Malformed ciphertext");
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    XChaCha20Poly1305::new(key.into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("Ciphertext failed authentication"))
+}
+
+fn decode_key(s: &str) -> Option<[u8; 32]> {
+    hex::decode(s).ok()?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recipients_can_open_and_others_cannot() {
+        let alice = SecretKey::generate();
+        let bob = SecretKey::generate();
+        let eve = SecretKey::generate();
+
+        let sealed = seal("meet at noon", &[alice.public_key(), bob.public_key()]).unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("noon"));
+
+        assert_eq!(open(&sealed, &alice).unwrap(), "meet at noon");
+        assert_eq!(open(&sealed, &bob).unwrap(), "meet at noon");
+        assert!(open(&sealed, &eve).unwrap_err().to_string().contains("not encrypted for this client"));
+    }
+
+    #[test]
+    fn test_tampered_envelope_fails() {
+        let alice = SecretKey::generate();
+        let sealed = seal("meet at noon", &[alice.public_key()]).unwrap();
+
+        let mut envelope: Envelope = serde_json::from_str(&sealed).unwrap();
+        let mut ciphertext = hex::decode(&envelope.ciphertext).unwrap();
+        let last = ciphertext.len() - 1;
+        ciphertext[last] ^= 1;
+        envelope.ciphertext = hex::encode(ciphertext);
+
+        assert!(open(&serde_json::to_string(&envelope).unwrap(), &alice).is_err());
+    }
+
+    #[test]
+    fn test_files_round_trip() {
+        let alice = SecretKey::generate();
+        let data: Vec<u8> = (0..=255).collect();
+
+        let sealed = seal_bytes(&data, &[alice.public_key()]).unwrap();
+        assert!(is_sealed_bytes(&sealed));
+        assert!(!is_sealed_bytes(&data));
+
+        assert_eq!(open_bytes(&sealed, &alice).unwrap(), data);
+        assert!(open_bytes(&sealed, &SecretKey::generate()).is_err());
+    }
+
+    #[test]
+    fn test_plain_content_is_not_sealed() {
+        assert!(!is_sealed("hello"));
+        assert!(!is_sealed("{\"json\": \"but not an envelope\"}"));
+        assert!(open("hello", &SecretKey::generate()).is_err());
+        assert!(seal("hello", &[]).is_err());
+    }
+
+    #[test]
+    fn test_keys_round_trip_as_hex() {
+        let secret = SecretKey::generate();
+        let stored: SecretKey = secret.to_hex().parse().unwrap();
+        assert_eq!(stored.public_key(), secret.public_key());
+
+        let public = secret.public_key();
+        let json = serde_json::to_string(&public).unwrap();
+        assert_eq!(json, format!("\"{}\"", public));
+        assert_eq!(serde_json::from_str::<PublicKey>(&json).unwrap(), public);
+        assert!("abcd".parse::<PublicKey>().is_err());
+
+        // A low-order point would make the shared secret predictable
+        let zero = PublicKey([0; 32]);
+        assert!(seal("hello", &[zero]).is_err());
+    }
+}
@@ -0,0 +1,543 @@
+// Wire framing and protocol versions
+//
+// Connections start out as newline-delimited JSON, the original (version 1)
+// protocol. A client that opens with a `Hello` frame, itself still a line of
+// JSON, gets one back with the protocol version and capabilities both sides
+// share. If that includes `length-prefixed`, every later frame in either
+// direction is a 4-byte big-endian length followed by that many bytes of JSON.
+// The server writes that way straight after its `Hello`, but a client only
+// switches once it reads it, which may be after it gave up waiting and sent
+// more lines; so the server reads lines until the first frame that is not
+// one. Clients that never say hello keep speaking lines. Both framings have a
+// maximum frame size, so a peer cannot make the other buffer without bound.
+
+use crate::msgserver::message::{Envelope, ProtocolMessage};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Protocol version this build speaks
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Version of clients that never send `Hello` (newline-delimited JSON only)
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+
+/// Largest frame a server accepts from a client (1 MiB)
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Largest frame a client accepts from a server (64 MiB)
+///
+/// Larger than `MAX_FRAME_SIZE`, as one receive response carries a whole queue.
+pub const MAX_SERVER_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Capability: switch to length-prefixed frames after the hello exchange
+pub const LENGTH_PREFIXED: &str = "length-prefixed";
+
+/// Capability: uploaded attachment chunks with a request ID are answered
+pub const CHUNK_ACKS: &str = "chunk-acks";
+
+/// Everything this build supports, as advertised in `Hello`
+pub const CAPABILITIES: &[&str] = &[
+    LENGTH_PREFIXED,
+    "channels",
+    "e2e",
+    "signatures",
+    "acks",
+    "attachments",
+    CHUNK_ACKS,
+];
+
+/// How frames are delimited on a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// One JSON document per line
+    #[default]
+    Lines,
+    /// A 4-byte big-endian length, then that many bytes of JSON
+    LengthPrefixed,
+}
+
+impl Framing {
+    /// The framing to use once both sides' capabilities are known
+    pub fn negotiated(capabilities: &[String]) -> Self {
+        if capabilities.iter().any(|c| c == LENGTH_PREFIXED) {
+            Framing::LengthPrefixed
+        } else {
+            Framing::Lines
+        }
+    }
+}
+
+/// Our capabilities, in the form `Hello` carries them
+pub fn capabilities() -> Vec<String> {
+    CAPABILITIES.iter().map(|c| c.to_string()).collect()
+}
+
+/// Pick the version and capabilities to answer a client's `Hello` with
+///
+/// Fails if the client is too old to have sent one.
+pub fn negotiate(version: u32, capabilities: &[String]) -> Result<(u32, Vec<String>)> {
+    if version <= LEGACY_PROTOCOL_VERSION {
+        anyhow::bail!("Unsupported protocol version {}", version);
+    }
+
+    let shared = CAPABILITIES
+        .iter()
+        .filter(|ours| capabilities.iter().any(|theirs| theirs == *ours))
+        .map(|c| c.to_string())
+        .collect();
+
+    Ok((version.min(PROTOCOL_VERSION), shared))
+}
+
+/// A frame that could not be read
+#[derive(Debug, thiserror::Error)]
+pub enum FrameError {
+    #[error("Frame of {size} bytes exceeds the {max} byte limit")]
+    TooLarge { size: usize, max: usize },
+    #[error("Malformed frame: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl FrameError {
+    /// Whether the connection can carry on past this error
+    ///
+    /// A malformed frame was still delimited, so the next one can be read;
+    /// after anything else the stream's position is lost.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, FrameError::Malformed(_))
+    }
+}
+
+/// Encode a message, or an `Envelope` around one, as one frame
+pub fn encode<T: Serialize>(message: &T, framing: Framing) -> Result<Vec<u8>> {
+    let json = serde_json::to_vec(message).context("Failed to encode message")?;
+    match framing {
+        Framing::Lines => {
+            let mut bytes = json;
+            bytes.push(b'\n');
+            Ok(bytes)
+        }
+        Framing::LengthPrefixed => {
+            let length = u32::try_from(json.len()).context("Message too large to frame")?;
+
+            let mut bytes = Vec::with_capacity(4 + json.len());
+            bytes.extend_from_slice(&length.to_be_bytes());
+            bytes.extend(json);
+            Ok(bytes)
+        }
+    }
+}
+
+/// Splits a byte stream into frames, whatever pieces it arrives in
+#[derive(Debug)]
+pub struct FrameDecoder {
+    framing: Framing,
+    max_size: usize,
+    buf: Vec<u8>,
+    // How much of `buf` is known to hold no newline, so a line that
+    // arrives in many pieces is only searched once
+    scanned: usize,
+    // Framing to switch to at the first frame that is not a line
+    next: Option<Framing>,
+}
+
+impl FrameDecoder {
+    /// Decode lines of at most `max_size` bytes, until told otherwise
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            framing: Framing::Lines,
+            max_size,
+            buf: Vec::new(),
+            scanned: 0,
+            next: None,
+        }
+    }
+
+    /// The framing frames are currently decoded with
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Decode frames after the current one with `framing`
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+        self.next = None;
+    }
+
+    /// Keep decoding lines until a frame arrives that is not one, then
+    /// switch to `framing` for it and everything after
+    ///
+    /// A line is JSON, so starts with `{` after any blank space; a length
+    /// prefix under the size limit starts with a zero byte.
+    pub fn switch_when_seen(&mut self, framing: Framing) {
+        self.next = Some(framing).filter(|&framing| framing != self.framing);
+    }
+
+    /// Add bytes read from the stream
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// The next complete frame, if one has arrived
+    pub fn decode(&mut self) -> Result<Option<Envelope>, FrameError> {
+        if let Some(next) = self.next {
+            // Blank space between frames means nothing either way
+            let start = self.buf.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(self.buf.len());
+            if start > 0 {
+                self.buf.drain(..start);
+                self.scanned = 0;
+            }
+            match self.buf.first() {
+                None => return Ok(None),
+                Some(b'{') => {
+                    return match self.take_line()? {
+                        Some(line) => Ok(Some(Envelope::from_bytes(line.trim_ascii())?)),
+                        None => Ok(None),
+                    };
+                }
+                Some(_) => self.set_framing(next),
+            }
+        }
+
+        match self.framing {
+            Framing::Lines => self.decode_line(),
+            Framing::LengthPrefixed => self.decode_prefixed(),
+        }
+    }
+
+    fn decode_line(&mut self) -> Result<Option<Envelope>, FrameError> {
+        loop {
+            let Some(line) = self.take_line()? else {
+                return Ok(None);
+            };
+            let trimmed = line.trim_ascii();
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            return Ok(Some(Envelope::from_bytes(trimmed)?));
+        }
+    }
+
+    /// Remove the first whole line from the buffer, if one has arrived
+    fn take_line(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let Some(end) = self.buf[self.scanned..].iter().position(|&b| b == b'\n') else {
+            self.scanned = self.buf.len();
+            // However the line ends, it will be too long
+            if self.buf.len() > self.max_size {
+                return Err(self.too_large(self.buf.len()));
+            }
+            return Ok(None);
+        };
+        let end = self.scanned + end;
+
+        if end > self.max_size {
+            return Err(self.too_large(end));
+        }
+        let line = self.buf.drain(..=end).collect();
+        self.scanned = 0;
+        Ok(Some(line))
+    }
+
+    fn decode_prefixed(&mut self) -> Result<Option<Envelope>, FrameError> {
+        let Some(prefix) = self.buf.first_chunk::<4>() else {
+            return Ok(None);
+        };
+
+        let length = u32::from_be_bytes(*prefix) as usize;
+        if length > self.max_size {
+            return Err(self.too_large(length));
+        }
+        if self.buf.len() < 4 + length {
+            return Ok(None);
+        }
+
+        let frame: Vec<u8> = self.buf.drain(..4 + length).skip(4).collect();
+        self.scanned = 0;
+        Ok(Some(Envelope::from_bytes(&frame)?))
+    }
+
+    fn too_large(&self, size: usize) -> FrameError {
+        FrameError::TooLarge {
+            size,
+            max: self.max_size,
+        }
+    }
+}
+
+/// Reads frames from a byte stream
+///
+/// Cancel safe: bytes of a partly read frame are kept for the next call.
+pub struct FrameReader<R> {
+    reader: R,
+    decoder: FrameDecoder,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    /// Read lines of at most `max_size` bytes, until told otherwise
+    pub fn new(reader: R, max_size: usize) -> Self {
+        Self {
+            reader,
+            decoder: FrameDecoder::new(max_size),
+        }
+    }
+
+    /// The framing frames are currently read with
+    pub fn framing(&self) -> Framing {
+        self.decoder.framing()
+    }
+
+    /// Read frames after the current one with `framing`
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.decoder.set_framing(framing);
+    }
+
+    /// Read lines until the first frame in `framing`, then read that way
+    pub fn switch_when_seen(&mut self, framing: Framing) {
+        self.decoder.switch_when_seen(framing);
+    }
+
+    /// Read the next frame's message, dropping any request ID
+    pub async fn read_frame(&mut self) -> Result<Option<ProtocolMessage>, FrameError> {
+        Ok(self.read_envelope().await?.map(|envelope| envelope.message))
+    }
+
+    /// Read the next frame (None once the stream ends)
+    ///
+    /// A frame cut off by the end of the stream is dropped.
+    pub async fn read_envelope(&mut self) -> Result<Option<Envelope>, FrameError> {
+        let mut chunk = [0u8; 8192];
+        loop {
+            if let Some(envelope) = self.decoder.decode()? {
+                return Ok(Some(envelope));
+            }
+
+            let read = self.reader.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.decoder.extend(&chunk[..read]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgserver::message::ErrorCode;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn samples() -> Vec<ProtocolMessage> {
+        vec![
+            ProtocolMessage::Hello {
+                version: PROTOCOL_VERSION,
+                capabilities: capabilities(),
+            },
+            ProtocolMessage::Ping,
+            ProtocolMessage::Send {
+                content: "multi\nline ünïcode".to_string(),
+                to: Some("bob".to_string()),
+                channel: None,
+                signature: None,
+                attachment: None,
+            },
+            ProtocolMessage::error(ErrorCode::Internal, "x".repeat(10_000)),
+        ]
+    }
+
+    /// Feed `bytes` to a decoder in random pieces, collecting every frame
+    fn decode_in_pieces(
+        bytes: &[u8],
+        framing: Framing,
+        max_size: usize,
+        rng: &mut StdRng,
+    ) -> Vec<Result<Envelope, FrameError>> {
+        let mut decoder = FrameDecoder::new(max_size);
+        decoder.set_framing(framing);
+
+        let mut frames = Vec::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let (piece, tail) = rest.split_at(rng.gen_range(1..=rest.len().min(64)));
+            rest = tail;
+            decoder.extend(piece);
+            loop {
+                match decoder.decode() {
+                    Ok(Some(frame)) => frames.push(Ok(frame)),
+                    Ok(None) => break,
+                    Err(e) => {
+                        let fatal = !e.is_recoverable();
+                        frames.push(Err(e));
+                        if fatal {
+                            return frames;
+                        }
+                    }
+                }
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn test_frames_survive_any_split() {
+        let mut rng = StdRng::seed_from_u64(47);
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let bytes: Vec<u8> = samples().iter().flat_map(|m| encode(m, framing).unwrap()).collect();
+            for _ in 0..50 {
+                let frames = decode_in_pieces(&bytes, framing, MAX_FRAME_SIZE, &mut rng);
+                let frames: Vec<_> = frames.into_iter().map(|f| f.unwrap().message).collect();
+                assert_eq!(format!("{:?}", frames), format!("{:?}", samples()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_oversized_frames_are_refused() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let big = encode(&samples()[3], Framing::LengthPrefixed).unwrap();
+        let frames = decode_in_pieces(&big, Framing::LengthPrefixed, 1000, &mut rng);
+        assert!(matches!(frames[..], [Err(FrameError::TooLarge { .. })]));
+
+        // A line is refused as soon as it is too long, before its end arrives
+        let mut decoder = FrameDecoder::new(1000);
+        decoder.extend(&[b'x'; 1001]);
+        assert!(matches!(decoder.decode(), Err(FrameError::TooLarge { size: 1001, max: 1000 })));
+
+        // A prefix is enough to refuse a length-prefixed frame
+        let mut decoder = FrameDecoder::new(1000);
+        decoder.set_framing(Framing::LengthPrefixed);
+        decoder.extend(&u32::MAX.to_be_bytes());
+        assert!(matches!(decoder.decode(), Err(FrameError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn test_dripped_line_is_searched_once() {
+        // Searching the whole line again for each byte would not finish
+        let request = Envelope::reply(Some("x".repeat(MAX_FRAME_SIZE / 4)), ProtocolMessage::Ping);
+        let bytes = encode(&request, Framing::Lines).unwrap();
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+        for (i, byte) in bytes.iter().enumerate() {
+            decoder.extend(&[*byte]);
+            let frame = decoder.decode().unwrap();
+            assert_eq!(frame.is_some(), i == bytes.len() - 1);
+        }
+    }
+
+    #[test]
+    fn test_malformed_frames_are_skipped() {
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+        decoder.extend(b"not json\n\n  \n{\"type\":\"ping\"}\n");
+        assert!(matches!(decoder.decode(), Err(FrameError::Malformed(_))));
+        assert!(matches!(decoder.decode(), Ok(Some(Envelope { message: ProtocolMessage::Ping, .. }))));
+        assert!(matches!(decoder.decode(), Ok(None)));
+    }
+
+    #[test]
+    fn test_request_ids_ride_along() {
+        let request = Envelope::reply(Some("7".to_string()), ProtocolMessage::Ping);
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+            decoder.set_framing(framing);
+            decoder.extend(&encode(&request, framing).unwrap());
+            let envelope = decoder.decode().unwrap().unwrap();
+            assert_eq!(envelope.request_id.as_deref(), Some("7"));
+            assert!(matches!(envelope.message, ProtocolMessage::Ping));
+        }
+
+        // Frames without one, as older peers send, still decode
+        let mut decoder = FrameDecoder::new(MAX_FRAME_SIZE);
+        decoder.extend(&encode(&ProtocolMessage::Pong, Framing::Lines).unwrap());
+        assert!(decoder.decode().unwrap().unwrap().request_id.is_none());
+    }
+
+    #[test]
+    fn test_fuzz_decoder_never_panics() {
+        let mut rng = StdRng::seed_from_u64(0xfeed);
+        let valid: Vec<Vec<u8>> = samples()
+            .iter()
+            .flat_map(|m| [encode(m, Framing::Lines).unwrap(), encode(m, Framing::LengthPrefixed).unwrap()])
+            .collect();
+
+        for _ in 0..2000 {
+            // Random bytes, or valid frames with random bytes flipped, cut or added
+            let mut bytes: Vec<u8> = if rng.gen_bool(0.3) {
+                (0..rng.gen_range(0..512)).map(|_| rng.gen()).collect()
+            } else {
+                let mut bytes: Vec<u8> = (0..rng.gen_range(1..4))
+                    .flat_map(|_| valid[rng.gen_range(0..valid.len())].clone())
+                    .collect();
+                for _ in 0..rng.gen_range(0..8) {
+                    let at = rng.gen_range(0..bytes.len());
+                    match rng.gen_range(0..3) {
+                        0 => bytes[at] = rng.gen(),
+                        1 => bytes.truncate(at.max(1)),
+                        _ => bytes.insert(at, rng.gen()),
+                    }
+                }
+                bytes
+            };
+            if bytes.is_empty() {
+                bytes.push(b'\n');
+            }
+
+            let framing = if rng.gen_bool(0.5) { Framing::Lines } else { Framing::LengthPrefixed };
+            let max_size = rng.gen_range(1..=20_000);
+            // Whatever decodes must be a message that encodes again
+            for message in decode_in_pieces(&bytes, framing, max_size, &mut rng).into_iter().flatten() {
+                assert!(encode(&message, framing).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let (version, shared) = negotiate(99, &["attachments".to_string(), "teleport".to_string()]).unwrap();
+        assert_eq!(version, PROTOCOL_VERSION);
+        assert_eq!(shared, ["attachments"]);
+        assert_eq!(Framing::negotiated(&shared), Framing::Lines);
+
+        let (_, shared) = negotiate(PROTOCOL_VERSION, &capabilities()).unwrap();
+        assert_eq!(Framing::negotiated(&shared), Framing::LengthPrefixed);
+
+        assert!(negotiate(LEGACY_PROTOCOL_VERSION, &capabilities()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reader_switches_framing_mid_stream() {
+        let hello = ProtocolMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: capabilities(),
+        };
+        let mut bytes = encode(&hello, Framing::Lines).unwrap();
+        bytes.extend(encode(&ProtocolMessage::Ping, Framing::LengthPrefixed).unwrap());
+
+        // Both frames arrive in one read; the second must wait for the switch
+        let mut reader = FrameReader::new(&bytes[..], MAX_FRAME_SIZE);
+        assert!(matches!(reader.read_frame().await.unwrap(), Some(ProtocolMessage::Hello { .. })));
+        reader.set_framing(Framing::LengthPrefixed);
+        assert!(matches!(reader.read_frame().await.unwrap(), Some(ProtocolMessage::Ping)));
+        assert!(reader.read_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reader_switches_at_first_prefixed_frame() {
+        // Lines the peer sent before it switched, then prefixed frames
+        let mut bytes = b"\n{\"type\":\"ping\"}\n  \n".to_vec();
+        bytes.extend(encode(&Envelope::reply(Some("2".to_string()), ProtocolMessage::Ping), Framing::LengthPrefixed).unwrap());
+        bytes.extend(b"{\"type\":\"ping\"}\n");
+
+        let mut reader = FrameReader::new(&bytes[..], MAX_FRAME_SIZE);
+        reader.switch_when_seen(Framing::LengthPrefixed);
+        assert_eq!(reader.read_envelope().await.unwrap().unwrap().request_id, None);
+        assert_eq!(reader.framing(), Framing::Lines);
+        assert_eq!(reader.read_envelope().await.unwrap().unwrap().request_id.as_deref(), Some("2"));
+        assert_eq!(reader.framing(), Framing::LengthPrefixed);
+
+        // There is no going back to lines
+        assert!(matches!(reader.read_envelope().await, Err(FrameError::TooLarge { .. })));
+    }
+}
@@ -1,18 +1,64 @@
 // Handshake and authentication utilities for broker/client introduction
 
+use crate::msgserver::e2e::PublicKey;
+use crate::msgserver::pake::{Pake, Role};
+use argon2::{Algorithm, Argon2, Params, Version};
+use safelog::DisplayRedacted;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tor_hscrypto::pk::{HsIdKey, HsIdKeypair};
+use tor_llcrypto::pk::ed25519;
 
 /// Introduction handshake data
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IntroductionData {
     /// Fortress onion address
     pub fortress_address: String,
+    /// Fortress Unix socket, for fortresses that are only reachable locally
+    #[serde(default)]
+    pub fortress_socket: Option<PathBuf>,
     /// Access token for fortress
     pub access_token: String,
     /// Token expiration time
     pub expires_at: SystemTime,
+    /// Encryption keys of the fortress's other clients
+    #[serde(default)]
+    pub peer_keys: Vec<PublicKey>,
+}
+
+/// Messages exchanged between a broker and a client being introduced
+///
+/// The two sides run a SPAKE2 exchange keyed by the short code (see
+/// [`crate::msgserver::pake`]); the introduction itself is only ever sent
+/// encrypted under the resulting session key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IntroMessage {
+    /// Broker's session ID and key share, sent as soon as a client connects
+    Challenge {
+        session_id: String,
+        share: String,
+    },
+    /// Client's key share and key confirmation
+    Request {
+        client_id: String,
+        share: String,
+        confirmation: String,
+        /// Key other clients encrypt messages to this one with
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_key: Option<PublicKey>,
+    },
+    /// Broker's key confirmation and the sealed `IntroductionData`
+    Accepted {
+        confirmation: String,
+        sealed: String,
+    },
+    /// Broker could not confirm the client's key
+    Rejected {
+        message: String,
+    },
 }
 
 /// Generate a short code for broker discovery
@@ -53,16 +99,69 @@ pub fn generate_access_token() -> String {
         .collect()
 }
 
+/// Argon2id memory cost for broker identifiers, in KiB
+///
+/// With the pass count below this is OWASP's minimum recommendation, so every
+/// guess at a short code from a published broker address costs real work.
+const BROKER_KDF_MEMORY_KIB: u32 = 19 * 1024;
+
+/// Argon2id passes for broker identifiers
+const BROKER_KDF_PASSES: u32 = 2;
+
+/// Length of a broker identifier in bytes
+const BROKER_IDENTIFIER_LEN: usize = 16;
+
 /// Generate deterministic onion service identifier for broker
 /// Based on namespace (email/identifier) + timestamp + short code
+///
+/// The short code is stretched with Argon2id, salted by the namespace and
+/// timestamp. The broker's onion key, address and socket all derive from
+/// this identifier, so brute-forcing a code from them costs one Argon2id
+/// evaluation per guess.
 pub fn generate_broker_identifier(namespace: &str, timestamp: u64, code: &str) -> String {
+    let mut salt = Sha256::new();
+    salt.update(b"eddi-broker-salt");
+    salt.update(namespace.as_bytes());
+    salt.update(timestamp.to_le_bytes());
+
+    let params = Params::new(
+        BROKER_KDF_MEMORY_KIB,
+        BROKER_KDF_PASSES,
+        1,
+        Some(BROKER_IDENTIFIER_LEN),
+    )
+    .expect("valid Argon2 parameters");
+
+    let mut identifier = [0u8; BROKER_IDENTIFIER_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(code.as_bytes(), &salt.finalize(), &mut identifier)
+        .expect("valid Argon2 input");
+
+    hex::encode(identifier)
+}
+
+/// Derive the onion service identity key a broker publishes under
+pub fn broker_keypair(identifier: &str) -> HsIdKeypair {
     let mut hasher = Sha256::new();
-    hasher.update(namespace.as_bytes());
-    hasher.update(timestamp.to_le_bytes());
-    hasher.update(code.as_bytes());
+    hasher.update(b"eddi-broker-key");
+    hasher.update(identifier.as_bytes());
+    let seed: [u8; 32] = hasher.finalize().into();
+
+    let keypair = ed25519::Keypair::from_bytes(&seed);
+    ed25519::ExpandedKeypair::from(&keypair).into()
+}
 
-    let result = hasher.finalize();
-    hex::encode(&result[..16]) // Use first 16 bytes for identifier
+/// Get the v3 onion address (`<base32>.onion`) of a broker
+pub fn broker_onion_address(identifier: &str) -> String {
+    HsIdKey::from(&broker_keypair(identifier))
+        .id()
+        .display_unredacted()
+        .to_string()
+}
+
+/// Get the Unix socket path a broker listens on for local clients
+pub fn broker_socket_path(identifier: &str) -> PathBuf {
+    PathBuf::from(format!("/tmp/eddi-msgsrv-broker-{}.sock", identifier))
 }
 
 /// Get current timestamp in seconds
@@ -105,25 +204,75 @@ pub struct BrokerHandshake {
     namespace: String,
     code: String,
     timestamp: u64,
+    // Derived once: it costs an Argon2id evaluation
+    identifier: String,
     fortress_address: String,
+    fortress_socket: Option<PathBuf>,
 }
 
 impl BrokerHandshake {
     /// Create a new broker handshake
     pub fn new(namespace: String, code: String, fortress_address: String) -> Self {
         let timestamp = round_timestamp(current_timestamp(), 60);
+        let identifier = generate_broker_identifier(&namespace, timestamp, &code);
 
         Self {
             namespace,
             code,
             timestamp,
+            identifier,
             fortress_address,
+            fortress_socket: None,
         }
     }
 
+    /// Create a new broker handshake from async code, deriving the identifier
+    /// on the blocking pool so Argon2id does not stall the runtime
+    pub async fn derive(namespace: String, code: String, fortress_address: String) -> Self {
+        tokio::task::spawn_blocking(move || Self::new(namespace, code, fortress_address))
+            .await
+            .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))
+    }
+
+    /// Also hand out the fortress socket, for fortresses without an onion address
+    pub fn with_fortress_socket(mut self, socket_path: PathBuf) -> Self {
+        self.fortress_socket = Some(socket_path);
+        self
+    }
+
     /// Get the broker identifier
     pub fn identifier(&self) -> String {
-        generate_broker_identifier(&self.namespace, self.timestamp, &self.code)
+        self.identifier.clone()
+    }
+
+    /// Get the namespace the broker was created in
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Get the short code clients must know
+    pub fn code(&self) -> &str {
+        &self.code
+    }
+
+    /// Get the (rounded) timestamp the broker identifier was derived from
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Get the onion service identity key this broker publishes under
+    pub fn keypair(&self) -> HsIdKeypair {
+        broker_keypair(&self.identifier)
+    }
+
+    /// Get the onion address this broker publishes
+    pub fn onion_address(&self) -> String {
+        broker_onion_address(&self.identifier)
+    }
+
+    /// Get the Unix socket this broker listens on
+    pub fn socket_path(&self) -> PathBuf {
+        broker_socket_path(&self.identifier)
     }
 
     /// Create introduction data for a client
@@ -133,18 +282,21 @@ impl BrokerHandshake {
 
         IntroductionData {
             fortress_address: self.fortress_address.clone(),
+            fortress_socket: self.fortress_socket.clone(),
             access_token,
             expires_at,
+            peer_keys: Vec::new(),
         }
     }
 
-    /// Validate a client's connection attempt
-    pub fn validate_code(&self, provided_code: &str) -> bool {
-        self.code == provided_code
+    /// Start the broker's side of a code-keyed exchange for `session_id`
+    pub fn start_pake(&self, session_id: &str) -> Pake {
+        Pake::start(Role::Broker, &self.code, &self.identifier, session_id)
     }
 }
 
 /// Client handshake handler
+#[derive(Debug, Clone)]
 pub struct ClientHandshake {
     namespace: String,
     code: String,
@@ -169,6 +321,19 @@ impl ClientHandshake {
             .collect()
     }
 
+    /// Generate the onion addresses a matching broker may have published
+    pub fn possible_onion_addresses(&self, time_window_minutes: i64) -> Vec<(u64, String)> {
+        self.possible_identifiers(time_window_minutes)
+            .into_iter()
+            .map(|(ts, identifier)| (ts, broker_onion_address(&identifier)))
+            .collect()
+    }
+
+    /// Start the client's side of a code-keyed exchange with broker `identifier`
+    pub fn start_pake(&self, identifier: &str, session_id: &str) -> Pake {
+        Pake::start(Role::Client, &self.code, identifier, session_id)
+    }
+
     /// Get the code
     pub fn code(&self) -> &str {
         &self.code
@@ -271,8 +436,16 @@ mod tests {
         let identifier = handshake.identifier();
         assert!(!identifier.is_empty());
 
-        assert!(handshake.validate_code("ABC-XYZ"));
-        assert!(!handshake.validate_code("WRONG"));
+        // Only a client with the same code completes the exchange
+        for (code, accepted) in [("ABC-XYZ", true), ("WRONG", false)] {
+            let client = ClientHandshake::new("test@example.com".to_string(), code.to_string());
+            let broker_pake = handshake.start_pake("session");
+            let client_pake = client.start_pake(&identifier, "session");
+            let broker_share = broker_pake.share();
+            let broker_keys = broker_pake.finish(&client_pake.share()).unwrap();
+            let client_keys = client_pake.finish(&broker_share).unwrap();
+            assert_eq!(broker_keys.verify_confirmation(&client_keys.confirmation()), accepted);
+        }
     }
 
     #[test]
@@ -292,6 +465,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_broker_onion_address_matches_client() {
+        let broker = BrokerHandshake::new(
+            "test@example.com".to_string(),
+            "ABC-XYZ".to_string(),
+            "test123.onion".to_string(),
+        );
+        let client = ClientHandshake::new(
+            "test@example.com".to_string(),
+            "ABC-XYZ".to_string(),
+        );
+
+        let address = broker.onion_address();
+        assert!(address.ends_with(".onion"));
+        assert_eq!(address.len(), 56 + ".onion".len());
+
+        // The client derives the same address from one of its candidates
+        assert!(client
+            .possible_onion_addresses(1)
+            .iter()
+            .any(|(ts, candidate)| *ts == broker.timestamp() && *candidate == address));
+    }
+
+    #[test]
+    fn test_broker_onion_address_depends_on_all_inputs() {
+        let address = |namespace: &str, timestamp: u64, code: &str| {
+            broker_onion_address(&generate_broker_identifier(namespace, timestamp, code))
+        };
+
+        let base = address("test@example.com", 1234567860, "ABC-XYZ");
+        assert_eq!(base, address("test@example.com", 1234567860, "ABC-XYZ"));
+        assert_ne!(base, address("other@example.com", 1234567860, "ABC-XYZ"));
+        assert_ne!(base, address("test@example.com", 1234567920, "ABC-XYZ"));
+        assert_ne!(base, address("test@example.com", 1234567860, "DEF-123"));
+    }
+
     #[test]
     fn test_introduction_data() {
         let handshake = BrokerHandshake::new(
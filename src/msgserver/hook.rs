@@ -0,0 +1,372 @@
+// Commands run for each received message
+//
+// A hook runs its command through `sh -c` once per message, the way a webhook
+// would be called: the message content is written to stdin and the rest of
+// the message is passed in `EDDI_MSG_*` environment variables. Receiving
+// clients configure one with `listen --hook`, fortresses with
+// `create-server --hook`. Runs are limited in number, killed (with anything
+// they started) when they take too long, and retried with backoff when they
+// fail. Messages arriving while too many runs are waiting are dropped.
+
+use crate::msgserver::message::Message;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::{mpsc, Semaphore};
+
+/// Delay before the first retry; it doubles for each further one
+pub const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between retries
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Messages that may wait for a run before further ones are dropped
+pub const HOOK_QUEUE_CAPACITY: usize = 256;
+
+/// What to run for each message, and how
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HookConfig {
+    /// Shell command, run with `sh -c`
+    pub command: String,
+    /// Most runs in progress at once (1 keeps messages in order)
+    pub concurrency: usize,
+    /// Seconds before a run is killed and counted as failed
+    pub timeout_secs: u64,
+    /// Times a failed run is retried
+    pub retries: u32,
+}
+
+impl HookConfig {
+    /// One run at a time, a 30 second timeout and no retries
+    pub fn new(command: String) -> Self {
+        Self {
+            command,
+            concurrency: 1,
+            timeout_secs: 30,
+            retries: 0,
+        }
+    }
+
+    /// Allow up to `concurrency` runs at once
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Kill runs that take longer than `timeout_secs`
+    pub fn with_timeout(mut self, timeout_secs: u64) -> Self {
+        self.timeout_secs = timeout_secs;
+        self
+    }
+
+    /// Retry failed runs up to `retries` times
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+/// A configured hook, ready to run
+#[derive(Debug, Clone)]
+pub struct Hook {
+    config: HookConfig,
+    server: Option<String>,
+    retry_delay: Duration,
+}
+
+impl Hook {
+    /// Create a hook from its configuration
+    pub fn new(config: HookConfig) -> Self {
+        Self {
+            config,
+            server: None,
+            retry_delay: RETRY_DELAY,
+        }
+    }
+
+    /// Name the server messages come from (passed as `EDDI_MSG_SERVER`)
+    pub fn with_server(mut self, server: String) -> Self {
+        self.server = Some(server);
+        self
+    }
+
+    /// Run the hook for one message, retrying until it succeeds or gives up
+    pub async fn run(&self, message: &Message) -> Result<()> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+
+        loop {
+            let err = match self.run_once(message).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+
+            if attempt >= self.config.retries {
+                return Err(err);
+            }
+            attempt += 1;
+
+            tracing::warn!(
+                "Hook failed for message {} ({:#}), retry {}/{} in {:?}",
+                message.id,
+                err,
+                attempt,
+                self.config.retries,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Run the hook once, failing if it exits unsuccessfully or times out
+    async fn run_once(&self, message: &Message) -> Result<()> {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.config.command)
+            .env("EDDI_MSG_ID", &message.id)
+            .env("EDDI_MSG_FROM", &message.from)
+            .env("EDDI_MSG_TIMESTAMP", unix_seconds(message.timestamp).to_string())
+            .env("EDDI_MSG_EXPIRES_AT", unix_seconds(message.expires_at).to_string())
+            .stdin(Stdio::piped())
+            // Its own process group, so a timeout kills whatever it started too
+            .process_group(0)
+            .kill_on_drop(true);
+        if let Some(ref server) = self.server {
+            command.env("EDDI_MSG_SERVER", server);
+        }
+        if let Some(ref to) = message.to {
+            command.env("EDDI_MSG_TO", to);
+        }
+        if let Some(ref channel) = message.channel {
+            command.env("EDDI_MSG_CHANNEL", channel);
+        }
+        if let Some(ref signature) = message.signature {
+            command.env("EDDI_MSG_SIGNER", signature.key.fingerprint());
+        }
+        if let Some(ref attachment) = message.attachment {
+            command.env("EDDI_MSG_ATTACHMENT", &attachment.name);
+            command.env("EDDI_MSG_ATTACHMENT_SIZE", attachment.size.to_string());
+        }
+
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to start hook: {}", self.config.command))?;
+
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let run = async {
+            if let Some(mut stdin) = child.stdin.take() {
+                // A hook that ignores its input may exit before reading it
+                if let Err(e) = stdin.write_all(message.content.as_bytes()).await {
+                    tracing::debug!("Hook did not read its input: {}", e);
+                }
+            }
+            child.wait().await.context("Failed to wait for hook")
+        };
+
+        let status = match tokio::time::timeout(timeout, run).await {
+            Ok(status) => status?,
+            Err(_) => {
+                if let Some(pid) = child.id() {
+                    // SAFETY: kill(2) has no memory-safety requirements; the group
+                    // is led by our unreaped child, so its ID cannot be reused
+                    unsafe {
+                        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+                    }
+                }
+                let _ = child.kill().await;
+                anyhow::bail!("Hook timed out after {:?}", timeout);
+            }
+        };
+
+        if !status.success() {
+            anyhow::bail!("Hook exited with {}", status);
+        }
+
+        Ok(())
+    }
+
+    /// Run the hook for each message submitted to the returned sender
+    ///
+    /// Runs start in the order messages arrive, at most `concurrency` at once.
+    pub fn spawn(self) -> HookSender {
+        let (tx, mut rx) = mpsc::channel::<Message>(HOOK_QUEUE_CAPACITY);
+        let permits = Arc::new(Semaphore::new(self.config.concurrency.max(1)));
+        let hook = Arc::new(self);
+
+        tokio::spawn(async move {
+            while let Some(message) = rx.recv().await {
+                // Taking the permit here, not in the task, keeps the order
+                let Ok(permit) = permits.clone().acquire_owned().await else {
+                    break;
+                };
+
+                let hook = hook.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = hook.run(&message).await {
+                        tracing::error!("Hook failed for message {}: {:#}", message.id, e);
+                    }
+                    drop(permit);
+                });
+            }
+        });
+
+        HookSender { tx }
+    }
+}
+
+/// Hands messages to a spawned hook
+#[derive(Debug, Clone)]
+pub struct HookSender {
+    tx: mpsc::Sender<Message>,
+}
+
+impl HookSender {
+    /// Queue a run for `message`, dropping it if `HOOK_QUEUE_CAPACITY`
+    /// messages are already waiting
+    pub fn submit(&self, message: Message) {
+        match self.tx.try_send(message) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(message)) => {
+                tracing::warn!("Hook is falling behind, not running it for message {}", message.id);
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgserver::message::Audience;
+    use std::time::Instant;
+    use tempfile::tempdir;
+
+    fn message(content: &str) -> Message {
+        Message::new("sender".to_string(), content.to_string(), Duration::from_secs(60))
+    }
+
+    #[tokio::test]
+    async fn test_hook_receives_message_in_env_and_stdin() {
+        let dir = tempdir().unwrap();
+        let out = dir.path().join("out");
+
+        let command = format!(
+            "{{ echo \"$EDDI_MSG_ID $EDDI_MSG_FROM $EDDI_MSG_TIMESTAMP $EDDI_MSG_SERVER $EDDI_MSG_CHANNEL\"; cat; }} > {}",
+            out.display()
+        );
+        let hook = Hook::new(HookConfig::new(command)).with_server("fortress".to_string());
+        let message = message("hello").with_audience(Audience::Channel("ops".to_string()));
+        hook.run(&message).await.unwrap();
+
+        let expected = format!(
+            "{} sender {} fortress ops\nhello",
+            message.id,
+            unix_seconds(message.timestamp)
+        );
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn test_hook_failure_is_reported() {
+        let hook = Hook::new(HookConfig::new("exit 3".to_string()));
+
+        let err = hook.run(&message("hello")).await.unwrap_err();
+        assert!(err.to_string().contains("exit status: 3"));
+    }
+
+    #[tokio::test]
+    async fn test_hook_is_killed_after_timeout() {
+        let hook = Hook::new(HookConfig::new("sleep 10".to_string()).with_timeout(1));
+
+        let start = Instant::now();
+        let err = hook.run(&message("hello")).await.unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_processes_the_hook_started() {
+        let dir = tempdir().unwrap();
+        let pidfile = dir.path().join("pid");
+
+        let command = format!("sleep 10 & echo $! > {}; wait", pidfile.display());
+        let hook = Hook::new(HookConfig::new(command).with_timeout(1));
+        assert!(hook.run(&message("hello")).await.is_err());
+
+        let pid: u32 = std::fs::read_to_string(&pidfile).unwrap().trim().parse().unwrap();
+        // Killed, though it may linger as a zombie until `init` reaps it
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        assert!(stat.is_empty() || stat.contains(") Z "));
+    }
+
+    #[tokio::test]
+    async fn test_failed_hook_is_retried() {
+        let dir = tempdir().unwrap();
+        let attempts = dir.path().join("attempts");
+
+        // Fails until its third run
+        let command = format!(
+            "echo x >> {0}; [ $(wc -l < {0}) -ge 3 ]",
+            attempts.display()
+        );
+        let mut hook = Hook::new(HookConfig::new(command.clone()).with_retries(2));
+        hook.retry_delay = Duration::from_millis(10);
+        hook.run(&message("hello")).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&attempts).unwrap().lines().count(), 3);
+
+        // Without enough retries it gives up
+        std::fs::remove_file(&attempts).unwrap();
+        let mut hook = Hook::new(HookConfig::new(command).with_retries(1));
+        hook.retry_delay = Duration::from_millis(10);
+        assert!(hook.run(&message("hello")).await.is_err());
+        assert_eq!(std::fs::read_to_string(&attempts).unwrap().lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limits_runs_in_progress() {
+        let dir = tempdir().unwrap();
+        let running = dir.path().join("running");
+        let peak = dir.path().join("peak");
+        std::fs::create_dir(&running).unwrap();
+
+        // Each run records how many runs were in progress alongside it
+        let command = format!(
+            "touch {0}/$EDDI_MSG_ID; ls {0} | wc -l >> {1}; sleep 0.3; rm {0}/$EDDI_MSG_ID",
+            running.display(),
+            peak.display()
+        );
+        let tx = Hook::new(HookConfig::new(command).with_concurrency(2)).spawn();
+        for i in 0..4 {
+            tx.submit(message(&i.to_string()));
+        }
+
+        for _ in 0..100 {
+            let done = std::fs::read_to_string(&peak).map(|p| p.lines().count()).unwrap_or(0) == 4;
+            if done && std::fs::read_dir(&running).unwrap().next().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let counts: Vec<usize> = std::fs::read_to_string(&peak)
+            .unwrap()
+            .lines()
+            .map(|line| line.trim().parse().unwrap())
+            .collect();
+        assert_eq!(counts.len(), 4);
+        assert!(counts.iter().all(|&count| count <= 2));
+        assert!(counts.contains(&2));
+    }
+}
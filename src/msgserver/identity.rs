@@ -0,0 +1,354 @@
+// Long-term sender identities
+//
+// Each installation has one ed25519 identity, kept in the state database.
+// Clients sign every message they send with it, over the content, the
+// audience, the hash of any attached file, the sender's alias, the server and
+// a random nonce. Fortresses refuse messages whose signature does not verify
+// or is stale, and receivers check again rather than trusting the fortress,
+// also flagging a signature seen before on another message. Senders are shown
+// by the fingerprint of their key. The key first seen for each alias on a
+// server is pinned, so a later message under that alias with another key, or
+// with no signature at all, stands out.
+
+use crate::msgserver::message::Audience;
+use anyhow::{Context, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tor_llcrypto::pk::ed25519;
+
+/// Domain separation tag for message signatures
+const SIGNATURE_DSI: &[u8] = b"eddi-msg-signature-v2";
+
+/// How far a signature's time may be from the fortress's clock
+pub const MAX_SIGNATURE_SKEW: Duration = Duration::from_secs(10 * 60);
+
+/// Bytes of the random nonce in each signature
+const NONCE_LEN: usize = 16;
+
+/// Bytes of the key hash shown as a fingerprint
+const FINGERPRINT_LEN: usize = 16;
+
+/// An ed25519 public key identifying a sender, hex encoded on the wire
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IdentityKey([u8; 32]);
+
+impl IdentityKey {
+    /// Short, stable name for the key: part of its SHA-256, in groups of four
+    pub fn fingerprint(&self) -> String {
+        let hash = Sha256::digest(self.0);
+        hex::encode(&hash[..FINGERPRINT_LEN])
+            .as_bytes()
+            .chunks(4)
+            .map(|group| std::str::from_utf8(group).expect("hex is ASCII"))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    fn verifying_key(&self) -> Result<ed25519::PublicKey> {
+        ed25519::PublicKey::from_bytes(&self.0).map_err(|_| anyhow::anyhow!("Malformed identity key"))
+    }
+}
+
+impl fmt::Display for IdentityKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl FromStr for IdentityKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes: [u8; 32] = hex::decode(s)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .context("Malformed identity key")?;
+        Ok(Self(bytes))
+    }
+}
+
+impl TryFrom<String> for IdentityKey {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<IdentityKey> for String {
+    fn from(key: IdentityKey) -> Self {
+        key.to_string()
+    }
+}
+
+/// This installation's signing key
+pub struct Identity {
+    keypair: ed25519::Keypair,
+}
+
+impl Identity {
+    /// Generate a new random identity
+    pub fn generate() -> Self {
+        let mut seed = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut seed);
+        Self {
+            keypair: ed25519::Keypair::from_bytes(&seed),
+        }
+    }
+
+    /// The public key receivers verify our messages with
+    pub fn public_key(&self) -> IdentityKey {
+        IdentityKey(self.keypair.verifying_key().to_bytes())
+    }
+
+    /// Hex encoding of the secret seed, for storage
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.keypair.to_bytes())
+    }
+
+    /// Sign a message about to be sent to `audience` from `origin`, with the
+    /// SHA-256 of its attachment if it has one
+    pub fn sign(
+        &self,
+        content: &str,
+        audience: &Audience,
+        attachment: Option<&str>,
+        origin: Origin<'_>,
+    ) -> MessageSignature {
+        let signed_at = unix_seconds(SystemTime::now());
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let nonce = hex::encode(nonce);
+        let signature = self
+            .keypair
+            .sign(&signed_bytes(content, audience, attachment, origin, &nonce, signed_at));
+
+        MessageSignature {
+            key: self.public_key(),
+            signed_at,
+            nonce,
+            signature: hex::encode(signature.to_bytes()),
+        }
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", self.public_key().fingerprint())
+    }
+}
+
+impl FromStr for Identity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let seed: [u8; 32] = hex::decode(s)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .context("Malformed identity")?;
+        Ok(Self {
+            keypair: ed25519::Keypair::from_bytes(&seed),
+        })
+    }
+}
+
+/// Who sent a message, and where: covered by its signature so that it
+/// cannot be passed off as another sender's or replayed on another server
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Origin<'a> {
+    /// The sender's alias (None when sending without one)
+    pub sender: Option<&'a str>,
+    /// ID of the server it was sent to, as reported on authentication
+    pub server: Option<&'a str>,
+}
+
+/// A sender's signature over a message's content, audience, attachment and
+/// origin
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageSignature {
+    /// Key the message was signed with
+    pub key: IdentityKey,
+    /// When the sender signed it (Unix seconds)
+    pub signed_at: u64,
+    /// Random hex, so no two signatures are alike even for the same message
+    #[serde(default)]
+    pub nonce: String,
+    /// Hex encoded ed25519 signature
+    pub signature: String,
+}
+
+impl MessageSignature {
+    /// Check the signature against what was sent
+    pub fn verify(&self, content: &str, audience: &Audience, attachment: Option<&str>, origin: Origin<'_>) -> Result<()> {
+        let bytes: [u8; 64] = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .context("Malformed signature")?;
+
+        self.key
+            .verifying_key()?
+            .verify(
+                &signed_bytes(content, audience, attachment, origin, &self.nonce, self.signed_at),
+                &ed25519::Signature::from_bytes(&bytes),
+            )
+            .map_err(|_| anyhow::anyhow!("Invalid message signature"))
+    }
+
+    /// Whether it was signed within `MAX_SIGNATURE_SKEW` of `now`
+    pub fn is_fresh(&self, now: SystemTime) -> bool {
+        unix_seconds(now).abs_diff(self.signed_at) <= MAX_SIGNATURE_SKEW.as_secs()
+    }
+}
+
+/// How a sender's key compares with the one pinned for its alias
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trust {
+    /// The message was not signed
+    Unsigned,
+    /// The message was not signed, though a key is pinned for its sender
+    Stripped { pinned: IdentityKey },
+    /// The signature was already seen on another message
+    Replayed,
+    /// No key was pinned for this sender yet (it is now, if it has an alias)
+    New,
+    /// The key pinned for this sender
+    Pinned,
+    /// A different key from the one pinned for this sender
+    Changed { pinned: IdentityKey },
+}
+
+/// What a signature covers: the content, who it is for, the attachment's
+/// hash (if any), who sent it where, the nonce, and when it was signed
+fn signed_bytes(
+    content: &str,
+    audience: &Audience,
+    attachment: Option<&str>,
+    origin: Origin<'_>,
+    nonce: &str,
+    signed_at: u64,
+) -> Vec<u8> {
+    let (kind, name) = match audience {
+        Audience::Everyone => ("everyone", ""),
+        Audience::Client(alias) => ("client", alias.as_str()),
+        Audience::Channel(channel) => ("channel", channel.as_str()),
+    };
+
+    let parts = [
+        SIGNATURE_DSI,
+        kind.as_bytes(),
+        name.as_bytes(),
+        content.as_bytes(),
+        attachment.unwrap_or_default().as_bytes(),
+        origin.sender.unwrap_or_default().as_bytes(),
+        origin.server.unwrap_or_default().as_bytes(),
+        nonce.as_bytes(),
+    ];
+
+    let mut bytes = Vec::new();
+    for part in parts {
+        // Length-prefix each part so they cannot run into each other
+        bytes.extend((part.len() as u64).to_le_bytes());
+        bytes.extend(part);
+    }
+    bytes.extend(signed_at.to_le_bytes());
+    bytes
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_covers_content_and_audience() {
+        let identity = Identity::generate();
+        let audience = Audience::Client("bob".to_string());
+        let origin = Origin::default();
+        let signature = identity.sign("hello", &audience, None, origin);
+
+        assert!(signature.verify("hello", &audience, None, origin).is_ok());
+        assert!(signature.verify("hello!", &audience, None, origin).is_err());
+        assert!(signature.verify("hello", &Audience::Everyone, None, origin).is_err());
+        assert!(signature.verify("hello", &Audience::Channel("bob".to_string()), None, origin).is_err());
+        assert!(signature.verify("hello", &audience, Some("00"), origin).is_err());
+
+        // An attachment's hash is covered too
+        let attached = identity.sign("hello", &audience, Some("aa"), origin);
+        assert!(attached.verify("hello", &audience, Some("aa"), origin).is_ok());
+        assert!(attached.verify("hello", &audience, Some("bb"), origin).is_err());
+        assert!(attached.verify("hello", &audience, None, origin).is_err());
+
+        // Someone else's key does not verify it
+        let forged = MessageSignature {
+            key: Identity::generate().public_key(),
+            ..signature.clone()
+        };
+        assert!(forged.verify("hello", &audience, None, origin).is_err());
+
+        let mangled = MessageSignature {
+            signature: "00".repeat(64),
+            ..signature
+        };
+        assert!(mangled.verify("hello", &audience, None, origin).is_err());
+    }
+
+    #[test]
+    fn test_signature_covers_sender_server_and_nonce() {
+        let identity = Identity::generate();
+        let origin = Origin {
+            sender: Some("alice"),
+            server: Some("fortress-id"),
+        };
+        let signature = identity.sign("hello", &Audience::Everyone, None, origin);
+        assert!(signature.verify("hello", &Audience::Everyone, None, origin).is_ok());
+
+        let elsewhere = [
+            Origin { sender: Some("mallory"), ..origin },
+            Origin { sender: None, ..origin },
+            Origin { server: Some("other-id"), ..origin },
+            Origin::default(),
+        ];
+        for other in elsewhere {
+            assert!(signature.verify("hello", &Audience::Everyone, None, other).is_err());
+        }
+
+        let renonced = MessageSignature {
+            nonce: "00".repeat(NONCE_LEN),
+            ..signature.clone()
+        };
+        assert!(renonced.verify("hello", &Audience::Everyone, None, origin).is_err());
+
+        // The same message signed twice still gets two signatures
+        let again = identity.sign("hello", &Audience::Everyone, None, origin);
+        assert_ne!(again.signature, signature.signature);
+
+        assert!(signature.is_fresh(SystemTime::now()));
+        assert!(!signature.is_fresh(SystemTime::now() + MAX_SIGNATURE_SKEW * 2));
+    }
+
+    #[test]
+    fn test_identity_round_trips_and_fingerprints_are_stable() {
+        let identity = Identity::generate();
+        let stored: Identity = identity.to_hex().parse().unwrap();
+        assert_eq!(stored.public_key(), identity.public_key());
+
+        let fingerprint = identity.public_key().fingerprint();
+        assert_eq!(fingerprint.len(), FINGERPRINT_LEN * 2 + FINGERPRINT_LEN / 2 - 1);
+        assert_eq!(fingerprint.split(':').count(), FINGERPRINT_LEN / 2);
+        assert_eq!(fingerprint, stored.public_key().fingerprint());
+        assert_ne!(fingerprint, Identity::generate().public_key().fingerprint());
+
+        let json = serde_json::to_string(&identity.public_key()).unwrap();
+        assert_eq!(serde_json::from_str::<IdentityKey>(&json).unwrap(), identity.public_key());
+    }
+}
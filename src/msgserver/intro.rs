@@ -0,0 +1,592 @@
+// Broker introductions
+//
+// A broker listens on an onion service whose key is derived from its
+// identifier (and on a Unix socket named after it, for clients on the same
+// machine). Clients derive the same candidates from the namespace and short
+// code and run a SPAKE2 exchange keyed by the code with the broker. Once both
+// sides have confirmed the session key, the broker sends the fortress address
+// and a freshly minted access token encrypted under it. The client's
+// encryption key travels the other way and is registered with the fortress.
+
+use crate::msgserver::e2e::PublicKey;
+use crate::msgserver::handshake::{
+    broker_onion_address, broker_socket_path, generate_access_token, BrokerHandshake,
+    ClientHandshake, IntroMessage, IntroductionData,
+};
+use crate::msgserver::remote::ServerStream;
+use crate::msgserver::storage::StateManager;
+use crate::msgserver::cli::MsgSrvCli;
+use crate::msgserver::tor::{TorManager, MESSAGE_PORT};
+use anyhow::{Context, Result};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+
+/// How long either side waits for the other during an introduction
+pub const INTRO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a client waits for each candidate broker to answer over Tor
+pub const ONION_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Lifetime of the access tokens brokers hand out
+pub const TOKEN_TTL_HOURS: u64 = 24;
+
+/// Wrong codes a broker takes before it stops for good, so the short code
+/// cannot be guessed online
+pub const MAX_INTRO_FAILURES: u32 = 3;
+
+/// Longest introduction message either side accepts
+const MAX_INTRO_MESSAGE: u64 = 16 * 1024;
+
+/// Newline-delimited JSON framing for introduction messages
+struct IntroChannel {
+    stream: BufReader<Box<dyn ServerStream>>,
+}
+
+impl IntroChannel {
+    fn new(stream: Box<dyn ServerStream>) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    async fn send(&mut self, message: &IntroMessage) -> Result<()> {
+        let mut bytes = serde_json::to_vec(message).context("Failed to encode introduction message")?;
+        bytes.push(b'\n');
+
+        self.stream.write_all(&bytes).await.context("Failed to write introduction message")?;
+        self.stream.flush().await.context("Failed to write introduction message")?;
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> Result<IntroMessage> {
+        let mut line = String::new();
+        let mut limited = (&mut self.stream).take(MAX_INTRO_MESSAGE);
+
+        let read = tokio::time::timeout(INTRO_TIMEOUT, limited.read_line(&mut line))
+            .await
+            .context("Timed out during introduction")?
+            .context("Failed to read introduction message")?;
+
+        if read == 0 {
+            anyhow::bail!("Connection closed during introduction");
+        }
+        if !line.ends_with('\n') {
+            anyhow::bail!("Introduction message too long or truncated");
+        }
+
+        serde_json::from_str(line.trim()).context("Invalid introduction message")
+    }
+}
+
+/// Broker side of an introduction: hands out access to one fortress, once
+pub struct IntroductionServer {
+    handshake: BrokerHandshake,
+    fortress_id: String,
+    state_manager: Arc<StateManager>,
+    used: AtomicBool,
+    // Clients that failed to prove the code
+    failures: AtomicU32,
+}
+
+impl IntroductionServer {
+    /// Create an introduction server for the fortress with ID `fortress_id`
+    pub fn new(handshake: BrokerHandshake, fortress_id: String, state_manager: Arc<StateManager>) -> Self {
+        Self {
+            handshake,
+            fortress_id,
+            state_manager,
+            used: AtomicBool::new(false),
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Whether a client has already been introduced
+    pub fn is_used(&self) -> bool {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Whether so many wrong codes were tried that the broker must stop
+    pub fn is_exhausted(&self) -> bool {
+        self.failures.load(Ordering::SeqCst) >= MAX_INTRO_FAILURES
+    }
+
+    /// Get the broker handshake
+    pub fn handshake(&self) -> &BrokerHandshake {
+        &self.handshake
+    }
+
+    /// Introduce one connected client, returning whether it knew the code
+    ///
+    /// After `MAX_INTRO_FAILURES` wrong codes every client is refused, even
+    /// one that knows the code, as it may have been guessed.
+    pub async fn serve(&self, stream: Box<dyn ServerStream>) -> Result<bool> {
+        let mut channel = IntroChannel::new(stream);
+        if self.is_exhausted() {
+            channel
+                .send(&IntroMessage::Rejected {
+                    message: "Broker closed after too many wrong codes".to_string(),
+                })
+                .await?;
+            return Ok(false);
+        }
+
+        let session_id = generate_access_token();
+        let pake = self.handshake.start_pake(&session_id);
+        channel
+            .send(&IntroMessage::Challenge {
+                session_id,
+                share: pake.share(),
+            })
+            .await?;
+
+        let (client_id, share, confirmation, public_key) = match channel.recv().await? {
+            IntroMessage::Request {
+                client_id,
+                share,
+                confirmation,
+                public_key,
+            } => (client_id, share, confirmation, public_key),
+            other => anyhow::bail!("Unexpected introduction message: {:?}", other),
+        };
+
+        let keys = pake.finish(&share)?;
+        if !keys.verify_confirmation(&confirmation) {
+            let failures = self.failures.fetch_add(1, Ordering::SeqCst) + 1;
+            tracing::warn!(
+                "Rejected introduction for client {} ({} of {} wrong codes)",
+                client_id,
+                failures,
+                MAX_INTRO_FAILURES
+            );
+            channel
+                .send(&IntroMessage::Rejected {
+                    message: "Invalid code".to_string(),
+                })
+                .await?;
+            return Ok(false);
+        }
+        if self.is_exhausted() {
+            channel
+                .send(&IntroMessage::Rejected {
+                    message: "Broker closed after too many wrong codes".to_string(),
+                })
+                .await?;
+            return Ok(false);
+        }
+
+        // Only the first client to prove the code is introduced
+        if self.used.swap(true, Ordering::SeqCst) {
+            channel
+                .send(&IntroMessage::Rejected {
+                    message: "Broker already used".to_string(),
+                })
+                .await?;
+            return Ok(false);
+        }
+
+        // The fortress stores only a hash of the token, valid until it expires
+        let mut introduction = self.handshake.create_introduction(TOKEN_TTL_HOURS);
+        let fortress_id = self.fortress_id.clone();
+        introduction.peer_keys = self
+            .blocking(move |store| store.list_client_keys(&fortress_id))
+            .await?
+            .into_iter()
+            .map(|(_, key)| key)
+            .collect();
+        let sealed = keys.seal(&serde_json::to_vec(&introduction).context("Failed to encode introduction")?)?;
+        let (fortress_id, token, expires_at) = (
+            self.fortress_id.clone(),
+            introduction.access_token.clone(),
+            introduction.expires_at,
+        );
+        let client = self
+            .blocking(move |store| store.create_access_token(&fortress_id, &token, expires_at))
+            .await?;
+
+        // A token the client never received must not stay valid
+        let delivered = async {
+            if let Some(public_key) = public_key {
+                let id = client.id.clone();
+                self.blocking(move |store| store.set_client_key(&id, &public_key)).await?;
+            }
+            channel
+                .send(&IntroMessage::Accepted {
+                    confirmation: keys.confirmation(),
+                    sealed,
+                })
+                .await
+        };
+        if let Err(e) = delivered.await {
+            let id = client.id.clone();
+            if let Err(cleanup) = self.blocking(move |store| store.delete_client(&id)).await {
+                tracing::warn!("Failed to remove undelivered access token: {:#}", cleanup);
+            }
+            return Err(e);
+        }
+
+        tracing::info!("Introduced client {} to fortress {}", client_id, self.fortress_id);
+
+        Ok(true)
+    }
+
+    /// Run a store operation on the blocking pool, so a slow or locked
+    /// database does not hold up the runtime the broker listens on
+    async fn blocking<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&StateManager) -> Result<T> + Send + 'static,
+    {
+        let state_manager = self.state_manager.clone();
+        tokio::task::spawn_blocking(move || op(&state_manager))
+            .await
+            .context("Client store task failed")?
+    }
+}
+
+/// Client side of an introduction over a stream to the broker `identifier`
+///
+/// `public_key`, if given, is registered as the client's encryption key.
+pub async fn request_introduction(
+    stream: Box<dyn ServerStream>,
+    handshake: &ClientHandshake,
+    identifier: &str,
+    client_id: &str,
+    public_key: Option<PublicKey>,
+) -> Result<IntroductionData> {
+    let mut channel = IntroChannel::new(stream);
+
+    let (session_id, broker_share) = match channel.recv().await? {
+        IntroMessage::Challenge { session_id, share } => (session_id, share),
+        IntroMessage::Rejected { message } => anyhow::bail!("Broker rejected introduction: {}", message),
+        other => anyhow::bail!("Unexpected introduction message: {:?}", other),
+    };
+
+    let pake = handshake.start_pake(identifier, &session_id);
+    let share = pake.share();
+    let keys = pake.finish(&broker_share)?;
+
+    channel
+        .send(&IntroMessage::Request {
+            client_id: client_id.to_string(),
+            share,
+            confirmation: keys.confirmation(),
+            public_key,
+        })
+        .await?;
+
+    let (confirmation, sealed) = match channel.recv().await? {
+        IntroMessage::Accepted { confirmation, sealed } => (confirmation, sealed),
+        IntroMessage::Rejected { message } => anyhow::bail!("Broker rejected introduction: {}", message),
+        other => anyhow::bail!("Unexpected introduction message: {:?}", other),
+    };
+
+    // Only a broker that knows the code can produce this
+    if !keys.verify_confirmation(&confirmation) {
+        anyhow::bail!("Broker failed key confirmation");
+    }
+
+    let introduction = keys.open(&sealed)?;
+    serde_json::from_slice(&introduction).context("Invalid introduction")
+}
+
+/// Find the broker for `handshake` within the time window and get introduced
+///
+/// Candidates closest to the current time are tried first, over their Unix
+/// socket when the broker runs on this machine and otherwise (unless
+/// `local_only`) over Tor.
+pub async fn discover(
+    handshake: &ClientHandshake,
+    time_window_minutes: i64,
+    client_id: &str,
+    public_key: Option<PublicKey>,
+    local_only: bool,
+) -> Result<IntroductionData> {
+    let now = crate::msgserver::handshake::current_timestamp();
+
+    // Each candidate costs an Argon2id evaluation
+    let deriving = handshake.clone();
+    let mut candidates = tokio::task::spawn_blocking(move || deriving.possible_identifiers(time_window_minutes))
+        .await
+        .context("Failed to derive broker candidates")?;
+    candidates.sort_by_key(|(timestamp, _)| timestamp.abs_diff(now));
+
+    for (timestamp, identifier) in &candidates {
+        let socket_path = broker_socket_path(identifier);
+        if !socket_path.exists() {
+            continue;
+        }
+
+        match UnixStream::connect(&socket_path).await {
+            Ok(stream) => {
+                tracing::info!("Found local broker for timestamp {}", timestamp);
+                return request_introduction(Box::new(stream), handshake, identifier, client_id, public_key).await;
+            }
+            Err(e) => tracing::debug!("Broker socket {:?} not answering: {}", socket_path, e),
+        }
+    }
+
+    if local_only {
+        anyhow::bail!("No local broker found in time window");
+    }
+
+    let tor = TorManager::new(MsgSrvCli::state_dir().join("tor-keys")).await?;
+
+    for (timestamp, identifier) in &candidates {
+        let address = broker_onion_address(identifier);
+
+        let stream = match tokio::time::timeout(
+            ONION_CONNECT_TIMEOUT,
+            tor.connect_to_onion(&address, MESSAGE_PORT),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                tracing::debug!("No broker at {}: {:#}", address, e);
+                continue;
+            }
+            Err(_) => {
+                tracing::debug!("Timed out reaching {}", address);
+                continue;
+            }
+        };
+
+        tracing::info!("Found broker for timestamp {} at {}", timestamp, address);
+        return request_introduction(Box::new(stream), handshake, identifier, client_id, public_key).await;
+    }
+
+    anyhow::bail!("No brokers found in time window")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgserver::e2e::SecretKey;
+    use crate::msgserver::pake::{Pake, Role};
+    use crate::msgserver::storage::{ServerConfig, ServerStatus};
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_introduces_client_with_code() {
+        let dir = tempdir().unwrap();
+        let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+        state_manager
+            .create_server(ServerConfig {
+                id: "fortress-id".to_string(),
+                name: "fortress".to_string(),
+                socket_path: dir.path().join("fortress.sock"),
+                created_at: std::time::SystemTime::now(),
+                ttl_minutes: 5,
+                onion_address: None,
+                status: ServerStatus::Running,
+                hook: None,
+                persistent: false,
+                max_attachment_size: None,
+                max_attachment_storage: None,
+                slow_consumer_policy: Default::default(),
+                client_queue_size: None,
+                limits: Default::default(),
+            })
+            .unwrap();
+
+        // A client the fortress already has a key for
+        let existing = state_manager.create_client("fortress-id").unwrap();
+        let existing_key = SecretKey::generate().public_key();
+        state_manager.set_client_key(&existing.id, &existing_key).unwrap();
+
+        let broker = BrokerHandshake::derive(
+            "test@example.com".to_string(),
+            "ABC-XYZ".to_string(),
+            "fortress".to_string(),
+        )
+        .await;
+        let identifier = broker.identifier();
+        let server = Arc::new(IntroductionServer::new(broker, "fortress-id".to_string(), state_manager.clone()));
+
+        let (client_side, server_side) = tokio::io::duplex(4096);
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move { server.serve(Box::new(server_side)).await }
+        });
+
+        let client = ClientHandshake::new("test@example.com".to_string(), "ABC-XYZ".to_string());
+        let client_key = SecretKey::generate().public_key();
+        let intro = request_introduction(Box::new(client_side), &client, &identifier, "client", Some(client_key))
+            .await
+            .unwrap();
+
+        assert!(serving.await.unwrap().unwrap());
+        assert!(server.is_used());
+        assert_eq!(intro.fortress_address, "fortress");
+        assert_eq!(intro.peer_keys, vec![existing_key]);
+
+        // The broker introduces only one client, even with the right code
+        let (client_side, server_side) = tokio::io::duplex(4096);
+        let serving = tokio::spawn({
+            let server = server.clone();
+            async move { server.serve(Box::new(server_side)).await }
+        });
+        let err = request_introduction(Box::new(client_side), &client, &identifier, "client", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("already used"));
+        assert!(!serving.await.unwrap().unwrap());
+
+        // The token the client received is registered with the fortress
+        let registered = state_manager.get_client_by_token(&intro.access_token).unwrap().unwrap();
+        assert_eq!(registered.server_id, "fortress-id");
+        assert_ne!(registered.code, intro.access_token);
+        let keys: Vec<_> = state_manager.list_client_keys("fortress-id").unwrap();
+        assert!(keys.contains(&(registered.id.clone(), client_key)));
+        assert!(keys.contains(&(existing.id, existing_key)));
+        assert!(state_manager
+            .authenticate_client("fortress-id", &intro.access_token)
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_wrong_code() {
+        let dir = tempdir().unwrap();
+        let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+
+        let broker = BrokerHandshake::derive(
+            "test@example.com".to_string(),
+            "ABC-XYZ".to_string(),
+            "fortress".to_string(),
+        )
+        .await;
+        let identifier = broker.identifier();
+        let server = IntroductionServer::new(broker, "fortress-id".to_string(), state_manager.clone());
+
+        let (client_side, server_side) = tokio::io::duplex(4096);
+        let serving = tokio::spawn(async move { server.serve(Box::new(server_side)).await });
+
+        let wrong = ClientHandshake::new("test@example.com".to_string(), "DEF-123".to_string());
+        let result = request_introduction(Box::new(client_side), &wrong, &identifier, "client", None).await;
+
+        assert!(result.is_err());
+        assert!(!serving.await.unwrap().unwrap());
+        assert!(state_manager.list_clients("fortress-id").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stops_after_too_many_wrong_codes() {
+        let dir = tempdir().unwrap();
+        let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+
+        let broker = BrokerHandshake::derive(
+            "test@example.com".to_string(),
+            "ABC-XYZ".to_string(),
+            "fortress".to_string(),
+        )
+        .await;
+        let identifier = broker.identifier();
+        let server = Arc::new(IntroductionServer::new(broker, "fortress-id".to_string(), state_manager.clone()));
+        let introduce = |code: &str| {
+            let (client_side, server_side) = tokio::io::duplex(4096);
+            let serving = tokio::spawn({
+                let server = server.clone();
+                async move { server.serve(Box::new(server_side)).await }
+            });
+            let client = ClientHandshake::new("test@example.com".to_string(), code.to_string());
+            let identifier = identifier.clone();
+            async move {
+                let result = request_introduction(Box::new(client_side), &client, &identifier, "client", None).await;
+                (result, serving.await.unwrap().unwrap())
+            }
+        };
+
+        for n in 0..MAX_INTRO_FAILURES {
+            assert!(!server.is_exhausted(), "stopped after {} wrong codes", n);
+            let (result, introduced) = introduce("DEF-123").await;
+            assert!(result.unwrap_err().to_string().contains("Invalid code"));
+            assert!(!introduced);
+        }
+        assert!(server.is_exhausted());
+
+        // Now even the right code is refused
+        let (result, introduced) = introduce("ABC-XYZ").await;
+        assert!(result.unwrap_err().to_string().contains("too many wrong codes"));
+        assert!(!introduced);
+        assert!(state_manager.list_clients("fortress-id").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_undelivered_token_is_removed() {
+        let dir = tempdir().unwrap();
+        let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+
+        let broker = BrokerHandshake::derive(
+            "test@example.com".to_string(),
+            "ABC-XYZ".to_string(),
+            "fortress".to_string(),
+        )
+        .await;
+        let identifier = broker.identifier();
+        let server = IntroductionServer::new(broker, "fortress-id".to_string(), state_manager.clone());
+
+        let (client_side, server_side) = tokio::io::duplex(4096);
+        let serving = tokio::spawn(async move { server.serve(Box::new(server_side)).await });
+
+        // Knows the code, but hangs up before the introduction arrives
+        let mut channel = IntroChannel::new(Box::new(client_side));
+        let IntroMessage::Challenge { session_id, share } = channel.recv().await.unwrap() else {
+            panic!("expected a challenge");
+        };
+        let client = ClientHandshake::new("test@example.com".to_string(), "ABC-XYZ".to_string());
+        let pake = client.start_pake(&identifier, &session_id);
+        let request = IntroMessage::Request {
+            client_id: "client".to_string(),
+            share: pake.share(),
+            confirmation: pake.finish(&share).unwrap().confirmation(),
+            public_key: None,
+        };
+        channel.send(&request).await.unwrap();
+        drop(channel);
+
+        assert!(serving.await.unwrap().is_err());
+        assert!(state_manager.list_clients("fortress-id").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_rejects_impostor_broker() {
+        let (client_side, server_side) = tokio::io::duplex(4096);
+
+        // Answers like a broker, but does not know the code
+        let impostor = tokio::spawn(async move {
+            let mut channel = IntroChannel::new(Box::new(server_side));
+            let pake = Pake::start(Role::Broker, "DEF-123", "broker-id", "session");
+            channel
+                .send(&IntroMessage::Challenge {
+                    session_id: "session".to_string(),
+                    share: pake.share(),
+                })
+                .await
+                .unwrap();
+
+            let IntroMessage::Request { share, .. } = channel.recv().await.unwrap() else {
+                panic!("expected a request");
+            };
+            let keys = pake.finish(&share).unwrap();
+            channel
+                .send(&IntroMessage::Accepted {
+                    confirmation: keys.confirmation(),
+                    sealed: keys.seal(b"{}").unwrap(),
+                })
+                .await
+                .unwrap();
+        });
+
+        let client = ClientHandshake::new("test@example.com".to_string(), "ABC-XYZ".to_string());
+        let err = request_introduction(Box::new(client_side), &client, "broker-id", "client", None)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("key confirmation"));
+        impostor.await.unwrap();
+    }
+}
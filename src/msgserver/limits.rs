@@ -0,0 +1,339 @@
+// Per-client sending limits
+//
+// A fortress's queue is shared: it keeps the last `MAX_QUEUE_SIZE` messages
+// from everyone, so one client sending in a loop would push everyone else's
+// messages out of it. Limits cap how fast each client may send, how much of
+// the queue it may hold at once and how large one message may be. What
+// happens to a client that goes over them is the server's `LimitAction`.
+
+use crate::msgserver::message::ErrorCode;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a client is muted for unless configured otherwise
+pub const DEFAULT_MUTE_SECS: u64 = 60;
+
+/// Clients tracked before idle ones are forgotten
+const MAX_TRACKED_CLIENTS: usize = 1024;
+
+/// What to do with a client that goes over a limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LimitAction {
+    /// Refuse the message with an error frame
+    #[default]
+    Error,
+    /// Refuse the message, and every message after it until the mute ends
+    Mute,
+    /// Refuse the message and disconnect the client
+    Disconnect,
+}
+
+impl std::fmt::Display for LimitAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitAction::Error => write!(f, "error"),
+            LimitAction::Mute => write!(f, "mute"),
+            LimitAction::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+/// Limits on what each client of a server may send
+///
+/// Every limit is off unless set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientLimits {
+    /// Messages a client may send per minute, all at once if it has been quiet
+    pub messages_per_minute: Option<u32>,
+    /// Messages a client may have queued at once
+    pub max_queued_messages: Option<usize>,
+    /// Bytes of content and attachments a client may have queued at once
+    pub max_queued_bytes: Option<u64>,
+    /// Largest message content, in bytes
+    pub max_message_size: Option<usize>,
+    /// What to do with a client that goes over a limit
+    pub action: LimitAction,
+    /// How long `LimitAction::Mute` mutes a client for
+    pub mute_secs: u64,
+}
+
+impl Default for ClientLimits {
+    fn default() -> Self {
+        Self {
+            messages_per_minute: None,
+            max_queued_messages: None,
+            max_queued_bytes: None,
+            max_message_size: None,
+            action: LimitAction::default(),
+            mute_secs: DEFAULT_MUTE_SECS,
+        }
+    }
+}
+
+impl ClientLimits {
+    /// Whether any limit is set
+    pub fn is_limited(&self) -> bool {
+        self.messages_per_minute.is_some()
+            || self.has_quota()
+            || self.max_message_size.is_some()
+    }
+
+    /// Whether a client's share of the queue is limited
+    pub fn has_quota(&self) -> bool {
+        self.max_queued_messages.is_some() || self.max_queued_bytes.is_some()
+    }
+}
+
+/// Why a message was refused
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Violation {
+    #[error("Message is too large ({size} bytes; at most {max})")]
+    TooLarge { size: usize, max: usize },
+    #[error("Sending too fast (at most {per_minute} messages a minute)")]
+    RateLimited { per_minute: u32 },
+    #[error("Too many messages queued (at most {max}); wait for some to expire")]
+    TooManyQueued { max: usize },
+    #[error("Queued messages take too much space (at most {max} bytes); wait for some to expire")]
+    QueueQuotaExceeded { max: u64 },
+    #[error("Muted for {secs} more seconds for going over the server's limits")]
+    Muted { secs: u64 },
+}
+
+impl Violation {
+    /// The error code a refusal is reported with
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Violation::TooLarge { .. } => ErrorCode::TooLarge,
+            Violation::RateLimited { .. } | Violation::Muted { .. } => ErrorCode::RateLimited,
+            Violation::TooManyQueued { .. } | Violation::QueueQuotaExceeded { .. } => ErrorCode::QuotaExceeded,
+        }
+    }
+}
+
+/// What a client already has queued
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Queued {
+    pub messages: usize,
+    pub bytes: u64,
+}
+
+/// One client's standing against the limits
+#[derive(Debug)]
+struct Usage {
+    // Messages it may send right now, refilled continuously up to the rate
+    tokens: f64,
+    updated: Instant,
+    muted_until: Option<Instant>,
+}
+
+/// Checks each client's messages against a server's limits
+///
+/// Clients are tracked by whatever identifies them across reconnects, so
+/// reconnecting does not reset a client's rate or end its mute.
+#[derive(Debug)]
+pub struct Limiter {
+    limits: ClientLimits,
+    usage: HashMap<String, Usage>,
+}
+
+impl Limiter {
+    pub fn new(limits: ClientLimits) -> Self {
+        Self {
+            limits,
+            usage: HashMap::new(),
+        }
+    }
+
+    /// The limits being applied
+    pub fn limits(&self) -> &ClientLimits {
+        &self.limits
+    }
+
+    /// Check a message with `size` bytes of content and an `attachment_size`
+    /// byte file from `client`, who already has `queued` in the queue,
+    /// counting it against its rate if it is allowed
+    pub fn check(
+        &mut self,
+        client: &str,
+        size: usize,
+        attachment_size: u64,
+        queued: Queued,
+        now: Instant,
+    ) -> Result<(), Violation> {
+        if self.usage.len() >= MAX_TRACKED_CLIENTS {
+            self.forget_idle(now);
+        }
+
+        let rate = self.limits.messages_per_minute.map(f64::from);
+        let usage = self.usage.entry(client.to_string()).or_insert_with(|| Usage {
+            tokens: rate.unwrap_or_default(),
+            updated: now,
+            muted_until: None,
+        });
+
+        if let Some(until) = usage.muted_until {
+            if until > now {
+                return Err(Violation::Muted {
+                    secs: (until - now).as_secs_f64().ceil() as u64,
+                });
+            }
+            usage.muted_until = None;
+        }
+
+        if let Some(max) = self.limits.max_message_size {
+            if size > max {
+                return Err(Violation::TooLarge { size, max });
+            }
+        }
+        if let Some(max) = self.limits.max_queued_messages {
+            if queued.messages >= max {
+                return Err(Violation::TooManyQueued { max });
+            }
+        }
+        if let Some(max) = self.limits.max_queued_bytes {
+            if queued.bytes + size as u64 + attachment_size > max {
+                return Err(Violation::QueueQuotaExceeded { max });
+            }
+        }
+
+        if let Some(rate) = rate {
+            let elapsed = now.saturating_duration_since(usage.updated).as_secs_f64();
+            usage.tokens = (usage.tokens + elapsed * rate / 60.0).min(rate);
+            usage.updated = now;
+            if usage.tokens < 1.0 {
+                return Err(Violation::RateLimited {
+                    per_minute: rate as u32,
+                });
+            }
+            usage.tokens -= 1.0;
+        }
+
+        Ok(())
+    }
+
+    /// Refuse everything from `client` for the configured mute time
+    pub fn mute(&mut self, client: &str, now: Instant) {
+        let rate = self.limits.messages_per_minute.map(f64::from).unwrap_or_default();
+        let usage = self.usage.entry(client.to_string()).or_insert_with(|| Usage {
+            tokens: rate,
+            updated: now,
+            muted_until: None,
+        });
+        usage.muted_until = Some(now + Duration::from_secs(self.limits.mute_secs));
+    }
+
+    /// Stop tracking clients that are not muted and could send a full burst
+    fn forget_idle(&mut self, now: Instant) {
+        let rate = self.limits.messages_per_minute.map(f64::from).unwrap_or_default();
+        self.usage.retain(|_, usage| {
+            let muted = usage.muted_until.is_some_and(|until| until > now);
+            let refilled = usage.tokens + now.saturating_duration_since(usage.updated).as_secs_f64() * rate / 60.0;
+            muted || refilled < rate
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTHING: Queued = Queued { messages: 0, bytes: 0 };
+
+    #[test]
+    fn test_rate_allows_a_burst_then_refills() {
+        let mut limiter = Limiter::new(ClientLimits {
+            messages_per_minute: Some(3),
+            ..Default::default()
+        });
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            limiter.check("alice", 10, 0, NOTHING, start).unwrap();
+        }
+        assert_eq!(
+            limiter.check("alice", 10, 0, NOTHING, start),
+            Err(Violation::RateLimited { per_minute: 3 })
+        );
+
+        // Other clients have their own allowance
+        limiter.check("bob", 10, 0, NOTHING, start).unwrap();
+
+        // One message's worth comes back every 20 seconds
+        let later = start + Duration::from_secs(20);
+        limiter.check("alice", 10, 0, NOTHING, later).unwrap();
+        assert!(limiter.check("alice", 10, 0, NOTHING, later).is_err());
+    }
+
+    #[test]
+    fn test_size_and_quotas() {
+        let mut limiter = Limiter::new(ClientLimits {
+            max_message_size: Some(100),
+            max_queued_messages: Some(2),
+            max_queued_bytes: Some(150),
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        assert_eq!(
+            limiter.check("alice", 101, 0, NOTHING, now),
+            Err(Violation::TooLarge { size: 101, max: 100 })
+        );
+        limiter.check("alice", 100, 0, Queued { messages: 1, bytes: 50 }, now).unwrap();
+        assert_eq!(
+            limiter.check("alice", 10, 0, Queued { messages: 2, bytes: 20 }, now),
+            Err(Violation::TooManyQueued { max: 2 })
+        );
+        assert_eq!(
+            limiter.check("alice", 60, 0, Queued { messages: 1, bytes: 100 }, now),
+            Err(Violation::QueueQuotaExceeded { max: 150 })
+        );
+        // Files count towards the queue, not the message size
+        assert_eq!(
+            limiter.check("alice", 10, 1000, Queued { messages: 1, bytes: 0 }, now),
+            Err(Violation::QueueQuotaExceeded { max: 150 })
+        );
+
+        // Nothing is limited by default
+        assert!(!Limiter::new(ClientLimits::default()).limits().is_limited());
+    }
+
+    #[test]
+    fn test_mute_refuses_everything_until_it_ends() {
+        let mut limiter = Limiter::new(ClientLimits {
+            action: LimitAction::Mute,
+            mute_secs: 30,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        limiter.mute("alice", now);
+        assert_eq!(
+            limiter.check("alice", 1, 0, NOTHING, now + Duration::from_secs(10)),
+            Err(Violation::Muted { secs: 20 })
+        );
+        limiter.check("bob", 1, 0, NOTHING, now).unwrap();
+        limiter.check("alice", 1, 0, NOTHING, now + Duration::from_secs(30)).unwrap();
+    }
+
+    #[test]
+    fn test_idle_clients_are_forgotten() {
+        let mut limiter = Limiter::new(ClientLimits {
+            messages_per_minute: Some(60),
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        for n in 0..MAX_TRACKED_CLIENTS {
+            limiter.check(&n.to_string(), 1, 0, NOTHING, now).unwrap();
+        }
+        limiter.mute("0", now);
+
+        // A second later everyone but the muted client has a full allowance again
+        limiter.check("new", 1, 0, NOTHING, now + Duration::from_secs(1)).unwrap();
+        assert_eq!(limiter.usage.len(), 2);
+        assert!(limiter.check("0", 1, 0, NOTHING, now + Duration::from_secs(2)).is_err());
+    }
+}
@@ -1,9 +1,16 @@
 // Message types and protocol for the message passing system
 
+use crate::msgserver::attachment::AttachmentInfo;
+use crate::msgserver::e2e::PeerKey;
+use crate::msgserver::identity::{MessageSignature, Origin};
+use crate::msgserver::limits::Queued;
+use crate::msgserver::storage::StateManager;
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
@@ -20,6 +27,99 @@ pub struct Message {
     pub timestamp: SystemTime,
     /// When the message expires
     pub expires_at: SystemTime,
+    /// Alias of the client a direct message was sent to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    /// Channel the message was sent on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    /// Sender's signature over the content and audience
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<MessageSignature>,
+    /// File sent with the message (fetched separately)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<AttachmentInfo>,
+}
+
+/// One recipient's copy of a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    /// Stored client (or, without one, alias) the message was delivered to
+    pub recipient: String,
+    /// Alias the recipient was connected under
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// When the message was first sent to the recipient
+    pub delivered_at: SystemTime,
+    /// When the recipient acknowledged it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acked_at: Option<SystemTime>,
+}
+
+impl Delivery {
+    fn new(recipient: &str, alias: Option<&str>) -> Self {
+        Self {
+            recipient: recipient.to_string(),
+            alias: alias.map(str::to_string),
+            delivered_at: SystemTime::now(),
+            acked_at: None,
+        }
+    }
+}
+
+/// Who a message is for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Audience {
+    /// Every authenticated client
+    #[default]
+    Everyone,
+    /// One client, by alias
+    Client(String),
+    /// Clients subscribed to a channel
+    Channel(String),
+}
+
+impl Audience {
+    /// Audience for a `Send` request's optional recipient and channel
+    pub fn from_parts(to: Option<String>, channel: Option<String>) -> Result<Self> {
+        match (to, channel) {
+            (None, None) => Ok(Audience::Everyone),
+            (Some(to), None) => Ok(Audience::Client(validate_name(to)?)),
+            (None, Some(channel)) => Ok(Audience::Channel(validate_name(channel)?)),
+            (Some(_), Some(_)) => anyhow::bail!("A message goes to a client or a channel, not both"),
+        }
+    }
+}
+
+/// Longest client alias or channel name
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Check a client alias or channel name
+///
+/// Names are 1 to `MAX_NAME_LEN` letters, digits or `.`, `_`, `-`, `@`, `#`.
+pub fn validate_name(name: String) -> Result<String> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "._-@#".contains(c));
+
+    if !valid {
+        anyhow::bail!("Invalid name '{}'", name);
+    }
+
+    Ok(name)
+}
+
+/// Check a client alias
+///
+/// On top of `validate_name`, an alias may not look like a connection ID:
+/// receivers tell an aliased sender from one known only by its ID by shape.
+pub fn validate_alias(alias: String) -> Result<String> {
+    let alias = validate_name(alias)?;
+    if Uuid::parse_str(&alias).is_ok() {
+        anyhow::bail!("Alias '{}' looks like a client ID", alias);
+    }
+
+    Ok(alias)
 }
 
 impl Message {
@@ -34,6 +134,69 @@ impl Message {
             content,
             timestamp: now,
             expires_at: now + ttl,
+            to: None,
+            channel: None,
+            signature: None,
+            attachment: None,
+        }
+    }
+
+    /// Address the message to a client or channel
+    pub fn with_audience(mut self, audience: Audience) -> Self {
+        (self.to, self.channel) = match audience {
+            Audience::Everyone => (None, None),
+            Audience::Client(alias) => (Some(alias), None),
+            Audience::Channel(channel) => (None, Some(channel)),
+        };
+        self
+    }
+
+    /// Attach the sender's signature
+    pub fn with_signature(mut self, signature: Option<MessageSignature>) -> Self {
+        self.signature = signature;
+        self
+    }
+
+    /// Attach a file (already stored) to the message
+    pub fn with_attachment(mut self, attachment: Option<AttachmentInfo>) -> Self {
+        self.attachment = attachment;
+        self
+    }
+
+    /// Check the sender's signature, if the message has one, as sent to the
+    /// server with ID `server`
+    pub fn verify_signature(&self, server: Option<&str>) -> Result<()> {
+        match self.signature {
+            Some(ref signature) => signature.verify(
+                &self.content,
+                &self.audience(),
+                self.attachment.as_ref().map(|a| a.sha256.as_str()),
+                Origin {
+                    sender: self.sender_alias(),
+                    server,
+                },
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Bytes the message takes in the queue: its content and attachment
+    pub fn queued_bytes(&self) -> u64 {
+        self.content.len() as u64 + self.attachment.as_ref().map_or(0, |a| a.size)
+    }
+
+    /// Alias the message was sent under; None for a sender known only by
+    /// its connection ID (`validate_alias` keeps the two apart)
+    pub fn sender_alias(&self) -> Option<&str> {
+        Uuid::parse_str(&self.from).is_err().then_some(self.from.as_str())
+    }
+
+    /// Who the message is for
+    pub fn audience(&self) -> Audience {
+        match (&self.to, &self.channel) {
+            (Some(to), _) => Audience::Client(to.clone()),
+            (None, Some(channel)) => Audience::Channel(channel.clone()),
+            (None, None) => Audience::Everyone,
         }
     }
 
@@ -55,10 +218,21 @@ impl Message {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ProtocolMessage {
+    /// Opening frame with the protocol version and capabilities a side supports
+    ///
+    /// A client that sends it first gets one back with the version and
+    /// capabilities both sides share (see `framing`).
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
     /// Client authentication request
     Auth {
         code: String,
         client_id: String,
+        /// Name other clients can send direct messages to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        alias: Option<String>,
     },
     /// Authentication response
     AuthResponse {
@@ -66,34 +240,276 @@ pub enum ProtocolMessage {
         message: String,
         server_id: Option<String>,
     },
-    /// Client sending a message
+    /// Client sending a message, to everyone unless `to` or `channel` is set
     Send {
         content: String,
+        /// Alias of the client to send a direct message to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<String>,
+        /// Channel to send the message on
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+        /// Sender's signature over the content and audience
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        signature: Option<MessageSignature>,
+        /// ID of a file uploaded on this connection to send with the message
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        attachment: Option<String>,
+    },
+    /// Server acknowledging a sent message
+    SendAck {
+        message_id: String,
     },
     /// Server broadcasting a message to clients
     Broadcast {
         message: Message,
     },
+    /// Client confirming it received a message
+    Ack {
+        message_id: String,
+    },
+    /// Server taking in an acknowledgement (only for acks with a request ID)
+    Acked {
+        message_id: String,
+    },
+    /// Request for who a message was delivered to (only for its sender)
+    DeliveryStatus {
+        message_id: String,
+    },
+    /// Response with a message's deliveries
+    DeliveryStatusResponse {
+        message_id: String,
+        deliveries: Vec<Delivery>,
+    },
     /// Request to receive pending messages
     Receive {
         since: Option<SystemTime>,
+        /// Only messages queued after this message ID (to resume a stream)
+        #[serde(default)]
+        after: Option<String>,
+        /// Only messages on this channel
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        channel: Option<String>,
+    },
+    /// Start receiving a channel's messages as they arrive
+    Subscribe {
+        channel: String,
+    },
+    /// Stop receiving a channel's messages
+    Unsubscribe {
+        channel: String,
+    },
+    /// Server confirming a subscription
+    Subscribed {
+        channel: String,
+    },
+    /// Server confirming an unsubscription
+    Unsubscribed {
+        channel: String,
     },
     /// Response with messages
     ReceiveResponse {
         messages: Vec<Message>,
     },
+    /// Client announcing a file it is about to upload
+    AttachmentStart {
+        name: String,
+        size: u64,
+        /// Hex encoded SHA-256 of the whole file
+        sha256: String,
+    },
+    /// Server accepting an upload, under the ID chunks are sent with
+    AttachmentAccepted {
+        attachment_id: String,
+    },
+    /// Part of a file, in either direction
+    AttachmentChunk {
+        attachment_id: String,
+        /// Position of the chunk, from 0
+        index: u32,
+        /// Base64 encoded bytes
+        data: String,
+        /// Hex encoded SHA-256 of the bytes
+        sha256: String,
+    },
+    /// Server taking in an uploaded chunk (only for chunks with a request ID)
+    AttachmentChunkReceived {
+        attachment_id: String,
+        index: u32,
+    },
+    /// All of a file's chunks have been sent
+    AttachmentEnd {
+        attachment_id: String,
+    },
+    /// Server confirming an uploaded file arrived intact
+    AttachmentStored {
+        attachment: AttachmentInfo,
+    },
+    /// Request for a file attached to a message
+    FetchAttachment {
+        attachment_id: String,
+    },
+    /// Server about to send a requested file's chunks
+    AttachmentOffer {
+        attachment: AttachmentInfo,
+    },
+    /// Request for the encryption keys of the server's clients
+    Keys,
+    /// Response with encryption keys
+    KeysResponse {
+        keys: Vec<PeerKey>,
+    },
     /// Ping to keep connection alive
     Ping,
     /// Pong response
     Pong,
     /// Error message
     Error {
+        /// What went wrong; `unknown` from servers that predate codes
+        #[serde(default)]
+        code: ErrorCode,
         message: String,
+        /// Broadcasts dropped for reading too slowly, on a `slow_consumer`
+        /// error that does not end the connection
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        gap: Option<Gap>,
     },
 }
 
+/// What kind of error an `Error` frame reports
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame could not be parsed, or a field in it is invalid
+    InvalidRequest,
+    /// The request is not one a client may make now (e.g. `Send` before `Auth`)
+    UnexpectedMessage,
+    /// The request needs an authenticated connection
+    NotAuthenticated,
+    /// Too many failed authentication attempts
+    AuthFailed,
+    /// The client's access was revoked or has expired
+    AccessRevoked,
+    /// The message or attachment asked about does not exist (any more)
+    NotFound,
+    /// The client may not see what it asked for
+    Forbidden,
+    /// The client is sending too fast, or was muted for it
+    RateLimited,
+    /// The message or file is larger than the server accepts
+    TooLarge,
+    /// The client has as much queued as the server allows
+    QuotaExceeded,
+    /// The client did not read its messages fast enough
+    SlowConsumer,
+    /// The frame broke the wire protocol (e.g. it was too large)
+    Protocol,
+    /// The server failed to handle the request
+    Internal,
+    /// A code this side does not know
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::UnexpectedMessage => "unexpected_message",
+            ErrorCode::NotAuthenticated => "not_authenticated",
+            ErrorCode::AuthFailed => "auth_failed",
+            ErrorCode::AccessRevoked => "access_revoked",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::TooLarge => "too_large",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::SlowConsumer => "slow_consumer",
+            ErrorCode::Protocol => "protocol",
+            ErrorCode::Internal => "internal",
+            ErrorCode::Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Broadcasts a slow client was not sent, which a `Receive` from `since` gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Gap {
+    /// How many were dropped
+    pub dropped: u64,
+    /// When the earliest of them was sent
+    pub since: SystemTime,
+}
+
+impl Gap {
+    /// Count one more dropped broadcast, sent at `timestamp`
+    pub fn add(&mut self, timestamp: SystemTime) {
+        self.dropped += 1;
+        self.since = self.since.min(timestamp);
+    }
+}
+
+/// A protocol message with the ID of the request it makes or answers
+///
+/// Clients may give each request an ID; every reply to it, including an
+/// `Error`, carries the same ID. Frames the server sends unasked, such as
+/// broadcasts, have none. On the wire the ID is just another field of the
+/// message, so peers that do not know about IDs can ignore it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: ProtocolMessage,
+}
+
+impl Envelope {
+    /// `message`, as a reply to request `request_id`
+    pub fn reply(request_id: Option<String>, message: ProtocolMessage) -> Self {
+        Self { request_id, message }
+    }
+
+    /// Deserialize from JSON bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(bytes)
+    }
+}
+
+impl From<ProtocolMessage> for Envelope {
+    fn from(message: ProtocolMessage) -> Self {
+        Self {
+            request_id: None,
+            message,
+        }
+    }
+}
+
 impl ProtocolMessage {
-    /// Serialize to JSON bytes
+    /// An error frame
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ProtocolMessage::Error {
+            code,
+            message: message.into(),
+            gap: None,
+        }
+    }
+
+    /// A `slow_consumer` error telling a client which broadcasts it missed
+    pub fn gap(gap: Gap) -> Self {
+        let since = gap.since.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        ProtocolMessage::Error {
+            code: ErrorCode::SlowConsumer,
+            message: format!(
+                "{} broadcasts dropped while not reading; receive --since {} to get them",
+                gap.dropped, since
+            ),
+            gap: Some(gap),
+        }
+    }
+
+    /// Serialize to a line of JSON
     pub fn to_bytes(&self) -> Result<Vec<u8>, serde_json::Error> {
         let mut bytes = serde_json::to_vec(self)?;
         bytes.push(b'\n'); // Add newline delimiter
@@ -106,9 +522,132 @@ impl ProtocolMessage {
     }
 }
 
+/// Where a server keeps its queued messages
+///
+/// `MessageQueue` keeps them in memory; `PersistentMessageQueue` keeps them
+/// in the state database so they survive a restart. Brokers only use this
+/// trait, so either can back a server.
+pub trait MessageStore: Send + Sync {
+    /// Queue a new message, dropping the oldest if the queue is full
+    ///
+    /// `sender` is who it counts against in `queued_by`: unlike `from`,
+    /// something a client cannot change by taking another alias.
+    fn push(
+        &self,
+        from: String,
+        content: String,
+        audience: Audience,
+        signature: Option<MessageSignature>,
+        attachment: Option<AttachmentInfo>,
+        sender: Option<String>,
+    ) -> BoxFuture<'_, Result<Message>>;
+
+    /// Unexpired messages pushed by `sender`, and their bytes of content
+    /// and attachments
+    fn queued_by<'a>(&'a self, sender: &'a str) -> BoxFuture<'a, Result<Queued>>;
+
+    /// Unexpired messages since a time, and after a given message ID
+    ///
+    /// If `after` is no longer queued (e.g. it expired), all messages
+    /// matching `since` are returned.
+    fn get_after<'a>(
+        &'a self,
+        since: Option<SystemTime>,
+        after: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Vec<Message>>>;
+
+    /// An unexpired message by ID
+    fn get<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Message>>>;
+
+    /// The unexpired message a file is attached to
+    fn get_by_attachment<'a>(&'a self, attachment_id: &'a str) -> BoxFuture<'a, Result<Option<Message>>>;
+
+    /// Record that a message was sent to a recipient
+    ///
+    /// Sending it again (e.g. redelivering it) keeps the first record.
+    fn record_delivery<'a>(
+        &'a self,
+        message_id: &'a str,
+        recipient: &'a str,
+        alias: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// Record a recipient's acknowledgement, moving its cursor up to the message
+    ///
+    /// Returns false if the message is not queued (any more).
+    fn ack<'a>(
+        &'a self,
+        message_id: &'a str,
+        recipient: &'a str,
+        alias: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<bool>>;
+
+    /// Who a message was delivered to, and which of them acknowledged it
+    fn deliveries<'a>(&'a self, message_id: &'a str) -> BoxFuture<'a, Result<Vec<Delivery>>>;
+
+    /// Messages a recipient has not acknowledged, oldest first
+    ///
+    /// These are the ones delivered to it without an acknowledgement, and,
+    /// once it has acknowledged any, every message queued after its cursor.
+    fn unacked<'a>(&'a self, recipient: &'a str) -> BoxFuture<'a, Result<Vec<Message>>>;
+
+    /// Number of unexpired messages
+    fn len(&self) -> BoxFuture<'_, Result<usize>>;
+
+    /// Whether there are no unexpired messages
+    fn is_empty(&self) -> BoxFuture<'_, Result<bool>> {
+        Box::pin(async move { Ok(self.len().await? == 0) })
+    }
+
+    /// Keep an attached file until `expires_at` (its message's expiry)
+    fn store_attachment<'a>(
+        &'a self,
+        info: &'a AttachmentInfo,
+        data: Vec<u8>,
+        expires_at: SystemTime,
+    ) -> BoxFuture<'a, Result<()>>;
+
+    /// An unexpired attached file's contents
+    fn attachment<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>>;
+
+    /// Bytes of attached files kept, until they are swept
+    fn attachment_bytes(&self) -> BoxFuture<'_, Result<u64>>;
+
+    /// Drop expired messages and attachments, returning how many messages there were
+    fn remove_expired(&self) -> BoxFuture<'_, Result<usize>>;
+
+    /// Drop all messages and attachments
+    fn clear(&self) -> BoxFuture<'_, Result<()>>;
+}
+
+/// Periodically drop expired messages from a store
+pub fn start_expiry_sweep(store: Arc<dyn MessageStore>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut interval_timer = tokio::time::interval(interval);
+
+        loop {
+            interval_timer.tick().await;
+
+            match store.remove_expired().await {
+                Ok(0) => {}
+                Ok(removed) => tracing::debug!("Cleaned up {} expired messages", removed),
+                Err(e) => tracing::error!("Failed to clean up expired messages: {}", e),
+            }
+        }
+    });
+}
+
 /// In-memory message queue with expiration
 pub struct MessageQueue {
     messages: Arc<RwLock<VecDeque<Message>>>,
+    // Deliveries by message ID, dropped along with their messages
+    deliveries: RwLock<HashMap<String, Vec<Delivery>>>,
+    // Last message each recipient acknowledged, by recipient
+    cursors: RwLock<HashMap<String, String>>,
+    // Attached files and when they expire, by attachment ID
+    attachments: RwLock<HashMap<String, (Vec<u8>, SystemTime)>>,
+    // Who pushed each message, for those pushed with a sender
+    senders: RwLock<HashMap<String, String>>,
     ttl: Duration,
     max_size: usize,
 }
@@ -118,14 +657,40 @@ impl MessageQueue {
     pub fn new(ttl: Duration, max_size: usize) -> Self {
         Self {
             messages: Arc::new(RwLock::new(VecDeque::new())),
+            deliveries: RwLock::new(HashMap::new()),
+            cursors: RwLock::new(HashMap::new()),
+            attachments: RwLock::new(HashMap::new()),
+            senders: RwLock::new(HashMap::new()),
             ttl,
             max_size,
         }
     }
 
-    /// Add a message to the queue
+    /// Add a message for everyone to the queue
     pub async fn push(&self, from: String, content: String) -> Message {
-        let message = Message::new(from, content, self.ttl);
+        self.push_to(from, content, Audience::Everyone).await
+    }
+
+    /// Add a message for a client or channel to the queue
+    pub async fn push_to(&self, from: String, content: String, audience: Audience) -> Message {
+        self.push_message(from, content, audience, None, None, None).await
+    }
+
+    /// Add a message, with its sender's signature and attachment, to the
+    /// queue, counting it against `sender` if given
+    pub async fn push_message(
+        &self,
+        from: String,
+        content: String,
+        audience: Audience,
+        signature: Option<MessageSignature>,
+        attachment: Option<AttachmentInfo>,
+        sender: Option<String>,
+    ) -> Message {
+        let message = Message::new(from, content, self.ttl)
+            .with_audience(audience)
+            .with_signature(signature)
+            .with_attachment(attachment);
 
         let mut queue = self.messages.write().await;
 
@@ -138,9 +703,38 @@ impl MessageQueue {
         }
 
         queue.push_back(message.clone());
+        if let Some(sender) = sender {
+            self.senders.write().await.insert(message.id.clone(), sender);
+        }
+        self.forget_dropped(&queue).await;
         message
     }
 
+    /// Drop the deliveries, attachments and senders of messages no longer queued
+    async fn forget_dropped(&self, queue: &VecDeque<Message>) {
+        let mut senders = self.senders.write().await;
+        if !senders.is_empty() {
+            let queued: HashSet<&str> = queue.iter().map(|m| m.id.as_str()).collect();
+            senders.retain(|id, _| queued.contains(id.as_str()));
+        }
+
+        let mut deliveries = self.deliveries.write().await;
+        if !deliveries.is_empty() {
+            let queued: HashSet<&str> = queue.iter().map(|m| m.id.as_str()).collect();
+            deliveries.retain(|id, _| queued.contains(id.as_str()));
+        }
+
+        let mut attachments = self.attachments.write().await;
+        if !attachments.is_empty() {
+            let attached: HashSet<&str> = queue
+                .iter()
+                .filter_map(|m| m.attachment.as_ref())
+                .map(|a| a.id.as_str())
+                .collect();
+            attachments.retain(|id, _| attached.contains(id.as_str()));
+        }
+    }
+
     /// Get all non-expired messages
     pub async fn get_all(&self) -> Vec<Message> {
         let mut queue = self.messages.write().await;
@@ -165,6 +759,28 @@ impl MessageQueue {
             .collect()
     }
 
+    /// Get messages since a time, and after a given message ID
+    ///
+    /// If `after` is no longer queued (e.g. it expired), all messages
+    /// matching `since` are returned.
+    pub async fn get_after(&self, since: Option<SystemTime>, after: Option<&str>) -> Vec<Message> {
+        let mut queue = self.messages.write().await;
+
+        // Remove expired messages
+        queue.retain(|m| !m.is_expired());
+
+        let start = after
+            .and_then(|id| queue.iter().position(|m| m.id == id))
+            .map_or(0, |pos| pos + 1);
+
+        queue
+            .iter()
+            .skip(start)
+            .filter(|m| since.is_none_or(|since| m.timestamp >= since))
+            .cloned()
+            .collect()
+    }
+
     /// Get the number of active messages
     pub async fn len(&self) -> usize {
         let mut queue = self.messages.write().await;
@@ -175,40 +791,399 @@ impl MessageQueue {
         queue.len()
     }
 
+    /// Check whether there are no active messages
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
     /// Clear all messages
     pub async fn clear(&self) {
         let mut queue = self.messages.write().await;
         queue.clear();
+        self.deliveries.write().await.clear();
+        self.attachments.write().await.clear();
+        self.senders.write().await.clear();
     }
 
     /// Start background task to clean up expired messages
     pub fn start_cleanup_task(self: Arc<Self>, interval: Duration) {
-        tokio::spawn(async move {
-            let mut interval_timer = tokio::time::interval(interval);
-
-            loop {
-                interval_timer.tick().await;
-
-                let mut queue = self.messages.write().await;
-                let before = queue.len();
-                queue.retain(|m| !m.is_expired());
-                let after = queue.len();
-
-                if before != after {
-                    tracing::debug!(
-                        "Cleaned up {} expired messages ({} remaining)",
-                        before - after,
-                        after
-                    );
+        start_expiry_sweep(self, interval);
+    }
+}
+
+impl MessageStore for MessageQueue {
+    fn push(
+        &self,
+        from: String,
+        content: String,
+        audience: Audience,
+        signature: Option<MessageSignature>,
+        attachment: Option<AttachmentInfo>,
+        sender: Option<String>,
+    ) -> BoxFuture<'_, Result<Message>> {
+        Box::pin(async move { Ok(self.push_message(from, content, audience, signature, attachment, sender).await) })
+    }
+
+    fn queued_by<'a>(&'a self, sender: &'a str) -> BoxFuture<'a, Result<Queued>> {
+        Box::pin(async move {
+            let queue = self.messages.read().await;
+            let senders = self.senders.read().await;
+
+            let mut queued = Queued::default();
+            for message in queue.iter().filter(|m| !m.is_expired()) {
+                if senders.get(&message.id).is_some_and(|s| s == sender) {
+                    queued.messages += 1;
+                    queued.bytes += message.queued_bytes();
                 }
             }
-        });
+            Ok(queued)
+        })
+    }
+
+    fn get_after<'a>(
+        &'a self,
+        since: Option<SystemTime>,
+        after: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Vec<Message>>> {
+        Box::pin(async move { Ok(MessageQueue::get_after(self, since, after).await) })
+    }
+
+    fn get<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Message>>> {
+        Box::pin(async move {
+            let queue = self.messages.read().await;
+            Ok(queue.iter().find(|m| m.id == id && !m.is_expired()).cloned())
+        })
+    }
+
+    fn get_by_attachment<'a>(&'a self, attachment_id: &'a str) -> BoxFuture<'a, Result<Option<Message>>> {
+        Box::pin(async move {
+            let queue = self.messages.read().await;
+            Ok(queue
+                .iter()
+                .find(|m| m.attachment.as_ref().is_some_and(|a| a.id == attachment_id) && !m.is_expired())
+                .cloned())
+        })
+    }
+
+    fn record_delivery<'a>(
+        &'a self,
+        message_id: &'a str,
+        recipient: &'a str,
+        alias: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut deliveries = self.deliveries.write().await;
+            let records = deliveries.entry(message_id.to_string()).or_default();
+            if !records.iter().any(|d| d.recipient == recipient) {
+                records.push(Delivery::new(recipient, alias));
+            }
+            Ok(())
+        })
+    }
+
+    fn ack<'a>(
+        &'a self,
+        message_id: &'a str,
+        recipient: &'a str,
+        alias: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let queue = self.messages.read().await;
+            let Some(position) = queue.iter().position(|m| m.id == message_id) else {
+                return Ok(false);
+            };
+
+            let mut deliveries = self.deliveries.write().await;
+            let records = deliveries.entry(message_id.to_string()).or_default();
+            let record = match records.iter().position(|d| d.recipient == recipient) {
+                Some(index) => &mut records[index],
+                None => {
+                    records.push(Delivery::new(recipient, alias));
+                    records.last_mut().expect("just pushed")
+                }
+            };
+            record.acked_at.get_or_insert_with(SystemTime::now);
+
+            // Cursors only move forward; one whose message is gone is replaced
+            let mut cursors = self.cursors.write().await;
+            let behind = cursors
+                .get(recipient)
+                .and_then(|cursor| queue.iter().position(|m| &m.id == cursor))
+                .is_none_or(|cursor| cursor < position);
+            if behind {
+                cursors.insert(recipient.to_string(), message_id.to_string());
+            }
+
+            Ok(true)
+        })
+    }
+
+    fn deliveries<'a>(&'a self, message_id: &'a str) -> BoxFuture<'a, Result<Vec<Delivery>>> {
+        Box::pin(async move {
+            let deliveries = self.deliveries.read().await;
+            Ok(deliveries.get(message_id).cloned().unwrap_or_default())
+        })
+    }
+
+    fn unacked<'a>(&'a self, recipient: &'a str) -> BoxFuture<'a, Result<Vec<Message>>> {
+        Box::pin(async move {
+            let queue = self.messages.read().await;
+            let deliveries = self.deliveries.read().await;
+            let cursor = self.cursors.read().await.get(recipient).cloned();
+
+            // Without a cursor (or once its message is gone) nothing is known
+            // to have been missed; otherwise everything after it was
+            let after = cursor.and_then(|cursor| queue.iter().position(|m| m.id == cursor));
+
+            Ok(queue
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| !m.is_expired())
+                .filter(|(position, m)| {
+                    let record = deliveries
+                        .get(&m.id)
+                        .and_then(|records| records.iter().find(|d| d.recipient == recipient));
+                    match record {
+                        Some(delivery) => delivery.acked_at.is_none(),
+                        None => after.is_some_and(|after| *position > after),
+                    }
+                })
+                .map(|(_, m)| m.clone())
+                .collect())
+        })
+    }
+
+    fn len(&self) -> BoxFuture<'_, Result<usize>> {
+        Box::pin(async move { Ok(MessageQueue::len(self).await) })
+    }
+
+    fn store_attachment<'a>(
+        &'a self,
+        info: &'a AttachmentInfo,
+        data: Vec<u8>,
+        expires_at: SystemTime,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut attachments = self.attachments.write().await;
+            attachments.insert(info.id.clone(), (data, expires_at));
+            Ok(())
+        })
+    }
+
+    fn attachment<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            let attachments = self.attachments.read().await;
+            Ok(attachments
+                .get(id)
+                .filter(|(_, expires_at)| SystemTime::now() < *expires_at)
+                .map(|(data, _)| data.clone()))
+        })
+    }
+
+    fn attachment_bytes(&self) -> BoxFuture<'_, Result<u64>> {
+        Box::pin(async move {
+            let attachments = self.attachments.read().await;
+            Ok(attachments.values().map(|(data, _)| data.len() as u64).sum())
+        })
+    }
+
+    fn remove_expired(&self) -> BoxFuture<'_, Result<usize>> {
+        Box::pin(async move {
+            let mut queue = self.messages.write().await;
+            let before = queue.len();
+            queue.retain(|m| !m.is_expired());
+            self.forget_dropped(&queue).await;
+
+            Ok(before - queue.len())
+        })
+    }
+
+    fn clear(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            MessageQueue::clear(self).await;
+            Ok(())
+        })
+    }
+}
+
+/// Message queue kept in the state database
+///
+/// Messages outlive the server process: a server restarted with the same ID
+/// picks up every message that has not expired yet.
+pub struct PersistentMessageQueue {
+    state_manager: Arc<StateManager>,
+    server_id: String,
+    ttl: Duration,
+    max_size: usize,
+}
+
+impl PersistentMessageQueue {
+    /// Open the queue for a server, dropping messages that expired meanwhile
+    pub fn new(
+        state_manager: Arc<StateManager>,
+        server_id: String,
+        ttl: Duration,
+        max_size: usize,
+    ) -> Result<Self> {
+        let expired = state_manager.delete_expired_messages(Some(&server_id))?;
+        let replayed = state_manager.count_messages(&server_id)?;
+        tracing::info!(
+            "Restored {} queued messages for server {} ({} expired)",
+            replayed,
+            server_id,
+            expired
+        );
+
+        Ok(Self {
+            state_manager,
+            server_id,
+            ttl,
+            max_size,
+        })
+    }
+}
+
+impl PersistentMessageQueue {
+    /// Run a store operation on the blocking pool, off the broker task
+    async fn blocking<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&StateManager, &str) -> Result<T> + Send + 'static,
+    {
+        let state_manager = self.state_manager.clone();
+        let server_id = self.server_id.clone();
+        tokio::task::spawn_blocking(move || op(&state_manager, &server_id))
+            .await
+            .context("Message store task failed")?
+    }
+}
+
+impl MessageStore for PersistentMessageQueue {
+    fn push(
+        &self,
+        from: String,
+        content: String,
+        audience: Audience,
+        signature: Option<MessageSignature>,
+        attachment: Option<AttachmentInfo>,
+        sender: Option<String>,
+    ) -> BoxFuture<'_, Result<Message>> {
+        Box::pin(async move {
+            let message = Message::new(from, content, self.ttl)
+                .with_audience(audience)
+                .with_signature(signature)
+                .with_attachment(attachment);
+
+            let max_size = self.max_size;
+            self.blocking(move |state_manager, server_id| {
+                state_manager.store_message(server_id, &message, sender.as_deref())?;
+                state_manager.trim_messages(server_id, max_size)?;
+                Ok(message)
+            })
+            .await
+        })
+    }
+
+    fn get_after<'a>(
+        &'a self,
+        since: Option<SystemTime>,
+        after: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Vec<Message>>> {
+        let after = after.map(str::to_string);
+        Box::pin(self.blocking(move |state_manager, server_id| {
+            state_manager.list_messages(server_id, since, after.as_deref())
+        }))
+    }
+
+    fn queued_by<'a>(&'a self, sender: &'a str) -> BoxFuture<'a, Result<Queued>> {
+        let sender = sender.to_string();
+        Box::pin(self.blocking(move |state_manager, server_id| state_manager.queued_by(server_id, &sender)))
+    }
+
+    fn get<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Message>>> {
+        let id = id.to_string();
+        Box::pin(self.blocking(move |state_manager, server_id| state_manager.get_message(server_id, &id)))
+    }
+
+    fn get_by_attachment<'a>(&'a self, attachment_id: &'a str) -> BoxFuture<'a, Result<Option<Message>>> {
+        let attachment_id = attachment_id.to_string();
+        Box::pin(self.blocking(move |state_manager, server_id| {
+            state_manager.get_message_by_attachment(server_id, &attachment_id)
+        }))
+    }
+
+    fn record_delivery<'a>(
+        &'a self,
+        message_id: &'a str,
+        recipient: &'a str,
+        alias: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<()>> {
+        let (message_id, recipient, alias) = (message_id.to_string(), recipient.to_string(), alias.map(str::to_string));
+        Box::pin(self.blocking(move |state_manager, server_id| {
+            state_manager.record_delivery(server_id, &message_id, &recipient, alias.as_deref())
+        }))
+    }
+
+    fn ack<'a>(
+        &'a self,
+        message_id: &'a str,
+        recipient: &'a str,
+        alias: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<bool>> {
+        let (message_id, recipient, alias) = (message_id.to_string(), recipient.to_string(), alias.map(str::to_string));
+        Box::pin(self.blocking(move |state_manager, server_id| {
+            state_manager.ack_message(server_id, &message_id, &recipient, alias.as_deref())
+        }))
+    }
+
+    fn deliveries<'a>(&'a self, message_id: &'a str) -> BoxFuture<'a, Result<Vec<Delivery>>> {
+        let message_id = message_id.to_string();
+        Box::pin(self.blocking(move |state_manager, _| state_manager.list_deliveries(&message_id)))
+    }
+
+    fn unacked<'a>(&'a self, recipient: &'a str) -> BoxFuture<'a, Result<Vec<Message>>> {
+        let recipient = recipient.to_string();
+        Box::pin(self.blocking(move |state_manager, server_id| state_manager.unacked_messages(server_id, &recipient)))
+    }
+
+    fn len(&self) -> BoxFuture<'_, Result<usize>> {
+        Box::pin(self.blocking(|state_manager, server_id| state_manager.count_messages(server_id)))
+    }
+
+    fn store_attachment<'a>(
+        &'a self,
+        info: &'a AttachmentInfo,
+        data: Vec<u8>,
+        expires_at: SystemTime,
+    ) -> BoxFuture<'a, Result<()>> {
+        let id = info.id.clone();
+        Box::pin(self.blocking(move |state_manager, server_id| {
+            state_manager.store_attachment(server_id, &id, &data, expires_at)
+        }))
+    }
+
+    fn attachment<'a>(&'a self, id: &'a str) -> BoxFuture<'a, Result<Option<Vec<u8>>>> {
+        let id = id.to_string();
+        Box::pin(self.blocking(move |state_manager, server_id| state_manager.get_attachment(server_id, &id)))
+    }
+
+    fn attachment_bytes(&self) -> BoxFuture<'_, Result<u64>> {
+        Box::pin(self.blocking(|state_manager, server_id| state_manager.attachment_bytes(server_id)))
+    }
+
+    fn remove_expired(&self) -> BoxFuture<'_, Result<usize>> {
+        Box::pin(self.blocking(|state_manager, server_id| state_manager.delete_expired_messages(Some(server_id))))
+    }
+
+    fn clear(&self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.blocking(|state_manager, server_id| state_manager.clear_messages(server_id)))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::msgserver::identity::Identity;
     use std::time::Duration;
 
     #[tokio::test]
@@ -252,4 +1227,238 @@ mod tests {
         assert_eq!(messages[0].content, "msg2"); // msg1 was dropped
         assert_eq!(messages[1].content, "msg3");
     }
+
+    #[tokio::test]
+    async fn test_message_queue_get_after() {
+        let queue = MessageQueue::new(Duration::from_secs(60), 10);
+
+        let first = queue.push("client1".to_string(), "msg1".to_string()).await;
+        queue.push("client1".to_string(), "msg2".to_string()).await;
+        queue.push("client1".to_string(), "msg3".to_string()).await;
+
+        let messages = queue.get_after(None, Some(&first.id)).await;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "msg2");
+
+        // Unknown IDs fall back to everything
+        let messages = queue.get_after(None, Some("expired")).await;
+        assert_eq!(messages.len(), 3);
+    }
+
+    /// The same delivery tracking, whichever store keeps the messages
+    async fn check_delivery_tracking(store: &dyn MessageStore) {
+        let push = |content: &str| {
+            let sender = Some("alice-id".to_string());
+            store.push("alice".to_string(), content.to_string(), Audience::Everyone, None, None, sender)
+        };
+        let first = push("msg1").await.unwrap();
+        let second = push("msg2").await.unwrap();
+
+        // Messages count against who sent them, whatever alias they used
+        let queued = store.queued_by("alice-id").await.unwrap();
+        assert_eq!((queued.messages, queued.bytes), (2, 8));
+        assert_eq!(store.queued_by("alice").await.unwrap(), Queued::default());
+
+        // Delivered but unacknowledged messages are owed; recording twice keeps the first
+        store.record_delivery(&first.id, "bob-id", Some("bob")).await.unwrap();
+        let delivered_at = store.deliveries(&first.id).await.unwrap()[0].delivered_at;
+        store.record_delivery(&first.id, "bob-id", Some("bob")).await.unwrap();
+        let deliveries = store.deliveries(&first.id).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].delivered_at, delivered_at);
+        assert_eq!(deliveries[0].alias.as_deref(), Some("bob"));
+        assert!(deliveries[0].acked_at.is_none());
+        let contents = |messages: Vec<Message>| messages.into_iter().map(|m| m.content).collect::<Vec<_>>();
+        assert_eq!(contents(store.unacked("bob-id").await.unwrap()), ["msg1"]);
+
+        // Once acknowledged, everything after the cursor is owed too
+        assert!(store.ack(&first.id, "bob-id", Some("bob")).await.unwrap());
+        assert!(store.deliveries(&first.id).await.unwrap()[0].acked_at.is_some());
+        let third = push("msg3").await.unwrap();
+        assert_eq!(contents(store.unacked("bob-id").await.unwrap()), ["msg2", "msg3"]);
+
+        // Acknowledging out of order never moves the cursor back
+        assert!(store.ack(&third.id, "bob-id", None).await.unwrap());
+        assert!(store.ack(&second.id, "bob-id", None).await.unwrap());
+        push("msg4").await.unwrap();
+        assert_eq!(contents(store.unacked("bob-id").await.unwrap()), ["msg4"]);
+
+        // Recipients without a cursor are only owed what was delivered to them
+        assert!(store.unacked("carol-id").await.unwrap().is_empty());
+        assert!(!store.ack("unknown", "bob-id", None).await.unwrap());
+        assert_eq!(store.get(&second.id).await.unwrap().unwrap().content, "msg2");
+        assert!(store.get("unknown").await.unwrap().is_none());
+
+        // Deliveries go with their messages
+        store.clear().await.unwrap();
+        assert!(store.deliveries(&first.id).await.unwrap().is_empty());
+        assert!(store.unacked("bob-id").await.unwrap().is_empty());
+        assert_eq!(store.queued_by("alice-id").await.unwrap(), Queued::default());
+    }
+
+    /// The same attachment lifetime, whichever store keeps the messages
+    async fn check_attachments_go_with_messages(store: &dyn MessageStore) {
+        let push = |content: &str, id: &str| {
+            let info = AttachmentInfo {
+                id: id.to_string(),
+                name: format!("{}.bin", id),
+                size: 3,
+                sha256: crate::msgserver::attachment::sha256_hex(b"abc"),
+            };
+            let content = content.to_string();
+            async move {
+                let message = store
+                    .push("alice".to_string(), content, Audience::Everyone, None, Some(info.clone()), None)
+                    .await
+                    .unwrap();
+                store.store_attachment(&info, b"abc".to_vec(), message.expires_at).await.unwrap();
+                message
+            }
+        };
+
+        let first = push("msg1", "file1").await;
+        assert_eq!(first.attachment.as_ref().unwrap().id, "file1");
+        assert_eq!(store.attachment("file1").await.unwrap().as_deref(), Some(&b"abc"[..]));
+        assert_eq!(store.attachment_bytes().await.unwrap(), 3);
+        assert!(store.attachment("unknown").await.unwrap().is_none());
+        assert_eq!(store.get_by_attachment("file1").await.unwrap().map(|m| m.id), Some(first.id.clone()));
+        assert!(store.get_by_attachment("unknown").await.unwrap().is_none());
+
+        // Pushing out the oldest message drops its file too
+        push("msg2", "file2").await;
+        push("msg3", "file3").await;
+        assert!(store.attachment("file1").await.unwrap().is_none());
+        assert!(store.get_by_attachment("file1").await.unwrap().is_none());
+        assert!(store.attachment("file3").await.unwrap().is_some());
+
+        store.clear().await.unwrap();
+        assert!(store.attachment("file3").await.unwrap().is_none());
+        assert_eq!(store.attachment_bytes().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_message_queue_keeps_attachments_with_messages() {
+        check_attachments_go_with_messages(&MessageQueue::new(Duration::from_secs(60), 2)).await;
+    }
+
+    #[tokio::test]
+    async fn test_persistent_queue_keeps_attachments_with_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        check_attachments_go_with_messages(&persistent_queue(dir.path(), Duration::from_secs(60), 2)).await;
+    }
+
+    #[tokio::test]
+    async fn test_message_queue_tracks_deliveries() {
+        check_delivery_tracking(&MessageQueue::new(Duration::from_secs(60), 10)).await;
+    }
+
+    #[tokio::test]
+    async fn test_persistent_queue_tracks_deliveries() {
+        let dir = tempfile::tempdir().unwrap();
+        check_delivery_tracking(&persistent_queue(dir.path(), Duration::from_secs(60), 10)).await;
+    }
+
+    fn persistent_queue(dir: &std::path::Path, ttl: Duration, max_size: usize) -> PersistentMessageQueue {
+        use crate::msgserver::storage::{ServerConfig, ServerStatus};
+
+        let state_manager = Arc::new(StateManager::new(dir).unwrap());
+        if state_manager.get_server_by_id("server-id").unwrap().is_none() {
+            state_manager
+                .create_server(ServerConfig {
+                    id: "server-id".to_string(),
+                    name: "server".to_string(),
+                    socket_path: dir.join("server.sock"),
+                    created_at: SystemTime::now(),
+                    ttl_minutes: 5,
+                    onion_address: None,
+                    status: ServerStatus::Running,
+                    hook: None,
+                    persistent: true,
+                    max_attachment_size: None,
+                    max_attachment_storage: None,
+                    slow_consumer_policy: Default::default(),
+                    client_queue_size: None,
+                    limits: Default::default(),
+                })
+                .unwrap();
+        }
+
+        PersistentMessageQueue::new(state_manager, "server-id".to_string(), ttl, max_size).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_persistent_queue_survives_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = persistent_queue(dir.path(), Duration::from_secs(60), 2);
+
+        let first = MessageStore::push(&queue, "client1".to_string(), "msg1".to_string(), Audience::Everyone, None, None, None)
+            .await
+            .unwrap();
+        let audience = Audience::Channel("ops".to_string());
+        let origin = Origin {
+            sender: Some("client1"),
+            server: Some("server-id"),
+        };
+        let signature = Identity::generate().sign("msg2", &audience, None, origin);
+        let second = MessageStore::push(
+            &queue,
+            "client1".to_string(),
+            "msg2".to_string(),
+            audience.clone(),
+            Some(signature.clone()),
+            None,
+            Some("client1-id".to_string()),
+        )
+        .await
+        .unwrap();
+        drop(queue);
+
+        // A new queue (as after a restart) sees the same messages, in order
+        let queue = persistent_queue(dir.path(), Duration::from_secs(60), 2);
+        let messages = MessageStore::get_after(&queue, None, None).await.unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(queue.queued_by("client1-id").await.unwrap().messages, 1);
+        assert_eq!(messages[0].id, first.id);
+        assert_eq!(messages[0].timestamp, first.timestamp);
+        assert_eq!(messages[1].content, "msg2");
+        assert_eq!(messages[1].audience(), audience);
+        assert_eq!(messages[1].signature, Some(signature));
+        assert!(messages[1].verify_signature(Some("server-id")).is_ok());
+
+        let messages = MessageStore::get_after(&queue, None, Some(&first.id)).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, second.id);
+        let messages = MessageStore::get_after(&queue, Some(second.timestamp), None).await.unwrap();
+        assert_eq!(messages.len(), 1);
+
+        // The oldest message is dropped once the queue is full
+        MessageStore::push(&queue, "client2".to_string(), "msg3".to_string(), Audience::Everyone, None, None, None)
+            .await
+            .unwrap();
+        let messages = MessageStore::get_after(&queue, None, Some("expired")).await.unwrap();
+        let contents: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, ["msg2", "msg3"]);
+
+        MessageStore::clear(&queue).await.unwrap();
+        assert!(MessageStore::is_empty(&queue).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_persistent_queue_sweeps_expired_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = persistent_queue(dir.path(), Duration::from_millis(100), 10);
+
+        MessageStore::push(&queue, "client1".to_string(), "msg1".to_string(), Audience::Everyone, None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(MessageStore::len(&queue).await.unwrap(), 1);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        // Expired messages are hidden before the sweep removes them
+        assert_eq!(MessageStore::len(&queue).await.unwrap(), 0);
+        assert!(MessageStore::get_after(&queue, None, None).await.unwrap().is_empty());
+        assert_eq!(MessageStore::remove_expired(&queue).await.unwrap(), 1);
+        assert_eq!(MessageStore::remove_expired(&queue).await.unwrap(), 0);
+    }
 }
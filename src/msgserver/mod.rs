@@ -5,21 +5,37 @@
 // for message expiration, authentication, and Tor hidden services.
 
 pub mod message;
+pub mod framing;
 pub mod storage;
 pub mod client;
 pub mod broker;
 pub mod server;
 pub mod handshake;
+pub mod intro;
+pub mod pake;
+pub mod e2e;
+pub mod identity;
+pub mod attachment;
+pub mod outbox;
+pub mod limits;
 pub mod cli;
 pub mod commands;
+pub mod daemon;
+pub mod detach;
+pub mod hook;
 pub mod tor;
+pub mod remote;
 
-pub use message::{Message, MessageQueue};
-pub use storage::{StateManager, ServerConfig, ClientConfig};
+pub use message::{Message, MessageQueue, MessageStore, PersistentMessageQueue};
+pub use storage::{StateManager, ServerConfig, ClientConfig, BrokerConfig};
 pub use client::{ClientConnection, ClientManager};
 pub use broker::{MessageBroker, BrokerHandle};
-pub use server::{ServerInstance, ServerManager};
+pub use server::{ServerInstance, ServerManager, ServerOptions};
 pub use handshake::{BrokerHandshake, ClientHandshake, IntroductionData};
+pub use intro::IntroductionServer;
 pub use cli::{MsgSrvCli, MsgSrvCommand};
 pub use commands::execute_command;
+pub use daemon::{Daemon, DaemonClient};
+pub use hook::{Hook, HookConfig};
 pub use tor::TorManager;
+pub use remote::RemoteClient;
@@ -0,0 +1,460 @@
+// Bounded outbound queues
+//
+// Frames for a connection wait in its `Outbox` until the connection's writer
+// task gets them onto the socket. Outboxes are bounded: what happens when a
+// client reads too slowly to keep up is the server's `SlowConsumerPolicy`.
+// Only broadcasts are ever dropped, as a client that loses a reply or a
+// chunk of a file it asked for is left waiting on a half answered request,
+// and the client is told which it missed in the next frame it is sent.
+// Each outbox counts what went through it, so a server can show how far
+// behind each of its clients is.
+
+use crate::msgserver::message::{Envelope, Gap, ProtocolMessage};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Frames a connection's outbox holds unless configured otherwise
+pub const DEFAULT_OUTBOX_CAPACITY: usize = 256;
+
+/// How long a blocked sender waits for room before giving up on the client
+pub const BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do when a client's outbox is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued broadcast to make room, telling the client
+    /// what it missed, and disconnect it if only replies are queued
+    DropOldest,
+    /// Disconnect the client; it catches up on what it missed when it reconnects
+    #[default]
+    Disconnect,
+    /// Make the sender wait for room, disconnecting the client after `BLOCK_TIMEOUT`
+    Block,
+}
+
+impl std::fmt::Display for SlowConsumerPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlowConsumerPolicy::DropOldest => write!(f, "drop-oldest"),
+            SlowConsumerPolicy::Disconnect => write!(f, "disconnect"),
+            SlowConsumerPolicy::Block => write!(f, "block"),
+        }
+    }
+}
+
+impl SlowConsumerPolicy {
+    pub fn from_string(s: &str) -> Self {
+        match s {
+            "drop-oldest" => SlowConsumerPolicy::DropOldest,
+            "block" => SlowConsumerPolicy::Block,
+            _ => SlowConsumerPolicy::Disconnect,
+        }
+    }
+}
+
+/// Why a frame could not be queued
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum OutboxError {
+    /// The connection is closing
+    #[error("Connection closed")]
+    Closed,
+    /// The client is too slow; its policy says to disconnect it
+    #[error("Client is not reading its messages")]
+    Full,
+}
+
+/// What went through an outbox
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutboxMetrics {
+    /// Frames waiting to be written now
+    pub queued: usize,
+    /// Most frames ever waiting at once
+    pub high_water: usize,
+    /// Frames handed to the writer
+    pub sent: u64,
+    /// Frames dropped to make room
+    pub dropped: u64,
+    /// Times a sender had to wait for room
+    pub blocked: u64,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    frames: VecDeque<Envelope>,
+    // Broadcasts dropped since the client was last told
+    gap: Option<Gap>,
+    closed: bool,
+    metrics: OutboxMetrics,
+}
+
+impl State {
+    fn drop_frame(&mut self, frame: Envelope) {
+        if let ProtocolMessage::Broadcast { message } = frame.message {
+            match self.gap {
+                Some(ref mut gap) => gap.add(message.timestamp),
+                None => {
+                    self.gap = Some(Gap {
+                        dropped: 1,
+                        since: message.timestamp,
+                    })
+                }
+            }
+        }
+        self.metrics.dropped += 1;
+    }
+
+    fn enqueue(&mut self, frame: Envelope) {
+        self.frames.push_back(frame);
+        self.metrics.queued = self.frames.len();
+        self.metrics.high_water = self.metrics.high_water.max(self.frames.len());
+    }
+}
+
+/// A connection's bounded queue of frames waiting to be written
+#[derive(Debug)]
+pub struct Outbox {
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    block_timeout: Duration,
+    state: Mutex<State>,
+    // Signalled when a frame is queued or the outbox closes
+    readable: Notify,
+    // Signalled when a frame is taken or the outbox closes
+    writable: Notify,
+}
+
+impl Outbox {
+    /// An outbox holding up to `capacity` frames (at least one)
+    pub fn new(capacity: usize, policy: SlowConsumerPolicy) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            policy,
+            block_timeout: BLOCK_TIMEOUT,
+            state: Mutex::new(State::default()),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    /// Under `Block`, give up on the client after `timeout` instead
+    pub fn with_block_timeout(mut self, timeout: Duration) -> Self {
+        self.block_timeout = timeout;
+        self
+    }
+
+    /// What the outbox does when it is full
+    pub fn policy(&self) -> SlowConsumerPolicy {
+        self.policy
+    }
+
+    /// Queue a frame, applying the policy if the outbox is full
+    pub async fn push(&self, frame: impl Into<Envelope>) -> Result<(), OutboxError> {
+        let frame = frame.into();
+        match self.policy {
+            SlowConsumerPolicy::Block => match tokio::time::timeout(self.block_timeout, self.push_wait(frame)).await {
+                Ok(result) => result,
+                Err(_) => Err(OutboxError::Full),
+            },
+            policy => self.try_push(frame, policy == SlowConsumerPolicy::DropOldest),
+        }
+    }
+
+    /// Queue a frame, waiting for room however long it takes
+    ///
+    /// For frames the client asked for and will read, such as a file's
+    /// chunks, so that the policy does not apply.
+    pub async fn push_wait(&self, frame: impl Into<Envelope>) -> Result<(), OutboxError> {
+        let frame = frame.into();
+        let mut counted = false;
+        loop {
+            let writable = self.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return Err(OutboxError::Closed);
+                }
+                if state.frames.len() < self.capacity {
+                    state.enqueue(frame);
+                    drop(state);
+                    self.readable.notify_one();
+                    return Ok(());
+                }
+                if !counted {
+                    state.metrics.blocked += 1;
+                    counted = true;
+                }
+            }
+
+            writable.await;
+        }
+    }
+
+    /// Queue a last frame, making room if need be, and close the outbox
+    ///
+    /// The writer still gets every frame queued before it closes.
+    pub fn close_with(&self, frame: impl Into<Envelope>) {
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            if state.frames.len() >= self.capacity {
+                state.frames.pop_front();
+                state.metrics.dropped += 1;
+            }
+            state.enqueue(frame.into());
+        }
+        drop(state);
+        self.close();
+    }
+
+    /// Stop accepting frames; the writer ends once the rest are written
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_one();
+        self.writable.notify_waiters();
+    }
+
+    /// Take the next frame, waiting for one (None once closed and empty)
+    pub async fn pop(&self) -> Option<Envelope> {
+        loop {
+            let readable = self.readable.notified();
+            tokio::pin!(readable);
+            readable.as_mut().enable();
+
+            if let Some(frame) = self.try_pop() {
+                return Some(frame);
+            }
+            if self.state.lock().unwrap().closed {
+                return None;
+            }
+
+            readable.await;
+        }
+    }
+
+    /// Take the next frame if there is one
+    ///
+    /// Dropped broadcasts are reported before the frames queued after them.
+    pub fn try_pop(&self) -> Option<Envelope> {
+        let mut state = self.state.lock().unwrap();
+        let frame = match state.gap.take() {
+            Some(gap) => ProtocolMessage::gap(gap).into(),
+            None => state.frames.pop_front()?,
+        };
+        state.metrics.queued = state.frames.len();
+        state.metrics.sent += 1;
+        drop(state);
+
+        self.writable.notify_one();
+        Some(frame)
+    }
+
+    /// What has gone through the outbox so far
+    pub fn metrics(&self) -> OutboxMetrics {
+        self.state.lock().unwrap().metrics.clone()
+    }
+
+    fn try_push(&self, frame: Envelope, drop_oldest: bool) -> Result<(), OutboxError> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(OutboxError::Closed);
+        }
+        if state.frames.len() >= self.capacity {
+            if !drop_oldest {
+                return Err(OutboxError::Full);
+            }
+            match state.frames.iter().position(is_unsolicited) {
+                Some(index) => {
+                    let dropped = state.frames.remove(index).expect("index is in bounds");
+                    state.drop_frame(dropped);
+                }
+                // Nothing older may go, but the new frame may
+                None if is_unsolicited(&frame) => {
+                    state.drop_frame(frame);
+                    drop(state);
+                    self.readable.notify_one();
+                    return Ok(());
+                }
+                None => return Err(OutboxError::Full),
+            }
+        }
+        state.enqueue(frame);
+        drop(state);
+
+        self.readable.notify_one();
+        Ok(())
+    }
+}
+
+/// Whether a frame was pushed to the client rather than asked for, so may
+/// be dropped (a broadcast missed stays unacknowledged, so comes again on
+/// reconnect, or sooner if the client receives it after hearing of the gap)
+fn is_unsolicited(frame: &Envelope) -> bool {
+    frame.request_id.is_none() && matches!(frame.message, ProtocolMessage::Broadcast { .. })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msgserver::message::{ErrorCode, Message};
+    use std::sync::Arc;
+
+    fn error(n: usize) -> ProtocolMessage {
+        ProtocolMessage::error(ErrorCode::Internal, n.to_string())
+    }
+
+    fn broadcast(n: usize) -> ProtocolMessage {
+        ProtocolMessage::Broadcast {
+            message: Message::new("alice".to_string(), n.to_string(), Duration::from_secs(60)),
+        }
+    }
+
+    fn drain(outbox: &Outbox) -> Vec<String> {
+        std::iter::from_fn(|| outbox.try_pop())
+            .map(|frame| match frame.message {
+                ProtocolMessage::Error { gap: Some(gap), .. } => format!("gap of {}", gap.dropped),
+                ProtocolMessage::Error { message, .. } => message,
+                ProtocolMessage::Broadcast { message } => message.content,
+                other => panic!("Unexpected frame {:?}", other),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_keeps_the_newest_frames() {
+        let outbox = Outbox::new(3, SlowConsumerPolicy::DropOldest);
+        for n in 0..5 {
+            outbox.push(broadcast(n)).await.unwrap();
+        }
+
+        let metrics = outbox.metrics();
+        assert_eq!((metrics.queued, metrics.high_water, metrics.dropped), (3, 3, 2));
+        assert_eq!(drain(&outbox), ["gap of 2", "2", "3", "4"]);
+        assert_eq!(outbox.metrics().sent, 4);
+        assert_eq!(outbox.metrics().queued, 0);
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_reports_the_gap() {
+        let outbox = Outbox::new(1, SlowConsumerPolicy::DropOldest);
+        let first = Message::new("alice".to_string(), "0".to_string(), Duration::from_secs(60));
+        let since = first.timestamp;
+        outbox.push(ProtocolMessage::Broadcast { message: first }).await.unwrap();
+        outbox.push(broadcast(1)).await.unwrap();
+        outbox.push(broadcast(2)).await.unwrap();
+
+        // The client hears what it missed, and from when to receive it again
+        let notice = outbox.pop().await.unwrap();
+        assert!(notice.request_id.is_none());
+        match notice.message {
+            ProtocolMessage::Error { code, message, gap } => {
+                assert_eq!(code, ErrorCode::SlowConsumer);
+                assert!(message.contains("receive --since"));
+                assert_eq!(gap, Some(Gap { dropped: 2, since }));
+            }
+            other => panic!("Expected a gap notice, got {:?}", other),
+        }
+        assert_eq!(drain(&outbox), ["2"]);
+
+        // Only once, until more are dropped
+        outbox.push(broadcast(3)).await.unwrap();
+        assert_eq!(drain(&outbox), ["3"]);
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest_never_drops_replies() {
+        let outbox = Outbox::new(2, SlowConsumerPolicy::DropOldest);
+        let reply = |n: usize| Envelope::reply(Some(n.to_string()), error(n));
+        outbox.push(reply(0)).await.unwrap();
+        outbox.push(broadcast(1)).await.unwrap();
+
+        // The broadcast makes room for a reply; then a broadcast has to go
+        outbox.push(reply(2)).await.unwrap();
+        outbox.push(broadcast(3)).await.unwrap();
+        assert_eq!(outbox.metrics().dropped, 2);
+
+        // With only replies queued, there is nothing to drop
+        assert_eq!(outbox.push(reply(4)).await, Err(OutboxError::Full));
+        assert_eq!(drain(&outbox), ["gap of 2", "0", "2"]);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_refuses_frames_once_full() {
+        let outbox = Outbox::new(2, SlowConsumerPolicy::Disconnect);
+        outbox.push(error(0)).await.unwrap();
+        outbox.push(error(1)).await.unwrap();
+        assert_eq!(outbox.push(error(2)).await, Err(OutboxError::Full));
+
+        // The goodbye still gets through, and nothing after it
+        outbox.close_with(error(3));
+        assert_eq!(outbox.push(error(4)).await, Err(OutboxError::Closed));
+        assert_eq!(drain(&outbox), ["1", "3"]);
+        assert!(outbox.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_block_waits_for_the_reader() {
+        let outbox = Arc::new(Outbox::new(1, SlowConsumerPolicy::Block));
+        outbox.push(error(0)).await.unwrap();
+
+        let sender = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.push(error(1)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!sender.is_finished());
+        assert_eq!(outbox.metrics().blocked, 1);
+
+        // A slow reader eventually takes a frame, letting the sender through
+        assert!(matches!(outbox.pop().await.unwrap().message, ProtocolMessage::Error { .. }));
+        sender.await.unwrap().unwrap();
+        assert_eq!(drain(&outbox), ["1"]);
+    }
+
+    #[tokio::test]
+    async fn test_block_gives_up_on_a_stalled_reader() {
+        let outbox = Outbox::new(1, SlowConsumerPolicy::Block).with_block_timeout(Duration::from_millis(50));
+        outbox.push(error(0)).await.unwrap();
+
+        assert_eq!(outbox.push(error(1)).await, Err(OutboxError::Full));
+        assert_eq!(outbox.metrics().blocked, 1);
+    }
+
+    #[tokio::test]
+    async fn test_pop_wakes_on_push_and_close() {
+        let outbox = Arc::new(Outbox::new(4, SlowConsumerPolicy::Disconnect));
+        let reader = tokio::spawn({
+            let outbox = outbox.clone();
+            async move {
+                let mut frames = 0;
+                while outbox.pop().await.is_some() {
+                    frames += 1;
+                }
+                frames
+            }
+        });
+
+        for n in 0..3 {
+            outbox.push(error(n)).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        outbox.close();
+        assert_eq!(reader.await.unwrap(), 3);
+
+        // Blocked senders are released when the outbox closes too
+        let outbox = Arc::new(Outbox::new(1, SlowConsumerPolicy::Block));
+        outbox.push(error(0)).await.unwrap();
+        let sender = tokio::spawn({
+            let outbox = outbox.clone();
+            async move { outbox.push_wait(error(1)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        outbox.close();
+        assert_eq!(sender.await.unwrap(), Err(OutboxError::Closed));
+    }
+}
@@ -0,0 +1,237 @@
+// Password-authenticated key exchange for broker introductions
+//
+// SPAKE2 (RFC 9382) from the `spake2` crate: both sides blind an ephemeral
+// share with the short code, exchange shares, and derive the same key only if
+// they used the same code. The broker identifier and a fresh session ID are
+// bound into the session keys. Each attempt lets an impostor test a single
+// guess, and nothing sent on the wire can be used to check guesses offline.
+
+use anyhow::{Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+/// SPAKE2 identity of the client side; the broker's is its identifier
+const CLIENT_IDENTITY: &[u8] = b"eddi-intro-client";
+
+/// Domain separation tag for the session keys
+const SESSION_DSI: &[u8] = b"eddi-pake-session";
+
+/// Length of an XChaCha20-Poly1305 nonce
+const NONCE_LEN: usize = 24;
+
+/// Which side of the exchange we are
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Broker,
+    Client,
+}
+
+/// One side of a PAKE exchange, before the peer's share has arrived
+pub struct Pake {
+    role: Role,
+    session_id: String,
+    state: Spake2<Ed25519Group>,
+    share: Vec<u8>,
+}
+
+impl Pake {
+    /// Start an exchange keyed by `code` for the broker `identifier`
+    ///
+    /// `session_id` must be fresh for every exchange; the broker picks it.
+    pub fn start(role: Role, code: &str, identifier: &str, session_id: &str) -> Self {
+        let password = Password::new(code.as_bytes());
+        let broker = Identity::new(identifier.as_bytes());
+        let client = Identity::new(CLIENT_IDENTITY);
+
+        let (state, share) = match role {
+            Role::Broker => Spake2::<Ed25519Group>::start_a(&password, &broker, &client),
+            Role::Client => Spake2::<Ed25519Group>::start_b(&password, &broker, &client),
+        };
+
+        Self {
+            role,
+            session_id: session_id.to_string(),
+            state,
+            share,
+        }
+    }
+
+    /// Our share, to send to the peer
+    pub fn share(&self) -> String {
+        hex::encode(&self.share)
+    }
+
+    /// Combine with the peer's share into session keys
+    ///
+    /// Succeeds whenever the share is well formed; whether both sides used
+    /// the same code is only known once a confirmation has been checked.
+    pub fn finish(self, peer_share: &str) -> Result<SessionKeys> {
+        let peer_share = hex::decode(peer_share).context("Malformed key share")?;
+        let shared = self
+            .state
+            .finish(&peer_share)
+            .map_err(|err| anyhow::anyhow!("Malformed key share: {err}"))?;
+
+        let mut salt = SESSION_DSI.to_vec();
+        salt.extend(self.session_id.as_bytes());
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), &shared);
+
+        let mut keys = SessionKeys {
+            role: self.role,
+            broker_confirm: [0; 32],
+            client_confirm: [0; 32],
+            encryption: [0; 32],
+        };
+        hkdf.expand(b"broker confirmation", &mut keys.broker_confirm)
+            .expect("valid HKDF length");
+        hkdf.expand(b"client confirmation", &mut keys.client_confirm)
+            .expect("valid HKDF length");
+        hkdf.expand(b"encryption", &mut keys.encryption)
+            .expect("valid HKDF length");
+
+        Ok(keys)
+    }
+}
+
+/// Keys shared by both sides after a PAKE exchange
+pub struct SessionKeys {
+    role: Role,
+    broker_confirm: [u8; 32],
+    client_confirm: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl SessionKeys {
+    /// Our key confirmation, proving to the peer that we derived the same keys
+    pub fn confirmation(&self) -> String {
+        hex::encode(confirmation_mac(self.confirm_key(self.role)).finalize().into_bytes())
+    }
+
+    /// Check the peer's key confirmation (false means a different code)
+    pub fn verify_confirmation(&self, confirmation: &str) -> bool {
+        let peer = match self.role {
+            Role::Broker => Role::Client,
+            Role::Client => Role::Broker,
+        };
+
+        hex::decode(confirmation).is_ok_and(|tag| {
+            confirmation_mac(self.confirm_key(peer))
+                .verify_slice(&tag)
+                .is_ok()
+        })
+    }
+
+    /// Encrypt `plaintext` for the peer
+    pub fn seal(&self, plaintext: &[u8]) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher()
+            .encrypt(XNonce::from_slice(&nonce), plaintext)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(hex::encode(sealed))
+    }
+
+    /// Decrypt and authenticate what the peer sealed
+    pub fn open(&self, sealed: &str) -> Result<Vec<u8>> {
+        let sealed = hex::decode(sealed).context("Malformed sealed data")?;
+        if sealed.len() < NONCE_LEN {
+            anyhow::bail!("Malformed sealed data");
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+        self.cipher()
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow::anyhow!("Sealed data failed authentication"))
+    }
+
+    fn confirm_key(&self, role: Role) -> &[u8; 32] {
+        match role {
+            Role::Broker => &self.broker_confirm,
+            Role::Client => &self.client_confirm,
+        }
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.encryption.into())
+    }
+}
+
+fn confirmation_mac(key: &[u8; 32]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(b"eddi-pake-confirm");
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(broker_code: &str, client_code: &str) -> (SessionKeys, SessionKeys) {
+        let broker = Pake::start(Role::Broker, broker_code, "broker-id", "session");
+        let client = Pake::start(Role::Client, client_code, "broker-id", "session");
+
+        let broker_share = broker.share();
+        let client_share = client.share();
+
+        (broker.finish(&client_share).unwrap(), client.finish(&broker_share).unwrap())
+    }
+
+    #[test]
+    fn test_same_code_confirms_and_shares_keys() {
+        let (broker, client) = exchange("ABC-XYZ", "ABC-XYZ");
+
+        assert!(broker.verify_confirmation(&client.confirmation()));
+        assert!(client.verify_confirmation(&broker.confirmation()));
+        // A side's own confirmation is not accepted back
+        assert!(!broker.verify_confirmation(&broker.confirmation()));
+
+        let sealed = broker.seal(b"introduction").unwrap();
+        assert_eq!(client.open(&sealed).unwrap(), b"introduction");
+    }
+
+    #[test]
+    fn test_different_code_fails_confirmation() {
+        let (broker, client) = exchange("ABC-XYZ", "DEF-123");
+
+        assert!(!broker.verify_confirmation(&client.confirmation()));
+        assert!(!client.verify_confirmation(&broker.confirmation()));
+
+        let sealed = broker.seal(b"introduction").unwrap();
+        assert!(client.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_rejects_malformed_shares() {
+        let pake = Pake::start(Role::Client, "ABC-XYZ", "broker-id", "session");
+        assert!(pake.finish("not hex").is_err());
+
+        // A share from the same side of the exchange is refused
+        let pake = Pake::start(Role::Client, "ABC-XYZ", "broker-id", "session");
+        let other = Pake::start(Role::Client, "ABC-XYZ", "broker-id", "session");
+        assert!(pake.finish(&other.share()).is_err());
+
+        let pake = Pake::start(Role::Client, "ABC-XYZ", "broker-id", "session");
+        assert!(pake.finish("00").is_err());
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_fails() {
+        let (broker, client) = exchange("ABC-XYZ", "ABC-XYZ");
+
+        let mut sealed = hex::decode(broker.seal(b"introduction").unwrap()).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+
+        assert!(client.open(&hex::encode(sealed)).is_err());
+    }
+}
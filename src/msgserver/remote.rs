@@ -0,0 +1,1052 @@
+// Client-side connections to a messaging server
+//
+// Connects to a server over its Unix socket or onion address and speaks the
+// protocol from the client's side, negotiating length-prefixed frames with
+// servers that offer them. `listen` keeps a
+// stream of messages flowing across disconnects, resuming after the last
+// message it delivered, and acknowledges each message it hands over so the
+// server can redeliver whatever was missed. Attachments are uploaded and
+// fetched in hash-checked chunks. Messages are signed with the installation's identity
+// when one is given; received messages whose signature does not verify are
+// dropped, and those sealed for the connection's key are opened. Each request
+// carries an ID, so its reply (or error) is told apart from stale replies to
+// earlier requests.
+
+use crate::msgserver::attachment::{self, Assembly, AttachmentInfo};
+use crate::msgserver::cli::MsgSrvCli;
+use crate::msgserver::e2e::{self, PeerKey, PublicKey, SecretKey};
+use crate::msgserver::framing::{
+    self, FrameError, FrameReader, Framing, LEGACY_PROTOCOL_VERSION, MAX_SERVER_FRAME_SIZE, PROTOCOL_VERSION,
+};
+use crate::msgserver::identity::{Identity, Origin};
+use crate::msgserver::message::{Audience, Delivery, Envelope, ErrorCode, Gap, Message, ProtocolMessage};
+use crate::msgserver::storage::ConnectionConfig;
+use crate::msgserver::tor::{TorManager, MESSAGE_PORT};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::UnixStream;
+use tokio::time::Instant;
+
+/// How long to wait for the server to answer a request
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for a server over a Unix socket to answer `Hello` before
+/// assuming it predates it; servers over Tor get `RESPONSE_TIMEOUT`
+pub const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a listening client pings the server
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Silence after which a listening client assumes the connection is dead
+pub const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Maximum delay between reconnection attempts
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Chunks of an upload sent ahead of the server's answers
+const UPLOAD_WINDOW: usize = 16;
+
+/// Number of recent message IDs remembered to drop duplicates after a resume
+const SEEN_CAPACITY: usize = 1024;
+
+/// The server rejected our credentials; retrying will not help
+#[derive(Debug, thiserror::Error)]
+#[error("Authentication failed: {0}")]
+pub struct AuthRejected(pub String);
+
+/// The server refused a request
+#[derive(Debug, thiserror::Error)]
+#[error("Server error ({code}): {message}")]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// What a listening client identifies as and follows
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListenOptions {
+    /// Alias to authenticate as, so direct messages reach us
+    pub alias: Option<String>,
+    /// Only follow this channel's messages
+    pub channel: Option<String>,
+}
+
+/// Byte stream a client can talk to a server over
+pub trait ServerStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ServerStream for T {}
+
+/// A client's connection to a messaging server
+pub struct RemoteClient {
+    // Keeps partly read frames across reads, so `recv` is cancel safe
+    reader: FrameReader<ReadHalf<Box<dyn ServerStream>>>,
+    writer: WriteHalf<Box<dyn ServerStream>>,
+    framing: Framing,
+    protocol_version: u32,
+    capabilities: Vec<String>,
+    hello_timeout: Duration,
+    // Set when we stopped waiting for the server's `Hello`; one that still
+    // comes is obeyed, as the server has switched framing by then
+    hello_pending: bool,
+    // Broadcasts that arrived while waiting for a response
+    backlog: VecDeque<Message>,
+    // ID of the last request sent; replies to earlier ones are skipped
+    request_id: u64,
+    client_id: String,
+    alias: Option<String>,
+    // Reported on authentication; signatures are bound to it
+    server_id: Option<String>,
+    secret_key: Option<SecretKey>,
+    // Keys sealed for: pinned at the introduction or trusted on first use
+    peer_keys: Vec<PublicKey>,
+    alias_keys: BTreeMap<String, PublicKey>,
+    identity: Option<Arc<Identity>>,
+    // Keeps the Tor client alive for onion connections
+    _tor: Option<Arc<TorManager>>,
+}
+
+impl RemoteClient {
+    /// Wrap an already connected stream
+    pub fn new(stream: Box<dyn ServerStream>, client_id: String) -> Self {
+        let (read_half, writer) = tokio::io::split(stream);
+
+        Self {
+            reader: FrameReader::new(read_half, MAX_SERVER_FRAME_SIZE),
+            writer,
+            framing: Framing::Lines,
+            protocol_version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Vec::new(),
+            hello_timeout: HELLO_TIMEOUT,
+            hello_pending: false,
+            backlog: VecDeque::new(),
+            request_id: 0,
+            client_id,
+            alias: None,
+            server_id: None,
+            secret_key: None,
+            peer_keys: Vec::new(),
+            alias_keys: BTreeMap::new(),
+            identity: None,
+            _tor: None,
+        }
+    }
+
+    /// Authenticate under an alias, so other clients can send us direct messages
+    pub fn with_alias(mut self, alias: Option<String>) -> Self {
+        self.alias = alias;
+        self
+    }
+
+    /// Open sealed messages with `secret_key`, and seal for `peer_keys` too
+    pub fn with_keys(mut self, secret_key: Option<SecretKey>, peer_keys: Vec<PublicKey>) -> Self {
+        self.secret_key = secret_key;
+        self.peer_keys = peer_keys;
+        self
+    }
+
+    /// Seal direct messages to these aliases with these keys only
+    pub fn with_alias_keys(mut self, alias_keys: BTreeMap<String, PublicKey>) -> Self {
+        self.alias_keys = alias_keys;
+        self
+    }
+
+    /// Keys sealed for so far, including those trusted on first use: save
+    /// them with the connection so later runs hold the server to them
+    pub fn trusted_keys(&self) -> (&[PublicKey], &BTreeMap<String, PublicKey>) {
+        (&self.peer_keys, &self.alias_keys)
+    }
+
+    /// Wait up to `timeout` for the server to answer `Hello`
+    pub fn with_hello_timeout(mut self, timeout: Duration) -> Self {
+        self.hello_timeout = timeout;
+        self
+    }
+
+    /// Sign the messages we send with `identity`
+    pub fn with_identity(mut self, identity: Option<Arc<Identity>>) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Connect to the server described by a saved connection
+    ///
+    /// The Unix socket is preferred when one is stored; otherwise the onion
+    /// address is reached over Tor.
+    pub async fn connect(connection: &ConnectionConfig) -> Result<Self> {
+        Self::open(connection, &mut None).await
+    }
+
+    /// Protocol version agreed with the server (1 until `negotiate` succeeds)
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Capabilities both we and the server support, as agreed in `negotiate`
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    /// Exchange `Hello` with the server, switching framing if it offers to
+    ///
+    /// A server that refuses `Hello` with an error keeps newline-delimited
+    /// JSON straight away. Servers from before protocol versions never
+    /// answer, so after the hello timeout the connection carries on in
+    /// lines; should the answer come after all, it is still obeyed.
+    pub async fn negotiate(&mut self) -> Result<()> {
+        self.send(&ProtocolMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: framing::capabilities(),
+        })
+        .await?;
+
+        let timeout = self.hello_timeout;
+        let hello = self.wait_for(|message| match message {
+            ProtocolMessage::Hello { version, capabilities } => Some(Ok((version, capabilities))),
+            _ => None,
+        });
+        let hello = match tokio::time::timeout(timeout, hello).await {
+            Ok(hello) => hello,
+            Err(elapsed) => Err(elapsed.into()),
+        };
+
+        match hello {
+            Ok((version, capabilities)) => self.adopt_hello(version, capabilities),
+            Err(e) if e.is::<ServerError>() => {
+                tracing::debug!("Server refused hello ({}); using newline-delimited JSON", e);
+            }
+            Err(e) if e.is::<tokio::time::error::Elapsed>() => {
+                tracing::debug!("Server did not answer hello; using newline-delimited JSON");
+                self.hello_pending = true;
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Switch to what the server's `Hello` agreed to
+    fn adopt_hello(&mut self, version: u32, capabilities: Vec<String>) {
+        self.framing = Framing::negotiated(&capabilities);
+        self.reader.set_framing(self.framing);
+        self.protocol_version = version;
+        self.capabilities = capabilities;
+        self.hello_pending = false;
+    }
+
+    /// Connect, reusing (or creating and caching) a Tor client for onion addresses
+    async fn open(connection: &ConnectionConfig, tor: &mut Option<Arc<TorManager>>) -> Result<Self> {
+        if let Some(socket_path) = &connection.socket_path {
+            let stream = UnixStream::connect(socket_path)
+                .await
+                .with_context(|| format!("Failed to connect to {:?}", socket_path))?;
+
+            let mut client = Self::new(Box::new(stream), connection.id.clone())
+                .with_keys(connection.secret_key.clone(), connection.peer_keys.clone())
+                .with_alias_keys(connection.alias_keys.clone());
+            client.negotiate().await?;
+            return Ok(client);
+        }
+
+        let onion_address = connection
+            .onion_address
+            .as_deref()
+            .context("Connection has neither a socket path nor an onion address")?;
+
+        let tor = match tor {
+            Some(tor) => tor.clone(),
+            None => tor
+                .insert(Arc::new(TorManager::new(MsgSrvCli::state_dir().join("tor-keys")).await?))
+                .clone(),
+        };
+        let stream = tor.connect_to_onion(onion_address, MESSAGE_PORT).await?;
+
+        // Tor is often slower than a Unix socket's hello timeout allows
+        let mut client = Self {
+            _tor: Some(tor),
+            ..Self::new(Box::new(stream), connection.id.clone())
+                .with_keys(connection.secret_key.clone(), connection.peer_keys.clone())
+                .with_alias_keys(connection.alias_keys.clone())
+                .with_hello_timeout(RESPONSE_TIMEOUT)
+        };
+        client.negotiate().await?;
+        Ok(client)
+    }
+
+    /// Write a protocol message to the server, as a new request
+    pub async fn send(&mut self, message: &ProtocolMessage) -> Result<()> {
+        self.request_id += 1;
+        let request = Envelope::reply(Some(self.request_id.to_string()), message.clone());
+        let bytes = framing::encode(&request, self.framing)?;
+        self.writer.write_all(&bytes).await.context("Failed to write to server")?;
+        self.writer.flush().await.context("Failed to write to server")?;
+        Ok(())
+    }
+
+    /// Read the next protocol message (None once the server closes the connection)
+    ///
+    /// Cancel safe: a partially read frame is kept for the next call.
+    pub async fn recv(&mut self) -> Result<Option<ProtocolMessage>> {
+        Ok(self.recv_envelope().await?.map(|envelope| envelope.message))
+    }
+
+    /// Like `recv`, keeping the ID of the request the message answers
+    pub async fn recv_envelope(&mut self) -> Result<Option<Envelope>> {
+        loop {
+            let envelope = match self.reader.read_envelope().await {
+                Ok(envelope) => envelope,
+                Err(FrameError::Io(e)) => return Err(e).context("Failed to read from server"),
+                Err(e) => return Err(e).context("Invalid message from server"),
+            };
+
+            match envelope {
+                Some(Envelope {
+                    message: ProtocolMessage::Hello { version, capabilities },
+                    ..
+                }) if self.hello_pending => {
+                    tracing::debug!("Server answered hello late; switching framing");
+                    self.adopt_hello(version, capabilities);
+                }
+                envelope => return Ok(envelope),
+            }
+        }
+    }
+
+    /// Read messages until `select` picks one out, failing on timeout or disconnect
+    ///
+    /// Only replies to the last request sent are considered, and an error
+    /// for it (or one for no request in particular, bar notice of dropped
+    /// broadcasts) fails the wait; replies to earlier requests are skipped.
+    /// Servers that predate request IDs send none, so their replies are
+    /// always considered. Broadcasts that arrive in between are kept (up to
+    /// `SEEN_CAPACITY`) for `listen` to hand over.
+    async fn wait_for<T>(&mut self, select: impl FnMut(ProtocolMessage) -> Option<Result<T>>) -> Result<T> {
+        self.wait_for_reply(self.request_id, select).await
+    }
+
+    /// Like `wait_for`, for the replies to request `request_id` instead
+    async fn wait_for_reply<T>(
+        &mut self,
+        request_id: u64,
+        mut select: impl FnMut(ProtocolMessage) -> Option<Result<T>>,
+    ) -> Result<T> {
+        let request_id = request_id.to_string();
+        let wait = async {
+            loop {
+                let envelope = self
+                    .recv_envelope()
+                    .await?
+                    .context("Server closed the connection")?;
+                if envelope.request_id.as_ref().is_some_and(|id| *id != request_id) {
+                    tracing::debug!("Skipping reply to another request: {:?}", envelope);
+                    continue;
+                }
+
+                let message = envelope.message;
+                match message {
+                    // Not about this request: broadcasts were dropped, which
+                    // `listen` catches up on anyway once done waiting
+                    ProtocolMessage::Error { gap: Some(gap), .. } => {
+                        tracing::debug!("Server dropped {} broadcasts", gap.dropped);
+                        continue;
+                    }
+                    ProtocolMessage::Error { code, message, .. } => {
+                        return Err(ServerError { code, message }.into())
+                    }
+                    ProtocolMessage::Broadcast { message } => {
+                        if self.backlog.len() >= SEEN_CAPACITY {
+                            self.backlog.pop_front();
+                        }
+                        self.backlog.push_back(message);
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                if let Some(result) = select(message) {
+                    return result;
+                }
+            }
+        };
+
+        tokio::time::timeout(RESPONSE_TIMEOUT, wait)
+            .await
+            .context("Timed out waiting for the server")?
+    }
+
+    /// Authenticate with `code`, returning the server's ID
+    pub async fn authenticate(&mut self, code: &str) -> Result<Option<String>> {
+        self.send(&ProtocolMessage::Auth {
+            code: code.to_string(),
+            client_id: self.client_id.clone(),
+            alias: self.alias.clone(),
+        })
+        .await?;
+
+        let server_id = self
+            .wait_for(|message| match message {
+                ProtocolMessage::AuthResponse { success: true, server_id, .. } => Some(Ok(server_id)),
+                ProtocolMessage::AuthResponse { success: false, message, .. } => {
+                    Some(Err(AuthRejected(message).into()))
+                }
+                _ => None,
+            })
+            .await?;
+        self.server_id = server_id.clone();
+        Ok(server_id)
+    }
+
+    /// Send a message and wait for the server to acknowledge it, returning its ID
+    pub async fn send_message(&mut self, content: &str) -> Result<String> {
+        self.send_message_to(content, Audience::Everyone).await
+    }
+
+    /// Send a message to one client or channel, returning its ID once acknowledged
+    pub async fn send_message_to(&mut self, content: &str, audience: Audience) -> Result<String> {
+        self.send_with(content, audience, None).await
+    }
+
+    /// Upload `data` as file `name`, then send a message with it attached
+    ///
+    /// The file travels as it is; `send_encrypted_attachment` seals it.
+    pub async fn send_attachment(
+        &mut self,
+        content: &str,
+        audience: Audience,
+        name: &str,
+        data: &[u8],
+    ) -> Result<String> {
+        let attachment = self.upload(name, data).await?;
+        self.send_with(content, audience, Some(attachment)).await
+    }
+
+    /// Sign and send a message, returning its ID once acknowledged
+    async fn send_with(
+        &mut self,
+        content: &str,
+        audience: Audience,
+        attachment: Option<AttachmentInfo>,
+    ) -> Result<String> {
+        let sha256 = attachment.as_ref().map(|a| a.sha256.as_str());
+        let origin = Origin {
+            sender: self.alias.as_deref(),
+            server: self.server_id.as_deref(),
+        };
+        let signature = self
+            .identity
+            .as_ref()
+            .map(|identity| identity.sign(content, &audience, sha256, origin));
+        let (to, channel) = match audience {
+            Audience::Everyone => (None, None),
+            Audience::Client(alias) => (Some(alias), None),
+            Audience::Channel(channel) => (None, Some(channel)),
+        };
+        self.send(&ProtocolMessage::Send {
+            content: content.to_string(),
+            to,
+            channel,
+            signature,
+            attachment: attachment.map(|a| a.id),
+        })
+        .await?;
+
+        self.wait_for(|message| match message {
+            ProtocolMessage::SendAck { message_id } => Some(Ok(message_id)),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Encrypt a message for its audience, then send it like `send_message_to`
+    ///
+    /// A direct message is sealed for the key pinned for its recipient's
+    /// alias, anything else for every key we trust. The server's key list is
+    /// only believed for keys we have never seen: they are trusted on first
+    /// use, with a warning. A key the server reports for an alias pinned to
+    /// a different one is never used, and a direct message to that alias is
+    /// refused. The sender's own key is always included.
+    pub async fn send_encrypted(&mut self, content: &str, audience: Audience) -> Result<String> {
+        let recipients = self.recipients(&audience).await?;
+        let sealed = e2e::seal(content, &recipients)?;
+        self.send_message_to(&sealed, audience).await
+    }
+
+    /// Encrypt a message and its file for its audience, as `send_encrypted`
+    /// does, then upload and send them like `send_attachment`
+    ///
+    /// The server only sees the sealed file, and its hashes are of that.
+    pub async fn send_encrypted_attachment(
+        &mut self,
+        content: &str,
+        audience: Audience,
+        name: &str,
+        data: &[u8],
+    ) -> Result<String> {
+        let recipients = self.recipients(&audience).await?;
+        let sealed = e2e::seal(content, &recipients)?;
+        let sealed_data = e2e::seal_bytes(data, &recipients)?;
+        self.send_attachment(&sealed, audience, name, &sealed_data).await
+    }
+
+    /// The keys to seal a message to `audience` for, as `send_encrypted` describes
+    async fn recipients(&mut self, audience: &Audience) -> Result<Vec<PublicKey>> {
+        let secret_key = self
+            .secret_key
+            .clone()
+            .context("This connection has no encryption key; connect through a broker to get one")?;
+        let keys = self.keys().await?;
+
+        let mut recipients = vec![secret_key.public_key()];
+        match audience {
+            Audience::Client(alias) => {
+                let offered = keys
+                    .iter()
+                    .find(|key| key.alias.as_deref() == Some(alias.as_str()))
+                    .map(|key| key.public_key);
+                let key = match (self.alias_keys.get(alias).copied(), offered) {
+                    (Some(pinned), Some(offered)) if pinned != offered => {
+                        anyhow::bail!("The server reports a different encryption key for {} than the one pinned", alias)
+                    }
+                    (Some(pinned), _) => pinned,
+                    (None, Some(offered)) => {
+                        self.trust_on_first_use(Some(alias), offered);
+                        offered
+                    }
+                    (None, None) => anyhow::bail!("No encryption key for {}", alias),
+                };
+                recipients.push(key);
+            }
+            Audience::Everyone | Audience::Channel(_) => {
+                for key in &keys {
+                    let pinned = key.alias.as_ref().and_then(|alias| self.alias_keys.get(alias));
+                    match pinned {
+                        Some(pinned) if *pinned != key.public_key => tracing::warn!(
+                            "Not encrypting to {}: the server reports a different key than the one pinned",
+                            key.alias.as_deref().unwrap_or_default()
+                        ),
+                        _ if self.is_trusted(&key.public_key) => {}
+                        _ => self.trust_on_first_use(key.alias.as_deref(), key.public_key),
+                    }
+                }
+                recipients.extend(self.peer_keys.iter().copied());
+                recipients.extend(self.alias_keys.values().copied());
+            }
+        }
+
+        Ok(recipients)
+    }
+
+    /// Whether messages are already sealed for a key
+    fn is_trusted(&self, key: &PublicKey) -> bool {
+        self.peer_keys.contains(key) || self.alias_keys.values().any(|k| k == key)
+    }
+
+    /// Start sealing for a key we had not seen, pinning it to its alias
+    fn trust_on_first_use(&mut self, alias: Option<&str>, key: PublicKey) {
+        tracing::warn!(
+            "Trusting new encryption key {} for {} on first use",
+            key,
+            alias.unwrap_or("a client without an alias")
+        );
+        match alias {
+            Some(alias) => {
+                self.alias_keys.insert(alias.to_string(), key);
+            }
+            None => self.peer_keys.push(key),
+        }
+    }
+
+    /// Upload a file for a message to refer to, returning what the server stored
+    pub async fn upload(&mut self, name: &str, data: &[u8]) -> Result<AttachmentInfo> {
+        attachment::validate_file_name(name)?;
+        let sha256 = attachment::sha256_hex(data);
+        self.send(&ProtocolMessage::AttachmentStart {
+            name: name.to_string(),
+            size: data.len() as u64,
+            sha256: sha256.clone(),
+        })
+        .await?;
+
+        let attachment_id = self
+            .wait_for(|message| match message {
+                ProtocolMessage::AttachmentAccepted { attachment_id } => Some(Ok(attachment_id)),
+                _ => None,
+            })
+            .await?;
+
+        // Servers that answer each chunk have the answers read as we go, so
+        // they do not pile up there; older ones answer only a bad chunk
+        let acked = self.capabilities.iter().any(|c| c == framing::CHUNK_ACKS);
+        let mut unanswered = VecDeque::new();
+        for chunk in attachment::chunks(&attachment_id, data) {
+            if unanswered.len() >= UPLOAD_WINDOW {
+                if let Some(request_id) = unanswered.pop_front() {
+                    self.wait_for_chunk(request_id).await?;
+                }
+            }
+            self.send(&chunk).await?;
+            if acked {
+                unanswered.push_back(self.request_id);
+            }
+        }
+        while let Some(request_id) = unanswered.pop_front() {
+            self.wait_for_chunk(request_id).await?;
+        }
+        self.send(&ProtocolMessage::AttachmentEnd { attachment_id }).await?;
+
+        let stored = self
+            .wait_for(|message| match message {
+                ProtocolMessage::AttachmentStored { attachment } => Some(Ok(attachment)),
+                _ => None,
+            })
+            .await?;
+        if stored.sha256 != sha256 {
+            anyhow::bail!("Server stored a different file than was uploaded");
+        }
+
+        Ok(stored)
+    }
+
+    /// Wait for the server to take in the upload chunk sent as `request_id`
+    async fn wait_for_chunk(&mut self, request_id: u64) -> Result<()> {
+        self.wait_for_reply(request_id, |message| match message {
+            ProtocolMessage::AttachmentChunkReceived { .. } => Some(Ok(())),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Download the file attached to a message, checking it against the message
+    ///
+    /// A file sealed for the connection's key is opened; anything we cannot
+    /// open is returned as it is.
+    pub async fn fetch_attachment(&mut self, info: &AttachmentInfo) -> Result<Vec<u8>> {
+        self.send(&ProtocolMessage::FetchAttachment {
+            attachment_id: info.id.clone(),
+        })
+        .await?;
+
+        let offered = self
+            .wait_for(|message| match message {
+                ProtocolMessage::AttachmentOffer { attachment } => Some(Ok(attachment)),
+                _ => None,
+            })
+            .await?;
+        if offered != *info {
+            anyhow::bail!("Server offered a different file than the message refers to");
+        }
+
+        let mut assembly = Assembly::new(offered);
+        loop {
+            let chunk = self
+                .wait_for(|message| match message {
+                    ProtocolMessage::AttachmentChunk { index, data, sha256, .. } => {
+                        Some(Ok(Some((index, data, sha256))))
+                    }
+                    ProtocolMessage::AttachmentEnd { .. } => Some(Ok(None)),
+                    _ => None,
+                })
+                .await?;
+
+            match chunk {
+                Some((index, data, sha256)) => assembly.add(index, &data, &sha256)?,
+                None => {
+                    let (_, data) = assembly.finish()?;
+                    return Ok(self.open_file(&info.id, data));
+                }
+            }
+        }
+    }
+
+    /// Fetch the encryption keys of the server's clients
+    pub async fn keys(&mut self) -> Result<Vec<PeerKey>> {
+        self.send(&ProtocolMessage::Keys).await?;
+
+        self.wait_for(|message| match message {
+            ProtocolMessage::KeysResponse { keys } => Some(Ok(keys)),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Tell the server a message was received, so it is not redelivered
+    pub async fn acknowledge(&mut self, message_id: &str) -> Result<()> {
+        self.send(&ProtocolMessage::Ack {
+            message_id: message_id.to_string(),
+        })
+        .await
+    }
+
+    /// Acknowledge several messages, waiting until the server has taken them in
+    pub async fn acknowledge_all(&mut self, message_ids: impl IntoIterator<Item = &str>) -> Result<()> {
+        for message_id in message_ids {
+            self.acknowledge(message_id).await?;
+        }
+
+        // Requests are handled in order, so the pong comes after the acks
+        self.send(&ProtocolMessage::Ping).await?;
+        self.wait_for(|message| match message {
+            ProtocolMessage::Pong => Some(Ok(())),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Who a message we sent was delivered to, and who acknowledged it
+    pub async fn delivery_status(&mut self, message_id: &str) -> Result<Vec<Delivery>> {
+        self.send(&ProtocolMessage::DeliveryStatus {
+            message_id: message_id.to_string(),
+        })
+        .await?;
+
+        self.wait_for(|message| match message {
+            ProtocolMessage::DeliveryStatusResponse { deliveries, .. } => Some(Ok(deliveries)),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Open a fetched file if it was sealed for us
+    fn open_file(&self, attachment_id: &str, data: Vec<u8>) -> Vec<u8> {
+        match &self.secret_key {
+            Some(secret_key) if e2e::is_sealed_bytes(&data) => match e2e::open_bytes(&data, secret_key) {
+                Ok(data) => data,
+                Err(e) => {
+                    tracing::debug!("Could not open attachment {}: {:#}", attachment_id, e);
+                    data
+                }
+            },
+            _ => data,
+        }
+    }
+
+    /// Check a received message's signature and open it if it was sealed for us
+    ///
+    /// Forged messages are dropped; anything we cannot open is left as is.
+    fn accept(&self, mut message: Message) -> Option<Message> {
+        if let Err(e) = message.verify_signature(self.server_id.as_deref()) {
+            tracing::warn!("Dropping message {} from {}: {:#}", message.id, message.from, e);
+            return None;
+        }
+
+        if let Some(ref secret_key) = self.secret_key {
+            if e2e::is_sealed(&message.content) {
+                match e2e::open(&message.content, secret_key) {
+                    Ok(content) => message.content = content,
+                    Err(e) => tracing::debug!("Could not open message {}: {:#}", message.id, e),
+                }
+            }
+        }
+        Some(message)
+    }
+
+    /// Pass a streamed message to `handler` (unless it already was), then acknowledge it
+    async fn hand_over<F: FnMut(&Message)>(
+        &mut self,
+        message: Message,
+        cursor: &mut Cursor,
+        handler: &mut F,
+    ) -> Result<()> {
+        let message_id = message.id.clone();
+        if let Some(message) = self.accept(message) {
+            cursor.deliver(&message, handler);
+        }
+        self.acknowledge(&message_id).await
+    }
+
+    /// Fetch queued messages since `since`, and after message `after` if given
+    pub async fn receive(&mut self, since: Option<SystemTime>, after: Option<String>) -> Result<Vec<Message>> {
+        self.receive_on(None, since, after).await
+    }
+
+    /// Ask again for broadcasts the server dropped, for `listen` to hand over
+    async fn catch_up(&mut self, gap: Gap, options: &ListenOptions) -> Result<()> {
+        tracing::warn!("Server dropped {} broadcasts while we were behind; fetching them", gap.dropped);
+        self.send(&ProtocolMessage::Receive {
+            since: Some(gap.since),
+            after: None,
+            channel: options.channel.clone(),
+        })
+        .await
+    }
+
+    /// Like `receive`, but only messages on `channel` if one is given
+    pub async fn receive_on(
+        &mut self,
+        channel: Option<String>,
+        since: Option<SystemTime>,
+        after: Option<String>,
+    ) -> Result<Vec<Message>> {
+        self.send(&ProtocolMessage::Receive { since, after, channel }).await?;
+
+        let messages = self
+            .wait_for(|message| match message {
+                ProtocolMessage::ReceiveResponse { messages } => Some(Ok(messages)),
+                _ => None,
+            })
+            .await?;
+
+        Ok(messages.into_iter().filter_map(|message| self.accept(message)).collect())
+    }
+
+    /// Receive a channel's messages as they are sent
+    pub async fn subscribe(&mut self, channel: &str) -> Result<()> {
+        self.send(&ProtocolMessage::Subscribe {
+            channel: channel.to_string(),
+        })
+        .await?;
+
+        self.wait_for(|message| match message {
+            ProtocolMessage::Subscribed { .. } => Some(Ok(())),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Stop receiving a channel's messages
+    pub async fn unsubscribe(&mut self, channel: &str) -> Result<()> {
+        self.send(&ProtocolMessage::Unsubscribe {
+            channel: channel.to_string(),
+        })
+        .await?;
+
+        self.wait_for(|message| match message {
+            ProtocolMessage::Unsubscribed { .. } => Some(Ok(())),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Deliver every message queued from `since` onward, then each new one, forever
+    ///
+    /// Disconnects are retried with exponential backoff, resuming after the
+    /// last delivered message so nothing is shown twice. Each message is
+    /// acknowledged once `handler` has seen it; the server redelivers any
+    /// that were not on reconnect. Returns only if the server rejects our
+    /// credentials.
+    pub async fn listen<F>(connection: &ConnectionConfig, since: SystemTime, handler: F) -> Result<()>
+    where
+        F: FnMut(&Message),
+    {
+        Self::listen_with(connection, since, &ListenOptions::default(), handler).await
+    }
+
+    /// Like `listen`, under an alias or following only one channel
+    pub async fn listen_with<F>(
+        connection: &ConnectionConfig,
+        since: SystemTime,
+        options: &ListenOptions,
+        mut handler: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&Message),
+    {
+        let mut tor = None;
+        let mut cursor = Cursor::new(since);
+        let mut delay = Duration::from_secs(1);
+
+        loop {
+            let err = match Self::stream(connection, options, &mut tor, &mut cursor, &mut delay, &mut handler).await {
+                Ok(()) => anyhow::anyhow!("Server closed the connection"),
+                Err(e) => e,
+            };
+
+            if err.is::<AuthRejected>() {
+                return Err(err);
+            }
+
+            tracing::warn!("Connection to {} lost: {:#}", connection.server_name, err);
+            tracing::info!("Reconnecting in {:?}...", delay);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    /// One connection's worth of `listen`: returns when the connection drops
+    async fn stream<F>(
+        connection: &ConnectionConfig,
+        options: &ListenOptions,
+        tor: &mut Option<Arc<TorManager>>,
+        cursor: &mut Cursor,
+        delay: &mut Duration,
+        handler: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(&Message),
+    {
+        let mut client = Self::open(connection, tor).await?.with_alias(options.alias.clone());
+        client.authenticate(&connection.code).await?;
+        if let Some(ref channel) = options.channel {
+            client.subscribe(channel).await?;
+        }
+        *delay = Duration::from_secs(1);
+
+        tracing::info!("Listening for messages from {}", connection.server_name);
+
+        // Only the channel's messages, if following one
+        let mut handler = |message: &Message| {
+            if options.channel.is_none() || message.channel == options.channel {
+                handler(message);
+            }
+        };
+        let handler = &mut handler;
+
+        // Redelivered messages may have arrived while subscribing
+        while let Some(message) = client.backlog.pop_front() {
+            client.hand_over(message, cursor, handler).await?;
+        }
+
+        // Catch up on anything missed, then follow broadcasts
+        client
+            .send(&ProtocolMessage::Receive {
+                since: Some(cursor.since),
+                after: cursor.last_id.clone(),
+                channel: options.channel.clone(),
+            })
+            .await?;
+
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        ping.tick().await;
+        let mut last_heard = Instant::now();
+
+        loop {
+            tokio::select! {
+                message = client.recv() => {
+                    let Some(message) = message? else {
+                        return Ok(());
+                    };
+                    last_heard = Instant::now();
+
+                    match message {
+                        ProtocolMessage::ReceiveResponse { messages } => {
+                            for message in messages {
+                                client.hand_over(message, cursor, handler).await?;
+                            }
+                        }
+                        ProtocolMessage::Broadcast { message } => {
+                            client.hand_over(message, cursor, handler).await?;
+                        }
+                        ProtocolMessage::Error { gap: Some(gap), .. } => {
+                            client.catch_up(gap, options).await?;
+                        }
+                        ProtocolMessage::Error { code, message, .. } => {
+                            tracing::warn!("Server error ({}): {}", code, message)
+                        }
+                        _ => {}
+                    }
+                }
+                _ = ping.tick() => {
+                    if last_heard.elapsed() > KEEPALIVE_TIMEOUT {
+                        anyhow::bail!("No response from server for {:?}", last_heard.elapsed());
+                    }
+                    client.send(&ProtocolMessage::Ping).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Position of a listening client in the message stream
+struct Cursor {
+    since: SystemTime,
+    last_id: Option<String>,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Cursor {
+    fn new(since: SystemTime) -> Self {
+        Self {
+            since,
+            last_id: None,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Pass a message to `handler` unless it was already delivered
+    fn deliver<F: FnMut(&Message)>(&mut self, message: &Message, handler: &mut F) {
+        if !self.seen.insert(message.id.clone()) {
+            return;
+        }
+
+        self.order.push_back(message.id.clone());
+        if self.order.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        self.last_id = Some(message.id.clone());
+        handler(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_skips_duplicates() {
+        let mut cursor = Cursor::new(SystemTime::UNIX_EPOCH);
+        let first = Message::new("a".to_string(), "one".to_string(), Duration::from_secs(60));
+        let second = Message::new("a".to_string(), "two".to_string(), Duration::from_secs(60));
+
+        let mut delivered = Vec::new();
+        let mut handler = |m: &Message| delivered.push(m.content.clone());
+
+        cursor.deliver(&first, &mut handler);
+        cursor.deliver(&second, &mut handler);
+        // A resumed stream may repeat a message that was also broadcast
+        cursor.deliver(&second, &mut handler);
+
+        assert_eq!(delivered, vec!["one", "two"]);
+        assert_eq!(cursor.last_id.as_deref(), Some(second.id.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_recv_handles_split_lines() {
+        let (client_side, mut server_side) = tokio::io::duplex(1024);
+        let mut client = RemoteClient::new(Box::new(client_side), "test".to_string());
+
+        let bytes = ProtocolMessage::Pong.to_bytes().unwrap();
+        let (head, tail) = bytes.split_at(4);
+
+        server_side.write_all(head).await.unwrap();
+        // Cancelling a read mid-line must not lose the partial data
+        let pending = tokio::time::timeout(Duration::from_millis(50), client.recv()).await;
+        assert!(pending.is_err());
+
+        server_side.write_all(tail).await.unwrap();
+        assert!(matches!(client.recv().await.unwrap(), Some(ProtocolMessage::Pong)));
+    }
+
+    #[tokio::test]
+    async fn test_refused_or_late_hello_still_settles_framing() {
+        // A server that refuses hello is spoken to in lines without waiting
+        let (client_side, mut server_side) = tokio::io::duplex(4096);
+        let mut client = RemoteClient::new(Box::new(client_side), "test".to_string());
+        let refusal = ProtocolMessage::error(ErrorCode::Protocol, "Unsupported protocol version");
+        server_side.write_all(&framing::encode(&refusal, Framing::Lines).unwrap()).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), client.negotiate()).await.unwrap().unwrap();
+        assert_eq!(client.protocol_version(), LEGACY_PROTOCOL_VERSION);
+
+        // One that answers after we stopped waiting has switched framing all the same
+        let (client_side, mut server_side) = tokio::io::duplex(4096);
+        let mut client =
+            RemoteClient::new(Box::new(client_side), "test".to_string()).with_hello_timeout(Duration::from_millis(50));
+        client.negotiate().await.unwrap();
+        assert_eq!(client.protocol_version(), LEGACY_PROTOCOL_VERSION);
+
+        let hello = ProtocolMessage::Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: framing::capabilities(),
+        };
+        server_side.write_all(&framing::encode(&hello, Framing::Lines).unwrap()).await.unwrap();
+        let pong = framing::encode(&ProtocolMessage::Pong, Framing::LengthPrefixed).unwrap();
+        server_side.write_all(&pong).await.unwrap();
+        assert!(matches!(client.recv().await.unwrap(), Some(ProtocolMessage::Pong)));
+        assert_eq!(client.protocol_version(), PROTOCOL_VERSION);
+    }
+}
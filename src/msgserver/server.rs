@@ -1,8 +1,15 @@
 // Server instances for Fortress and Broker
 
-use crate::msgserver::broker::{BrokerCommand, BrokerHandle, FortressBroker, MessageBroker};
-use crate::msgserver::client::{handle_client_stream, ClientConnection, ClientManager};
-use crate::msgserver::storage::{ServerConfig, ServerStatus, StateManager};
+use crate::msgserver::attachment::{DEFAULT_MAX_ATTACHMENT_SIZE, DEFAULT_MAX_ATTACHMENT_STORAGE};
+use crate::msgserver::broker::{BrokerCommand, BrokerHandle, FortressBroker};
+use crate::msgserver::client::{handle_client_stream, ClientConnection, ClientManager, ClientQueue, INCOMING_QUEUE_CAPACITY};
+use crate::msgserver::handshake::BrokerHandshake;
+use crate::msgserver::hook::{Hook, HookConfig};
+use crate::msgserver::intro::IntroductionServer;
+use crate::msgserver::limits::ClientLimits;
+use crate::msgserver::message::{Envelope, MessageStore, PersistentMessageQueue};
+use crate::msgserver::outbox::{SlowConsumerPolicy, DEFAULT_OUTBOX_CAPACITY};
+use crate::msgserver::storage::{self, BrokerConfig, BrokerStatus, ClientConfig, ServerConfig, ServerStatus, StateManager};
 use crate::msgserver::tor::TorManager;
 use crate::msgserver::cli::MsgSrvCli;
 use anyhow::{Context, Result};
@@ -10,18 +17,50 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, watch, RwLock};
 use uuid::Uuid;
+use futures::stream::BoxStream;
 use futures::StreamExt;
 use tor_hsservice::StreamRequest;
 use tor_proto::client::stream::IncomingStreamRequest;
 use tor_cell::relaycell::msg::Connected;
 
+/// How often a broker re-reads its record, to notice `stop-broker` from other processes
+pub const BROKER_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most messages a server keeps queued
+pub const MAX_QUEUE_SIZE: usize = 1000;
+
+/// Optional settings for a new eddi messaging server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerOptions {
+    /// Command run for each message the server receives
+    pub hook: Option<HookConfig>,
+    /// Keep queued messages in the state database across restarts
+    pub persistent: bool,
+    /// Largest attachment accepted, in bytes (default 10 MiB)
+    pub max_attachment_size: Option<u64>,
+    /// Most bytes of attachments kept at once (default 1 GiB)
+    pub max_attachment_storage: Option<u64>,
+    /// What to do with clients that stop reading their messages
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// Frames queued for each client before the policy applies (default 256)
+    pub client_queue_size: Option<usize>,
+    /// What each client may send
+    pub limits: ClientLimits,
+}
+
 /// A running server instance (Fortress or Broker)
 pub struct ServerInstance {
     config: ServerConfig,
-    broker_handle: BrokerHandle,
+    // Brokers only hand out introductions and have no message broker
+    broker_handle: Option<BrokerHandle>,
+    client_manager: Option<Arc<ClientManager>>,
     shutdown_tx: mpsc::UnboundedSender<()>,
+    // Set once the instance's listener has exited
+    stopped: watch::Receiver<bool>,
+    // Keeps the onion service published while the instance runs
+    _tor: Option<Arc<TorManager>>,
 }
 
 impl ServerInstance {
@@ -32,37 +71,73 @@ impl ServerInstance {
         ttl_minutes: u64,
         state_manager: Arc<StateManager>,
         use_tor: bool,
+        options: ServerOptions,
+    ) -> Result<Self> {
+        let config = ServerConfig {
+            id: Uuid::new_v4().to_string(),
+            name,
+            socket_path,
+            created_at: SystemTime::now(),
+            ttl_minutes,
+            onion_address: None,
+            status: ServerStatus::Running,
+            hook: options.hook,
+            persistent: options.persistent,
+            max_attachment_size: options.max_attachment_size,
+            max_attachment_storage: options.max_attachment_storage,
+            slow_consumer_policy: options.slow_consumer_policy,
+            client_queue_size: options.client_queue_size,
+            limits: options.limits,
+        };
+
+        Self::start_server(config, state_manager, use_tor, true).await
+    }
+
+    /// Restart a server from its stored configuration
+    ///
+    /// The server keeps its ID (and so its clients and access tokens); it
+    /// uses Tor if it had an onion address, whose key is kept in the key store.
+    pub async fn restore_server(config: ServerConfig, state_manager: Arc<StateManager>) -> Result<Self> {
+        let use_tor = config.onion_address.is_some();
+        Self::start_server(config, state_manager, use_tor, false).await
+    }
+
+    /// Start listening for a server, recording it as running
+    async fn start_server(
+        mut config: ServerConfig,
+        state_manager: Arc<StateManager>,
+        use_tor: bool,
+        is_new: bool,
     ) -> Result<Self> {
-        let server_id = Uuid::new_v4().to_string();
+        let server_id = config.id.clone();
+        let socket_path = config.socket_path.clone();
+        let ttl_minutes = config.ttl_minutes;
 
         // Initialize Tor if requested
-        let (onion_address, tor_stream) = if use_tor {
-            tracing::info!("🧅 Initializing Tor for server: {}", name);
+        let (onion_address, tor_stream, tor) = if use_tor {
+            tracing::info!("🧅 Initializing Tor for server: {}", config.name);
 
             let key_dir = MsgSrvCli::state_dir().join("tor-keys");
             let tor = Arc::new(TorManager::new(key_dir).await?);
 
-            let (addr, stream) = tor.create_onion_service(&name).await?;
+            let (addr, stream) = tor.create_onion_service(&config.name).await?;
 
             tracing::info!("🧅 Server onion address: {}", addr);
-            (Some(addr), Some(stream))
+            (Some(addr), Some(stream), Some(tor))
         } else {
             tracing::info!("📍 Server will use Unix sockets only (local access)");
-            (None, None)
+            (None, None, None)
         };
 
-        let config = ServerConfig {
-            id: server_id.clone(),
-            name: name.clone(),
-            socket_path: socket_path.clone(),
-            created_at: SystemTime::now(),
-            ttl_minutes,
-            onion_address: onion_address.clone(),
-            status: ServerStatus::Running,
-        };
+        config.onion_address = onion_address.clone();
+        config.status = ServerStatus::Running;
 
         // Save to state
-        state_manager.create_server(config.clone())?;
+        if is_new {
+            state_manager.create_server(config.clone())?;
+        } else {
+            state_manager.update_server_status(&server_id, ServerStatus::Running)?;
+        }
 
         // Update state with onion address if we have one
         if let Some(ref addr) = onion_address {
@@ -72,12 +147,36 @@ impl ServerInstance {
         // Create broker
         let (broker, handle) = FortressBroker::new(
             Duration::from_secs(ttl_minutes * 60),
-            1000, // Max queue size
+            MAX_QUEUE_SIZE,
             state_manager.clone(),
             server_id.clone(),
         );
+        let broker = match config.hook {
+            Some(ref hook) => broker.with_hook(Hook::new(hook.clone()).with_server(config.name.clone())),
+            None => broker,
+        };
+        let broker = broker
+            .with_max_attachment_size(config.max_attachment_size.unwrap_or(DEFAULT_MAX_ATTACHMENT_SIZE))
+            .with_max_attachment_storage(config.max_attachment_storage.unwrap_or(DEFAULT_MAX_ATTACHMENT_STORAGE))
+            .with_client_queue(
+                config.client_queue_size.unwrap_or(DEFAULT_OUTBOX_CAPACITY),
+                config.slow_consumer_policy,
+            )
+            .with_limits(config.limits.clone());
+        let broker = if config.persistent {
+            let queue = PersistentMessageQueue::new(
+                state_manager.clone(),
+                server_id.clone(),
+                Duration::from_secs(ttl_minutes * 60),
+                MAX_QUEUE_SIZE,
+            )?;
+            broker.with_message_store(Arc::new(queue) as Arc<dyn MessageStore>)
+        } else {
+            broker
+        };
 
         let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel();
+        let (stopped_tx, stopped) = watch::channel(false);
 
         // Get client manager before moving broker
         let client_manager = broker.client_manager();
@@ -89,62 +188,59 @@ impl ServerInstance {
 
         // Spawn Unix socket listener
         let broker_tx = handle.clone();
-        let client_manager_clone = client_manager.clone();
+        let listener_client_manager = client_manager.clone();
         tokio::spawn(async move {
             if let Err(e) = Self::run_listener(
                 socket_path,
                 broker_tx,
-                client_manager,
+                listener_client_manager,
                 &mut shutdown_rx,
             )
             .await
             {
                 tracing::error!("Unix socket listener error: {}", e);
             }
+            let _ = stopped_tx.send(true);
         });
 
         // Spawn Tor onion service listener (if enabled)
-        if let Some(mut stream) = tor_stream {
-            let broker_tx_tor = handle.clone();
-            let socket_path_tor = config.socket_path.clone();
-
-            tokio::spawn(async move {
-                tracing::info!("🧅 Starting onion service listener");
-
-                while let Some(request) = stream.next().await {
-                    let socket_path_clone = socket_path_tor.clone();
-                    let broker_handle_clone = broker_tx_tor.clone();
-                    let client_mgr = client_manager_clone.clone();
-
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_onion_request(
-                            request,
-                            socket_path_clone,
-                            broker_handle_clone,
-                            client_mgr,
-                        ).await {
-                            tracing::error!("Error handling onion request: {}", e);
-                        }
-                    });
-                }
-
-                tracing::info!("🧅 Onion service listener stopped");
-            });
+        if let Some(stream) = tor_stream {
+            Self::spawn_onion_listener(stream, config.socket_path.clone());
         }
 
         Ok(Self {
             config,
-            broker_handle: handle,
+            broker_handle: Some(handle),
+            client_manager: Some(client_manager),
             shutdown_tx,
+            stopped,
+            _tor: tor,
         })
     }
 
+    /// Forward onion service connections to the instance's Unix socket
+    fn spawn_onion_listener(mut stream: BoxStream<'static, StreamRequest>, socket_path: PathBuf) {
+        tokio::spawn(async move {
+            tracing::info!("🧅 Starting onion service listener");
+
+            while let Some(request) = stream.next().await {
+                let socket_path_clone = socket_path.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = Self::handle_onion_request(request, socket_path_clone).await {
+                        tracing::error!("Error handling onion request: {}", e);
+                    }
+                });
+            }
+
+            tracing::info!("🧅 Onion service listener stopped");
+        });
+    }
+
     /// Handle an onion service request
     async fn handle_onion_request(
         stream_request: StreamRequest,
         socket_path: PathBuf,
-        _broker_handle: BrokerHandle,
-        _client_manager: Arc<ClientManager>,
     ) -> Result<()> {
         // Check the stream request type and accept only Begin requests
         match stream_request.request() {
@@ -191,71 +287,198 @@ impl ServerInstance {
     }
 
     /// Create a new Broker server instance (ephemeral)
+    ///
+    /// The broker listens on the Unix socket and, with `use_tor`, the onion
+    /// service derived from its handshake identifier. It is recorded in the
+    /// state database and stops after introducing one client that knows the
+    /// short code to `fortress`, once `timeout` elapses, or when stopped.
     pub async fn new_broker(
-        fortress_id: String,
-        socket_path: PathBuf,
+        fortress: ServerConfig,
+        handshake: BrokerHandshake,
+        state_manager: Arc<StateManager>,
         timeout: Duration,
+        use_tor: bool,
     ) -> Result<Self> {
-        let server_id = Uuid::new_v4().to_string();
+        let identifier = handshake.identifier();
+        let name = format!("broker-{}", identifier);
+
+        let (onion_address, tor_stream, tor) = if use_tor {
+            tracing::info!("🧅 Initializing Tor for broker: {}", name);
+
+            let key_dir = MsgSrvCli::state_dir().join("tor-keys");
+            let tor = Arc::new(TorManager::new(key_dir).await?);
+
+            let (addr, stream) = tor
+                .create_onion_service_with_key(&name, handshake.keypair())
+                .await?;
+
+            tracing::info!("🧅 Broker onion address: {}", addr);
+            (Some(addr), Some(stream), Some(tor))
+        } else {
+            (None, None, None)
+        };
 
         let config = ServerConfig {
-            id: server_id.clone(),
-            name: format!("broker-{}", fortress_id),
-            socket_path: socket_path.clone(),
+            id: Uuid::new_v4().to_string(),
+            name,
+            socket_path: handshake.socket_path(),
             created_at: SystemTime::now(),
-            ttl_minutes: 5,
-            onion_address: None,
+            ttl_minutes: timeout.as_secs().div_ceil(60),
+            onion_address,
             status: ServerStatus::Running,
+            hook: None,
+            persistent: false,
+            max_attachment_size: None,
+            max_attachment_storage: None,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+            client_queue_size: None,
+            limits: ClientLimits::default(),
         };
 
-        // Create a simple broker without state manager
-        let (broker, handle) = MessageBroker::new(
-            Duration::from_secs(300),
-            10,
-            None,
-            Some(server_id.clone()),
-        );
-
-        let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel();
+        let record = BrokerConfig {
+            id: identifier.clone(),
+            fortress_id: fortress.id.clone(),
+            namespace: handshake.namespace().to_string(),
+            code_hash: storage::hash_token(handshake.code()),
+            socket_path: config.socket_path.clone(),
+            onion_address: config.onion_address.clone(),
+            pid: std::process::id(),
+            created_at: config.created_at,
+            expires_at: config.created_at + timeout,
+            status: BrokerStatus::Active,
+        };
+        state_manager.create_broker(&record)?;
 
-        // Get client manager before moving broker
-        let client_manager = broker.client_manager();
+        let server = Arc::new(IntroductionServer::new(handshake, fortress.id, state_manager.clone()));
 
-        // Spawn broker task
-        tokio::spawn(async move {
-            broker.run().await;
-        });
+        let (shutdown_tx, mut shutdown_rx) = mpsc::unbounded_channel();
+        let (stopped_tx, stopped) = watch::channel(false);
 
-        // Spawn listener task with timeout
-        let broker_tx = handle.clone();
-        let shutdown_tx_clone = shutdown_tx.clone();
+        let socket_path = config.socket_path.clone();
         tokio::spawn(async move {
-            // Auto-shutdown after timeout
-            tokio::spawn(async move {
-                tokio::time::sleep(timeout).await;
-                tracing::info!("Broker timeout reached, shutting down");
-                let _ = shutdown_tx_clone.send(());
-            });
-
-            if let Err(e) = Self::run_listener(
+            let status = match Self::run_intro_listener(
                 socket_path,
-                broker_tx,
-                client_manager,
+                server,
+                &state_manager,
+                &identifier,
+                timeout,
                 &mut shutdown_rx,
             )
             .await
             {
-                tracing::error!("Broker listener error: {}", e);
+                Ok(status) => status,
+                Err(e) => {
+                    tracing::error!("Broker listener error: {}", e);
+                    BrokerStatus::Stopped
+                }
+            };
+
+            tracing::info!("Broker {} finished: {}", identifier, status);
+            if let Err(e) = state_manager.update_broker_status(&identifier, status) {
+                tracing::error!("Failed to update broker status: {}", e);
             }
+            let _ = stopped_tx.send(true);
         });
 
+        if let Some(stream) = tor_stream {
+            Self::spawn_onion_listener(stream, config.socket_path.clone());
+        }
+
         Ok(Self {
             config,
-            broker_handle: handle,
+            broker_handle: None,
+            client_manager: None,
             shutdown_tx,
+            stopped,
+            _tor: tor,
         })
     }
 
+    /// Run a broker's Unix socket listener until it has introduced a client
+    ///
+    /// Returns why the broker stopped: it was used, it timed out, or it was
+    /// stopped by this process or (through its record) by another one.
+    async fn run_intro_listener(
+        socket_path: PathBuf,
+        server: Arc<IntroductionServer>,
+        state_manager: &StateManager,
+        identifier: &str,
+        timeout: Duration,
+        shutdown_rx: &mut mpsc::UnboundedReceiver<()>,
+    ) -> Result<BrokerStatus> {
+        // Remove old socket if exists
+        let _ = std::fs::remove_file(&socket_path);
+
+        let listener = UnixListener::bind(&socket_path)
+            .context("Failed to bind broker socket")?;
+
+        tracing::info!("Broker listening on {:?}", socket_path);
+
+        // Told when an introduction leaves the broker with nothing more to do
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        let mut poll = tokio::time::interval(BROKER_POLL_INTERVAL);
+
+        let status = loop {
+            tokio::select! {
+                result = listener.accept() => {
+                    match result {
+                        Ok((stream, _addr)) => {
+                            let server = server.clone();
+                            let done_tx = done_tx.clone();
+                            tokio::spawn(async move {
+                                match server.serve(Box::new(stream)).await {
+                                    Ok(true) => {
+                                        let _ = done_tx.send(BrokerStatus::Used);
+                                    }
+                                    Ok(false) if server.is_exhausted() => {
+                                        let _ = done_tx.send(BrokerStatus::Stopped);
+                                    }
+                                    Ok(false) => {}
+                                    Err(e) => tracing::warn!("Introduction failed: {:#}", e),
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            tracing::error!("Accept error: {}", e);
+                        }
+                    }
+                }
+                Some(status) = done_rx.recv() => {
+                    match status {
+                        BrokerStatus::Used => tracing::info!("Broker introduced a client, shutting down"),
+                        _ => tracing::warn!("Too many wrong codes tried, shutting broker down"),
+                    }
+                    break status;
+                }
+                _ = &mut deadline => {
+                    tracing::info!("Broker timeout reached, shutting down");
+                    break BrokerStatus::Expired;
+                }
+                _ = shutdown_rx.recv() => {
+                    tracing::info!("Broker listener shutting down");
+                    break BrokerStatus::Stopped;
+                }
+                _ = poll.tick() => {
+                    match state_manager.get_broker(identifier) {
+                        Ok(Some(record)) if record.status == BrokerStatus::Active => {}
+                        Ok(_) => {
+                            tracing::info!("Broker stopped externally, shutting down");
+                            break BrokerStatus::Stopped;
+                        }
+                        Err(e) => tracing::warn!("Failed to read broker record: {}", e),
+                    }
+                }
+            }
+        };
+
+        // Cleanup
+        let _ = std::fs::remove_file(&socket_path);
+
+        Ok(status)
+    }
+
     /// Run the Unix socket listener
     async fn run_listener(
         socket_path: PathBuf,
@@ -276,11 +499,12 @@ impl ServerInstance {
                 result = listener.accept() => {
                     match result {
                         Ok((stream, _addr)) => {
-                            Self::handle_connection(
+                            // Serve clients concurrently
+                            tokio::spawn(Self::handle_connection(
                                 stream,
                                 broker_handle.clone(),
                                 client_manager.clone(),
-                            ).await;
+                            ));
                         }
                         Err(e) => {
                             tracing::error!("Accept error: {}", e);
@@ -306,12 +530,14 @@ impl ServerInstance {
         broker_handle: BrokerHandle,
         client_manager: Arc<ClientManager>,
     ) {
-        // Create channels for bidirectional communication
-        let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
-        let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel();
+        // Frames for the client wait in its outbox; frames from it wait for
+        // the broker in a bounded channel, so a flooding client is slowed down
+        let outbox = client_manager.new_outbox();
+        let (incoming_tx, mut incoming_rx) = mpsc::channel::<Envelope>(INCOMING_QUEUE_CAPACITY);
 
         // Create client connection
-        let client = ClientConnection::new(outgoing_tx.clone());
+        // Removing the client from the manager closes its outbox, and so the stream
+        let client = ClientConnection::new(outbox.clone());
         let client_id = client_manager.add_client(client).await;
 
         // Clone for the message handler
@@ -320,12 +546,14 @@ impl ServerInstance {
 
         // Spawn task to forward incoming messages to broker
         tokio::spawn(async move {
-            while let Some(msg) = incoming_rx.recv().await {
+            while let Some(envelope) = incoming_rx.recv().await {
                 if broker_handle_clone
                     .send_command(BrokerCommand::ClientMessage {
                         client_id: client_id_clone.clone(),
-                        message: msg,
+                        request_id: envelope.request_id,
+                        message: Box::new(envelope.message),
                     })
+                    .await
                     .is_err()
                 {
                     break;
@@ -334,14 +562,16 @@ impl ServerInstance {
         });
 
         // Handle client stream
-        if let Err(e) = handle_client_stream(stream, outgoing_rx, incoming_tx).await {
+        if let Err(e) = handle_client_stream(stream, outbox, incoming_tx).await {
             tracing::error!("Client stream error: {}", e);
         }
 
         // Notify broker of disconnect
-        let _ = broker_handle.send_command(BrokerCommand::ClientDisconnected {
-            client_id: client_id.clone(),
-        });
+        let _ = broker_handle
+            .send_command(BrokerCommand::ClientDisconnected {
+                client_id: client_id.clone(),
+            })
+            .await;
     }
 
     /// Get server configuration
@@ -349,15 +579,49 @@ impl ServerInstance {
         &self.config
     }
 
+    /// Every connected client's outbound queue (none for brokers)
+    pub async fn client_queues(&self) -> Vec<ClientQueue> {
+        match &self.client_manager {
+            Some(client_manager) => client_manager.queues().await,
+            None => Vec::new(),
+        }
+    }
+
+    /// Have the broker re-check its clients' access now rather than at its
+    /// next periodic check, dropping revoked ones
+    pub fn enforce_access(&self) -> Result<()> {
+        let Some(broker_handle) = &self.broker_handle else {
+            return Ok(());
+        };
+
+        // A broker too busy to take this now gets to it on its next periodic check
+        match broker_handle.try_send_command(BrokerCommand::EnforceAccess) {
+            Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => Ok(()),
+            Err(mpsc::error::TrySendError::Closed(_)) => anyhow::bail!("Failed to send command to broker"),
+        }
+    }
+
+    /// Wait until the instance has stopped listening
+    ///
+    /// For brokers this is when they were used, timed out or were stopped.
+    pub async fn stopped(&self) {
+        let mut stopped = self.stopped.clone();
+        let _ = stopped.wait_for(|stopped| *stopped).await;
+    }
+
     /// Shutdown the server
     pub async fn shutdown(&self) -> Result<()> {
-        self.broker_handle
-            .send_command(BrokerCommand::Shutdown)
-            .context("Failed to send shutdown command")?;
+        if let Some(broker_handle) = &self.broker_handle {
+            broker_handle
+                .send_command(BrokerCommand::Shutdown)
+                .await
+                .context("Failed to send shutdown command")?;
+        }
 
-        self.shutdown_tx
-            .send(())
-            .context("Failed to send listener shutdown")?;
+        // A broker's listener stops by itself once it is used or times out
+        if self.shutdown_tx.send(()).is_err() && self.broker_handle.is_some() {
+            anyhow::bail!("Failed to send listener shutdown");
+        }
 
         Ok(())
     }
@@ -384,6 +648,18 @@ impl ServerManager {
         name: String,
         ttl_minutes: u64,
         use_tor: bool,
+    ) -> Result<Arc<ServerInstance>> {
+        self.create_server_with_options(name, ttl_minutes, use_tor, ServerOptions::default())
+            .await
+    }
+
+    /// Create a new eddi messaging server with a hook or a persistent queue
+    pub async fn create_server_with_options(
+        &self,
+        name: String,
+        ttl_minutes: u64,
+        use_tor: bool,
+        options: ServerOptions,
     ) -> Result<Arc<ServerInstance>> {
         // Check if server with this name already exists
         if self.state_manager.get_server(&name)?.is_some() {
@@ -398,6 +674,7 @@ impl ServerManager {
             ttl_minutes,
             self.state_manager.clone(),
             use_tor,
+            options,
         )
         .await?;
 
@@ -409,30 +686,42 @@ impl ServerManager {
         Ok(instance)
     }
 
-    /// Create a new broker
+    /// Restart a stored server, e.g. one that was running when the daemon last stopped
+    pub async fn restore_server(&self, config: ServerConfig) -> Result<Arc<ServerInstance>> {
+        let name = config.name.clone();
+        let instance = Arc::new(ServerInstance::restore_server(config, self.state_manager.clone()).await?);
+
+        let mut servers = self.servers.write().await;
+        servers.insert(name, instance.clone());
+
+        Ok(instance)
+    }
+
+    /// Create a new broker introducing clients to `fortress_name`
     pub async fn create_broker(
         &self,
         fortress_name: String,
+        handshake: BrokerHandshake,
         timeout: Duration,
+        use_tor: bool,
     ) -> Result<Arc<ServerInstance>> {
         // Verify fortress exists
         let fortress = self.state_manager.get_server(&fortress_name)?
             .context("Fortress not found")?;
 
-        let broker_name = format!("broker-{}", Uuid::new_v4());
-        let socket_path = self.get_socket_path(&broker_name);
-
         let instance = ServerInstance::new_broker(
-            fortress.id,
-            socket_path,
+            fortress,
+            handshake,
+            self.state_manager.clone(),
             timeout,
+            use_tor,
         )
         .await?;
 
         let instance = Arc::new(instance);
 
         let mut servers = self.servers.write().await;
-        servers.insert(broker_name, instance.clone());
+        servers.insert(instance.config().name.clone(), instance.clone());
 
         Ok(instance)
     }
@@ -471,6 +760,73 @@ impl ServerManager {
         }
     }
 
+    /// Revoke a client of a fortress, by its code or an access token
+    ///
+    /// A fortress running here drops the client's live connections at once;
+    /// one running elsewhere does within its access check interval.
+    pub async fn revoke_client(&self, server_name: &str, code: &str) -> Result<ClientConfig> {
+        let server = self.state_manager.get_server(server_name)?.context("Server not found")?;
+
+        // By code, or by an access token minted during introduction
+        let client = match self.state_manager.get_client_by_code(code)? {
+            Some(client) => client,
+            None => self.state_manager.get_client_by_token(code)?.context("Client not found")?,
+        };
+        if client.server_id != server.id {
+            anyhow::bail!("Client does not belong to server '{}'", server_name);
+        }
+
+        self.state_manager.revoke_client(&client.id)?;
+        if let Some(instance) = self.get_server(server_name).await {
+            instance.enforce_access()?;
+        }
+
+        Ok(client)
+    }
+
+    /// Stop a broker by identifier (or `broker-<identifier>` name)
+    ///
+    /// Works for brokers running in other processes too: they notice their
+    /// record was stopped within `BROKER_POLL_INTERVAL`.
+    pub async fn stop_broker(&self, id: &str) -> Result<()> {
+        let identifier = id.strip_prefix("broker-").unwrap_or(id);
+
+        let record = self.state_manager.get_broker(identifier)?
+            .with_context(|| format!("Broker '{}' not found", id))?;
+        if !record.is_active() {
+            anyhow::bail!("Broker '{}' is not active ({})", id, record.status);
+        }
+
+        self.state_manager.update_broker_status(identifier, BrokerStatus::Stopped)?;
+
+        let instance = {
+            let mut servers = self.servers.write().await;
+            servers.remove(&format!("broker-{}", identifier))
+        };
+        if let Some(instance) = instance {
+            instance.shutdown().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Shut down every instance without recording them as stopped
+    ///
+    /// Used when the daemon exits: its servers are restored when it starts again.
+    pub async fn shutdown_all(&self) {
+        let instances: Vec<_> = {
+            let mut servers = self.servers.write().await;
+            servers.drain().map(|(_, instance)| instance).collect()
+        };
+
+        for instance in instances {
+            if let Err(e) = instance.shutdown().await {
+                tracing::warn!("Failed to shut down {}: {}", instance.config().name, e);
+            }
+            instance.stopped().await;
+        }
+    }
+
     /// Get socket path for a server
     fn get_socket_path(&self, name: &str) -> PathBuf {
         PathBuf::from(format!("/tmp/eddi-msgsrv-{}.sock", name))
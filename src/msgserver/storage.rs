@@ -1,9 +1,19 @@
 // Persistent state management using SQLite
 
+use crate::msgserver::e2e::{PublicKey, SecretKey};
+use crate::msgserver::hook::HookConfig;
+use crate::msgserver::limits::{ClientLimits, Queued};
+use crate::msgserver::outbox::SlowConsumerPolicy;
+use crate::msgserver::identity::{Identity, IdentityKey, MessageSignature, Trust};
+use crate::msgserver::message::{Delivery, Message};
 use anyhow::{Context, Result};
 use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::SystemTime;
 use uuid::Uuid;
 
@@ -17,6 +27,28 @@ pub struct ServerConfig {
     pub ttl_minutes: u64,
     pub onion_address: Option<String>,
     pub status: ServerStatus,
+    /// Command run for each message the server receives
+    #[serde(default)]
+    pub hook: Option<HookConfig>,
+    /// Keep queued messages in the database so they survive restarts
+    #[serde(default)]
+    pub persistent: bool,
+    /// Largest attachment accepted, in bytes (default: `DEFAULT_MAX_ATTACHMENT_SIZE`)
+    #[serde(default)]
+    pub max_attachment_size: Option<u64>,
+    /// Most bytes of attachments kept at once (default:
+    /// `DEFAULT_MAX_ATTACHMENT_STORAGE`)
+    #[serde(default)]
+    pub max_attachment_storage: Option<u64>,
+    /// What to do with clients that stop reading their messages
+    #[serde(default)]
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// Frames queued for each client (default: `DEFAULT_OUTBOX_CAPACITY`)
+    #[serde(default)]
+    pub client_queue_size: Option<usize>,
+    /// What each client may send
+    #[serde(default)]
+    pub limits: ClientLimits,
 }
 
 /// Server status
@@ -28,15 +60,18 @@ pub enum ServerStatus {
     Error,
 }
 
-impl ServerStatus {
-    pub fn to_string(&self) -> String {
-        match self {
-            ServerStatus::Running => "running".to_string(),
-            ServerStatus::Stopped => "stopped".to_string(),
-            ServerStatus::Error => "error".to_string(),
-        }
+impl std::fmt::Display for ServerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ServerStatus::Running => "running",
+            ServerStatus::Stopped => "stopped",
+            ServerStatus::Error => "error",
+        };
+        f.write_str(s)
     }
+}
 
+impl ServerStatus {
     pub fn from_string(s: &str) -> Self {
         match s {
             "running" => ServerStatus::Running,
@@ -65,27 +100,101 @@ pub enum ClientStatus {
     Pending,
     Connected,
     Disconnected,
+    Revoked,
 }
 
-impl ClientStatus {
-    pub fn to_string(&self) -> String {
-        match self {
-            ClientStatus::Pending => "pending".to_string(),
-            ClientStatus::Connected => "connected".to_string(),
-            ClientStatus::Disconnected => "disconnected".to_string(),
-        }
+impl std::fmt::Display for ClientStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ClientStatus::Pending => "pending",
+            ClientStatus::Connected => "connected",
+            ClientStatus::Disconnected => "disconnected",
+            ClientStatus::Revoked => "revoked",
+        };
+        f.write_str(s)
     }
+}
 
+impl ClientStatus {
     pub fn from_string(s: &str) -> Self {
         match s {
             "pending" => ClientStatus::Pending,
             "connected" => ClientStatus::Connected,
             "disconnected" => ClientStatus::Disconnected,
+            "revoked" => ClientStatus::Revoked,
             _ => ClientStatus::Disconnected,
         }
     }
 }
 
+/// Broker configuration (an ephemeral introducer for a fortress)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrokerConfig {
+    /// Derived broker identifier
+    pub id: String,
+    pub fortress_id: String,
+    pub namespace: String,
+    /// Hash of the short code; the code itself is only shown at creation
+    pub code_hash: String,
+    pub socket_path: PathBuf,
+    pub onion_address: Option<String>,
+    /// Process running the broker's listener
+    pub pid: u32,
+    pub created_at: SystemTime,
+    pub expires_at: SystemTime,
+    pub status: BrokerStatus,
+}
+
+impl BrokerConfig {
+    /// Whether the broker is still waiting for a client
+    ///
+    /// A broker whose timeout passed or whose process died without updating
+    /// its record is no longer active, whatever its stored status says.
+    pub fn is_active(&self) -> bool {
+        self.status == BrokerStatus::Active
+            && self.expires_at > SystemTime::now()
+            && process_alive(self.pid)
+    }
+}
+
+/// Broker status
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BrokerStatus {
+    /// Waiting for a client
+    Active,
+    /// Introduced a client and shut down
+    Used,
+    /// Timed out without introducing anyone
+    Expired,
+    /// Stopped by the operator
+    Stopped,
+}
+
+impl std::fmt::Display for BrokerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            BrokerStatus::Active => "active",
+            BrokerStatus::Used => "used",
+            BrokerStatus::Expired => "expired",
+            BrokerStatus::Stopped => "stopped",
+        };
+        f.write_str(s)
+    }
+}
+
+impl BrokerStatus {
+    pub fn from_string(s: &str) -> Self {
+        match s {
+            "active" => BrokerStatus::Active,
+            "used" => BrokerStatus::Used,
+            "expired" => BrokerStatus::Expired,
+            "stopped" => BrokerStatus::Stopped,
+            _ => BrokerStatus::Stopped,
+        }
+    }
+}
+
 /// Connection configuration (for clients connecting to remote servers)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionConfig {
@@ -97,8 +206,22 @@ pub struct ConnectionConfig {
     pub onion_address: Option<String>,
     pub connected_at: SystemTime,
     pub status: ClientStatus,
+    /// Key messages sealed for this client are opened with
+    #[serde(default)]
+    pub secret_key: Option<SecretKey>,
+    /// Encryption keys of the fortress's clients: those handed over at the
+    /// introduction, and any trusted on first use since
+    #[serde(default)]
+    pub peer_keys: Vec<PublicKey>,
+    /// Keys direct messages are sealed with, pinned by alias on first use
+    #[serde(default)]
+    pub alias_keys: BTreeMap<String, PublicKey>,
 }
 
+/// How long signatures are remembered to catch replays; older ones cannot
+/// be told apart from replays
+pub const SEEN_SIGNATURE_RETENTION: std::time::Duration = std::time::Duration::from_secs(30 * 24 * 3600);
+
 /// State manager for persistent storage
 pub struct StateManager {
     db_path: PathBuf,
@@ -138,10 +261,24 @@ impl StateManager {
                 created_at INTEGER NOT NULL,
                 ttl_minutes INTEGER NOT NULL,
                 onion_address TEXT,
-                status TEXT NOT NULL
+                status TEXT NOT NULL,
+                hook TEXT,
+                persistent INTEGER NOT NULL DEFAULT 0,
+                max_attachment_size INTEGER,
+                slow_consumer_policy TEXT NOT NULL DEFAULT 'disconnect',
+                client_queue_size INTEGER,
+                limits TEXT,
+                max_attachment_storage INTEGER
             )",
             [],
         )?;
+        add_column_if_missing(&conn, "servers", "hook", "TEXT")?;
+        add_column_if_missing(&conn, "servers", "persistent", "INTEGER NOT NULL DEFAULT 0")?;
+        add_column_if_missing(&conn, "servers", "max_attachment_size", "INTEGER")?;
+        add_column_if_missing(&conn, "servers", "slow_consumer_policy", "TEXT NOT NULL DEFAULT 'disconnect'")?;
+        add_column_if_missing(&conn, "servers", "client_queue_size", "INTEGER")?;
+        add_column_if_missing(&conn, "servers", "limits", "TEXT")?;
+        add_column_if_missing(&conn, "servers", "max_attachment_storage", "INTEGER")?;
 
         // Clients table (authentication codes for server)
         conn.execute(
@@ -152,10 +289,154 @@ impl StateManager {
                 created_at INTEGER NOT NULL,
                 connected_at INTEGER,
                 status TEXT NOT NULL,
+                public_key TEXT,
+                FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE
+            )",
+            [],
+        )?;
+        add_column_if_missing(&conn, "clients", "public_key", "TEXT")?;
+
+        // Access tokens handed out by brokers (only their hashes are stored)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS access_tokens (
+                token_hash TEXT PRIMARY KEY,
+                client_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                FOREIGN KEY (client_id) REFERENCES clients(id) ON DELETE CASCADE
+            )",
+            [],
+        )?;
+
+        // Aliases, each kept by the client that first claimed it on a server
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS client_aliases (
+                server_id TEXT NOT NULL,
+                alias TEXT NOT NULL,
+                client_id TEXT NOT NULL,
+                claimed_at INTEGER NOT NULL,
+                PRIMARY KEY (server_id, alias)
+            )",
+            [],
+        )?;
+
+        // Brokers table (ephemeral introducers for a fortress)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS brokers (
+                id TEXT PRIMARY KEY,
+                fortress_id TEXT NOT NULL,
+                namespace TEXT NOT NULL,
+                code TEXT NOT NULL, -- hashed
+                socket_path TEXT NOT NULL,
+                onion_address TEXT,
+                pid INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                status TEXT NOT NULL,
+                FOREIGN KEY (fortress_id) REFERENCES servers(id) ON DELETE CASCADE
+            )",
+            [],
+        )?;
+        // Records from before codes were hashed held the code itself
+        conn.execute("DELETE FROM brokers WHERE length(code) != 64", [])?;
+
+        // Messages queued by persistent servers (times in Unix nanoseconds)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS messages (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                id TEXT UNIQUE NOT NULL,
+                server_id TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                content TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                recipient TEXT,
+                channel TEXT,
+                signature TEXT,
+                attachment TEXT,
                 FOREIGN KEY (server_id) REFERENCES servers(id) ON DELETE CASCADE
             )",
             [],
         )?;
+        add_column_if_missing(&conn, "messages", "recipient", "TEXT")?;
+        add_column_if_missing(&conn, "messages", "channel", "TEXT")?;
+        add_column_if_missing(&conn, "messages", "signature", "TEXT")?;
+        add_column_if_missing(&conn, "messages", "attachment", "TEXT")?;
+        // Who the message counts against for quotas, and how many bytes
+        add_column_if_missing(&conn, "messages", "sender_id", "TEXT")?;
+        add_column_if_missing(&conn, "messages", "queued_bytes", "INTEGER NOT NULL DEFAULT 0")?;
+        // The attached file's ID, to find the message by when it is fetched
+        add_column_if_missing(&conn, "messages", "attachment_id", "TEXT")?;
+
+        // Files attached to stored messages, kept until their message expires
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS attachments (
+                id TEXT PRIMARY KEY,
+                server_id TEXT NOT NULL,
+                data BLOB NOT NULL,
+                expires_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // Who each stored message was delivered to, and who acknowledged it
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS deliveries (
+                message_id TEXT NOT NULL,
+                server_id TEXT NOT NULL,
+                recipient TEXT NOT NULL,
+                alias TEXT,
+                delivered_at INTEGER NOT NULL,
+                acked_at INTEGER,
+                PRIMARY KEY (message_id, recipient)
+            )",
+            [],
+        )?;
+
+        // Last message each recipient of a persistent server acknowledged
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS delivery_cursors (
+                server_id TEXT NOT NULL,
+                recipient TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                PRIMARY KEY (server_id, recipient)
+            )",
+            [],
+        )?;
+
+        // This installation's signing identity (a single row)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS identity (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                secret TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        // Sender keys pinned the first time each alias was seen on a server
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS pinned_keys (
+                server_name TEXT NOT NULL,
+                alias TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                pinned_at INTEGER NOT NULL,
+                PRIMARY KEY (server_name, alias)
+            )",
+            [],
+        )?;
+
+        // Signatures of received messages, to spot one replayed on another
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS seen_signatures (
+                server_name TEXT NOT NULL,
+                signature TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                signed_at INTEGER NOT NULL,
+                PRIMARY KEY (server_name, signature)
+            )",
+            [],
+        )?;
 
         // Connections table (client connections to remote servers)
         conn.execute(
@@ -167,10 +448,16 @@ impl StateManager {
                 socket_path TEXT,
                 onion_address TEXT,
                 connected_at INTEGER NOT NULL,
-                status TEXT NOT NULL
+                status TEXT NOT NULL,
+                secret_key TEXT,
+                peer_keys TEXT,
+                alias_keys TEXT
             )",
             [],
         )?;
+        add_column_if_missing(&conn, "connections", "secret_key", "TEXT")?;
+        add_column_if_missing(&conn, "connections", "peer_keys", "TEXT")?;
+        add_column_if_missing(&conn, "connections", "alias_keys", "TEXT")?;
 
         // Create indices
         conn.execute(
@@ -185,6 +472,38 @@ impl StateManager {
             "CREATE INDEX IF NOT EXISTS idx_clients_code ON clients(code)",
             [],
         )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_access_tokens_client_id ON access_tokens(client_id)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_brokers_fortress_id ON brokers(fortress_id)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_messages_server_expires ON messages(server_id, expires_at)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_messages_expires_at ON messages(expires_at)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_messages_server_sender ON messages(server_id, sender_id)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_messages_server_attachment ON messages(server_id, attachment_id)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_deliveries_server_id ON deliveries(server_id)",
+            [],
+        )?;
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_seen_signatures_signed_at ON seen_signatures(signed_at)",
+            [],
+        )?;
 
         Ok(())
     }
@@ -201,8 +520,10 @@ impl StateManager {
             .as_secs() as i64;
 
         conn.execute(
-            "INSERT INTO servers (id, name, socket_path, created_at, ttl_minutes, onion_address, status)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT INTO servers (id, name, socket_path, created_at, ttl_minutes, onion_address, status, hook, persistent,
+                                  max_attachment_size, slow_consumer_policy, client_queue_size, limits,
+                                  max_attachment_storage)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)",
             params![
                 config.id,
                 config.name,
@@ -211,6 +532,13 @@ impl StateManager {
                 config.ttl_minutes as i64,
                 config.onion_address,
                 config.status.to_string(),
+                config.hook.as_ref().map(serde_json::to_string).transpose()?,
+                config.persistent,
+                config.max_attachment_size.map(|size| size as i64),
+                config.slow_consumer_policy.to_string(),
+                config.client_queue_size.map(|size| size as i64),
+                serde_json::to_string(&config.limits)?,
+                config.max_attachment_storage.map(|size| size as i64),
             ],
         )?;
 
@@ -223,7 +551,9 @@ impl StateManager {
 
         let result: Option<ServerConfig> = conn
             .query_row(
-                "SELECT id, name, socket_path, created_at, ttl_minutes, onion_address, status
+                "SELECT id, name, socket_path, created_at, ttl_minutes, onion_address, status, hook, persistent,
+                        max_attachment_size, slow_consumer_policy, client_queue_size, limits,
+                        max_attachment_storage
                  FROM servers WHERE name = ?1",
                 params![name],
                 |row| {
@@ -238,6 +568,13 @@ impl StateManager {
                         ttl_minutes: row.get::<_, i64>(4)? as u64,
                         onion_address: row.get(5)?,
                         status: ServerStatus::from_string(&row.get::<_, String>(6)?),
+                        hook: json_from_column(row, 7)?,
+                        persistent: row.get(8)?,
+                        max_attachment_size: row.get::<_, Option<i64>>(9)?.map(|size| size as u64),
+                        max_attachment_storage: row.get::<_, Option<i64>>(13)?.map(|size| size as u64),
+                        slow_consumer_policy: SlowConsumerPolicy::from_string(&row.get::<_, String>(10)?),
+                        client_queue_size: row.get::<_, Option<i64>>(11)?.map(|size| size as usize),
+                        limits: json_from_column(row, 12)?.unwrap_or_default(),
                     })
                 },
             )
@@ -252,7 +589,9 @@ impl StateManager {
 
         let result: Option<ServerConfig> = conn
             .query_row(
-                "SELECT id, name, socket_path, created_at, ttl_minutes, onion_address, status
+                "SELECT id, name, socket_path, created_at, ttl_minutes, onion_address, status, hook, persistent,
+                        max_attachment_size, slow_consumer_policy, client_queue_size, limits,
+                        max_attachment_storage
                  FROM servers WHERE id = ?1",
                 params![id],
                 |row| {
@@ -267,6 +606,13 @@ impl StateManager {
                         ttl_minutes: row.get::<_, i64>(4)? as u64,
                         onion_address: row.get(5)?,
                         status: ServerStatus::from_string(&row.get::<_, String>(6)?),
+                        hook: json_from_column(row, 7)?,
+                        persistent: row.get(8)?,
+                        max_attachment_size: row.get::<_, Option<i64>>(9)?.map(|size| size as u64),
+                        max_attachment_storage: row.get::<_, Option<i64>>(13)?.map(|size| size as u64),
+                        slow_consumer_policy: SlowConsumerPolicy::from_string(&row.get::<_, String>(10)?),
+                        client_queue_size: row.get::<_, Option<i64>>(11)?.map(|size| size as usize),
+                        limits: json_from_column(row, 12)?.unwrap_or_default(),
                     })
                 },
             )
@@ -280,7 +626,9 @@ impl StateManager {
         let conn = self.get_connection()?;
 
         let mut stmt = conn.prepare(
-            "SELECT id, name, socket_path, created_at, ttl_minutes, onion_address, status
+            "SELECT id, name, socket_path, created_at, ttl_minutes, onion_address, status, hook, persistent,
+                        max_attachment_size, slow_consumer_policy, client_queue_size, limits,
+                        max_attachment_storage
              FROM servers ORDER BY created_at DESC",
         )?;
 
@@ -297,6 +645,13 @@ impl StateManager {
                     ttl_minutes: row.get::<_, i64>(4)? as u64,
                     onion_address: row.get(5)?,
                     status: ServerStatus::from_string(&row.get::<_, String>(6)?),
+                    hook: json_from_column(row, 7)?,
+                    persistent: row.get(8)?,
+                    max_attachment_size: row.get::<_, Option<i64>>(9)?.map(|size| size as u64),
+                    max_attachment_storage: row.get::<_, Option<i64>>(13)?.map(|size| size as u64),
+                    slow_consumer_policy: SlowConsumerPolicy::from_string(&row.get::<_, String>(10)?),
+                    client_queue_size: row.get::<_, Option<i64>>(11)?.map(|size| size as usize),
+                    limits: json_from_column(row, 12)?.unwrap_or_default(),
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
@@ -329,10 +684,33 @@ impl StateManager {
     }
 
     /// Delete server
+    ///
+    /// Also deletes its clients, brokers and stored messages: foreign keys are
+    /// not enforced, so nothing cascades by itself.
     pub fn delete_server(&self, name: &str) -> Result<()> {
-        let conn = self.get_connection()?;
+        let mut conn = self.get_connection()?;
+        let tx = conn.transaction()?;
 
-        conn.execute("DELETE FROM servers WHERE name = ?1", params![name])?;
+        let id: Option<String> = tx
+            .query_row("SELECT id FROM servers WHERE name = ?1", params![name], |row| row.get(0))
+            .optional()?;
+        let Some(id) = id else {
+            return Ok(());
+        };
+
+        tx.execute(
+            "DELETE FROM access_tokens WHERE client_id IN (SELECT id FROM clients WHERE server_id = ?1)",
+            params![id],
+        )?;
+        tx.execute("DELETE FROM clients WHERE server_id = ?1", params![id])?;
+        tx.execute("DELETE FROM client_aliases WHERE server_id = ?1", params![id])?;
+        tx.execute("DELETE FROM brokers WHERE fortress_id = ?1", params![id])?;
+        tx.execute("DELETE FROM messages WHERE server_id = ?1", params![id])?;
+        tx.execute("DELETE FROM attachments WHERE server_id = ?1", params![id])?;
+        tx.execute("DELETE FROM deliveries WHERE server_id = ?1", params![id])?;
+        tx.execute("DELETE FROM delivery_cursors WHERE server_id = ?1", params![id])?;
+        tx.execute("DELETE FROM servers WHERE id = ?1", params![id])?;
+        tx.commit()?;
 
         Ok(())
     }
@@ -342,35 +720,20 @@ impl StateManager {
     /// Create a new client authentication code
     pub fn create_client(&self, server_id: &str) -> Result<ClientConfig> {
         let conn = self.get_connection()?;
+        insert_client(&conn, server_id)
+    }
 
-        let client = ClientConfig {
-            id: Uuid::new_v4().to_string(),
-            server_id: server_id.to_string(),
-            code: generate_client_code(),
-            created_at: SystemTime::now(),
-            connected_at: None,
-            status: ClientStatus::Pending,
-        };
-
-        let created_at = client.created_at
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+    /// Delete a client with its access tokens and aliases
+    pub fn delete_client(&self, id: &str) -> Result<()> {
+        let mut conn = self.get_connection()?;
+        let tx = conn.transaction()?;
 
-        conn.execute(
-            "INSERT INTO clients (id, server_id, code, created_at, connected_at, status)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![
-                client.id,
-                client.server_id,
-                client.code,
-                created_at,
-                None::<i64>,
-                client.status.to_string(),
-            ],
-        )?;
+        tx.execute("DELETE FROM access_tokens WHERE client_id = ?1", params![id])?;
+        tx.execute("DELETE FROM client_aliases WHERE client_id = ?1", params![id])?;
+        tx.execute("DELETE FROM clients WHERE id = ?1", params![id])?;
+        tx.commit()?;
 
-        Ok(client)
+        Ok(())
     }
 
     /// Get client by code
@@ -406,6 +769,39 @@ impl StateManager {
         Ok(result)
     }
 
+    /// Get client by ID
+    pub fn get_client_by_id(&self, id: &str) -> Result<Option<ClientConfig>> {
+        let conn = self.get_connection()?;
+
+        let result: Option<ClientConfig> = conn
+            .query_row(
+                "SELECT id, server_id, code, created_at, connected_at, status
+                 FROM clients WHERE id = ?1",
+                params![id],
+                |row| {
+                    let created_at = SystemTime::UNIX_EPOCH
+                        + std::time::Duration::from_secs(row.get::<_, i64>(3)? as u64);
+
+                    let connected_at: Option<i64> = row.get(4)?;
+                    let connected_at = connected_at.map(|t| {
+                        SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(t as u64)
+                    });
+
+                    Ok(ClientConfig {
+                        id: row.get(0)?,
+                        server_id: row.get(1)?,
+                        code: row.get(2)?,
+                        created_at,
+                        connected_at,
+                        status: ClientStatus::from_string(&row.get::<_, String>(5)?),
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(result)
+    }
+
     /// List clients for a server
     pub fn list_clients(&self, server_id: &str) -> Result<Vec<ClientConfig>> {
         let conn = self.get_connection()?;
@@ -462,106 +858,1012 @@ impl StateManager {
         Ok(())
     }
 
-    // ========== Connection Management ==========
-
-    /// Create a new connection
-    pub fn create_connection(&self, config: ConnectionConfig) -> Result<()> {
+    /// Revoke a client: its code and access tokens stop working
+    pub fn revoke_client(&self, id: &str) -> Result<()> {
         let conn = self.get_connection()?;
 
-        let connected_at = config.connected_at
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-
         conn.execute(
-            "INSERT INTO connections (id, server_name, alias, code, socket_path, onion_address, connected_at, status)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            "UPDATE clients SET status = ?1 WHERE id = ?2",
+            params![ClientStatus::Revoked.to_string(), id],
+        )?;
+
+        Ok(())
+    }
+
+    // ========== Access Tokens ==========
+
+    /// Register a new client for `server_id` holding `token` until `expires_at`
+    ///
+    /// Only a hash of the token is stored.
+    pub fn create_access_token(
+        &self,
+        server_id: &str,
+        token: &str,
+        expires_at: SystemTime,
+    ) -> Result<ClientConfig> {
+        let mut conn = self.get_connection()?;
+        let tx = conn.transaction()?;
+
+        let client = insert_client(&tx, server_id)?;
+        tx.execute(
+            "INSERT INTO access_tokens (token_hash, client_id, created_at, expires_at)
+             VALUES (?1, ?2, ?3, ?4)",
             params![
-                config.id,
-                config.server_name,
-                config.alias,
-                config.code,
-                config.socket_path.as_ref().map(|p| p.to_string_lossy().to_string()),
-                config.onion_address,
-                connected_at,
-                config.status.to_string(),
+                hash_token(token),
+                client.id,
+                unix_seconds(client.created_at),
+                unix_seconds(expires_at),
             ],
         )?;
+        tx.commit()?;
 
-        Ok(())
+        Ok(client)
     }
 
-    /// Get connection configuration by alias or server name
-    pub fn get_connection_config(&self, name: &str) -> Result<Option<ConnectionConfig>> {
+    /// Get the client holding an access token (expired or not)
+    pub fn get_client_by_token(&self, token: &str) -> Result<Option<ClientConfig>> {
         let conn = self.get_connection()?;
 
-        let result: Option<ConnectionConfig> = conn
+        let client_id: Option<String> = conn
             .query_row(
-                "SELECT id, server_name, alias, code, socket_path, onion_address, connected_at, status
-                 FROM connections WHERE server_name = ?1 OR alias = ?1",
-                params![name],
-                |row| {
-                    let connected_at = SystemTime::UNIX_EPOCH
-                        + std::time::Duration::from_secs(row.get::<_, i64>(6)? as u64);
-
-                    Ok(ConnectionConfig {
-                        id: row.get(0)?,
-                        server_name: row.get(1)?,
-                        alias: row.get(2)?,
-                        code: row.get(3)?,
-                        socket_path: row.get::<_, Option<String>>(4)?.map(PathBuf::from),
-                        onion_address: row.get(5)?,
-                        connected_at,
-                        status: ClientStatus::from_string(&row.get::<_, String>(7)?),
-                    })
-                },
+                "SELECT client_id FROM access_tokens WHERE token_hash = ?1",
+                params![hash_token(token)],
+                |row| row.get(0),
             )
             .optional()?;
 
-        Ok(result)
+        match client_id {
+            Some(id) => self.get_client_by_id(&id),
+            None => Ok(None),
+        }
     }
 
-    /// List all connections
-    pub fn list_connections(&self) -> Result<Vec<ConnectionConfig>> {
-        let conn = self.get_connection()?;
-
-        let mut stmt = conn.prepare(
-            "SELECT id, server_name, alias, code, socket_path, onion_address, connected_at, status
-             FROM connections ORDER BY connected_at DESC",
-        )?;
-
-        let connections = stmt
-            .query_map([], |row| {
-                let connected_at = SystemTime::UNIX_EPOCH
-                    + std::time::Duration::from_secs(row.get::<_, i64>(6)? as u64);
+    /// Check a code or access token presented to `server_id`
+    ///
+    /// Returns the client it belongs to if it is for this server, has not
+    /// been revoked, and (for tokens) has not expired.
+    pub fn authenticate_client(&self, server_id: &str, credential: &str) -> Result<Option<ClientConfig>> {
+        let client = match self.get_client_by_token(credential)? {
+            Some(client) => client,
+            None => match self.get_client_by_code(credential)? {
+                // Token-backed clients never hand out their code, so it must not work
+                Some(client) if !self.client_has_tokens(&client.id)? => client,
+                _ => return Ok(None),
+            },
+        };
 
-                Ok(ConnectionConfig {
-                    id: row.get(0)?,
-                    server_name: row.get(1)?,
-                    alias: row.get(2)?,
-                    code: row.get(3)?,
-                    socket_path: row.get::<_, Option<String>>(4)?.map(PathBuf::from),
-                    onion_address: row.get(5)?,
-                    connected_at,
-                    status: ClientStatus::from_string(&row.get::<_, String>(7)?),
-                })
-            })?
-            .collect::<Result<Vec<_>, _>>()?;
+        if client.server_id != server_id || !self.client_access_valid(&client.id)? {
+            return Ok(None);
+        }
 
-        Ok(connections)
+        Ok(Some(client))
     }
 
-    /// Delete connection
-    pub fn delete_connection(&self, name: &str) -> Result<()> {
+    /// Bind an alias on a server to a client, unless another client has it
+    ///
+    /// Returns false if the alias already belongs to a different client.
+    pub fn claim_alias(&self, server_id: &str, alias: &str, client_id: &str) -> Result<bool> {
         let conn = self.get_connection()?;
 
         conn.execute(
-            "DELETE FROM connections WHERE server_name = ?1 OR alias = ?1",
-            params![name],
+            "INSERT OR IGNORE INTO client_aliases (server_id, alias, client_id, claimed_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![server_id, alias, client_id, unix_seconds(SystemTime::now())],
+        )?;
+        let owner: String = conn.query_row(
+            "SELECT client_id FROM client_aliases WHERE server_id = ?1 AND alias = ?2",
+            params![server_id, alias],
+            |row| row.get(0),
+        )?;
+
+        Ok(owner == client_id)
+    }
+
+    /// Aliases claimed on a server, with the client each belongs to
+    pub fn list_client_aliases(&self, server_id: &str) -> Result<Vec<(String, String)>> {
+        let conn = self.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT alias, client_id FROM client_aliases WHERE server_id = ?1 ORDER BY alias",
+        )?;
+
+        let aliases = stmt
+            .query_map(params![server_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(aliases)
+    }
+
+    /// Whether a client was created for an access token
+    fn client_has_tokens(&self, id: &str) -> Result<bool> {
+        let conn = self.get_connection()?;
+
+        let exists = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM access_tokens WHERE client_id = ?1)",
+            params![id],
+            |row| row.get::<_, bool>(0),
+        )?;
+
+        Ok(exists)
+    }
+
+    /// Whether a client may still use its server
+    ///
+    /// False once it has been revoked, or when all its access tokens have
+    /// expired. Clients created with a plain code never expire.
+    pub fn client_access_valid(&self, id: &str) -> Result<bool> {
+        let conn = self.get_connection()?;
+
+        let valid = conn
+            .query_row(
+                "SELECT c.status != ?2
+                        AND (NOT EXISTS (SELECT 1 FROM access_tokens t WHERE t.client_id = c.id)
+                             OR EXISTS (SELECT 1 FROM access_tokens t
+                                        WHERE t.client_id = c.id AND t.expires_at > ?3))
+                 FROM clients c WHERE c.id = ?1",
+                params![id, ClientStatus::Revoked.to_string(), unix_seconds(SystemTime::now())],
+                |row| row.get::<_, bool>(0),
+            )
+            .optional()?;
+
+        Ok(valid.unwrap_or(false))
+    }
+
+    /// Record the key messages to a client are encrypted with
+    pub fn set_client_key(&self, id: &str, public_key: &PublicKey) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        conn.execute(
+            "UPDATE clients SET public_key = ?1 WHERE id = ?2",
+            params![public_key.to_string(), id],
+        )?;
+
+        Ok(())
+    }
+
+    /// (client ID, public key) for each unrevoked client of a server with a key
+    pub fn list_client_keys(&self, server_id: &str) -> Result<Vec<(String, PublicKey)>> {
+        let conn = self.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, public_key FROM clients
+             WHERE server_id = ?1 AND public_key IS NOT NULL AND status != ?2
+             ORDER BY created_at",
+        )?;
+
+        let keys = stmt
+            .query_map(params![server_id, ClientStatus::Revoked.to_string()], |row| {
+                Ok((row.get::<_, String>(0)?, key_from_column(row, 1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(keys.into_iter().filter_map(|(id, key)| Some((id, key?))).collect())
+    }
+
+    // ========== Broker Management ==========
+
+    /// Record a new broker
+    ///
+    /// Replaces any earlier record with the same identifier, since the same
+    /// code, namespace and minute always derive the same broker.
+    pub fn create_broker(&self, config: &BrokerConfig) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        conn.execute(
+            "INSERT OR REPLACE INTO brokers
+             (id, fortress_id, namespace, code, socket_path, onion_address, pid, created_at, expires_at, status)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                config.id,
+                config.fortress_id,
+                config.namespace,
+                config.code_hash,
+                config.socket_path.to_string_lossy().to_string(),
+                config.onion_address,
+                config.pid as i64,
+                unix_seconds(config.created_at),
+                unix_seconds(config.expires_at),
+                config.status.to_string(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Get broker by identifier
+    pub fn get_broker(&self, id: &str) -> Result<Option<BrokerConfig>> {
+        let conn = self.get_connection()?;
+
+        let result = conn
+            .query_row(
+                "SELECT id, fortress_id, namespace, code, socket_path, onion_address, pid, created_at, expires_at, status
+                 FROM brokers WHERE id = ?1",
+                params![id],
+                broker_from_row,
+            )
+            .optional()?;
+
+        Ok(result)
+    }
+
+    /// List all brokers, newest first
+    pub fn list_brokers(&self) -> Result<Vec<BrokerConfig>> {
+        let conn = self.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, fortress_id, namespace, code, socket_path, onion_address, pid, created_at, expires_at, status
+             FROM brokers ORDER BY created_at DESC",
+        )?;
+
+        let brokers = stmt
+            .query_map([], broker_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(brokers)
+    }
+
+    /// Update broker status
+    pub fn update_broker_status(&self, id: &str, status: BrokerStatus) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        conn.execute(
+            "UPDATE brokers SET status = ?1 WHERE id = ?2",
+            params![status.to_string(), id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Delete brokers that are no longer active
+    pub fn delete_inactive_brokers(&self) -> Result<usize> {
+        let conn = self.get_connection()?;
+
+        let mut deleted = 0;
+        for broker in self.list_brokers()? {
+            if !broker.is_active() {
+                deleted += conn.execute("DELETE FROM brokers WHERE id = ?1", params![broker.id])?;
+            }
+        }
+
+        Ok(deleted)
+    }
+
+    // ========== Message Persistence ==========
+
+    /// Store a message queued by a persistent server
+    pub fn store_message(&self, server_id: &str, message: &Message, sender_id: Option<&str>) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        conn.execute(
+            "INSERT INTO messages (id, server_id, sender, content, timestamp, expires_at, recipient, channel, signature,
+                                   attachment, sender_id, queued_bytes, attachment_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                message.id,
+                server_id,
+                message.from,
+                message.content,
+                unix_nanos(message.timestamp),
+                unix_nanos(message.expires_at),
+                message.to,
+                message.channel,
+                message.signature.as_ref().map(serde_json::to_string).transpose()?,
+                message.attachment.as_ref().map(serde_json::to_string).transpose()?,
+                sender_id,
+                message.queued_bytes() as i64,
+                message.attachment.as_ref().map(|a| a.id.as_str()),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Unexpired messages a sender has stored for a server, and their bytes
+    pub fn queued_by(&self, server_id: &str, sender_id: &str) -> Result<Queued> {
+        let conn = self.get_connection()?;
+
+        let (messages, bytes): (i64, i64) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(queued_bytes), 0) FROM messages
+             WHERE server_id = ?1 AND sender_id = ?2 AND expires_at > ?3",
+            params![server_id, sender_id, unix_nanos(SystemTime::now())],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        Ok(Queued {
+            messages: messages as usize,
+            bytes: bytes as u64,
+        })
+    }
+
+    /// Unexpired messages for a server, oldest first
+    ///
+    /// With `after`, only messages queued after that one; if it is no longer
+    /// stored (e.g. it expired), all of them.
+    pub fn list_messages(
+        &self,
+        server_id: &str,
+        since: Option<SystemTime>,
+        after: Option<&str>,
+    ) -> Result<Vec<Message>> {
+        let conn = self.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, sender, content, timestamp, expires_at, recipient, channel, signature, attachment FROM messages
+             WHERE server_id = ?1 AND expires_at > ?2 AND timestamp >= ?3
+               AND seq > COALESCE((SELECT seq FROM messages WHERE id = ?4 AND server_id = ?1), 0)
+             ORDER BY seq",
+        )?;
+
+        let messages = stmt
+            .query_map(
+                params![
+                    server_id,
+                    unix_nanos(SystemTime::now()),
+                    since.map_or(0, unix_nanos),
+                    after,
+                ],
+                message_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(messages)
+    }
+
+    /// An unexpired message stored for a server
+    pub fn get_message(&self, server_id: &str, id: &str) -> Result<Option<Message>> {
+        let conn = self.get_connection()?;
+
+        let message = conn
+            .query_row(
+                "SELECT id, sender, content, timestamp, expires_at, recipient, channel, signature, attachment FROM messages
+                 WHERE server_id = ?1 AND id = ?2 AND expires_at > ?3",
+                params![server_id, id, unix_nanos(SystemTime::now())],
+                message_from_row,
+            )
+            .optional()?;
+
+        Ok(message)
+    }
+
+    /// The unexpired message stored for a server that a file is attached to
+    pub fn get_message_by_attachment(&self, server_id: &str, attachment_id: &str) -> Result<Option<Message>> {
+        let conn = self.get_connection()?;
+
+        let message = conn
+            .query_row(
+                "SELECT id, sender, content, timestamp, expires_at, recipient, channel, signature, attachment FROM messages
+                 WHERE server_id = ?1 AND attachment_id = ?2 AND expires_at > ?3",
+                params![server_id, attachment_id, unix_nanos(SystemTime::now())],
+                message_from_row,
+            )
+            .optional()?;
+
+        Ok(message)
+    }
+
+    /// Record that a stored message was sent to a recipient, unless it already was
+    pub fn record_delivery(&self, server_id: &str, message_id: &str, recipient: &str, alias: Option<&str>) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        conn.execute(
+            "INSERT OR IGNORE INTO deliveries (message_id, server_id, recipient, alias, delivered_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![message_id, server_id, recipient, alias, unix_nanos(SystemTime::now())],
+        )?;
+
+        Ok(())
+    }
+
+    /// Record a recipient's acknowledgement of a stored message
+    ///
+    /// The recipient's cursor moves up to the message if it is further on.
+    /// Returns false if the message is not stored.
+    pub fn ack_message(&self, server_id: &str, message_id: &str, recipient: &str, alias: Option<&str>) -> Result<bool> {
+        let conn = self.get_connection()?;
+
+        let seq: Option<i64> = conn
+            .query_row(
+                "SELECT seq FROM messages WHERE server_id = ?1 AND id = ?2",
+                params![server_id, message_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(seq) = seq else {
+            return Ok(false);
+        };
+
+        let now = unix_nanos(SystemTime::now());
+        conn.execute(
+            "INSERT INTO deliveries (message_id, server_id, recipient, alias, delivered_at, acked_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+             ON CONFLICT (message_id, recipient) DO UPDATE SET acked_at = COALESCE(acked_at, excluded.acked_at)",
+            params![message_id, server_id, recipient, alias, now],
+        )?;
+
+        // A cursor whose message is gone counts as being at the start
+        let cursor: Option<i64> = conn
+            .query_row(
+                "SELECT m.seq FROM delivery_cursors c JOIN messages m ON m.id = c.message_id
+                 WHERE c.server_id = ?1 AND c.recipient = ?2",
+                params![server_id, recipient],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if cursor.is_none_or(|cursor| cursor < seq) {
+            conn.execute(
+                "INSERT OR REPLACE INTO delivery_cursors (server_id, recipient, message_id) VALUES (?1, ?2, ?3)",
+                params![server_id, recipient, message_id],
+            )?;
+        }
+
+        Ok(true)
+    }
+
+    /// Who a stored message was delivered to, in the order it was
+    pub fn list_deliveries(&self, message_id: &str) -> Result<Vec<Delivery>> {
+        let conn = self.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT recipient, alias, delivered_at, acked_at FROM deliveries
+             WHERE message_id = ?1 ORDER BY delivered_at, recipient",
+        )?;
+
+        let deliveries = stmt
+            .query_map(params![message_id], |row| {
+                Ok(Delivery {
+                    recipient: row.get(0)?,
+                    alias: row.get(1)?,
+                    delivered_at: from_unix_nanos(row.get(2)?),
+                    acked_at: row.get::<_, Option<i64>>(3)?.map(from_unix_nanos),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(deliveries)
+    }
+
+    /// Stored messages a recipient has not acknowledged, oldest first
+    ///
+    /// Those delivered to it without an acknowledgement, and every message
+    /// stored after its cursor (if it has one).
+    pub fn unacked_messages(&self, server_id: &str, recipient: &str) -> Result<Vec<Message>> {
+        let conn = self.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT m.id, m.sender, m.content, m.timestamp, m.expires_at, m.recipient, m.channel, m.signature,
+                    m.attachment
+             FROM messages m LEFT JOIN deliveries d ON d.message_id = m.id AND d.recipient = ?2
+             WHERE m.server_id = ?1 AND m.expires_at > ?3
+               AND CASE WHEN d.message_id IS NULL
+                   THEN m.seq > (SELECT cm.seq FROM delivery_cursors c JOIN messages cm ON cm.id = c.message_id
+                                 WHERE c.server_id = ?1 AND c.recipient = ?2)
+                   ELSE d.acked_at IS NULL
+                   END
+             ORDER BY m.seq",
+        )?;
+
+        let messages = stmt
+            .query_map(params![server_id, recipient, unix_nanos(SystemTime::now())], message_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(messages)
+    }
+
+    /// Store a file attached to one of a server's messages
+    pub fn store_attachment(&self, server_id: &str, id: &str, data: &[u8], expires_at: SystemTime) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        conn.execute(
+            "INSERT OR REPLACE INTO attachments (id, server_id, data, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![id, server_id, data, unix_nanos(expires_at)],
+        )?;
+
+        Ok(())
+    }
+
+    /// An unexpired file attached to one of a server's messages
+    pub fn get_attachment(&self, server_id: &str, id: &str) -> Result<Option<Vec<u8>>> {
+        let conn = self.get_connection()?;
+
+        let data = conn
+            .query_row(
+                "SELECT data FROM attachments WHERE server_id = ?1 AND id = ?2 AND expires_at > ?3",
+                params![server_id, id, unix_nanos(SystemTime::now())],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(data)
+    }
+
+    /// Bytes of files attached to a server's messages, expired or not
+    pub fn attachment_bytes(&self, server_id: &str) -> Result<u64> {
+        let conn = self.get_connection()?;
+
+        let bytes: i64 = conn.query_row(
+            "SELECT COALESCE(SUM(length(data)), 0) FROM attachments WHERE server_id = ?1",
+            params![server_id],
+            |row| row.get(0),
+        )?;
+
+        Ok(bytes as u64)
+    }
+
+    /// Number of unexpired messages stored for a server
+    pub fn count_messages(&self, server_id: &str) -> Result<usize> {
+        let conn = self.get_connection()?;
+
+        let count: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM messages WHERE server_id = ?1 AND expires_at > ?2",
+            params![server_id, unix_nanos(SystemTime::now())],
+            |row| row.get(0),
+        )?;
+
+        Ok(count as usize)
+    }
+
+    /// Drop a server's oldest messages beyond `max`
+    pub fn trim_messages(&self, server_id: &str, max: usize) -> Result<usize> {
+        let conn = self.get_connection()?;
+
+        // Their attachments first, while the messages still say which they are
+        conn.execute(
+            "DELETE FROM attachments WHERE server_id = ?1 AND id IN (
+                SELECT json_extract(attachment, '$.id') FROM messages
+                WHERE server_id = ?1 AND attachment IS NOT NULL AND seq NOT IN (
+                    SELECT seq FROM messages WHERE server_id = ?1 ORDER BY seq DESC LIMIT ?2
+                )
+             )",
+            params![server_id, max as i64],
+        )?;
+        let deleted = conn.execute(
+            "DELETE FROM messages WHERE server_id = ?1 AND seq NOT IN (
+                SELECT seq FROM messages WHERE server_id = ?1 ORDER BY seq DESC LIMIT ?2
+             )",
+            params![server_id, max as i64],
+        )?;
+        if deleted > 0 {
+            forget_dropped_deliveries(&conn, Some(server_id))?;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Delete expired messages, for one server or (with `None`) all of them
+    pub fn delete_expired_messages(&self, server_id: Option<&str>) -> Result<usize> {
+        let conn = self.get_connection()?;
+        let now = unix_nanos(SystemTime::now());
+
+        let deleted = match server_id {
+            Some(server_id) => {
+                conn.execute(
+                    "DELETE FROM attachments WHERE server_id = ?1 AND expires_at <= ?2",
+                    params![server_id, now],
+                )?;
+                conn.execute(
+                    "DELETE FROM messages WHERE server_id = ?1 AND expires_at <= ?2",
+                    params![server_id, now],
+                )?
+            }
+            None => {
+                conn.execute("DELETE FROM attachments WHERE expires_at <= ?1", params![now])?;
+                conn.execute("DELETE FROM messages WHERE expires_at <= ?1", params![now])?
+            }
+        };
+        if deleted > 0 {
+            forget_dropped_deliveries(&conn, server_id)?;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Delete all of a server's messages and attachments
+    pub fn clear_messages(&self, server_id: &str) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        conn.execute("DELETE FROM messages WHERE server_id = ?1", params![server_id])?;
+        conn.execute("DELETE FROM attachments WHERE server_id = ?1", params![server_id])?;
+        forget_dropped_deliveries(&conn, Some(server_id))?;
+
+        Ok(())
+    }
+
+    // ========== Identities ==========
+
+    /// This installation's signing identity, created the first time it is needed
+    pub fn identity(&self) -> Result<Identity> {
+        let conn = self.get_connection()?;
+
+        // Another process may be creating it at the same time; the first wins
+        conn.execute(
+            "INSERT OR IGNORE INTO identity (id, secret, created_at) VALUES (1, ?1, ?2)",
+            params![Identity::generate().to_hex(), unix_seconds(SystemTime::now())],
+        )?;
+
+        let secret: String = conn.query_row("SELECT secret FROM identity WHERE id = 1", [], |row| row.get(0))?;
+        secret.parse()
+    }
+
+    /// Compare a sender's key with the one pinned for its alias on a server
+    ///
+    /// The first key seen for an alias is pinned and reported as `New`.
+    pub fn check_sender_key(&self, server_name: &str, alias: &str, key: &IdentityKey) -> Result<Trust> {
+        let conn = self.get_connection()?;
+
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO pinned_keys (server_name, alias, public_key, pinned_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![server_name, alias, key.to_string(), unix_seconds(SystemTime::now())],
+        )?;
+        if inserted > 0 {
+            return Ok(Trust::New);
+        }
+
+        let pinned: IdentityKey = conn
+            .query_row(
+                "SELECT public_key FROM pinned_keys WHERE server_name = ?1 AND alias = ?2",
+                params![server_name, alias],
+                |row| row.get::<_, String>(0),
+            )?
+            .parse()?;
+
+        if pinned == *key {
+            Ok(Trust::Pinned)
+        } else {
+            Ok(Trust::Changed { pinned })
+        }
+    }
+
+    /// Keys pinned for a server's senders, by alias
+    pub fn list_pinned_keys(&self, server_name: &str) -> Result<Vec<(String, IdentityKey)>> {
+        let conn = self.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT alias, public_key FROM pinned_keys WHERE server_name = ?1 ORDER BY alias",
+        )?;
+
+        let pins = stmt
+            .query_map(params![server_name], |row| {
+                Ok((row.get::<_, String>(0)?, key_from_column(row, 1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(pins.into_iter().filter_map(|(alias, key)| Some((alias, key?))).collect())
+    }
+
+    /// The key pinned for an alias on a server, if any
+    pub fn pinned_key(&self, server_name: &str, alias: &str) -> Result<Option<IdentityKey>> {
+        let conn = self.get_connection()?;
+
+        let key: Option<String> = conn
+            .query_row(
+                "SELECT public_key FROM pinned_keys WHERE server_name = ?1 AND alias = ?2",
+                params![server_name, alias],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        key.map(|key| key.parse()).transpose()
+    }
+
+    /// Remember that a signature came with a message received from a server
+    ///
+    /// Returns false if it came with a different message before, or was made
+    /// longer than `SEEN_SIGNATURE_RETENTION` ago so that cannot be ruled out.
+    pub fn record_signature(&self, server_name: &str, signature: &MessageSignature, message_id: &str) -> Result<bool> {
+        let conn = self.get_connection()?;
+        let cutoff = unix_seconds(SystemTime::now() - SEEN_SIGNATURE_RETENTION);
+
+        if (signature.signed_at as i64) < cutoff {
+            return Ok(false);
+        }
+        conn.execute("DELETE FROM seen_signatures WHERE signed_at < ?1", params![cutoff])?;
+
+        conn.execute(
+            "INSERT OR IGNORE INTO seen_signatures (server_name, signature, message_id, signed_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![server_name, signature.signature, message_id, signature.signed_at as i64],
+        )?;
+        let first: String = conn.query_row(
+            "SELECT message_id FROM seen_signatures WHERE server_name = ?1 AND signature = ?2",
+            params![server_name, signature.signature],
+            |row| row.get(0),
+        )?;
+
+        Ok(first == message_id)
+    }
+
+    /// Forget the key pinned for an alias, so the next one seen is trusted
+    pub fn unpin_key(&self, server_name: &str, alias: &str) -> Result<bool> {
+        let conn = self.get_connection()?;
+
+        let deleted = conn.execute(
+            "DELETE FROM pinned_keys WHERE server_name = ?1 AND alias = ?2",
+            params![server_name, alias],
+        )?;
+
+        Ok(deleted > 0)
+    }
+
+    // ========== Connection Management ==========
+
+    /// Create a new connection
+    pub fn create_connection(&self, config: ConnectionConfig) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        let connected_at = config.connected_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        conn.execute(
+            "INSERT INTO connections (id, server_name, alias, code, socket_path, onion_address, connected_at, status,
+                                      secret_key, peer_keys, alias_keys)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                config.id,
+                config.server_name,
+                config.alias,
+                config.code,
+                config.socket_path.as_ref().map(|p| p.to_string_lossy().to_string()),
+                config.onion_address,
+                connected_at,
+                config.status.to_string(),
+                config.secret_key.as_ref().map(SecretKey::to_hex),
+                serde_json::to_string(&config.peer_keys)?,
+                serde_json::to_string(&config.alias_keys)?,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Save the encryption keys a connection has come to trust
+    pub fn update_connection_keys(
+        &self,
+        id: &str,
+        peer_keys: &[PublicKey],
+        alias_keys: &BTreeMap<String, PublicKey>,
+    ) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        conn.execute(
+            "UPDATE connections SET peer_keys = ?1, alias_keys = ?2 WHERE id = ?3",
+            params![serde_json::to_string(peer_keys)?, serde_json::to_string(alias_keys)?, id],
         )?;
 
         Ok(())
     }
+
+    /// Get connection configuration by alias or server name
+    pub fn get_connection_config(&self, name: &str) -> Result<Option<ConnectionConfig>> {
+        let conn = self.get_connection()?;
+
+        let result: Option<ConnectionConfig> = conn
+            .query_row(
+                "SELECT id, server_name, alias, code, socket_path, onion_address, connected_at, status,
+                        secret_key, peer_keys, alias_keys
+                 FROM connections WHERE server_name = ?1 OR alias = ?1",
+                params![name],
+                |row| {
+                    let connected_at = SystemTime::UNIX_EPOCH
+                        + std::time::Duration::from_secs(row.get::<_, i64>(6)? as u64);
+
+                    Ok(ConnectionConfig {
+                        id: row.get(0)?,
+                        server_name: row.get(1)?,
+                        alias: row.get(2)?,
+                        code: row.get(3)?,
+                        socket_path: row.get::<_, Option<String>>(4)?.map(PathBuf::from),
+                        onion_address: row.get(5)?,
+                        connected_at,
+                        status: ClientStatus::from_string(&row.get::<_, String>(7)?),
+                        secret_key: key_from_column(row, 8)?,
+                        peer_keys: json_from_column(row, 9)?.unwrap_or_default(),
+                        alias_keys: json_from_column(row, 10)?.unwrap_or_default(),
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(result)
+    }
+
+    /// List all connections
+    pub fn list_connections(&self) -> Result<Vec<ConnectionConfig>> {
+        let conn = self.get_connection()?;
+
+        let mut stmt = conn.prepare(
+            "SELECT id, server_name, alias, code, socket_path, onion_address, connected_at, status,
+                    secret_key, peer_keys, alias_keys
+             FROM connections ORDER BY connected_at DESC",
+        )?;
+
+        let connections = stmt
+            .query_map([], |row| {
+                let connected_at = SystemTime::UNIX_EPOCH
+                    + std::time::Duration::from_secs(row.get::<_, i64>(6)? as u64);
+
+                Ok(ConnectionConfig {
+                    id: row.get(0)?,
+                    server_name: row.get(1)?,
+                    alias: row.get(2)?,
+                    code: row.get(3)?,
+                    socket_path: row.get::<_, Option<String>>(4)?.map(PathBuf::from),
+                    onion_address: row.get(5)?,
+                    connected_at,
+                    status: ClientStatus::from_string(&row.get::<_, String>(7)?),
+                    secret_key: key_from_column(row, 8)?,
+                    peer_keys: json_from_column(row, 9)?.unwrap_or_default(),
+                    alias_keys: json_from_column(row, 10)?.unwrap_or_default(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(connections)
+    }
+
+    /// Delete connection
+    pub fn delete_connection(&self, name: &str) -> Result<()> {
+        let conn = self.get_connection()?;
+
+        conn.execute(
+            "DELETE FROM connections WHERE server_name = ?1 OR alias = ?1",
+            params![name],
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Build a broker from a `brokers` row
+fn broker_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<BrokerConfig> {
+    let from_unix = |secs: i64| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs as u64);
+
+    Ok(BrokerConfig {
+        id: row.get(0)?,
+        fortress_id: row.get(1)?,
+        namespace: row.get(2)?,
+        code_hash: row.get(3)?,
+        socket_path: PathBuf::from(row.get::<_, String>(4)?),
+        onion_address: row.get(5)?,
+        pid: row.get::<_, i64>(6)? as u32,
+        created_at: from_unix(row.get(7)?),
+        expires_at: from_unix(row.get(8)?),
+        status: BrokerStatus::from_string(&row.get::<_, String>(9)?),
+    })
+}
+
+/// Read a value stored as JSON, such as a server's hook
+fn json_from_column<T: DeserializeOwned>(row: &rusqlite::Row<'_>, index: usize) -> rusqlite::Result<Option<T>> {
+    let Some(json) = row.get::<_, Option<String>>(index)? else {
+        return Ok(None);
+    };
+
+    serde_json::from_str(&json).map(Some).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+/// Read a message stored by a persistent server
+fn message_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Message> {
+    Ok(Message {
+        id: row.get(0)?,
+        from: row.get(1)?,
+        content: row.get(2)?,
+        timestamp: from_unix_nanos(row.get(3)?),
+        expires_at: from_unix_nanos(row.get(4)?),
+        to: row.get(5)?,
+        channel: row.get(6)?,
+        signature: json_from_column(row, 7)?,
+        attachment: json_from_column(row, 8)?,
+    })
+}
+
+/// Delete the deliveries of messages that are no longer stored
+fn forget_dropped_deliveries(conn: &Connection, server_id: Option<&str>) -> Result<()> {
+    match server_id {
+        Some(server_id) => conn.execute(
+            "DELETE FROM deliveries WHERE server_id = ?1
+             AND message_id NOT IN (SELECT id FROM messages WHERE server_id = ?1)",
+            params![server_id],
+        )?,
+        None => conn.execute(
+            "DELETE FROM deliveries WHERE message_id NOT IN (SELECT id FROM messages)",
+            [],
+        )?,
+    };
+    Ok(())
+}
+
+/// Read a key stored as hex
+fn key_from_column<K: FromStr<Err = anyhow::Error>>(row: &rusqlite::Row<'_>, index: usize) -> rusqlite::Result<Option<K>> {
+    let Some(hex) = row.get::<_, Option<String>>(index)? else {
+        return Ok(None);
+    };
+
+    hex.parse().map(Some).map_err(|e: anyhow::Error| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, e.into())
+    })
+}
+
+/// Add a column to a table created by an older version
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
+
+    Ok(())
+}
+
+/// Whether a process with this PID still exists
+pub(crate) fn process_alive(pid: u32) -> bool {
+    // SAFETY: kill(2) with signal 0 only checks the PID and sends nothing
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    // EPERM means it exists but belongs to someone else
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Insert a new client for `server_id` with a fresh code
+fn insert_client(conn: &Connection, server_id: &str) -> Result<ClientConfig> {
+    let client = ClientConfig {
+        id: Uuid::new_v4().to_string(),
+        server_id: server_id.to_string(),
+        code: generate_client_code(),
+        created_at: SystemTime::now(),
+        connected_at: None,
+        status: ClientStatus::Pending,
+    };
+
+    let created_at = client.created_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+
+    conn.execute(
+        "INSERT INTO clients (id, server_id, code, created_at, connected_at, status)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            client.id,
+            client.server_id,
+            client.code,
+            created_at,
+            None::<i64>,
+            client.status.to_string(),
+        ],
+    )?;
+
+    Ok(client)
+}
+
+/// Hash an access token or broker code for storage
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Seconds since the Unix epoch, as stored in the database
+fn unix_seconds(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Nanoseconds since the Unix epoch, for message times
+fn unix_nanos(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as i64
+}
+
+fn from_unix_nanos(nanos: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + std::time::Duration::from_nanos(nanos.max(0) as u64)
 }
 
 /// Generate a random client code
@@ -583,6 +1885,8 @@ fn generate_client_code() -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::msgserver::identity::Origin;
+    use crate::msgserver::message::Audience;
     use tempfile::tempdir;
 
     #[test]
@@ -599,6 +1903,13 @@ mod tests {
             ttl_minutes: 5,
             onion_address: None,
             status: ServerStatus::Running,
+            hook: None,
+            persistent: false,
+            max_attachment_size: None,
+            max_attachment_storage: None,
+            slow_consumer_policy: Default::default(),
+            client_queue_size: None,
+            limits: Default::default(),
         };
 
         manager.create_server(server.clone()).unwrap();
@@ -619,4 +1930,271 @@ mod tests {
         let retrieved_client = manager.get_client_by_code(&client.code).unwrap().unwrap();
         assert_eq!(retrieved_client.code, client.code);
     }
+
+    fn create_test_server(manager: &StateManager) -> ServerConfig {
+        let server = ServerConfig {
+            id: Uuid::new_v4().to_string(),
+            name: "token-server".to_string(),
+            socket_path: PathBuf::from("/tmp/test.sock"),
+            created_at: SystemTime::now(),
+            ttl_minutes: 5,
+            onion_address: None,
+            status: ServerStatus::Running,
+            hook: None,
+            persistent: false,
+            max_attachment_size: None,
+            max_attachment_storage: None,
+            slow_consumer_policy: Default::default(),
+            client_queue_size: None,
+            limits: Default::default(),
+        };
+        manager.create_server(server.clone()).unwrap();
+        server
+    }
+
+    #[test]
+    fn test_server_hook_is_stored() {
+        let dir = tempdir().unwrap();
+
+        // A database created before servers had hooks
+        {
+            let conn = Connection::open(dir.path().join("state.db")).unwrap();
+            conn.execute(
+                "CREATE TABLE servers (
+                    id TEXT PRIMARY KEY,
+                    name TEXT UNIQUE NOT NULL,
+                    socket_path TEXT NOT NULL,
+                    created_at INTEGER NOT NULL,
+                    ttl_minutes INTEGER NOT NULL,
+                    onion_address TEXT,
+                    status TEXT NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        }
+
+        let manager = StateManager::new(dir.path()).unwrap();
+        let hook = HookConfig::new("logger".to_string()).with_retries(2);
+        let server = ServerConfig {
+            hook: Some(hook.clone()),
+            ..create_test_server(&manager)
+        };
+        manager.delete_server(&server.name).unwrap();
+        manager.create_server(server.clone()).unwrap();
+
+        let stored = manager.get_server(&server.name).unwrap().unwrap();
+        assert_eq!(stored.hook, Some(hook));
+        assert_eq!(manager.list_servers().unwrap()[0].hook, stored.hook);
+    }
+
+    #[test]
+    fn test_delete_server_removes_its_data() {
+        let dir = tempdir().unwrap();
+        let manager = StateManager::new(dir.path()).unwrap();
+        let server = create_test_server(&manager);
+
+        let expires_at = SystemTime::now() + std::time::Duration::from_secs(3600);
+        let client = manager.create_access_token(&server.id, "secret-token", expires_at).unwrap();
+        let message = Message::new("alice".to_string(), "hello".to_string(), std::time::Duration::from_secs(60));
+        manager.store_message(&server.id, &message, None).unwrap();
+        manager.record_delivery(&server.id, &message.id, &client.id, None).unwrap();
+
+        manager.delete_server(&server.name).unwrap();
+
+        let conn = manager.get_connection().unwrap();
+        for table in ["clients", "access_tokens", "messages", "deliveries"] {
+            let count: i64 = conn
+                .query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(count, 0, "{} left behind", table);
+        }
+    }
+
+    #[test]
+    fn test_access_tokens_are_hashed_and_validated() {
+        let dir = tempdir().unwrap();
+        let manager = StateManager::new(dir.path()).unwrap();
+        let server = create_test_server(&manager);
+
+        let expires_at = SystemTime::now() + std::time::Duration::from_secs(3600);
+        let client = manager.create_access_token(&server.id, "secret-token", expires_at).unwrap();
+
+        // The token itself is never stored
+        let conn = manager.get_connection().unwrap();
+        let stored: String = conn
+            .query_row("SELECT token_hash FROM access_tokens", [], |row| row.get(0))
+            .unwrap();
+        assert_ne!(stored, "secret-token");
+
+        let authenticated = manager.authenticate_client(&server.id, "secret-token").unwrap();
+        assert_eq!(authenticated.unwrap().id, client.id);
+        assert!(manager.authenticate_client("other-server", "secret-token").unwrap().is_none());
+        assert!(manager.authenticate_client(&server.id, "wrong-token").unwrap().is_none());
+        // The code stored alongside the token does not log in
+        assert!(manager.authenticate_client(&server.id, &client.code).unwrap().is_none());
+
+        manager.revoke_client(&client.id).unwrap();
+        assert!(manager.authenticate_client(&server.id, "secret-token").unwrap().is_none());
+        assert!(!manager.client_access_valid(&client.id).unwrap());
+    }
+
+    #[test]
+    fn test_expired_access_tokens_are_rejected() {
+        let dir = tempdir().unwrap();
+        let manager = StateManager::new(dir.path()).unwrap();
+        let server = create_test_server(&manager);
+
+        let expired = SystemTime::now() - std::time::Duration::from_secs(60);
+        let client = manager.create_access_token(&server.id, "old-token", expired).unwrap();
+
+        assert!(manager.authenticate_client(&server.id, "old-token").unwrap().is_none());
+        assert!(!manager.client_access_valid(&client.id).unwrap());
+        // It can still be found, e.g. to revoke it
+        assert!(manager.get_client_by_token("old-token").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_encryption_keys_are_stored() {
+        let dir = tempdir().unwrap();
+        let manager = StateManager::new(dir.path()).unwrap();
+        let server = create_test_server(&manager);
+
+        // Fortress side: the keys of unrevoked clients
+        let alice = manager.create_client(&server.id).unwrap();
+        let bob = manager.create_client(&server.id).unwrap();
+        manager.create_client(&server.id).unwrap();
+        let alice_key = SecretKey::generate().public_key();
+        let bob_key = SecretKey::generate().public_key();
+        manager.set_client_key(&alice.id, &alice_key).unwrap();
+        manager.set_client_key(&bob.id, &bob_key).unwrap();
+
+        let keys = manager.list_client_keys(&server.id).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&(alice.id.clone(), alice_key)));
+        manager.revoke_client(&bob.id).unwrap();
+        assert_eq!(manager.list_client_keys(&server.id).unwrap(), vec![(alice.id, alice_key)]);
+
+        // Client side: our own key and the peers' keys
+        let secret_key = SecretKey::generate();
+        manager
+            .create_connection(ConnectionConfig {
+                id: Uuid::new_v4().to_string(),
+                server_name: "fortress.onion".to_string(),
+                alias: None,
+                code: "token".to_string(),
+                socket_path: None,
+                onion_address: Some("fortress.onion".to_string()),
+                connected_at: SystemTime::now(),
+                status: ClientStatus::Connected,
+                secret_key: Some(secret_key.clone()),
+                peer_keys: vec![alice_key, bob_key],
+                alias_keys: BTreeMap::new(),
+            })
+            .unwrap();
+
+        let connection = manager.get_connection_config("fortress.onion").unwrap().unwrap();
+        assert_eq!(connection.secret_key, Some(secret_key));
+        assert_eq!(connection.peer_keys, vec![alice_key, bob_key]);
+        assert_eq!(manager.list_connections().unwrap()[0].peer_keys, connection.peer_keys);
+
+        // Keys trusted later are saved with it
+        let alias_keys = BTreeMap::from([("alice".to_string(), alice_key)]);
+        manager.update_connection_keys(&connection.id, &[alice_key], &alias_keys).unwrap();
+        let connection = manager.get_connection_config("fortress.onion").unwrap().unwrap();
+        assert_eq!(connection.peer_keys, vec![alice_key]);
+        assert_eq!(connection.alias_keys, alias_keys);
+    }
+
+    #[test]
+    fn test_sender_keys_are_pinned() {
+        let dir = tempdir().unwrap();
+        let manager = StateManager::new(dir.path()).unwrap();
+
+        // One identity per installation, created once
+        let identity = manager.identity().unwrap();
+        assert_eq!(manager.identity().unwrap().public_key(), identity.public_key());
+
+        let alice = identity.public_key();
+        let mallory = Identity::generate().public_key();
+        assert_eq!(manager.check_sender_key("fortress.onion", "alice", &alice).unwrap(), Trust::New);
+        assert_eq!(manager.check_sender_key("fortress.onion", "alice", &alice).unwrap(), Trust::Pinned);
+        assert_eq!(
+            manager.check_sender_key("fortress.onion", "alice", &mallory).unwrap(),
+            Trust::Changed { pinned: alice }
+        );
+
+        // Pins are per server
+        assert_eq!(manager.check_sender_key("other.onion", "alice", &mallory).unwrap(), Trust::New);
+        assert_eq!(manager.list_pinned_keys("fortress.onion").unwrap(), vec![("alice".to_string(), alice)]);
+
+        // Once forgotten, the next key seen is pinned instead
+        assert!(manager.unpin_key("fortress.onion", "alice").unwrap());
+        assert!(!manager.unpin_key("fortress.onion", "alice").unwrap());
+        assert_eq!(manager.check_sender_key("fortress.onion", "alice", &mallory).unwrap(), Trust::New);
+        assert_eq!(manager.check_sender_key("fortress.onion", "alice", &mallory).unwrap(), Trust::Pinned);
+        assert_eq!(manager.pinned_key("fortress.onion", "alice").unwrap(), Some(mallory));
+        assert_eq!(manager.pinned_key("fortress.onion", "bob").unwrap(), None);
+    }
+
+    #[test]
+    fn test_replayed_signatures_are_caught() {
+        let dir = tempdir().unwrap();
+        let manager = StateManager::new(dir.path()).unwrap();
+
+        let signature = Identity::generate().sign("hello", &Audience::Everyone, None, Origin::default());
+        assert!(manager.record_signature("fortress.onion", &signature, "first").unwrap());
+        // Seeing the same message again is fine; the signature on another is not
+        assert!(manager.record_signature("fortress.onion", &signature, "first").unwrap());
+        assert!(!manager.record_signature("fortress.onion", &signature, "second").unwrap());
+        assert!(manager.record_signature("other.onion", &signature, "second").unwrap());
+
+        let ancient = MessageSignature {
+            signed_at: 0,
+            ..signature
+        };
+        assert!(!manager.record_signature("fortress.onion", &ancient, "third").unwrap());
+    }
+
+    #[test]
+    fn test_broker_tracking() {
+        let dir = tempdir().unwrap();
+        let manager = StateManager::new(dir.path()).unwrap();
+        let server = create_test_server(&manager);
+
+        let now = SystemTime::now();
+        let broker = BrokerConfig {
+            id: "broker-id".to_string(),
+            fortress_id: server.id.clone(),
+            namespace: "test@example.com".to_string(),
+            code_hash: hash_token("ABC-XYZ"),
+            socket_path: PathBuf::from("/tmp/broker.sock"),
+            onion_address: None,
+            pid: std::process::id(),
+            created_at: now,
+            expires_at: now + std::time::Duration::from_secs(120),
+            status: BrokerStatus::Active,
+        };
+        manager.create_broker(&broker).unwrap();
+
+        let retrieved = manager.get_broker("broker-id").unwrap().unwrap();
+        assert_eq!(retrieved.fortress_id, server.id);
+        assert_eq!(retrieved.code_hash, hash_token("ABC-XYZ"));
+        assert!(retrieved.is_active());
+
+        manager.update_broker_status("broker-id", BrokerStatus::Used).unwrap();
+        assert!(!manager.get_broker("broker-id").unwrap().unwrap().is_active());
+
+        // Past its expiry a broker is inactive even if never updated
+        let expired = BrokerConfig {
+            id: "expired-id".to_string(),
+            expires_at: now - std::time::Duration::from_secs(1),
+            ..broker
+        };
+        manager.create_broker(&expired).unwrap();
+        assert!(!manager.get_broker("expired-id").unwrap().unwrap().is_active());
+
+        assert_eq!(manager.delete_inactive_brokers().unwrap(), 2);
+        assert!(manager.list_brokers().unwrap().is_empty());
+    }
 }
@@ -4,18 +4,24 @@
 
 use arti_client::TorClient;
 use tor_hsservice::config::OnionServiceConfigBuilder;
-use tor_hsservice::{HsNickname, StreamRequest, handle_rend_requests};
+use tor_hscrypto::pk::HsIdKeypair;
+use tor_hsservice::{HsNickname, RunningOnionService, StreamRequest, handle_rend_requests};
 use tor_rtcompat::PreferredRuntime;
 use anyhow::{Context, Result};
 use std::path::PathBuf;
-use std::sync::Arc;
-use futures::stream::BoxStream;
+use std::sync::{Arc, Mutex};
+use futures::stream::{BoxStream, StreamExt};
 use safelog::DisplayRedacted;
 
+/// Virtual port clients connect to on a server's onion service
+pub const MESSAGE_PORT: u16 = 80;
+
 /// Tor client wrapper for message server
 pub struct TorManager {
     client: Arc<TorClient<PreferredRuntime>>,
     key_dir: PathBuf,
+    // Onion services stop when their handle is dropped, so keep them here
+    services: Mutex<Vec<Arc<RunningOnionService>>>,
 }
 
 impl TorManager {
@@ -35,6 +41,7 @@ impl TorManager {
         Ok(Self {
             client: Arc::new(client),
             key_dir,
+            services: Mutex::new(Vec::new()),
         })
     }
 
@@ -47,6 +54,26 @@ impl TorManager {
     pub async fn create_onion_service(
         &self,
         nickname: &str,
+    ) -> Result<(String, BoxStream<'static, StreamRequest>)> {
+        self.launch_onion_service(nickname, None).await
+    }
+
+    /// Create an onion service whose address is fixed by `keypair`
+    ///
+    /// Used by brokers, whose address clients derive from the short code.
+    pub async fn create_onion_service_with_key(
+        &self,
+        nickname: &str,
+        keypair: HsIdKeypair,
+    ) -> Result<(String, BoxStream<'static, StreamRequest>)> {
+        self.launch_onion_service(nickname, Some(keypair)).await
+    }
+
+    /// Launch an onion service, with a generated key unless one is given
+    async fn launch_onion_service(
+        &self,
+        nickname: &str,
+        keypair: Option<HsIdKeypair>,
     ) -> Result<(String, BoxStream<'static, StreamRequest>)> {
         let key_path = self.key_dir.join(nickname);
         std::fs::create_dir_all(&key_path)?;
@@ -61,9 +88,15 @@ impl TorManager {
 
         tracing::info!("Launching onion service: {}", nickname);
 
-        let (onion_service, request_stream) = self.client
-            .launch_onion_service(svc_config)
-            .context("Failed to launch onion service")?;
+        let (onion_service, request_stream) = match keypair {
+            Some(keypair) => self.client
+                .launch_onion_service_with_hsid(svc_config, keypair)
+                .map(|(service, requests)| (service, requests.boxed())),
+            None => self.client
+                .launch_onion_service(svc_config)
+                .map(|(service, requests)| (service, requests.boxed())),
+        }
+        .context("Failed to launch onion service")?;
 
         // Wait for onion address (poll until available)
         tracing::info!("Waiting for onion address...");
@@ -77,6 +110,8 @@ impl TorManager {
         let onion_string = onion_addr.display_unredacted().to_string();
         tracing::info!("✓ Onion service ready: {}", onion_string);
 
+        self.services.lock().unwrap().push(onion_service);
+
         // Wrap the request stream with handle_rend_requests
         let stream_requests = handle_rend_requests(request_stream);
 
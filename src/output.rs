@@ -0,0 +1,332 @@
+//! Child process output capture
+//!
+//! This module pipes a child's stdout/stderr into eddi's tracing logs,
+//! optionally mirrors them to size-rotated log files, and keeps a ring
+//! buffer of recent lines for diagnostics when the child fails to start.
+
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tracing::{info, warn};
+
+/// Default number of recent output lines kept in memory
+pub const DEFAULT_RING_BUFFER_LINES: usize = 200;
+
+/// Default maximum size of a log file before it is rotated (10 MiB)
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Default number of rotated log files to keep
+pub const DEFAULT_MAX_FILES: usize = 5;
+
+/// Longest line kept whole (64 KiB); longer lines are split into pieces
+/// of this size, so a child that never prints a newline cannot make eddi
+/// buffer without bound
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Which output stream a line came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl fmt::Display for OutputStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputStream::Stdout => f.write_str("stdout"),
+            OutputStream::Stderr => f.write_str("stderr"),
+        }
+    }
+}
+
+/// Configuration for capturing child process output
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    /// Directory for rotating log files (None = tracing only)
+    pub log_dir: Option<PathBuf>,
+
+    /// Maximum size of a single log file in bytes before rotation
+    pub max_file_size: u64,
+
+    /// Number of rotated files to keep per stream
+    pub max_files: usize,
+
+    /// Number of recent lines kept in memory for diagnostics
+    pub ring_buffer_lines: usize,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            log_dir: None,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+            ring_buffer_lines: DEFAULT_RING_BUFFER_LINES,
+        }
+    }
+}
+
+/// A single captured line of output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputLine {
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+/// Fixed-capacity buffer of the most recent output lines
+#[derive(Debug, Clone)]
+pub struct RingBuffer {
+    lines: Arc<Mutex<VecDeque<OutputLine>>>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    /// Create a new ring buffer holding at most `capacity` lines
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Append a line, evicting the oldest one if full
+    pub fn push(&self, stream: OutputStream, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut lines = self.lines.lock().unwrap();
+        while lines.len() >= self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(OutputLine { stream, line });
+    }
+
+    /// Snapshot of the buffered lines, oldest first
+    pub fn lines(&self) -> Vec<OutputLine> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Log file writer that rotates by size
+///
+/// Files are named `<base>.log`, `<base>.log.1`, ... `<base>.log.<max_files>`.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    /// Open (or create) a rotating log file
+    pub fn open(path: PathBuf, max_size: u64, max_files: usize) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create log directory: {:?}", parent))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open log file: {:?}", path))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    /// Write a single line, rotating first if it would exceed the size limit
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift `<base>.log.N` to `<base>.log.N+1` and start a fresh file
+    fn rotate(&mut self) -> Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+
+        let _ = fs::remove_file(rotated_path(&self.path, self.max_files));
+        for i in (1..self.max_files).rev() {
+            let from = rotated_path(&self.path, i);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to reopen log file: {:?}", self.path))?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+/// Forward a child's output stream to tracing, log files and the ring buffer
+///
+/// Runs on a dedicated thread and returns when the stream reaches EOF. Lines
+/// longer than `MAX_LINE_LENGTH` are logged in pieces.
+pub fn spawn_output_reader<R>(
+    reader: R,
+    process_name: String,
+    stream: OutputStream,
+    config: &OutputConfig,
+    buffer: RingBuffer,
+) -> Result<JoinHandle<()>>
+where
+    R: Read + Send + 'static,
+{
+    let mut file = match &config.log_dir {
+        Some(dir) => {
+            let path = dir.join(format!("{}.{}.log", process_name, stream));
+            Some(RotatingFile::open(path, config.max_file_size, config.max_files)?)
+        }
+        None => None,
+    };
+
+    let handle = thread::Builder::new()
+        .name(format!("{}-{}", process_name, stream))
+        .spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut bytes = Vec::new();
+            loop {
+                bytes.clear();
+                match reader.by_ref().take(MAX_LINE_LENGTH as u64).read_until(b'\n', &mut bytes) {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!(process = %process_name, %stream, "Error reading child output: {}", e);
+                        break;
+                    }
+                }
+                if bytes.last() == Some(&b'\n') {
+                    bytes.pop();
+                }
+                let line = String::from_utf8_lossy(&bytes).trim_end_matches('\r').to_string();
+
+                info!(target: "eddi::child", process = %process_name, %stream, "{}", line);
+
+                if let Some(ref mut file) = file {
+                    if let Err(e) = file.write_line(&line) {
+                        warn!(process = %process_name, %stream, "Failed to write log file: {}", e);
+                    }
+                }
+
+                buffer.push(stream, line);
+            }
+        })
+        .context("Failed to spawn output reader thread")?;
+
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_ring_buffer_evicts_oldest() {
+        let buffer = RingBuffer::new(2);
+        buffer.push(OutputStream::Stdout, "one".to_string());
+        buffer.push(OutputStream::Stderr, "two".to_string());
+        buffer.push(OutputStream::Stdout, "three".to_string());
+
+        let lines = buffer.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].line, "two");
+        assert_eq!(lines[0].stream, OutputStream::Stderr);
+        assert_eq!(lines[1].line, "three");
+    }
+
+    #[test]
+    fn test_rotating_file_rotates() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("app.stdout.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+
+        for line in ["aaaaaaaa", "bbbbbbbb", "cccccccc", "dddddddd"] {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddddddd\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 1)).unwrap(), "cccccccc\n");
+        assert_eq!(fs::read_to_string(rotated_path(&path, 2)).unwrap(), "bbbbbbbb\n");
+        assert!(!rotated_path(&path, 3).exists());
+    }
+
+    #[test]
+    fn test_output_reader_captures_lines() {
+        let dir = tempdir().unwrap();
+        let config = OutputConfig {
+            log_dir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let buffer = RingBuffer::new(10);
+
+        let input: &'static [u8] = b"hello\nworld\n";
+        spawn_output_reader(input, "app".to_string(), OutputStream::Stdout, &config, buffer.clone())
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let lines: Vec<_> = buffer.lines().into_iter().map(|l| l.line).collect();
+        assert_eq!(lines, vec!["hello", "world"]);
+
+        let logged = fs::read_to_string(dir.path().join("app.stdout.log")).unwrap();
+        assert_eq!(logged, "hello\nworld\n");
+    }
+
+    #[test]
+    fn test_output_reader_splits_long_lines() {
+        let buffer = RingBuffer::new(10);
+        let mut input = vec![b'a'; MAX_LINE_LENGTH * 2 + 3];
+        input.extend_from_slice(b"\nend\n");
+
+        spawn_output_reader(
+            std::io::Cursor::new(input),
+            "app".to_string(),
+            OutputStream::Stdout,
+            &OutputConfig::default(),
+            buffer.clone(),
+        )
+        .unwrap()
+        .join()
+        .unwrap();
+
+        let lengths: Vec<_> = buffer.lines().into_iter().map(|l| l.line.len()).collect();
+        assert_eq!(lengths, vec![MAX_LINE_LENGTH, MAX_LINE_LENGTH, 3, 3]);
+        assert_eq!(buffer.lines()[3].line, "end");
+    }
+}
@@ -0,0 +1,342 @@
+//! Readiness and liveness probes for child processes
+//!
+//! Probes decide whether the web application behind the Unix Domain Socket
+//! is able to serve requests. The same probe definitions gate startup
+//! (readiness) and drive the supervisor's restart decisions (liveness).
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{debug, warn};
+
+/// What a probe checks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeKind {
+    /// The socket accepts a connection
+    Connect,
+
+    /// An HTTP GET over the socket returns the expected status
+    Http {
+        path: String,
+        expected_status: u16,
+    },
+
+    /// A command exits successfully
+    ///
+    /// The socket path is passed in the `EDDI_SOCKET_PATH` environment variable.
+    Exec {
+        command: String,
+        args: Vec<String>,
+    },
+}
+
+/// Probe definition with timing and thresholds
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    /// What to check
+    pub kind: ProbeKind,
+
+    /// Delay before the first check
+    pub initial_delay: Duration,
+
+    /// Time between checks
+    pub interval: Duration,
+
+    /// Time allowed for a single check
+    pub timeout: Duration,
+
+    /// Consecutive failures before the process is considered unhealthy
+    pub failure_threshold: u32,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            kind: ProbeKind::Connect,
+            initial_delay: Duration::ZERO,
+            interval: Duration::from_millis(500),
+            timeout: Duration::from_secs(2),
+            failure_threshold: 3,
+        }
+    }
+}
+
+impl ProbeConfig {
+    /// Create an HTTP probe for `path` expecting `expected_status`
+    pub fn http(path: &str, expected_status: u16) -> Self {
+        Self {
+            kind: ProbeKind::Http {
+                path: path.to_string(),
+                expected_status,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Create an exec probe running `command` with `args`
+    pub fn exec(command: &str, args: Vec<String>) -> Self {
+        Self {
+            kind: ProbeKind::Exec {
+                command: command.to_string(),
+                args,
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// Runs a probe against a socket
+#[derive(Debug, Clone)]
+pub struct Prober {
+    config: ProbeConfig,
+    socket_path: PathBuf,
+}
+
+impl Prober {
+    /// Create a prober for the given socket
+    pub fn new(config: ProbeConfig, socket_path: PathBuf) -> Self {
+        Self { config, socket_path }
+    }
+
+    /// Get the probe configuration
+    pub fn config(&self) -> &ProbeConfig {
+        &self.config
+    }
+
+    /// Run a single check, bounded by the probe timeout
+    pub async fn check(&self) -> Result<()> {
+        timeout(self.config.timeout, self.check_inner())
+            .await
+            .with_context(|| format!("Probe timed out after {:?}", self.config.timeout))?
+    }
+
+    async fn check_inner(&self) -> Result<()> {
+        match &self.config.kind {
+            ProbeKind::Connect => {
+                UnixStream::connect(&self.socket_path)
+                    .await
+                    .context("Failed to connect to socket")?;
+                Ok(())
+            }
+            ProbeKind::Http { path, expected_status } => {
+                let status = http_get_status(&self.socket_path, path).await?;
+                if status != *expected_status {
+                    anyhow::bail!("GET {} returned {} (expected {})", path, status, expected_status);
+                }
+                Ok(())
+            }
+            ProbeKind::Exec { command, args } => {
+                let status = Command::new(command)
+                    .args(args)
+                    .env("EDDI_SOCKET_PATH", &self.socket_path)
+                    .kill_on_drop(true)
+                    .status()
+                    .await
+                    .with_context(|| format!("Failed to run probe command: {}", command))?;
+                if !status.success() {
+                    anyhow::bail!("Probe command {} exited with {}", command, status);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Check repeatedly until one check succeeds or `deadline` passes
+    pub async fn wait_until_ready(&self, deadline: Duration) -> Result<()> {
+        let start = Instant::now();
+        sleep(self.config.initial_delay).await;
+
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let err = match self.check().await {
+                Ok(()) => {
+                    debug!("Readiness probe succeeded after {} attempt(s)", attempts);
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+
+            if start.elapsed() + self.config.interval > deadline {
+                return Err(err).context(format!(
+                    "Readiness probe failed after {} attempt(s)",
+                    attempts
+                ));
+            }
+
+            debug!("Readiness probe attempt {} failed: {:#}", attempts, err);
+            sleep(self.config.interval).await;
+        }
+    }
+
+    /// Check periodically, returning once `failure_threshold` consecutive checks fail
+    pub async fn wait_until_unhealthy(&self) -> anyhow::Error {
+        sleep(self.config.initial_delay).await;
+
+        let mut failures = 0;
+        loop {
+            match self.check().await {
+                Ok(()) => failures = 0,
+                Err(e) => {
+                    failures += 1;
+                    warn!(
+                        "Liveness probe failed ({}/{}): {:#}",
+                        failures, self.config.failure_threshold, e
+                    );
+                    if failures >= self.config.failure_threshold {
+                        return e;
+                    }
+                }
+            }
+            sleep(self.config.interval).await;
+        }
+    }
+}
+
+/// Issue `GET path` over a Unix socket and return the response status code
+async fn http_get_status(socket_path: &Path, path: &str) -> Result<u16> {
+    let mut stream = UnixStream::connect(socket_path)
+        .await
+        .context("Failed to connect to socket")?;
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nUser-Agent: eddi-probe\r\nConnection: close\r\n\r\n",
+        path
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    // Only the status line is needed
+    let mut buf = Vec::with_capacity(256);
+    let mut chunk = [0u8; 256];
+    while !buf.contains(&b'\n') && buf.len() < 4096 {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    parse_status_line(&buf)
+}
+
+/// Parse the status code from an HTTP response's status line
+fn parse_status_line(response: &[u8]) -> Result<u16> {
+    let text = String::from_utf8_lossy(response);
+    let line = text.lines().next().context("Empty HTTP response")?;
+
+    let mut parts = line.split_whitespace();
+    let version = parts.next().unwrap_or_default();
+    if !version.starts_with("HTTP/") {
+        anyhow::bail!("Invalid HTTP status line: {}", line);
+    }
+
+    parts
+        .next()
+        .and_then(|code| code.parse().ok())
+        .with_context(|| format!("Invalid HTTP status line: {}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use tokio::net::UnixListener;
+
+    /// Serve a fixed HTTP status on a socket, one response per connection
+    fn serve_status(listener: UnixListener, status: &'static str) {
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+    }
+
+    #[test]
+    fn test_parse_status_line() {
+        assert_eq!(parse_status_line(b"HTTP/1.1 200 OK\r\n").unwrap(), 200);
+        assert_eq!(parse_status_line(b"HTTP/1.0 503 Service Unavailable\r\n").unwrap(), 503);
+        assert!(parse_status_line(b"garbage\r\n").is_err());
+        assert!(parse_status_line(b"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_probe() {
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("app.sock");
+        let prober = Prober::new(ProbeConfig::default(), socket_path.clone());
+
+        assert!(prober.check().await.is_err());
+
+        let _listener = UnixListener::bind(&socket_path).unwrap();
+        assert!(prober.check().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_http_probe_status() {
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("app.sock");
+        serve_status(UnixListener::bind(&socket_path).unwrap(), "503 Service Unavailable");
+
+        let ok = Prober::new(ProbeConfig::http("/health", 503), socket_path.clone());
+        assert!(ok.check().await.is_ok());
+
+        let bad = Prober::new(ProbeConfig::http("/health", 200), socket_path);
+        assert!(bad.check().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec_probe() {
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("app.sock");
+
+        let ok = Prober::new(ProbeConfig::exec("true", vec![]), socket_path.clone());
+        assert!(ok.check().await.is_ok());
+
+        let bad = Prober::new(ProbeConfig::exec("false", vec![]), socket_path);
+        assert!(bad.check().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_ready_times_out() {
+        let dir = tempdir().unwrap();
+        let config = ProbeConfig {
+            interval: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let prober = Prober::new(config, dir.path().join("missing.sock"));
+
+        assert!(prober.wait_until_ready(Duration::from_millis(100)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_unhealthy_threshold() {
+        let dir = tempdir().unwrap();
+        let socket_path = dir.path().join("app.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        let config = ProbeConfig {
+            interval: Duration::from_millis(20),
+            failure_threshold: 2,
+            ..Default::default()
+        };
+        let prober = Prober::new(config, socket_path.clone());
+
+        let monitor = tokio::spawn(async move { prober.wait_until_unhealthy().await });
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!monitor.is_finished(), "Healthy socket should not trip the probe");
+
+        drop(listener);
+        std::fs::remove_file(&socket_path).unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(2), monitor).await;
+        assert!(result.is_ok(), "Probe should report failure after threshold");
+    }
+}
@@ -3,16 +3,20 @@
 //! This module provides utilities for spawning and managing web server
 //! processes (like gunicorn, uvicorn, php-fpm) bound to Unix Domain Sockets.
 
+use crate::activation::{ActivatedSocket, LISTEN_FDS_START};
+use crate::output::{spawn_output_reader, OutputConfig, OutputLine, OutputStream, RingBuffer};
+use crate::probe::{ProbeConfig, Prober};
+use crate::socket::{self, SocketPermissions};
 use anyhow::{Context, Result};
-use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command};
-use std::thread;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
 use std::time::Duration;
-use tracing::{info, debug};
+use tokio::time::Instant;
+use tracing::{info, error};
 
 /// Configuration for the child process
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ProcessConfig {
     /// Path to the Unix Domain Socket
     pub socket_path: PathBuf,
@@ -25,6 +29,21 @@ pub struct ProcessConfig {
 
     /// Arguments to pass to the command
     pub args: Vec<String>,
+
+    /// How the child's stdout/stderr are captured
+    pub output: OutputConfig,
+
+    /// Probe that gates startup
+    pub readiness: ProbeConfig,
+
+    /// Probe checked while running; failures trigger a restart (None = disabled)
+    pub liveness: Option<ProbeConfig>,
+
+    /// Owner, group and mode enforced on the socket once the child creates it
+    pub socket_permissions: SocketPermissions,
+
+    /// Bind the socket in eddi and pass it to the child as fd 3 (`LISTEN_FDS`)
+    pub socket_activation: bool,
 }
 
 impl ProcessConfig {
@@ -43,42 +62,157 @@ impl ProcessConfig {
                 bind_addr,
                 app_module.to_string(),
             ],
+            output: OutputConfig::default(),
+            readiness: ProbeConfig::default(),
+            liveness: None,
+            socket_permissions: SocketPermissions::default(),
+            socket_activation: false,
         }
     }
+
+    /// Enable socket activation, pointing `unix:<socket>` binds at the passed fd
+    ///
+    /// Gunicorn binds `fd://3` directly; other servers can read the systemd
+    /// `LISTEN_FDS`/`LISTEN_PID` variables.
+    pub fn with_socket_activation(mut self) -> Self {
+        let bind = format!("unix:{}", self.socket_path.display());
+        for arg in &mut self.args {
+            if *arg == bind {
+                *arg = format!("fd://{}", LISTEN_FDS_START);
+            }
+        }
+
+        self.socket_activation = true;
+        self
+    }
+
+    /// Copy of this configuration bound to a different socket path
+    ///
+    /// Arguments that name the old socket (e.g. gunicorn's `unix:<path>`)
+    /// are rewritten to the new one.
+    pub fn for_socket(&self, socket_path: PathBuf) -> Self {
+        let old = self.socket_path.to_string_lossy().to_string();
+        let new = socket_path.to_string_lossy().to_string();
+
+        let args = self
+            .args
+            .iter()
+            .map(|arg| {
+                if arg == &old {
+                    new.clone()
+                } else if arg.strip_prefix("unix:") == Some(old.as_str()) {
+                    format!("unix:{}", new)
+                } else {
+                    arg.clone()
+                }
+            })
+            .collect();
+
+        Self {
+            socket_path,
+            args,
+            ..self.clone()
+        }
+    }
+
+    /// Name used to tag this process in logs (the command's file name)
+    pub fn process_name(&self) -> String {
+        Path::new(&self.command)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.command.clone())
+    }
 }
 
 /// Manages a child process bound to a Unix Domain Socket
 pub struct ChildProcessManager {
     child: Child,
     socket_path: PathBuf,
+    recent_output: RingBuffer,
+    readiness: Prober,
+    socket_permissions: SocketPermissions,
+    activated_socket: Option<Arc<ActivatedSocket>>,
 }
 
 impl ChildProcessManager {
     /// Spawn a child process with the given configuration
+    ///
+    /// With socket activation enabled, the socket is bound here first.
     pub fn spawn(config: &ProcessConfig) -> Result<Self> {
-        // Remove existing socket file if it exists
-        if config.socket_path.exists() {
-            info!("Removing existing socket file: {:?}", config.socket_path);
-            fs::remove_file(&config.socket_path)
-                .context("Failed to remove existing socket file")?;
+        if config.socket_activation {
+            let socket = ActivatedSocket::bind(&config.socket_path, &config.socket_permissions)?;
+            return Self::spawn_with_socket(config, Arc::new(socket));
         }
 
+        // Clear a stale socket, but never one another process is serving
+        socket::prepare_socket_path(&config.socket_path)?;
+
+        let mut command = Command::new(&config.command);
+        command.args(&config.args);
+        Self::spawn_inner(config, command, None)
+    }
+
+    /// Spawn a child process that serves an already bound socket
+    ///
+    /// Used to restart an app without closing its listener, so connections
+    /// queue instead of failing while the new child starts.
+    pub fn spawn_with_socket(config: &ProcessConfig, socket: Arc<ActivatedSocket>) -> Result<Self> {
+        let command = socket.command(&config.command, &config.args);
+        Self::spawn_inner(config, command, Some(socket))
+    }
+
+    fn spawn_inner(
+        config: &ProcessConfig,
+        mut command: Command,
+        activated_socket: Option<Arc<ActivatedSocket>>,
+    ) -> Result<Self> {
         info!("Spawning child process...");
         info!("  Command: {}", config.command);
         info!("  Working directory: {:?}", config.app_dir);
         info!("  Args: {:?}", config.args);
+        if let Some(socket) = &activated_socket {
+            info!("  Socket: {:?} (passed as fd {})", socket.path(), LISTEN_FDS_START);
+        }
 
-        let child = Command::new(&config.command)
+        let mut child = command
             .current_dir(&config.app_dir)
-            .args(&config.args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .spawn()
             .with_context(|| format!("Failed to spawn {}. Is it installed?", config.command))?;
 
         info!("Child process spawned with PID: {}", child.id());
 
+        // Route stdout/stderr into our logs
+        let process_name = config.process_name();
+        let recent_output = RingBuffer::new(config.output.ring_buffer_lines);
+
+        if let Some(stdout) = child.stdout.take() {
+            spawn_output_reader(
+                stdout,
+                process_name.clone(),
+                OutputStream::Stdout,
+                &config.output,
+                recent_output.clone(),
+            )?;
+        }
+        if let Some(stderr) = child.stderr.take() {
+            spawn_output_reader(
+                stderr,
+                process_name,
+                OutputStream::Stderr,
+                &config.output,
+                recent_output.clone(),
+            )?;
+        }
+
         Ok(Self {
             child,
             socket_path: config.socket_path.clone(),
+            recent_output,
+            readiness: Prober::new(config.readiness.clone(), config.socket_path.clone()),
+            socket_permissions: config.socket_permissions.clone(),
+            activated_socket,
         })
     }
 
@@ -92,57 +226,107 @@ impl ChildProcessManager {
         &self.socket_path
     }
 
+    /// Get the socket eddi bound for this child, if socket activation is on
+    pub fn activated_socket(&self) -> Option<&Arc<ActivatedSocket>> {
+        self.activated_socket.as_ref()
+    }
+
+    /// Get the most recent lines written by the child, oldest first
+    pub fn recent_output(&self) -> Vec<OutputLine> {
+        self.recent_output.lines()
+    }
+
+    /// Log the buffered recent output at error level
+    fn log_recent_output(&self) {
+        let lines = self.recent_output();
+        if lines.is_empty() {
+            error!("Child process produced no output");
+            return;
+        }
+
+        error!("Last {} lines of child process output:", lines.len());
+        for line in lines {
+            error!("  [{}] {}", line.stream, line.line);
+        }
+    }
+
+    /// Ask the child to exit with SIGTERM, waiting up to `grace` before it is killed
+    pub async fn terminate(mut self, grace: Duration) {
+        let pid = self.pid();
+        info!("Sending SIGTERM to child process (PID: {})...", pid);
+
+        // SAFETY: kill(2) has no memory-safety requirements; the PID is our
+        // own child, which cannot be reaped (and reused) until we wait on it
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < grace {
+            if let Ok(Some(status)) = self.try_wait() {
+                info!("Child process (PID: {}) exited with {}", pid, status);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // Drop kills it if still running and removes the socket
+    }
+
+    /// Check whether the child has exited, without blocking
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        self.child.try_wait().context("Failed to query child process status")
+    }
+
     /// Wait for the socket file to be created
-    pub fn wait_for_socket(&self, timeout_secs: u64) -> Result<()> {
-        let start = std::time::Instant::now();
+    pub async fn wait_for_socket(&self, timeout_secs: u64) -> Result<()> {
+        let start = Instant::now();
 
         info!("Waiting for socket file to be created...");
 
         while start.elapsed().as_secs() < timeout_secs {
             if self.socket_path.exists() {
                 info!("Socket file created: {:?}", self.socket_path);
-                // Give the process a moment to start listening
-                thread::sleep(Duration::from_millis(500));
                 return Ok(());
             }
-            thread::sleep(Duration::from_millis(100));
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
 
         anyhow::bail!("Timeout waiting for socket file: {:?}", self.socket_path)
     }
 
-    /// Try to connect to the socket to verify it's ready
+    /// Wait until the readiness probe passes
+    ///
+    /// On failure, the child's recent output is logged to help diagnose why.
     pub async fn wait_for_ready(&self, timeout_secs: u64) -> Result<()> {
-        use tokio::net::UnixStream;
-        use tokio::time::{timeout, Duration};
-
-        self.wait_for_socket(timeout_secs)?;
-
-        info!("Attempting to connect to socket...");
-
-        // Try to actually connect
-        let connect_timeout = Duration::from_secs(timeout_secs);
-        timeout(connect_timeout, async {
-            let mut attempts = 0;
-            loop {
-                match UnixStream::connect(&self.socket_path).await {
-                    Ok(_) => {
-                        info!("Successfully connected to socket - process is ready");
-                        return Ok(());
-                    }
-                    Err(e) => {
-                        attempts += 1;
-                        if attempts > 10 {
-                            anyhow::bail!("Failed to connect after {} attempts: {}", attempts, e);
-                        }
-                        debug!("Connect attempt {} failed, retrying...", attempts);
-                        tokio::time::sleep(Duration::from_millis(500)).await;
-                    }
-                }
-            }
-        })
-        .await
-        .context("Timeout waiting for process to accept connections")?
+        let result = self.probe_ready(timeout_secs).await;
+        if result.is_err() {
+            self.log_recent_output();
+        }
+        result
+    }
+
+    async fn probe_ready(&self, timeout_secs: u64) -> Result<()> {
+        let start = Instant::now();
+        let deadline = Duration::from_secs(timeout_secs);
+
+        // An activated socket already exists with its permissions applied
+        if self.activated_socket.is_none() {
+            self.wait_for_socket(timeout_secs).await?;
+
+            socket::apply_permissions(&self.socket_path, &self.socket_permissions)
+                .context("Failed to secure socket file")?;
+        }
+
+        info!("Running readiness probe ({:?})...", self.readiness.config().kind);
+
+        self.readiness
+            .wait_until_ready(deadline.saturating_sub(start.elapsed()))
+            .await
+            .context("Child process did not pass its readiness probe")?;
+
+        info!("Readiness probe passed - process is ready");
+        Ok(())
     }
 }
 
@@ -154,9 +338,9 @@ impl Drop for ChildProcessManager {
         let _ = self.child.kill();
         let _ = self.child.wait();
 
-        // Clean up socket file
-        if self.socket_path.exists() {
-            let _ = fs::remove_file(&self.socket_path);
+        // Clean up socket file (an activated socket is removed by its owner)
+        if self.activated_socket.is_none() {
+            socket::remove_socket_file(&self.socket_path);
         }
 
         info!("Child process shut down successfully");
@@ -181,4 +365,46 @@ mod tests {
         assert!(config.args.contains(&"--workers".to_string()));
         assert!(config.args.contains(&"2".to_string()));
     }
+
+    #[test]
+    fn test_for_socket_rewrites_bind() {
+        let config = ProcessConfig::gunicorn(
+            PathBuf::from("/tmp/app.sock"),
+            PathBuf::from("/app"),
+            "app:app",
+            2,
+        );
+
+        let moved = config.for_socket(PathBuf::from("/tmp/app.sock.1"));
+
+        assert_eq!(moved.socket_path, PathBuf::from("/tmp/app.sock.1"));
+        assert!(moved.args.contains(&"unix:/tmp/app.sock.1".to_string()));
+        assert!(!moved.args.contains(&"unix:/tmp/app.sock".to_string()));
+        assert_eq!(moved.command, config.command);
+    }
+
+    #[test]
+    fn test_with_socket_activation_binds_fd() {
+        let config = ProcessConfig::gunicorn(
+            PathBuf::from("/tmp/app.sock"),
+            PathBuf::from("/app"),
+            "app:app",
+            2,
+        )
+        .with_socket_activation();
+
+        assert!(config.socket_activation);
+        assert!(config.args.contains(&"fd://3".to_string()));
+        assert!(!config.args.contains(&"unix:/tmp/app.sock".to_string()));
+    }
+
+    #[test]
+    fn test_process_name() {
+        let config = ProcessConfig {
+            command: "/usr/local/bin/gunicorn".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(config.process_name(), "gunicorn");
+    }
 }
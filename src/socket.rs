@@ -0,0 +1,367 @@
+//! Unix Domain Socket file safety
+//!
+//! Helpers for preparing a socket path before a child binds it (creating a
+//! private parent directory and clearing only *stale* sockets), for binding
+//! a socket with its permissions already applied, and for enforcing owner,
+//! group and mode on a socket a child created.
+
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use tracing::{info, warn};
+
+/// Mode used when eddi creates a socket's parent directory
+pub const SOCKET_DIR_MODE: u32 = 0o700;
+
+/// Default mode applied to the socket file
+pub const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+/// State of a socket path on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketState {
+    /// Nothing exists at the path
+    Missing,
+    /// A socket file exists but nothing is listening on it
+    Stale,
+    /// A socket file exists and accepts connections
+    Live,
+}
+
+/// Ownership and permissions to enforce on a socket file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketPermissions {
+    /// File mode bits (None = leave as created)
+    pub mode: Option<u32>,
+    /// Owning user name or numeric uid (None = leave as created)
+    pub owner: Option<String>,
+    /// Owning group name or numeric gid (None = leave as created)
+    pub group: Option<String>,
+}
+
+impl Default for SocketPermissions {
+    fn default() -> Self {
+        Self {
+            mode: Some(DEFAULT_SOCKET_MODE),
+            owner: None,
+            group: None,
+        }
+    }
+}
+
+/// Parse an octal mode string such as "660" or "0o660"
+pub fn parse_mode(s: &str) -> Result<u32> {
+    let digits = s.trim_start_matches("0o");
+    let mode = u32::from_str_radix(digits, 8)
+        .with_context(|| format!("Invalid octal mode: {}", s))?;
+    if mode > 0o7777 {
+        anyhow::bail!("Invalid octal mode: {}", s);
+    }
+    Ok(mode)
+}
+
+/// Determine whether a socket path is missing, stale or live
+///
+/// Fails if something other than a socket exists at the path.
+pub fn inspect_socket(path: &Path) -> Result<SocketState> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(SocketState::Missing),
+        Err(e) => return Err(e).with_context(|| format!("Failed to stat {:?}", path)),
+    };
+
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("{:?} exists and is not a socket; refusing to touch it", path);
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Ok(SocketState::Live),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(SocketState::Stale),
+        Err(e) => Err(e).with_context(|| format!("Failed to probe socket {:?}", path)),
+    }
+}
+
+/// Make a socket path ready for a new listener
+///
+/// Creates the parent directory with a private mode if missing, removes a
+/// stale socket, and refuses to proceed if the socket is live or the path is
+/// not a socket.
+pub fn prepare_socket_path(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if !parent.exists() {
+            info!("Creating socket directory: {:?}", parent);
+            DirBuilder::new()
+                .recursive(true)
+                .mode(SOCKET_DIR_MODE)
+                .create(parent)
+                .with_context(|| format!("Failed to create socket directory: {:?}", parent))?;
+        }
+    }
+
+    match inspect_socket(path)? {
+        SocketState::Missing => Ok(()),
+        SocketState::Stale => {
+            info!("Removing stale socket file: {:?}", path);
+            fs::remove_file(path).context("Failed to remove stale socket file")
+        }
+        SocketState::Live => {
+            anyhow::bail!(
+                "Socket {:?} is in use by another process; refusing to remove it",
+                path
+            )
+        }
+    }
+}
+
+/// Bind a listener at `path` with `perms` already in force
+///
+/// The socket is bound inside a fresh private directory next to `path`,
+/// secured there, and only then renamed into place, so it is never reachable
+/// with the looser mode the process umask would give it.
+pub fn bind_secured(path: &Path, perms: &SocketPermissions) -> Result<UnixListener> {
+    prepare_socket_path(path)?;
+
+    let parent = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let staging = parent.join(format!(".eddi-bind-{:016x}", rand::random::<u64>()));
+    DirBuilder::new()
+        .mode(SOCKET_DIR_MODE)
+        .create(&staging)
+        .with_context(|| format!("Failed to create staging directory: {:?}", staging))?;
+
+    let staged = staging.join("s");
+    let result = UnixListener::bind(&staged)
+        .with_context(|| format!("Failed to bind socket: {:?}", path))
+        .and_then(|listener| {
+            apply_permissions(&staged, perms)?;
+            fs::rename(&staged, path)
+                .with_context(|| format!("Failed to move socket into place: {:?}", path))?;
+            Ok(listener)
+        });
+
+    remove_socket_file(&staged);
+    let _ = fs::remove_dir(&staging);
+    result
+}
+
+/// Remove a socket file we own, leaving anything that is not a socket alone
+pub fn remove_socket_file(path: &Path) {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            let _ = fs::remove_file(path);
+        }
+        Ok(_) => warn!("Not removing {:?}: not a socket", path),
+        Err(_) => {}
+    }
+}
+
+/// Apply owner, group and mode to a socket, then verify them
+pub fn apply_permissions(path: &Path, perms: &SocketPermissions) -> Result<()> {
+    let uid = perms.owner.as_deref().map(resolve_user).transpose()?;
+    let gid = perms.group.as_deref().map(resolve_group).transpose()?;
+
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(path, uid, gid)
+            .with_context(|| format!("Failed to change ownership of {:?}", path))?;
+    }
+
+    if let Some(mode) = perms.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set mode {:o} on {:?}", mode, path))?;
+    }
+
+    verify_permissions(path, perms)
+}
+
+/// Check that a socket has the expected owner, group and mode
+pub fn verify_permissions(path: &Path, perms: &SocketPermissions) -> Result<()> {
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to stat {:?}", path))?;
+
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("{:?} is not a socket", path);
+    }
+
+    if let Some(owner) = perms.owner.as_deref() {
+        let uid = resolve_user(owner)?;
+        if metadata.uid() != uid {
+            anyhow::bail!("Socket {:?} is owned by uid {}, expected {}", path, metadata.uid(), uid);
+        }
+    }
+
+    if let Some(group) = perms.group.as_deref() {
+        let gid = resolve_group(group)?;
+        if metadata.gid() != gid {
+            anyhow::bail!("Socket {:?} has gid {}, expected {}", path, metadata.gid(), gid);
+        }
+    }
+
+    if let Some(mode) = perms.mode {
+        let actual = metadata.mode() & 0o7777;
+        if actual != mode {
+            anyhow::bail!("Socket {:?} has mode {:o}, expected {:o}", path, actual, mode);
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolve a user name or numeric uid
+fn resolve_user(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+
+    let name = CString::new(user).context("Invalid user name")?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+
+    // SAFETY: all pointers are valid for the duration of the call and
+    // `buf` outlives any use of the strings it backs
+    let rc = unsafe {
+        libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result)
+    };
+    if rc != 0 || result.is_null() {
+        anyhow::bail!("Unknown user: {}", user);
+    }
+
+    Ok(pwd.pw_uid)
+}
+
+/// Resolve a group name or numeric gid
+fn resolve_group(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group).context("Invalid group name")?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut result = std::ptr::null_mut();
+
+    // SAFETY: see resolve_user
+    let rc = unsafe {
+        libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result)
+    };
+    if rc != 0 || result.is_null() {
+        anyhow::bail!("Unknown group: {}", group);
+    }
+
+    Ok(grp.gr_gid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660").unwrap(), 0o660);
+        assert_eq!(parse_mode("0o600").unwrap(), 0o600);
+        assert_eq!(parse_mode("0700").unwrap(), 0o700);
+        assert!(parse_mode("999").is_err());
+        assert!(parse_mode("77777").is_err());
+    }
+
+    #[test]
+    fn test_inspect_socket_states() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("app.sock");
+
+        assert_eq!(inspect_socket(&path).unwrap(), SocketState::Missing);
+
+        let listener = UnixListener::bind(&path).unwrap();
+        assert_eq!(inspect_socket(&path).unwrap(), SocketState::Live);
+
+        drop(listener);
+        assert_eq!(inspect_socket(&path).unwrap(), SocketState::Stale);
+    }
+
+    #[test]
+    fn test_prepare_refuses_live_and_regular_files() {
+        let dir = tempdir().unwrap();
+
+        let live = dir.path().join("live.sock");
+        let _listener = UnixListener::bind(&live).unwrap();
+        assert!(prepare_socket_path(&live).is_err());
+        assert!(live.exists());
+
+        let regular = dir.path().join("regular.sock");
+        fs::write(&regular, b"data").unwrap();
+        assert!(prepare_socket_path(&regular).is_err());
+        assert!(regular.exists());
+    }
+
+    #[test]
+    fn test_prepare_removes_stale_and_creates_private_dir() {
+        let dir = tempdir().unwrap();
+
+        let stale = dir.path().join("stale.sock");
+        drop(UnixListener::bind(&stale).unwrap());
+        prepare_socket_path(&stale).unwrap();
+        assert!(!stale.exists());
+
+        let nested = dir.path().join("run").join("app.sock");
+        prepare_socket_path(&nested).unwrap();
+        let mode = fs::metadata(dir.path().join("run")).unwrap().mode() & 0o7777;
+        assert_eq!(mode, SOCKET_DIR_MODE);
+    }
+
+    #[test]
+    fn test_apply_and_verify_permissions() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("app.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+
+        let uid = fs::metadata(&path).unwrap().uid().to_string();
+        let perms = SocketPermissions {
+            mode: Some(0o640),
+            owner: Some(uid),
+            group: None,
+        };
+        apply_permissions(&path, &perms).unwrap();
+        verify_permissions(&path, &perms).unwrap();
+
+        let wrong = SocketPermissions {
+            mode: Some(0o600),
+            ..perms
+        };
+        assert!(verify_permissions(&path, &wrong).is_err());
+    }
+
+    #[test]
+    fn test_bind_secured_applies_permissions_before_moving_in() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("app.sock");
+        let perms = SocketPermissions {
+            mode: Some(0o640),
+            owner: None,
+            group: None,
+        };
+
+        let _listener = bind_secured(&path, &perms).unwrap();
+        verify_permissions(&path, &perms).unwrap();
+        assert_eq!(inspect_socket(&path).unwrap(), SocketState::Live);
+
+        // Only the socket is left behind
+        let entries: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+
+        // A live socket is never replaced
+        assert!(bind_secured(&path, &perms).is_err());
+    }
+
+    #[test]
+    fn test_resolve_root() {
+        assert_eq!(resolve_user("root").unwrap(), 0);
+        assert_eq!(resolve_user("0").unwrap(), 0);
+        assert!(resolve_user("no-such-user-eddi").is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! Child process supervision
+//!
+//! The supervisor owns a `ChildProcessManager`, restarts the child when it
+//! exits or fails its liveness probe, and gives up after too many restarts.
+//!
+//! With socket activation, the supervisor keeps the listening socket open
+//! across restarts so clients queue rather than fail while the app comes back.
+//!
+//! It also performs zero-downtime reloads: a new child is started on a fresh
+//! socket, and once it is ready the current [`Backend`] is switched over.
+//! The old child keeps serving the connections it already has and is stopped
+//! once they drain.
+
+use crate::activation::ActivatedSocket;
+use crate::probe::Prober;
+use crate::process::{ChildProcessManager, ProcessConfig};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+/// Maximum delay between restart attempts
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+/// Uptime after which a child's earlier restarts are forgiven
+const HEALTHY_UPTIME: Duration = Duration::from_secs(10 * 60);
+
+/// How often to check whether the child has exited
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Default time to let connections to an old child finish after a reload
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Time a draining child gets to exit after SIGTERM before it is killed
+const TERMINATE_GRACE: Duration = Duration::from_secs(10);
+
+/// Why the supervisor decided the child needs restarting
+#[derive(Debug)]
+enum Failure {
+    Exited(std::process::ExitStatus),
+    Unhealthy(anyhow::Error),
+}
+
+/// The socket new connections should be proxied to
+///
+/// Connections hold a clone of the `Arc<Backend>` while they are open, which
+/// is how a reload knows when the previous backend has drained.
+#[derive(Debug)]
+pub struct Backend {
+    socket_path: PathBuf,
+}
+
+impl Backend {
+    /// Create a backend for the given socket
+    pub fn new(socket_path: PathBuf) -> Self {
+        Self { socket_path }
+    }
+
+    /// Get the socket path
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+}
+
+/// Pending reload request, answered once the reload finishes
+type ReloadRequest = oneshot::Sender<Result<()>>;
+
+/// Handle for requesting reloads from a running supervisor
+#[derive(Clone)]
+pub struct ReloadHandle {
+    tx: mpsc::Sender<ReloadRequest>,
+}
+
+impl ReloadHandle {
+    /// Reload the application, returning once the new child serves traffic
+    pub async fn reload(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.tx
+            .send(tx)
+            .await
+            .map_err(|_| anyhow::anyhow!("Supervisor is not running"))?;
+        rx.await.context("Supervisor stopped during reload")?
+    }
+}
+
+/// Keeps a child process running
+pub struct Supervisor {
+    base_config: ProcessConfig,
+    config: ProcessConfig,
+    child: Option<ChildProcessManager>,
+    socket: Option<Arc<ActivatedSocket>>,
+    ready_timeout_secs: u64,
+    max_restarts: u32,
+    restarts: u32,
+    // When the current child became ready
+    started_at: Instant,
+    generation: u64,
+    drain_timeout: Duration,
+    backend_tx: watch::Sender<Arc<Backend>>,
+    reload_tx: mpsc::Sender<ReloadRequest>,
+    reload_rx: Option<mpsc::Receiver<ReloadRequest>>,
+}
+
+impl Supervisor {
+    /// Spawn the child and wait for it to become ready
+    pub async fn start(
+        config: ProcessConfig,
+        ready_timeout_secs: u64,
+        max_restarts: u32,
+    ) -> Result<Self> {
+        let child = Self::spawn_ready(&config, None, ready_timeout_secs).await?;
+        let (backend_tx, _) = watch::channel(Arc::new(Backend::new(config.socket_path.clone())));
+        let (reload_tx, reload_rx) = mpsc::channel(4);
+
+        Ok(Self {
+            base_config: config.clone(),
+            config,
+            socket: child.activated_socket().cloned(),
+            child: Some(child),
+            ready_timeout_secs,
+            max_restarts,
+            restarts: 0,
+            started_at: Instant::now(),
+            generation: 0,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            backend_tx,
+            reload_tx,
+            reload_rx: Some(reload_rx),
+        })
+    }
+
+    /// Spawn a child (on `socket` if given) and wait for it to become ready
+    async fn spawn_ready(
+        config: &ProcessConfig,
+        socket: Option<Arc<ActivatedSocket>>,
+        ready_timeout_secs: u64,
+    ) -> Result<ChildProcessManager> {
+        let child = match socket {
+            Some(socket) => ChildProcessManager::spawn_with_socket(config, socket),
+            None => ChildProcessManager::spawn(config),
+        }
+        .context("Failed to spawn child process")?;
+
+        child
+            .wait_for_ready(ready_timeout_secs)
+            .await
+            .context("Child process failed to become ready")?;
+
+        Ok(child)
+    }
+
+    /// Set how long an old child may keep serving after a reload
+    pub fn set_drain_timeout(&mut self, timeout: Duration) {
+        self.drain_timeout = timeout;
+    }
+
+    /// Get the currently running child (None while restarting)
+    pub fn child(&self) -> Option<&ChildProcessManager> {
+        self.child.as_ref()
+    }
+
+    /// Number of restarts performed so far
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Watch the backend that new connections should use
+    pub fn backend(&self) -> watch::Receiver<Arc<Backend>> {
+        self.backend_tx.subscribe()
+    }
+
+    /// Get a handle for requesting reloads once `run` is going
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            tx: self.reload_tx.clone(),
+        }
+    }
+
+    /// Supervise the child until it can no longer be kept running
+    ///
+    /// Only returns on error; dropping the future stops the child.
+    pub async fn run(mut self) -> Result<()> {
+        let mut reloads = self.reload_rx.take().context("Supervisor is already running")?;
+
+        loop {
+            let failure = tokio::select! {
+                failure = self.wait_for_failure() => failure?,
+                Some(reply) = reloads.recv() => match self.reload().await {
+                    Ok(None) => {
+                        let _ = reply.send(Ok(()));
+                        continue;
+                    }
+                    // The current child failed first; restart it as usual
+                    Ok(Some(failure)) => {
+                        let _ = reply.send(Err(anyhow::anyhow!("Child process failed during reload")));
+                        failure
+                    }
+                    Err(e) => {
+                        error!("Reload failed, keeping the current child: {:#}", e);
+                        let _ = reply.send(Err(e));
+                        continue;
+                    }
+                },
+            };
+
+            // Stop the old child before a new one takes over the socket path
+            if let Some(child) = self.child.take() {
+                match &failure {
+                    Failure::Exited(status) => {
+                        warn!("Child process (PID: {}) exited with {}", child.pid(), status);
+                    }
+                    Failure::Unhealthy(e) => {
+                        warn!("Child process (PID: {}) failed its liveness probe: {:#}", child.pid(), e);
+                    }
+                }
+            }
+
+            // Occasional crashes over a long healthy run do not add up
+            self.restarts = restarts_after_uptime(self.restarts, self.started_at.elapsed());
+
+            self.restart().await?;
+        }
+    }
+
+    /// Wait until the child exits or its liveness probe trips
+    async fn wait_for_failure(&mut self) -> Result<Failure> {
+        let liveness = self
+            .config
+            .liveness
+            .clone()
+            .map(|probe| Prober::new(probe, self.config.socket_path.clone()));
+
+        let unhealthy = async {
+            match &liveness {
+                Some(prober) => prober.wait_until_unhealthy().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::pin!(unhealthy);
+
+        let mut exit_poll = tokio::time::interval(EXIT_POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                e = &mut unhealthy => return Ok(Failure::Unhealthy(e)),
+                _ = exit_poll.tick() => {
+                    let child = self.child.as_mut().context("No child process to supervise")?;
+                    if let Some(status) = child.try_wait()? {
+                        return Ok(Failure::Exited(status));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replace the child with a fresh one, backing off between attempts
+    async fn restart(&mut self) -> Result<()> {
+        loop {
+            if self.restarts >= self.max_restarts {
+                error!("Child process restarted {} times, giving up", self.restarts);
+                anyhow::bail!("Child process exceeded maximum restarts ({})", self.max_restarts);
+            }
+
+            let delay = restart_delay(self.restarts);
+            self.restarts += 1;
+            info!(
+                "Restarting child process in {:?} (restart {}/{})",
+                delay, self.restarts, self.max_restarts
+            );
+            tokio::time::sleep(delay).await;
+
+            match Self::spawn_ready(&self.config, self.socket.clone(), self.ready_timeout_secs).await {
+                Ok(child) => {
+                    info!("✓ Child process restarted (PID: {})", child.pid());
+                    self.child = Some(child);
+                    self.started_at = Instant::now();
+                    return Ok(());
+                }
+                Err(e) => error!("Restart failed: {:#}", e),
+            }
+        }
+    }
+
+    /// Start a new child on a fresh socket and switch traffic to it
+    ///
+    /// The current child is still watched while the new one starts; if it
+    /// fails first, the reload is abandoned and its failure returned.
+    async fn reload(&mut self) -> Result<Option<Failure>> {
+        let generation = self.generation + 1;
+        let socket_path = generation_socket_path(&self.base_config.socket_path, generation);
+        let config = self.base_config.for_socket(socket_path.clone());
+
+        info!("Reloading application on {:?} (generation {})...", socket_path, generation);
+
+        let ready_timeout_secs = self.ready_timeout_secs;
+        let child = tokio::select! {
+            child = Self::spawn_ready(&config, None, ready_timeout_secs) => child?,
+            failure = self.wait_for_failure() => {
+                warn!("Abandoning reload: the current child failed first");
+                return Ok(Some(failure?));
+            }
+        };
+        info!("✓ New child process is ready (PID: {})", child.pid());
+
+        // Switch new connections over before touching the old child
+        let old_backend = self
+            .backend_tx
+            .send_replace(Arc::new(Backend::new(socket_path)));
+        let old_child = self.child.replace(child);
+
+        self.socket = self.child.as_ref().and_then(|c| c.activated_socket().cloned());
+        self.config = config;
+        self.generation = generation;
+        self.restarts = 0;
+        self.started_at = Instant::now();
+
+        if let Some(old_child) = old_child {
+            tokio::spawn(drain_and_stop(old_child, old_backend, self.drain_timeout));
+        }
+
+        info!("✓ Reload complete");
+        Ok(None)
+    }
+}
+
+/// Wait for connections to an old backend to finish, then stop its child
+async fn drain_and_stop(child: ChildProcessManager, backend: Arc<Backend>, timeout: Duration) {
+    info!(
+        "Draining old child process (PID: {}) on {:?}...",
+        child.pid(),
+        backend.socket_path()
+    );
+
+    let start = Instant::now();
+    // The only remaining reference is ours once all connections have closed
+    while Arc::strong_count(&backend) > 1 {
+        if start.elapsed() >= timeout {
+            warn!(
+                "Drain timeout reached with {} connection(s) still open",
+                Arc::strong_count(&backend) - 1
+            );
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    child.terminate(TERMINATE_GRACE).await;
+    info!("✓ Old child process stopped");
+}
+
+/// Socket path for the given reload generation (generation 0 is the base path)
+fn generation_socket_path(base: &Path, generation: u64) -> PathBuf {
+    if generation == 0 {
+        return base.to_path_buf();
+    }
+
+    let mut name = base.as_os_str().to_os_string();
+    name.push(format!(".{}", generation));
+    PathBuf::from(name)
+}
+
+/// Restarts still counted against a child that ran for `uptime` before failing
+fn restarts_after_uptime(restarts: u32, uptime: Duration) -> u32 {
+    if uptime >= HEALTHY_UPTIME {
+        0
+    } else {
+        restarts
+    }
+}
+
+/// Exponential backoff: 1s, 2s, 4s, ... capped at `MAX_RESTART_DELAY`
+fn restart_delay(restarts: u32) -> Duration {
+    Duration::from_secs(1u64 << restarts.min(5)).min(MAX_RESTART_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_delay_backoff() {
+        assert_eq!(restart_delay(0), Duration::from_secs(1));
+        assert_eq!(restart_delay(1), Duration::from_secs(2));
+        assert_eq!(restart_delay(3), Duration::from_secs(8));
+        assert_eq!(restart_delay(10), MAX_RESTART_DELAY);
+    }
+
+    #[test]
+    fn test_healthy_uptime_resets_restarts() {
+        assert_eq!(restarts_after_uptime(4, Duration::from_secs(5)), 4);
+        assert_eq!(restarts_after_uptime(4, HEALTHY_UPTIME), 0);
+    }
+
+    #[test]
+    fn test_generation_socket_path() {
+        let base = Path::new("/tmp/app.sock");
+        assert_eq!(generation_socket_path(base, 0), PathBuf::from("/tmp/app.sock"));
+        assert_eq!(generation_socket_path(base, 3), PathBuf::from("/tmp/app.sock.3"));
+    }
+}
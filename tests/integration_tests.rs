@@ -13,7 +13,6 @@ use eddi::ProcessConfig;
 fn test_full_project_compiles() {
     // This test ensures the project compiles
     // The fact that this test runs means compilation succeeded
-    assert!(true);
 }
 
 #[test]
@@ -22,7 +21,7 @@ fn test_all_binaries_exist() {
 
     // Test that binaries can be built
     let output = Command::new("cargo")
-        .args(&["build", "--bins"])
+        .args(["build", "--bins"])
         .output();
 
     assert!(output.is_ok(), "Should be able to build binaries");
@@ -63,7 +62,7 @@ fn test_flask_app_on_uds() {
             .expect("Should spawn gunicorn");
 
         // Wait for socket
-        manager.wait_for_socket(10)
+        manager.wait_for_socket(10).await
             .expect("Socket should be created");
 
         // Connect and send request
@@ -96,7 +95,7 @@ fn test_tor_check_without_network() {
 
     // Run tor-check binary
     let output = Command::new("cargo")
-        .args(&["run", "--bin", "tor-check"])
+        .args(["run", "--bin", "tor-check"])
         .env("RUST_LOG", "error") // Reduce noise
         .output()
         .expect("Should run tor-check");
@@ -236,7 +235,6 @@ fn test_library_public_api() {
     let _manager_type: Option<eddi::ChildProcessManager> = None;
 
     // If this compiles, the public API is accessible
-    assert!(true);
 }
 
 #[test]
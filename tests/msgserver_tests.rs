@@ -37,15 +37,22 @@ async fn test_broker_creation() {
         .await
         .unwrap();
 
-    // Create a broker
+    // Create a broker (without Tor for testing)
+    let handshake = BrokerHandshake::derive(
+        format!("{}@example.com", uuid::Uuid::new_v4()),
+        handshake::generate_short_code(),
+        "test-server".to_string(),
+    )
+    .await;
     let broker = server_manager
-        .create_broker("test-server".to_string(), Duration::from_secs(60))
+        .create_broker("test-server".to_string(), handshake, Duration::from_secs(60), false)
         .await
         .unwrap();
 
     assert!(broker.config().name.starts_with("broker-"));
 
     // Cleanup
+    server_manager.stop_server(&broker.config().name).await.unwrap();
     server_manager.stop_server("test-server").await.unwrap();
 }
 
@@ -82,6 +89,13 @@ async fn test_client_authentication() {
         ttl_minutes: 5,
         onion_address: None,
         status: storage::ServerStatus::Running,
+        hook: None,
+        persistent: false,
+        max_attachment_size: None,
+        max_attachment_storage: None,
+        slow_consumer_policy: Default::default(),
+        client_queue_size: None,
+        limits: Default::default(),
     };
 
     state_manager.create_server(server_config.clone()).unwrap();
@@ -123,15 +137,24 @@ async fn test_handshake_code_generation() {
 async fn test_broker_handshake_flow() {
     use handshake::*;
 
-    let broker = BrokerHandshake::new(
+    let broker = BrokerHandshake::derive(
         "test@example.com".to_string(),
         "ABC-XYZ".to_string(),
         "test123.onion".to_string(),
-    );
+    )
+    .await;
 
-    // Validate correct code
-    assert!(broker.validate_code("ABC-XYZ"));
-    assert!(!broker.validate_code("WRONG"));
+    // The code is checked by the exchange, which only a client with the
+    // same code completes
+    for (code, accepted) in [("ABC-XYZ", true), ("WRONG", false)] {
+        let client = ClientHandshake::new("test@example.com".to_string(), code.to_string());
+        let broker_pake = broker.start_pake("session");
+        let client_pake = client.start_pake(&broker.identifier(), "session");
+        let broker_share = broker_pake.share();
+        let broker_keys = broker_pake.finish(&client_pake.share()).unwrap();
+        let client_keys = client_pake.finish(&broker_share).unwrap();
+        assert_eq!(broker_keys.verify_confirmation(&client_keys.confirmation()), accepted);
+    }
 
     // Create introduction
     let intro = broker.create_introduction(24);
@@ -216,6 +239,16 @@ fn test_cli_parsing() {
             _ => panic!("Wrong command parsed"),
         }
     }
+
+    // Hook options only make sense with a hook
+    let args = ["msgsrv", "listen", "--hook", "logger", "--hook-retries", "2"];
+    match MsgSrvCli::try_parse_from(args).unwrap().command {
+        MsgSrvCommand::Listen { hook, .. } => {
+            assert_eq!(hook.config(), Some(HookConfig::new("logger".to_string()).with_retries(2)));
+        }
+        _ => panic!("Wrong command parsed"),
+    }
+    assert!(MsgSrvCli::try_parse_from(["msgsrv", "listen", "--hook-retries", "2"]).is_err());
 }
 
 #[tokio::test]
@@ -232,6 +265,13 @@ async fn test_state_persistence() {
         ttl_minutes: 5,
         onion_address: Some("test.onion".to_string()),
         status: storage::ServerStatus::Running,
+        hook: None,
+        persistent: false,
+        max_attachment_size: None,
+        max_attachment_storage: None,
+        slow_consumer_policy: Default::default(),
+        client_queue_size: None,
+        limits: Default::default(),
     };
 
     state_manager.create_server(server_config.clone()).unwrap();
@@ -247,3 +287,1453 @@ async fn test_state_persistence() {
     assert_eq!(retrieved.name, "test");
     assert_eq!(retrieved.onion_address, Some("test.onion".to_string()));
 }
+
+/// Connect a `RemoteClient` to a local server's socket, waiting for it to be bound
+async fn connect_local(socket_path: &std::path::Path) -> RemoteClient {
+    let stream = connect_socket(socket_path).await;
+    RemoteClient::new(Box::new(stream), uuid::Uuid::new_v4().to_string())
+}
+
+/// Connect to a server's socket, waiting for it to be bound
+async fn connect_socket(socket_path: &std::path::Path) -> tokio::net::UnixStream {
+    for _ in 0..50 {
+        if let Ok(stream) = tokio::net::UnixStream::connect(socket_path).await {
+            return stream;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Server socket never became available: {:?}", socket_path);
+}
+
+/// Read frames until the server pushes a message
+async fn next_broadcast(client: &mut RemoteClient) -> Message {
+    loop {
+        let frame = tokio::time::timeout(Duration::from_secs(5), client.recv())
+            .await
+            .expect("Timed out waiting for a message")
+            .unwrap()
+            .expect("Server closed the connection");
+        if let message::ProtocolMessage::Broadcast { message } = frame {
+            return message;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_send_is_acknowledged_with_message_id() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("send-ack-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let client_code = state_manager.create_client(&server.config().id).unwrap();
+
+    let mut client = connect_local(&server.config().socket_path).await;
+    let server_id = client.authenticate(&client_code.code).await.unwrap();
+    assert_eq!(server_id.as_deref(), Some(server.config().id.as_str()));
+
+    let first = client.send_message("hello").await.unwrap();
+    let second = client.send_message("again").await.unwrap();
+    assert!(!first.is_empty());
+    assert_ne!(first, second);
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_send_rejects_invalid_code() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("send-bad-code-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+
+    let mut client = connect_local(&server.config().socket_path).await;
+    let err = client.authenticate("NOT-A-CODE").await.unwrap_err();
+    assert!(err.to_string().contains("Authentication failed"));
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_listen_streams_backlog_and_broadcasts() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("listen-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let client_code = state_manager.create_client(&server.config().id).unwrap();
+
+    // Queue a message before the listener connects
+    let mut sender = connect_local(&server.config().socket_path).await;
+    sender.authenticate(&client_code.code).await.unwrap();
+    sender.send_message("backlog").await.unwrap();
+
+    let connection = storage::ConnectionConfig {
+        id: uuid::Uuid::new_v4().to_string(),
+        server_name: name.clone(),
+        alias: None,
+        code: client_code.code.clone(),
+        socket_path: Some(server.config().socket_path.clone()),
+        onion_address: None,
+        connected_at: std::time::SystemTime::now(),
+        status: storage::ClientStatus::Connected,
+        secret_key: None,
+        peer_keys: Vec::new(),
+        alias_keys: Default::default(),
+    };
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let listener = tokio::spawn(async move {
+        RemoteClient::listen(&connection, std::time::SystemTime::UNIX_EPOCH, move |m| {
+            let _ = tx.send(m.content.clone());
+        })
+        .await
+    });
+
+    let timeout = Duration::from_secs(5);
+    let received = tokio::time::timeout(timeout, rx.recv()).await.unwrap();
+    assert_eq!(received.as_deref(), Some("backlog"));
+
+    sender.send_message("live").await.unwrap();
+    let received = tokio::time::timeout(timeout, rx.recv()).await.unwrap();
+    assert_eq!(received.as_deref(), Some("live"));
+
+    listener.abort();
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_server_hook_runs_for_each_message() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let hook_out = dir.path().join("hook.out");
+    let hook = HookConfig::new(format!(
+        "{{ echo \"$EDDI_MSG_SERVER $EDDI_MSG_ID\"; cat; echo; }} >> {}",
+        hook_out.display()
+    ));
+
+    let name = format!("hook-{}", uuid::Uuid::new_v4());
+    let server = server_manager
+        .create_server_with_options(name.clone(), 5, false, ServerOptions {
+            hook: Some(hook.clone()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(state_manager.get_server(&name).unwrap().unwrap().hook, Some(hook));
+
+    let client_code = state_manager.create_client(&server.config().id).unwrap();
+    let mut sender = connect_local(&server.config().socket_path).await;
+    sender.authenticate(&client_code.code).await.unwrap();
+    let first = sender.send_message("first").await.unwrap();
+    let second = sender.send_message("second").await.unwrap();
+
+    let expected = format!("{name} {first}\nfirst\n{name} {second}\nsecond\n");
+    let mut output = String::new();
+    for _ in 0..100 {
+        output = std::fs::read_to_string(&hook_out).unwrap_or_default();
+        if output == expected {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(output, expected);
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_direct_and_channel_messages() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("channels-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let socket_path = server.config().socket_path.clone();
+
+    let mut clients = Vec::new();
+    for alias in ["alice", "bob", "carol"] {
+        let code = state_manager.create_client(&server.config().id).unwrap().code;
+        let mut client = connect_local(&socket_path).await.with_alias(Some(alias.to_string()));
+        client.authenticate(&code).await.unwrap();
+        clients.push(client);
+    }
+
+    // Another client cannot claim an alias that is in use
+    let code = state_manager.create_client(&server.config().id).unwrap().code;
+    let mut impostor = connect_local(&socket_path).await.with_alias(Some("bob".to_string()));
+    let err = impostor.authenticate(&code).await.unwrap_err();
+    assert!(err.to_string().contains("Alias already in use"));
+
+    clients[1].subscribe("ops").await.unwrap();
+    clients[0].send_message("hello everyone").await.unwrap();
+    clients[0]
+        .send_message_to("hi bob", message::Audience::Client("bob".to_string()))
+        .await
+        .unwrap();
+    clients[2]
+        .send_message_to("deploying", message::Audience::Channel("ops".to_string()))
+        .await
+        .unwrap();
+
+    // Bob is told about all three as they are sent
+    let mut live = Vec::new();
+    while live.len() < 3 {
+        let message = tokio::time::timeout(Duration::from_secs(5), clients[1].recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        if let message::ProtocolMessage::Broadcast { message } = message {
+            live.push((message.from, message.content));
+        }
+    }
+    assert_eq!(
+        live,
+        [
+            ("alice".to_string(), "hello everyone".to_string()),
+            ("alice".to_string(), "hi bob".to_string()),
+            ("carol".to_string(), "deploying".to_string()),
+        ]
+    );
+
+    let contents = |messages: Vec<Message>| messages.into_iter().map(|m| m.content).collect::<Vec<_>>();
+    assert_eq!(
+        contents(clients[1].receive(None, None).await.unwrap()),
+        ["hello everyone", "hi bob", "deploying"]
+    );
+    assert_eq!(contents(clients[0].receive(None, None).await.unwrap()), ["hello everyone", "hi bob"]);
+    assert_eq!(contents(clients[2].receive(None, None).await.unwrap()), ["hello everyone"]);
+    assert_eq!(
+        contents(clients[2].receive_on(Some("ops".to_string()), None, None).await.unwrap()),
+        ["deploying"]
+    );
+
+    // A message goes to a client or a channel, not both
+    clients[0]
+        .send(&message::ProtocolMessage::Send {
+            content: "both".to_string(),
+            to: Some("bob".to_string()),
+            channel: Some("ops".to_string()),
+            signature: None,
+            attachment: None,
+        })
+        .await
+        .unwrap();
+    let reply = tokio::time::timeout(Duration::from_secs(5), clients[0].recv()).await.unwrap();
+    assert!(matches!(reply.unwrap(), Some(message::ProtocolMessage::Error { .. })));
+
+    // Bob's alias stays his after he disconnects
+    clients.remove(1);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let mut impostor = connect_local(&socket_path).await.with_alias(Some("bob".to_string()));
+    let err = impostor.authenticate(&code).await.unwrap_err();
+    assert!(err.to_string().contains("Alias already in use"));
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_encrypted_messages_are_opaque_to_the_server() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    // Persistent, so what the server stores can be inspected
+    let name = format!("e2e-{}", uuid::Uuid::new_v4());
+    let server = server_manager
+        .create_server_with_options(name.clone(), 5, false, ServerOptions {
+            persistent: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let socket_path = server.config().socket_path.clone();
+
+    // Alice and Bob registered keys when introduced; Carol did not
+    let mut clients = Vec::new();
+    let mut ids = Vec::new();
+    for alias in ["alice", "bob", "carol"] {
+        let code = state_manager.create_client(&server.config().id).unwrap();
+        ids.push(code.id.clone());
+        let secret_key = (alias != "carol").then(e2e::SecretKey::generate);
+        if let Some(ref secret_key) = secret_key {
+            state_manager.set_client_key(&code.id, &secret_key.public_key()).unwrap();
+        }
+        let mut client = connect_local(&socket_path)
+            .await
+            .with_alias(Some(alias.to_string()))
+            .with_keys(secret_key, Vec::new());
+        client.authenticate(&code.code).await.unwrap();
+        clients.push(client);
+    }
+
+    let keys = clients[2].keys().await.unwrap();
+    assert_eq!(keys.len(), 2);
+    assert!(keys.iter().any(|key| key.alias.as_deref() == Some("bob")));
+
+    clients[0].send_encrypted("for everyone with a key", message::Audience::Everyone).await.unwrap();
+    clients[0]
+        .send_encrypted("just for bob", message::Audience::Client("bob".to_string()))
+        .await
+        .unwrap();
+    assert!(clients[2]
+        .send_encrypted("no key", message::Audience::Everyone)
+        .await
+        .unwrap_err()
+        .to_string()
+        .contains("no encryption key"));
+
+    // The server only ever holds envelopes
+    let queued = state_manager.list_messages(&server.config().id, None, None).unwrap();
+    assert_eq!(queued.len(), 2);
+    assert!(queued.iter().all(|m| e2e::is_sealed(&m.content) && !m.content.contains("everyone")));
+
+    let contents = |messages: Vec<Message>| messages.into_iter().map(|m| m.content).collect::<Vec<_>>();
+    assert_eq!(
+        contents(clients[1].receive(None, None).await.unwrap()),
+        ["for everyone with a key", "just for bob"]
+    );
+    assert_eq!(
+        contents(clients[0].receive(None, None).await.unwrap()),
+        ["for everyone with a key", "just for bob"]
+    );
+    let carol = clients[2].receive(None, None).await.unwrap();
+    assert_eq!(carol.len(), 1);
+    assert!(e2e::is_sealed(&carol[0].content));
+
+    // Alice trusted Bob's key on first use; a key the fortress swaps in is refused
+    let (_, alias_keys) = clients[0].trusted_keys();
+    assert_eq!(alias_keys.len(), 2);
+    let planted = e2e::SecretKey::generate().public_key();
+    state_manager.set_client_key(&ids[1], &planted).unwrap();
+    assert!(clients[0]
+        .send_encrypted("for bob only", message::Audience::Client("bob".to_string()))
+        .await
+        .unwrap_err()
+        .to_string()
+        .contains("different encryption key"));
+    clients[0].send_encrypted("still sealed", message::Audience::Everyone).await.unwrap();
+    let (peer_keys, alias_keys) = clients[0].trusted_keys();
+    assert!(!peer_keys.contains(&planted) && !alias_keys.values().any(|key| *key == planted));
+
+    // Files are sealed with their message; the server holds only the envelope
+    let data: Vec<u8> = (0..attachment::CHUNK_SIZE + 10).map(|i| (i % 256) as u8).collect();
+    let id = clients[0]
+        .send_encrypted_attachment("see attached", message::Audience::Everyone, "plan.bin", &data)
+        .await
+        .unwrap();
+    let stored = state_manager.get_message(&server.config().id, &id).unwrap().unwrap();
+    let info = stored.attachment.unwrap();
+    let held = state_manager.get_attachment(&server.config().id, &info.id).unwrap().unwrap();
+    assert!(e2e::is_sealed_bytes(&held));
+    assert_eq!(info.sha256, attachment::sha256_hex(&held));
+
+    assert_eq!(clients[1].fetch_attachment(&info).await.unwrap(), data);
+    assert_eq!(clients[2].fetch_attachment(&info).await.unwrap(), held);
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_signed_messages_are_verified() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("signed-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let socket_path = server.config().socket_path.clone();
+
+    let identity = Arc::new(identity::Identity::generate());
+    let mut clients = Vec::new();
+    for alias in ["alice", "bob"] {
+        let code = state_manager.create_client(&server.config().id).unwrap();
+        let mut client = connect_local(&socket_path)
+            .await
+            .with_alias(Some(alias.to_string()))
+            .with_identity((alias == "alice").then(|| identity.clone()));
+        client.authenticate(&code.code).await.unwrap();
+        clients.push(client);
+    }
+
+    clients[0].send_message("signed by alice").await.unwrap();
+    clients[1].send_message("unsigned").await.unwrap();
+
+    let received = clients[1].receive(None, None).await.unwrap();
+    assert_eq!(received.len(), 2);
+    let signature = received[0].signature.as_ref().unwrap();
+    assert_eq!(signature.key, identity.public_key());
+    assert!(received[0].verify_signature(Some(&server.config().id)).is_ok());
+    assert!(received[0].verify_signature(Some("another-server")).is_err());
+    assert!(received[1].signature.is_none());
+
+    // A signature made for another sender is refused, even on the same content
+    let origin = identity::Origin {
+        sender: Some("alice"),
+        server: Some(&server.config().id),
+    };
+    let lifted = identity.sign("forged", &message::Audience::Everyone, None, origin);
+    clients[1]
+        .send(&message::ProtocolMessage::Send {
+            content: "forged".to_string(),
+            to: None,
+            channel: None,
+            signature: Some(lifted),
+            attachment: None,
+        })
+        .await
+        .unwrap();
+    loop {
+        let reply = tokio::time::timeout(Duration::from_secs(5), clients[1].recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match reply {
+            message::ProtocolMessage::Broadcast { .. } => continue,
+            reply => {
+                assert!(matches!(reply, message::ProtocolMessage::Error { .. }));
+                break;
+            }
+        }
+    }
+    assert_eq!(clients[0].receive(None, None).await.unwrap().len(), 2);
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_unacknowledged_messages_are_redelivered() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("acks-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let socket_path = server.config().socket_path.clone();
+    let alice_code = state_manager.create_client(&server.config().id).unwrap();
+    let bob_code = state_manager.create_client(&server.config().id).unwrap();
+
+    let mut alice = connect_local(&socket_path).await.with_alias(Some("alice".to_string()));
+    alice.authenticate(&alice_code.code).await.unwrap();
+    let connect_bob = || async {
+        let mut bob = connect_local(&socket_path).await.with_alias(Some("bob".to_string()));
+        bob.authenticate(&bob_code.code).await.unwrap();
+        bob
+    };
+
+    // Bob gets the message but drops the connection before acknowledging it
+    let mut bob = connect_bob().await;
+    let first = alice.send_message("first").await.unwrap();
+    assert_eq!(next_broadcast(&mut bob).await.id, first);
+    drop(bob);
+
+    let deliveries = alice.delivery_status(&first).await.unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0].recipient, bob_code.id);
+    assert_eq!(deliveries[0].alias.as_deref(), Some("bob"));
+    assert!(deliveries[0].acked_at.is_none());
+
+    // So it comes again when he reconnects, and this time he acknowledges it
+    let mut bob = connect_bob().await;
+    assert_eq!(next_broadcast(&mut bob).await.id, first);
+    bob.acknowledge_all([first.as_str()]).await.unwrap();
+    assert!(alice.delivery_status(&first).await.unwrap()[0].acked_at.is_some());
+    drop(bob);
+
+    // Messages sent while he is away come once he is back
+    let second = alice.send_message("second").await.unwrap();
+    assert!(alice.delivery_status(&second).await.unwrap().is_empty());
+    let mut bob = connect_bob().await;
+    assert_eq!(next_broadcast(&mut bob).await.id, second);
+
+    // Only the sender may see who got a message
+    assert!(bob
+        .delivery_status(&first)
+        .await
+        .unwrap_err()
+        .to_string()
+        .contains("Only a message's sender"));
+
+    // Nor can anyone acknowledge a message they could not have seen
+    let carol_code = state_manager.create_client(&server.config().id).unwrap();
+    let mut carol = connect_local(&socket_path).await.with_alias(Some("carol".to_string()));
+    carol.authenticate(&carol_code.code).await.unwrap();
+    let third = alice
+        .send_message_to("for bob", message::Audience::Client("bob".to_string()))
+        .await
+        .unwrap();
+    carol.acknowledge_all([third.as_str()]).await.unwrap();
+    let deliveries = alice.delivery_status(&third).await.unwrap();
+    assert!(deliveries.iter().all(|d| d.recipient != carol_code.id));
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_attachments_are_uploaded_and_fetched() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("files-{}", uuid::Uuid::new_v4());
+    let options = ServerOptions {
+        max_attachment_size: Some(100_000),
+        ..Default::default()
+    };
+    let server = server_manager
+        .create_server_with_options(name.clone(), 5, false, options)
+        .await
+        .unwrap();
+    let socket_path = server.config().socket_path.clone();
+
+    let identity = Arc::new(identity::Identity::generate());
+    let mut clients = Vec::new();
+    for alias in ["alice", "bob", "carol"] {
+        let code = state_manager.create_client(&server.config().id).unwrap();
+        let mut client = connect_local(&socket_path)
+            .await
+            .with_alias(Some(alias.to_string()))
+            .with_identity((alias == "alice").then(|| identity.clone()));
+        client.authenticate(&code.code).await.unwrap();
+        clients.push(client);
+    }
+
+    // Binary data spanning several chunks, sent directly to bob
+    let data: Vec<u8> = (0..attachment::CHUNK_SIZE * 2 + 10).map(|i| (i % 256) as u8).collect();
+    let id = clients[0]
+        .send_attachment("the data", message::Audience::Client("bob".to_string()), "data.bin", &data)
+        .await
+        .unwrap();
+
+    let received = clients[1].receive(None, None).await.unwrap();
+    let message = received.into_iter().find(|m| m.id == id).unwrap();
+    let info = message.attachment.clone().unwrap();
+    assert_eq!((info.name.as_str(), info.size), ("data.bin", data.len() as u64));
+    assert_eq!(message.signature.as_ref().unwrap().key, identity.public_key());
+    assert_eq!(clients[1].fetch_attachment(&info).await.unwrap(), data);
+
+    // Carol cannot see the message, so she cannot fetch its file either
+    let err = clients[2].fetch_attachment(&info).await.unwrap_err();
+    assert!(err.to_string().contains("Unknown or expired attachment"));
+
+    // Files over the server's limit are refused before any data is sent
+    let err = clients[0].upload("big.bin", &vec![0; 100_001]).await.unwrap_err();
+    assert!(err.to_string().contains("too large"));
+
+    // A message can only refer to a file uploaded on the same connection
+    let stored = clients[2].upload("notes.txt", b"carol's notes").await.unwrap();
+    clients[0]
+        .send(&message::ProtocolMessage::Send {
+            content: "not mine".to_string(),
+            to: None,
+            channel: None,
+            signature: None,
+            attachment: Some(stored.id),
+        })
+        .await
+        .unwrap();
+    loop {
+        let reply = tokio::time::timeout(Duration::from_secs(5), clients[0].recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        match reply {
+            message::ProtocolMessage::Broadcast { .. } => continue,
+            message::ProtocolMessage::Error { code, message, .. } => {
+                assert_eq!(code, message::ErrorCode::NotFound);
+                assert!(message.contains("Unknown attachment"));
+                break;
+            }
+            reply => panic!("Unexpected reply: {:?}", reply),
+        }
+    }
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_negotiated_and_legacy_clients_interoperate() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("framing-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let socket_path = server.config().socket_path.clone();
+
+    let mut modern = connect_local(&socket_path).await;
+    modern.negotiate().await.unwrap();
+    assert_eq!(modern.protocol_version(), framing::PROTOCOL_VERSION);
+    assert!(modern.capabilities().iter().any(|c| c == framing::LENGTH_PREFIXED));
+    let code = state_manager.create_client(&server.config().id).unwrap().code;
+    modern.authenticate(&code).await.unwrap();
+
+    // A client that never says hello still speaks newline-delimited JSON
+    let mut legacy = connect_local(&socket_path).await;
+    assert_eq!(legacy.protocol_version(), framing::LEGACY_PROTOCOL_VERSION);
+    let code = state_manager.create_client(&server.config().id).unwrap().code;
+    legacy.authenticate(&code).await.unwrap();
+
+    // Attachment chunks are the largest frames a client sends
+    let data = vec![0xa5; attachment::CHUNK_SIZE + 1];
+    let id = modern
+        .send_attachment("from modern", message::Audience::Everyone, "blob.bin", &data)
+        .await
+        .unwrap();
+    assert_eq!(next_broadcast(&mut legacy).await.id, id);
+    let sent = legacy.send_message("from legacy").await.unwrap();
+    // Senders see their own broadcasts too
+    while next_broadcast(&mut modern).await.id != sent {}
+
+    let received = legacy.receive(None, None).await.unwrap();
+    let info = received[0].attachment.clone().unwrap();
+    assert_eq!(legacy.fetch_attachment(&info).await.unwrap(), data);
+    assert_eq!(modern.fetch_attachment(&info).await.unwrap(), data);
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_late_hello_keeps_the_connection() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("late-hello-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let upstream = connect_socket(&server.config().socket_path).await;
+
+    // Everything the server says is held back past the client's hello timeout,
+    // as a slow Tor circuit might
+    let (client_side, proxy_side) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        let (mut from_client, mut to_client) = tokio::io::split(proxy_side);
+        let (mut from_server, mut to_server) = upstream.into_split();
+        let held_back = async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            tokio::io::copy(&mut from_server, &mut to_client).await
+        };
+        let _ = tokio::join!(tokio::io::copy(&mut from_client, &mut to_server), held_back);
+    });
+
+    let mut client = RemoteClient::new(Box::new(client_side), "late".to_string())
+        .with_hello_timeout(Duration::from_millis(100));
+    client.negotiate().await.unwrap();
+    assert_eq!(client.protocol_version(), framing::LEGACY_PROTOCOL_VERSION);
+
+    // Sent as a line; the answer comes after the server's late hello
+    let code = state_manager.create_client(&server.config().id).unwrap().code;
+    client.authenticate(&code).await.unwrap();
+    assert_eq!(client.protocol_version(), framing::PROTOCOL_VERSION);
+
+    // The server reads the length-prefixed frames that follow
+    let id = client.send_message("after the switch").await.unwrap();
+    let received = client.receive(None, None).await.unwrap();
+    assert!(received.iter().any(|m| m.id == id));
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_uploaded_chunks_are_answered() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    // Far more chunks than the client's outbox holds, which is only as many
+    // answers as the uploader keeps outstanding
+    let name = format!("chunks-{}", uuid::Uuid::new_v4());
+    let options = ServerOptions {
+        client_queue_size: Some(16),
+        ..Default::default()
+    };
+    let server = server_manager
+        .create_server_with_options(name.clone(), 5, false, options)
+        .await
+        .unwrap();
+    let code = state_manager.create_client(&server.config().id).unwrap();
+    let mut client = connect_local(&server.config().socket_path).await;
+    client.negotiate().await.unwrap();
+    assert!(client.capabilities().iter().any(|c| c == framing::CHUNK_ACKS));
+    client.authenticate(&code.code).await.unwrap();
+
+    // Their answers are read as the upload goes, so it is not cut off
+    let data = vec![7; attachment::CHUNK_SIZE * 40];
+    let stored = client.upload("big.bin", &data).await.unwrap();
+    assert_eq!(stored.size, data.len() as u64);
+
+    // Each answer carries its chunk's request ID
+    client
+        .send(&message::ProtocolMessage::AttachmentStart {
+            name: "small.bin".to_string(),
+            size: 1,
+            sha256: attachment::sha256_hex(b"x"),
+        })
+        .await
+        .unwrap();
+    let attachment_id = match client.recv().await.unwrap() {
+        Some(message::ProtocolMessage::AttachmentAccepted { attachment_id }) => attachment_id,
+        other => panic!("Expected the upload to be accepted, got {:?}", other),
+    };
+    let chunk = attachment::chunks(&attachment_id, b"x").next().unwrap();
+    client.send(&chunk).await.unwrap();
+    client.send(&message::ProtocolMessage::Ping).await.unwrap();
+    let reply = client.recv_envelope().await.unwrap().unwrap();
+    assert!(matches!(
+        reply.message,
+        message::ProtocolMessage::AttachmentChunkReceived { index: 0, .. }
+    ));
+    let pong = client.recv_envelope().await.unwrap().unwrap();
+    let id = |envelope: &message::Envelope| envelope.request_id.as_ref().unwrap().parse::<u64>().unwrap();
+    assert_eq!(id(&reply) + 1, id(&pong));
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_slow_consumers_follow_server_policy() {
+    for policy in [outbox::SlowConsumerPolicy::Disconnect, outbox::SlowConsumerPolicy::DropOldest] {
+        let dir = tempdir().unwrap();
+        let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+        let server_manager = ServerManager::new(state_manager.clone());
+
+        let name = format!("slow-{}", uuid::Uuid::new_v4());
+        let options = ServerOptions {
+            slow_consumer_policy: policy,
+            client_queue_size: Some(4),
+            ..Default::default()
+        };
+        let server = server_manager
+            .create_server_with_options(name.clone(), 5, false, options)
+            .await
+            .unwrap();
+        let socket_path = server.config().socket_path.clone();
+
+        let mut clients = Vec::new();
+        for alias in ["sender", "stalled"] {
+            let code = state_manager.create_client(&server.config().id).unwrap();
+            let mut client = connect_local(&socket_path).await.with_alias(Some(alias.to_string()));
+            client.authenticate(&code.code).await.unwrap();
+            clients.push(client);
+        }
+
+        // Far more than the stalled client's socket buffer and queue can hold
+        let content = "x".repeat(32 * 1024);
+        for _ in 0..64 {
+            clients[0].send_message(&content).await.unwrap();
+        }
+
+        let queues = server.client_queues().await;
+        let sender = queues.iter().find(|q| q.name == "sender").unwrap();
+        assert_eq!(sender.metrics.dropped, 0);
+
+        let stalled = queues.iter().find(|q| q.name == "stalled");
+        match policy {
+            outbox::SlowConsumerPolicy::Disconnect => {
+                assert!(stalled.is_none(), "stalled client should have been disconnected");
+
+                // What was already queued still arrives, then the reason
+                let mut last = None;
+                while let Some(frame) = tokio::time::timeout(Duration::from_secs(5), clients[1].recv())
+                    .await
+                    .unwrap()
+                    .unwrap()
+                {
+                    last = Some(frame);
+                }
+                match last {
+                    Some(message::ProtocolMessage::Error { code, message, .. }) => {
+                        assert_eq!(code, message::ErrorCode::SlowConsumer);
+                        assert!(message.contains("not reading"));
+                    }
+                    last => panic!("Expected the reason for the disconnect, got {:?}", last),
+                }
+            }
+            _ => {
+                let stalled = stalled.unwrap();
+                assert!(stalled.metrics.dropped > 0);
+                assert_eq!(stalled.metrics.high_water, 4);
+
+                // Told what it missed, it can get all of it back
+                let gap = loop {
+                    match tokio::time::timeout(Duration::from_secs(5), clients[1].recv())
+                        .await
+                        .unwrap()
+                        .unwrap()
+                    {
+                        Some(message::ProtocolMessage::Error { gap: Some(gap), .. }) => break gap,
+                        Some(_) => continue,
+                        None => panic!("Stalled client was disconnected"),
+                    }
+                };
+                assert_eq!(gap.dropped, stalled.metrics.dropped);
+                let missed = clients[1].receive(Some(gap.since), None).await.unwrap();
+                assert!(missed.len() as u64 >= gap.dropped);
+            }
+        }
+
+        server_manager.stop_server(&name).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_every_request_is_answered() {
+    use tokio::io::AsyncWriteExt;
+
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("requests-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let socket_path = server.config().socket_path.clone();
+    let server_error = |err: anyhow::Error| err.downcast::<remote::ServerError>().unwrap().code;
+
+    // Nothing but authenticating (or pinging) before authenticating
+    let mut client = connect_local(&socket_path).await;
+    let err = client.send_message("too early").await.unwrap_err();
+    assert_eq!(server_error(err), message::ErrorCode::NotAuthenticated);
+    let code = state_manager.create_client(&server.config().id).unwrap().code;
+    client.authenticate(&code).await.unwrap();
+    client.send_message("on time").await.unwrap();
+
+    // Replies carry the request's ID; what cannot be parsed is still answered
+    let stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = framing::FrameReader::new(read_half, framing::MAX_SERVER_FRAME_SIZE);
+    write_half
+        .write_all(b"{\"type\":\"teleport\"}\n{\"type\":\"ping\",\"request_id\":\"p1\"}\n")
+        .await
+        .unwrap();
+    let reply = reader.read_envelope().await.unwrap().unwrap();
+    assert!(matches!(
+        reply.message,
+        message::ProtocolMessage::Error { code: message::ErrorCode::InvalidRequest, .. }
+    ));
+    let reply = reader.read_envelope().await.unwrap().unwrap();
+    assert_eq!(reply.request_id.as_deref(), Some("p1"));
+    assert!(matches!(reply.message, message::ProtocolMessage::Pong));
+
+    // Guessing codes is slowed down, then cut off
+    let mut guesser = connect_local(&socket_path).await;
+    let err = guesser.authenticate("wrong").await.unwrap_err();
+    assert!(err.is::<remote::AuthRejected>());
+    for _ in 0..broker::MAX_AUTH_FAILURES - 1 {
+        let err = guesser.authenticate("wrong").await.unwrap_err();
+        assert_eq!(server_error(err), message::ErrorCode::RateLimited);
+    }
+    match guesser.recv().await.unwrap() {
+        Some(message::ProtocolMessage::Error { code, .. }) => assert_eq!(code, message::ErrorCode::AuthFailed),
+        other => panic!("Expected to be disconnected, got {:?}", other),
+    }
+    assert!(guesser.recv().await.unwrap().is_none());
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+/// Start a server with client limits, and connect alice and bob to it,
+/// returning their codes too
+async fn start_limited_server(
+    state_manager: &Arc<StateManager>,
+    server_manager: &ServerManager,
+    limits: limits::ClientLimits,
+) -> (String, Vec<RemoteClient>, Vec<String>) {
+    let name = format!("limited-{}", uuid::Uuid::new_v4());
+    let options = ServerOptions {
+        limits,
+        ..Default::default()
+    };
+    let server = server_manager
+        .create_server_with_options(name.clone(), 5, false, options)
+        .await
+        .unwrap();
+
+    let mut clients = Vec::new();
+    let mut codes = Vec::new();
+    for alias in ["alice", "bob"] {
+        let code = state_manager.create_client(&server.config().id).unwrap();
+        let mut client = connect_local(&server.config().socket_path)
+            .await
+            .with_alias(Some(alias.to_string()));
+        client.authenticate(&code.code).await.unwrap();
+        clients.push(client);
+        codes.push(code.code);
+    }
+
+    (name, clients, codes)
+}
+
+#[tokio::test]
+async fn test_client_limits_protect_the_queue() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    // Over a quota, the message is refused but the client may carry on
+    let limits = limits::ClientLimits {
+        max_queued_messages: Some(2),
+        max_message_size: Some(100),
+        ..Default::default()
+    };
+    let (name, mut clients, codes) = start_limited_server(&state_manager, &server_manager, limits).await;
+    clients[0].send_message("one").await.unwrap();
+    clients[0].send_message("two").await.unwrap();
+    let err = clients[0].send_message("three").await.unwrap_err();
+    assert!(err.to_string().contains("Too many messages queued"), "{}", err);
+    // Coming back under another alias does not start the quota over
+    let server = server_manager.get_server(&name).await.unwrap();
+    let mut alias = connect_local(&server.config().socket_path)
+        .await
+        .with_alias(Some("not-alice".to_string()));
+    alias.authenticate(&codes[0]).await.unwrap();
+    let err = alias.send_message("three").await.unwrap_err();
+    assert!(err.to_string().contains("Too many messages queued"), "{}", err);
+    let err = clients[1].send_message(&"x".repeat(101)).await.unwrap_err();
+    assert!(err.to_string().contains("too large"), "{}", err);
+    // Alice's share of the queue does not use up bob's
+    clients[1].send_message("bob's turn").await.unwrap();
+    assert_eq!(clients[1].receive(None, None).await.unwrap().len(), 3);
+    server_manager.stop_server(&name).await.unwrap();
+
+    // Sending too fast gets a client muted, whatever it sends next
+    let limits = limits::ClientLimits {
+        messages_per_minute: Some(2),
+        action: limits::LimitAction::Mute,
+        ..Default::default()
+    };
+    let (name, mut clients, _) = start_limited_server(&state_manager, &server_manager, limits).await;
+    clients[0].send_message("one").await.unwrap();
+    clients[0].send_message("two").await.unwrap();
+    let err = clients[0].send_message("three").await.unwrap_err();
+    assert!(err.to_string().contains("muted for 60 seconds"), "{}", err);
+    let err = clients[0].send_message("four").await.unwrap_err();
+    assert!(err.to_string().contains("Muted for"), "{}", err);
+    clients[1].send_message("bob is fine").await.unwrap();
+    server_manager.stop_server(&name).await.unwrap();
+
+    // Or disconnected
+    let limits = limits::ClientLimits {
+        max_message_size: Some(10),
+        action: limits::LimitAction::Disconnect,
+        ..Default::default()
+    };
+    let (name, mut clients, _) = start_limited_server(&state_manager, &server_manager, limits).await;
+    let err = clients[0].send_message("far too long").await.unwrap_err();
+    assert!(err.to_string().contains("too large"), "{}", err);
+    let closed = tokio::time::timeout(Duration::from_secs(5), clients[0].recv()).await.unwrap();
+    assert!(closed.unwrap().is_none());
+    clients[1].send_message("short").await.unwrap();
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_receive_resumes_after_message_id() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("resume-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let client_code = state_manager.create_client(&server.config().id).unwrap();
+
+    let mut client = connect_local(&server.config().socket_path).await;
+    client.authenticate(&client_code.code).await.unwrap();
+
+    let first = client.send_message("one").await.unwrap();
+    client.send_message("two").await.unwrap();
+
+    let all = client.receive(None, None).await.unwrap();
+    assert_eq!(all.len(), 2);
+
+    let rest = client.receive(None, Some(first)).await.unwrap();
+    assert_eq!(rest.len(), 1);
+    assert_eq!(rest[0].content, "two");
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_connect_via_local_broker() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("intro-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+
+    let namespace = format!("{}@example.com", uuid::Uuid::new_v4());
+    let code = handshake::generate_short_code();
+    let broker_handshake = BrokerHandshake::derive(namespace.clone(), code.clone(), name.clone())
+        .await
+        .with_fortress_socket(server.config().socket_path.clone());
+    let broker_socket = broker_handshake.socket_path();
+
+    let broker = server_manager
+        .create_broker(name.clone(), broker_handshake, Duration::from_secs(60), false)
+        .await
+        .unwrap();
+    for _ in 0..50 {
+        if broker_socket.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    // A wrong code finds no broker
+    let wrong = ClientHandshake::new("nobody@example.com".to_string(), "ABC-XYZ".to_string());
+    assert!(intro::discover(&wrong, 1, "client", None, true).await.is_err());
+
+    // The client finds the broker from the namespace and code alone
+    let client_handshake = ClientHandshake::new(namespace, code);
+    let intro = intro::discover(&client_handshake, 1, "client", None, true).await.unwrap();
+    assert_eq!(intro.fortress_address, name);
+    assert_eq!(intro.fortress_socket.as_ref(), Some(&server.config().socket_path));
+
+    // Having introduced a client, the broker shuts itself down
+    tokio::time::timeout(Duration::from_secs(5), broker.stopped()).await.unwrap();
+    let identifier = broker.config().name.strip_prefix("broker-").unwrap();
+    let record = state_manager.get_broker(identifier).unwrap().unwrap();
+    assert_eq!(record.status, storage::BrokerStatus::Used);
+    assert!(!broker_socket.exists());
+    assert!(intro::discover(&client_handshake, 1, "client", None, true).await.is_err());
+
+    // The access token it was given lets it use the fortress
+    let connection = storage::ConnectionConfig {
+        id: uuid::Uuid::new_v4().to_string(),
+        server_name: intro.fortress_address,
+        alias: None,
+        code: intro.access_token,
+        socket_path: intro.fortress_socket,
+        onion_address: None,
+        connected_at: std::time::SystemTime::now(),
+        status: storage::ClientStatus::Connected,
+        secret_key: None,
+        peer_keys: Vec::new(),
+        alias_keys: Default::default(),
+    };
+    let mut client = RemoteClient::connect(&connection).await.unwrap();
+    client.authenticate(&connection.code).await.unwrap();
+    client.send_message("introduced").await.unwrap();
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_broker_stopped_from_another_process() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("stop-broker-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+
+    let namespace = format!("{}@example.com", uuid::Uuid::new_v4());
+    let broker_handshake = BrokerHandshake::derive(namespace, handshake::generate_short_code(), name.clone())
+        .await
+        .with_fortress_socket(server.config().socket_path.clone());
+    let identifier = broker_handshake.identifier();
+
+    let broker = server_manager
+        .create_broker(name.clone(), broker_handshake, Duration::from_secs(60), false)
+        .await
+        .unwrap();
+
+    let listed = state_manager.list_brokers().unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].id, identifier);
+    assert_eq!(listed[0].fortress_id, server.config().id);
+    assert!(listed[0].is_active());
+
+    // Another invocation only shares the state database
+    let other = ServerManager::new(Arc::new(StateManager::new(dir.path()).unwrap()));
+    other.stop_broker(&identifier).await.unwrap();
+
+    tokio::time::timeout(server::BROKER_POLL_INTERVAL * 5, broker.stopped())
+        .await
+        .unwrap();
+    let record = state_manager.get_broker(&identifier).unwrap().unwrap();
+    assert_eq!(record.status, storage::BrokerStatus::Stopped);
+    assert!(other.stop_broker(&identifier).await.is_err());
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_broker_stops_after_wrong_codes() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("guessed-broker-{}", uuid::Uuid::new_v4());
+    server_manager.create_server(name.clone(), 5, false).await.unwrap();
+
+    let namespace = format!("{}@example.com", uuid::Uuid::new_v4());
+    let broker_handshake = BrokerHandshake::derive(namespace.clone(), "ABC-XYZ".to_string(), name.clone()).await;
+    let identifier = broker_handshake.identifier();
+    let broker_socket = broker_handshake.socket_path();
+
+    let broker = server_manager
+        .create_broker(name.clone(), broker_handshake, Duration::from_secs(60), false)
+        .await
+        .unwrap();
+
+    // Someone who found the socket guesses at the code
+    let guess = ClientHandshake::new(namespace, "DEF-123".to_string());
+    for _ in 0..intro::MAX_INTRO_FAILURES {
+        let stream = connect_socket(&broker_socket).await;
+        let err = intro::request_introduction(Box::new(stream), &guess, &identifier, "guesser", None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Invalid code"));
+    }
+
+    tokio::time::timeout(Duration::from_secs(5), broker.stopped()).await.unwrap();
+    let record = state_manager.get_broker(&identifier).unwrap().unwrap();
+    assert_eq!(record.status, storage::BrokerStatus::Stopped);
+    assert!(!broker_socket.exists());
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_broker_expires_after_timeout() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("expire-broker-{}", uuid::Uuid::new_v4());
+    server_manager.create_server(name.clone(), 5, false).await.unwrap();
+
+    let namespace = format!("{}@example.com", uuid::Uuid::new_v4());
+    let broker_handshake = BrokerHandshake::derive(namespace, handshake::generate_short_code(), name.clone()).await;
+    let identifier = broker_handshake.identifier();
+
+    let broker = server_manager
+        .create_broker(name.clone(), broker_handshake, Duration::from_secs(1), false)
+        .await
+        .unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), broker.stopped()).await.unwrap();
+    let record = state_manager.get_broker(&identifier).unwrap().unwrap();
+    assert_eq!(record.status, storage::BrokerStatus::Expired);
+    assert!(!record.is_active());
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_revoked_token_drops_live_connection() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("revoke-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let expires_at = std::time::SystemTime::now() + Duration::from_secs(3600);
+    let client_config = state_manager
+        .create_access_token(&server.config().id, "access-token", expires_at)
+        .unwrap();
+
+    let mut client = connect_local(&server.config().socket_path).await;
+    client.authenticate("access-token").await.unwrap();
+    client.send_message("before").await.unwrap();
+
+    state_manager.revoke_client(&client_config.id).unwrap();
+
+    // The broker notices within its access check interval and drops us
+    tokio::time::sleep(broker::ACCESS_CHECK_INTERVAL * 2).await;
+    assert!(client.send_message("after").await.is_err());
+
+    let mut client = connect_local(&server.config().socket_path).await;
+    assert!(client.authenticate("access-token").await.is_err());
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_revoking_through_the_host_drops_connection_at_once() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("revoke-now-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let expires_at = std::time::SystemTime::now() + Duration::from_secs(3600);
+    let client_config = state_manager
+        .create_access_token(&server.config().id, "access-token", expires_at)
+        .unwrap();
+
+    let mut client = connect_local(&server.config().socket_path).await;
+    client.authenticate("access-token").await.unwrap();
+
+    // Let the broker's periodic check go by, so only the revocation can wake it
+    tokio::time::sleep(broker::ACCESS_CHECK_INTERVAL / 10).await;
+    let revoked = server_manager.revoke_client(&name, "access-token").await.unwrap();
+    assert_eq!(revoked.id, client_config.id);
+
+    let frame = tokio::time::timeout(broker::ACCESS_CHECK_INTERVAL / 2, client.recv())
+        .await
+        .expect("Not dropped before the next periodic check")
+        .unwrap();
+    assert!(matches!(
+        frame,
+        Some(message::ProtocolMessage::Error { code: message::ErrorCode::AccessRevoked, .. })
+    ));
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_expired_token_is_rejected() {
+    let dir = tempdir().unwrap();
+    let state_manager = Arc::new(StateManager::new(dir.path()).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("expired-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let expires_at = std::time::SystemTime::now() - Duration::from_secs(60);
+    state_manager
+        .create_access_token(&server.config().id, "stale-token", expires_at)
+        .unwrap();
+
+    let mut client = connect_local(&server.config().socket_path).await;
+    let err = client.authenticate("stale-token").await.unwrap_err();
+    assert!(err.to_string().contains("Authentication failed"));
+
+    server_manager.stop_server(&name).await.unwrap();
+}
+
+/// Start a daemon over `dir` and wait for its control socket
+async fn start_daemon(dir: &std::path::Path) -> (DaemonClient, tokio::task::JoinHandle<anyhow::Result<()>>) {
+    let state_manager = Arc::new(StateManager::new(dir).unwrap());
+    let socket_path = daemon::control_socket_path(dir);
+
+    let running = tokio::spawn({
+        let socket_path = socket_path.clone();
+        async move { Daemon::new(state_manager).run(&socket_path).await }
+    });
+
+    let client = DaemonClient::new(socket_path);
+    for _ in 0..50 {
+        if client.is_running() {
+            return (client, running);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Daemon never started");
+}
+
+#[tokio::test]
+async fn test_daemon_hosts_servers_across_clients() {
+    let dir = tempdir().unwrap();
+    let (daemon, running) = start_daemon(dir.path()).await;
+
+    let name = format!("daemon-{}", uuid::Uuid::new_v4());
+    let server = daemon.create_server(name.clone(), 5, false, ServerOptions::default()).await.unwrap();
+
+    // A second client (another invocation) sees the same server
+    let other = DaemonClient::new(daemon::control_socket_path(dir.path()));
+    let servers = other.list_servers().await.unwrap();
+    assert!(servers.iter().any(|s| s.name == name && s.status == storage::ServerStatus::Running));
+    assert_eq!(other.status().await.unwrap().servers, vec![name.clone()]);
+
+    // The server is reachable while the daemon hosts it
+    let state_manager = StateManager::new(dir.path()).unwrap();
+    let client_code = state_manager.create_client(&server.id).unwrap();
+    let mut client = connect_local(&server.socket_path).await;
+    client.authenticate(&client_code.code).await.unwrap();
+
+    // Brokers are hosted too, and can be stopped from another client
+    let (broker, code) = daemon
+        .create_broker(name.clone(), "daemon@example.com".to_string(), 60, false)
+        .await
+        .unwrap();
+    // Only the creator learns the code; the record keeps its hash
+    let listed = other.list_brokers().await.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].code_hash, storage::hash_token(&code));
+    other.stop_broker(broker.id.clone()).await.unwrap();
+    let finished = daemon.wait_broker(broker.id).await.unwrap();
+    assert_eq!(finished.status, storage::BrokerStatus::Stopped);
+
+    other.stop_server(name.clone()).await.unwrap();
+    assert!(other.status().await.unwrap().servers.is_empty());
+    let stored = state_manager.get_server(&name).unwrap().unwrap();
+    assert_eq!(stored.status, storage::ServerStatus::Stopped);
+
+    daemon.shutdown().await.unwrap();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_daemon_reconciles_state_on_startup() {
+    let dir = tempdir().unwrap();
+    let (daemon, running) = start_daemon(dir.path()).await;
+
+    let name = format!("restore-{}", uuid::Uuid::new_v4());
+    let server = daemon.create_server(name.clone(), 5, false, ServerOptions::default()).await.unwrap();
+    let state_manager = StateManager::new(dir.path()).unwrap();
+    let client_code = state_manager.create_client(&server.id).unwrap();
+
+    // Stopping the daemon leaves the server recorded as running
+    daemon.shutdown().await.unwrap();
+    running.await.unwrap().unwrap();
+    assert!(tokio::net::UnixStream::connect(&server.socket_path).await.is_err());
+
+    // A broker left behind by a process that is gone
+    let now = std::time::SystemTime::now();
+    state_manager
+        .create_broker(&BrokerConfig {
+            id: "stale-broker".to_string(),
+            fortress_id: server.id.clone(),
+            namespace: "stale@example.com".to_string(),
+            code_hash: storage::hash_token("ABC-XYZ"),
+            socket_path: dir.path().join("stale.sock"),
+            onion_address: None,
+            pid: std::process::id(),
+            created_at: now - Duration::from_secs(300),
+            expires_at: now - Duration::from_secs(180),
+            status: storage::BrokerStatus::Active,
+        })
+        .unwrap();
+
+    // On restart the server comes back with the same ID, so codes still work
+    let (daemon, running) = start_daemon(dir.path()).await;
+    assert_eq!(daemon.status().await.unwrap().servers, vec![name.clone()]);
+    let mut client = connect_local(&server.socket_path).await;
+    client.authenticate(&client_code.code).await.unwrap();
+
+    let stale = state_manager.get_broker("stale-broker").unwrap().unwrap();
+    assert_eq!(stale.status, storage::BrokerStatus::Expired);
+
+    daemon.shutdown().await.unwrap();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_persistent_server_replays_messages_after_restart() {
+    let dir = tempdir().unwrap();
+    let (daemon, running) = start_daemon(dir.path()).await;
+    let state_manager = StateManager::new(dir.path()).unwrap();
+
+    let persistent = ServerOptions {
+        persistent: true,
+        ..Default::default()
+    };
+    let durable = daemon
+        .create_server(format!("durable-{}", uuid::Uuid::new_v4()), 5, false, persistent)
+        .await
+        .unwrap();
+    let volatile = daemon
+        .create_server(format!("volatile-{}", uuid::Uuid::new_v4()), 5, false, ServerOptions::default())
+        .await
+        .unwrap();
+    assert!(durable.persistent);
+
+    let mut codes = Vec::new();
+    for server in [&durable, &volatile] {
+        let code = state_manager.create_client(&server.id).unwrap().code;
+        let mut client = connect_local(&server.socket_path).await;
+        client.authenticate(&code).await.unwrap();
+        client.send_message("first").await.unwrap();
+        client.send_message("second").await.unwrap();
+        codes.push(code);
+    }
+
+    daemon.shutdown().await.unwrap();
+    running.await.unwrap().unwrap();
+
+    // Only the persistent server still has its messages
+    let (daemon, running) = start_daemon(dir.path()).await;
+    let mut received = Vec::new();
+    for (server, code) in [&durable, &volatile].into_iter().zip(&codes) {
+        let mut client = connect_local(&server.socket_path).await;
+        client.authenticate(code).await.unwrap();
+        let messages = client.receive(None, None).await.unwrap();
+        received.push(messages.into_iter().map(|m| m.content).collect::<Vec<_>>());
+    }
+    assert_eq!(received[0], ["first", "second"]);
+    assert!(received[1].is_empty());
+
+    daemon.shutdown().await.unwrap();
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+#[ignore] // Spawns the eddi-msgsrv binary, which forks a background listener
+async fn test_background_listener_runs_hook_and_stops() {
+    let home = tempdir().unwrap();
+    let state_dir = home.path().join(".eddi").join("msgservers");
+    let state_manager = Arc::new(StateManager::new(&state_dir).unwrap());
+    let server_manager = ServerManager::new(state_manager.clone());
+
+    let name = format!("background-{}", uuid::Uuid::new_v4());
+    let server = server_manager.create_server(name.clone(), 5, false).await.unwrap();
+    let client_code = state_manager.create_client(&server.config().id).unwrap();
+    state_manager
+        .create_connection(storage::ConnectionConfig {
+            id: uuid::Uuid::new_v4().to_string(),
+            server_name: name.clone(),
+            alias: None,
+            code: client_code.code.clone(),
+            socket_path: Some(server.config().socket_path.clone()),
+            onion_address: None,
+            connected_at: std::time::SystemTime::now(),
+            status: storage::ClientStatus::Connected,
+            secret_key: None,
+            peer_keys: Vec::new(),
+            alias_keys: Default::default(),
+        })
+        .unwrap();
+
+    let msgsrv = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_eddi-msgsrv"))
+            .args(args)
+            .env("HOME", home.path())
+            .output()
+            .unwrap()
+    };
+
+    let hook_out = home.path().join("hook.out");
+    let hook = format!("cat >> {}", hook_out.display());
+    let output = msgsrv(&["listen", "--server", &name, "--background", "--hook", &hook]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("PID"));
+
+    let paths = detach::ListenerPaths::new(&state_dir, &name);
+    assert!(detach::running_pid(&paths.pidfile).unwrap().is_some());
+
+    let mut sender = connect_local(&server.config().socket_path).await;
+    sender.authenticate(&client_code.code).await.unwrap();
+    sender.send_message("from the background").await.unwrap();
+
+    let mut delivered = false;
+    for _ in 0..100 {
+        if std::fs::read_to_string(&hook_out).is_ok_and(|out| out.contains("from the background")) {
+            delivered = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(delivered, "hook never ran");
+
+    let output = msgsrv(&["listen", "--server", &name, "--status"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("running (PID"));
+
+    let output = msgsrv(&["listen", "--server", &name, "--stop"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!paths.pidfile.exists());
+    assert!(std::fs::read_to_string(&paths.log_file).unwrap().contains("from the background"));
+
+    server_manager.stop_server(&name).await.unwrap();
+}
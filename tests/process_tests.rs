@@ -4,7 +4,7 @@
 
 mod test_utils;
 
-use eddi::{ChildProcessManager, ProcessConfig};
+use eddi::{ChildProcessManager, OutputStream, ProcessConfig};
 use std::path::PathBuf;
 use test_utils::*;
 
@@ -73,6 +73,7 @@ fn test_process_config_custom() {
             "--uds".to_string(),
             socket_path.to_string_lossy().to_string(),
         ],
+        ..Default::default()
     };
 
     assert_eq!(config.command, "uvicorn");
@@ -126,6 +127,7 @@ fn test_spawn_echo_process() {
         app_dir: PathBuf::from("/tmp"),
         command: "sleep".to_string(),
         args: vec!["0.1".to_string()],
+        ..Default::default()
     };
 
     let result = ChildProcessManager::spawn(&config);
@@ -147,6 +149,7 @@ fn test_spawn_nonexistent_command() {
         app_dir: PathBuf::from("/tmp"),
         command: "this-command-does-not-exist-123456".to_string(),
         args: vec![],
+        ..Default::default()
     };
 
     let result = ChildProcessManager::spawn(&config);
@@ -167,6 +170,7 @@ fn test_process_manager_pid() {
         app_dir: PathBuf::from("/tmp"),
         command: "sleep".to_string(),
         args: vec!["1".to_string()],
+        ..Default::default()
     };
 
     let manager = ChildProcessManager::spawn(&config)
@@ -197,6 +201,7 @@ fn test_socket_path_getter() {
         app_dir: PathBuf::from("/tmp"),
         command: "sleep".to_string(),
         args: vec!["0.5".to_string()],
+        ..Default::default()
     };
 
     let manager = ChildProcessManager::spawn(&config)
@@ -213,8 +218,8 @@ fn test_cleanup_on_drop() {
     let socket_path = temp_socket_path();
     cleanup_socket(&socket_path);
 
-    // Create a socket file manually
-    std::fs::write(&socket_path, b"").expect("Create socket file");
+    // Leave a stale socket behind, as a crashed app would
+    drop(std::os::unix::net::UnixListener::bind(&socket_path).expect("Create socket file"));
     assert!(socket_path.exists());
 
     {
@@ -223,6 +228,7 @@ fn test_cleanup_on_drop() {
             app_dir: PathBuf::from("/tmp"),
             command: "sleep".to_string(),
             args: vec!["0.1".to_string()],
+            ..Default::default()
         };
 
         let manager = ChildProcessManager::spawn(&config)
@@ -239,3 +245,243 @@ fn test_cleanup_on_drop() {
     // Socket should be cleaned up
     assert!(!socket_path.exists(), "Socket should be cleaned up on drop");
 }
+
+#[test]
+#[ignore]
+fn test_child_output_captured() {
+    let socket_path = temp_socket_path();
+    cleanup_socket(&socket_path);
+
+    let config = ProcessConfig {
+        socket_path: socket_path.clone(),
+        app_dir: PathBuf::from("/tmp"),
+        command: "sh".to_string(),
+        args: vec!["-c".to_string(), "echo hello; echo oops >&2".to_string()],
+        ..Default::default()
+    };
+
+    let manager = ChildProcessManager::spawn(&config)
+        .expect("Should spawn process");
+
+    // Give the reader threads a moment to drain the pipes
+    std::thread::sleep(std::time::Duration::from_millis(300));
+
+    let lines = manager.recent_output();
+    assert!(lines.iter().any(|l| l.stream == OutputStream::Stdout && l.line == "hello"));
+    assert!(lines.iter().any(|l| l.stream == OutputStream::Stderr && l.line == "oops"));
+
+    cleanup_socket(&socket_path);
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_supervisor_restarts_exited_child() {
+    if !python_available() {
+        eprintln!("Skipping: python3 not available");
+        return;
+    }
+
+    let dir = temp_dir();
+    let socket_path = dir.path().join("app.sock");
+
+    // Binds the socket, then exits after a short while
+    let script = format!(
+        "import socket, time; s = socket.socket(socket.AF_UNIX); s.bind('{}'); s.listen(); time.sleep(0.5)",
+        socket_path.display()
+    );
+    let config = ProcessConfig {
+        socket_path: socket_path.clone(),
+        app_dir: dir.path().to_path_buf(),
+        command: "python3".to_string(),
+        args: vec!["-c".to_string(), script],
+        ..Default::default()
+    };
+
+    let supervisor = eddi::Supervisor::start(config, 5, 1)
+        .await
+        .expect("Child should become ready");
+    let first_pid = supervisor.child().expect("Child should be running").pid();
+    assert!(first_pid > 0);
+
+    // One restart is allowed, so the second exit ends supervision
+    let result = tokio::time::timeout(std::time::Duration::from_secs(15), supervisor.run()).await;
+    let err = result.expect("Supervisor should give up").unwrap_err();
+    assert!(err.to_string().contains("maximum restarts"));
+}
+
+#[test]
+fn test_spawn_refuses_live_socket() {
+    let dir = temp_dir();
+    let socket_path = dir.path().join("live.sock");
+    let _listener = std::os::unix::net::UnixListener::bind(&socket_path)
+        .expect("Bind socket");
+
+    let config = ProcessConfig {
+        socket_path: socket_path.clone(),
+        app_dir: dir.path().to_path_buf(),
+        command: "true".to_string(),
+        ..Default::default()
+    };
+
+    let result = ChildProcessManager::spawn(&config);
+
+    assert!(result.is_err(), "Should refuse to take over a live socket");
+    assert!(socket_path.exists(), "Live socket must not be removed");
+}
+
+#[test]
+fn test_spawn_refuses_non_socket_file() {
+    let dir = temp_dir();
+    let socket_path = dir.path().join("not-a-socket");
+    std::fs::write(&socket_path, b"data").expect("Create file");
+
+    let config = ProcessConfig {
+        socket_path: socket_path.clone(),
+        app_dir: dir.path().to_path_buf(),
+        command: "true".to_string(),
+        ..Default::default()
+    };
+
+    assert!(ChildProcessManager::spawn(&config).is_err());
+    assert_eq!(std::fs::read(&socket_path).unwrap(), b"data");
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_supervisor_reload_switches_backend() {
+    if !python_available() {
+        eprintln!("Skipping: python3 not available");
+        return;
+    }
+
+    let dir = temp_dir();
+    let socket_path = dir.path().join("app.sock");
+
+    // Serves forever on the socket passed as its last argument
+    let script = "import socket, sys, time; s = socket.socket(socket.AF_UNIX); \
+                  s.bind(sys.argv[1]); s.listen(); time.sleep(60)";
+    let config = ProcessConfig {
+        socket_path: socket_path.clone(),
+        app_dir: dir.path().to_path_buf(),
+        command: "python3".to_string(),
+        args: vec![
+            "-c".to_string(),
+            script.to_string(),
+            socket_path.to_string_lossy().to_string(),
+        ],
+        ..Default::default()
+    };
+
+    let mut supervisor = eddi::Supervisor::start(config, 5, 0)
+        .await
+        .expect("Child should become ready");
+    supervisor.set_drain_timeout(std::time::Duration::from_millis(100));
+
+    let backend = supervisor.backend();
+    let reload = supervisor.reload_handle();
+    let run = tokio::spawn(supervisor.run());
+
+    assert_eq!(backend.borrow().socket_path(), socket_path.as_path());
+
+    reload.reload().await.expect("Reload should succeed");
+
+    let new_path = dir.path().join("app.sock.1");
+    assert_eq!(backend.borrow().socket_path(), new_path.as_path());
+    assert!(std::os::unix::net::UnixStream::connect(&new_path).is_ok());
+
+    // The old child is stopped and its socket removed after draining
+    let drained = wait_for(|| !socket_path.exists(), 15).await;
+    assert!(drained, "Old socket should be removed after drain");
+
+    run.abort();
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_supervisor_restarts_child_that_fails_during_reload() {
+    if !python_available() {
+        eprintln!("Skipping: python3 not available");
+        return;
+    }
+
+    let dir = temp_dir();
+    let socket_path = dir.path().join("app.sock");
+
+    // The first generation serves briefly then exits; the reloaded one
+    // never becomes ready
+    let script = "import socket, sys, time\n\
+                  if sys.argv[1].endswith('.1'): time.sleep(60)\n\
+                  s = socket.socket(socket.AF_UNIX); s.bind(sys.argv[1]); s.listen(); time.sleep(1)";
+    let config = ProcessConfig {
+        socket_path: socket_path.clone(),
+        app_dir: dir.path().to_path_buf(),
+        command: "python3".to_string(),
+        args: vec![
+            "-c".to_string(),
+            script.to_string(),
+            socket_path.to_string_lossy().to_string(),
+        ],
+        ..Default::default()
+    };
+
+    let supervisor = eddi::Supervisor::start(config, 30, 0)
+        .await
+        .expect("Child should become ready");
+    let reload = supervisor.reload_handle();
+    let run = tokio::spawn(supervisor.run());
+
+    // The old child's exit ends the reload long before the new child's
+    // ready timeout, and goes through the restart path
+    let result = tokio::time::timeout(std::time::Duration::from_secs(10), reload.reload())
+        .await
+        .expect("Reload should end when the current child exits");
+    assert!(result.is_err());
+
+    let err = tokio::time::timeout(std::time::Duration::from_secs(10), run)
+        .await
+        .expect("Supervisor should give up")
+        .unwrap()
+        .unwrap_err();
+    assert!(err.to_string().contains("maximum restarts"));
+}
+
+#[tokio::test]
+#[ignore]
+async fn test_socket_activation_passes_listener() {
+    if !python_available() {
+        eprintln!("Skipping: python3 not available");
+        return;
+    }
+
+    let dir = temp_dir();
+    let socket_path = dir.path().join("app.sock");
+
+    // Serves on the inherited fd only when LISTEN_PID names this process
+    let script = "import os, socket; \
+                  assert os.environ['LISTEN_FDS'] == '1'; \
+                  assert os.environ['LISTEN_PID'] == str(os.getpid()); \
+                  s = socket.socket(fileno=3)\n\
+                  while True:\n    c, _ = s.accept(); c.sendall(b'activated'); c.close()";
+    let config = ProcessConfig {
+        socket_path: socket_path.clone(),
+        app_dir: dir.path().to_path_buf(),
+        command: "python3".to_string(),
+        args: vec!["-c".to_string(), script.to_string()],
+        socket_activation: true,
+        ..Default::default()
+    };
+
+    let manager = ChildProcessManager::spawn(&config).expect("Failed to spawn");
+
+    // No waiting for the child: eddi bound the socket before spawning
+    assert!(socket_path.exists());
+    assert!(manager.activated_socket().is_some());
+
+    let mut stream = std::os::unix::net::UnixStream::connect(&socket_path).unwrap();
+    let mut reply = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut reply).unwrap();
+    assert_eq!(reply, "activated");
+
+    drop(manager);
+    assert!(!socket_path.exists(), "Socket should be removed with its last owner");
+}
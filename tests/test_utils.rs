@@ -22,7 +22,7 @@ pub fn create_mock_flask_app(dir: &std::path::Path) -> PathBuf {
 
     writeln!(file, "from flask import Flask").unwrap();
     writeln!(file, "app = Flask(__name__)").unwrap();
-    writeln!(file, "").unwrap();
+    writeln!(file).unwrap();
     writeln!(file, "@app.route('/')").unwrap();
     writeln!(file, "def index():").unwrap();
     writeln!(file, "    return 'Test OK'").unwrap();
@@ -93,6 +93,7 @@ pub fn mock_process_config(socket_path: PathBuf) -> eddi::ProcessConfig {
         app_dir: PathBuf::from("/tmp"),
         command: "echo".to_string(),
         args: vec!["test".to_string()],
+        ..Default::default()
     }
 }
 
@@ -5,7 +5,7 @@ use std::process::Command;
 #[test]
 fn test_tor_check_compiles() {
     let output = Command::new("cargo")
-        .args(&["build", "--bin", "tor-check"])
+        .args(["build", "--bin", "tor-check"])
         .output()
         .expect("Should run cargo build");
 
@@ -16,7 +16,7 @@ fn test_tor_check_compiles() {
 fn test_tor_check_has_version() {
     // Verify the binary has version information
     let output = Command::new("cargo")
-        .args(&["run", "--bin", "tor-check"])
+        .args(["run", "--bin", "tor-check"])
         .env("RUST_LOG", "info")
         .output();
 
@@ -31,7 +31,7 @@ fn test_tor_check_has_version() {
 #[ignore] // Requires running the binary
 fn test_tor_check_output_format() {
     let output = Command::new("cargo")
-        .args(&["run", "--bin", "tor-check"])
+        .args(["run", "--bin", "tor-check"])
         .env("RUST_LOG", "info")
         .output()
         .expect("Should run tor-check");
@@ -63,7 +63,7 @@ fn test_tor_check_output_format() {
 #[ignore]
 fn test_tor_check_exit_codes() {
     let output = Command::new("cargo")
-        .args(&["run", "--bin", "tor-check"])
+        .args(["run", "--bin", "tor-check"])
         .env("RUST_LOG", "error") // Reduce output
         .output()
         .expect("Should run tor-check");
@@ -83,7 +83,7 @@ fn test_tor_check_exit_codes() {
 fn test_tor_check_handles_no_network() {
     // In a sandboxed environment, should fail gracefully
     let output = Command::new("cargo")
-        .args(&["run", "--bin", "tor-check"])
+        .args(["run", "--bin", "tor-check"])
         .env("RUST_LOG", "warn")
         .output()
         .expect("Should run tor-check");
@@ -102,14 +102,14 @@ fn test_tor_check_handles_no_network() {
 fn test_tor_check_respects_rust_log() {
     // Test with error level - should have minimal output
     let output1 = Command::new("cargo")
-        .args(&["run", "--bin", "tor-check"])
+        .args(["run", "--bin", "tor-check"])
         .env("RUST_LOG", "error")
         .output()
         .expect("Should run");
 
     // Test with debug level - should have more output
     let output2 = Command::new("cargo")
-        .args(&["run", "--bin", "tor-check"])
+        .args(["run", "--bin", "tor-check"])
         .env("RUST_LOG", "debug")
         .output()
         .expect("Should run");
@@ -132,7 +132,7 @@ async fn test_tor_check_timeout_behavior() {
     // tor-check should complete within reasonable time even on failure
     let result = timeout(Duration::from_secs(70), async {
         let output = Command::new("cargo")
-            .args(&["run", "--bin", "tor-check"])
+            .args(["run", "--bin", "tor-check"])
             .env("RUST_LOG", "error")
             .output()
             .expect("Should run");